@@ -21,7 +21,10 @@ use tokio::sync::Notify;
 use tokio::time::timeout;
 use tracing::{debug, warn};
 
-use capture::config::{Config, KafkaConfig};
+use capture::config::{
+    Config, EventPartitionStrategies, KafkaConfig, PartitionKeyOverrides, PartitionStrategy,
+    ProcessingConfig,
+};
 use capture::server::serve;
 
 pub static DEFAULT_CONFIG: Lazy<Config> = Lazy::new(|| Config {
@@ -31,6 +34,9 @@ pub static DEFAULT_CONFIG: Lazy<Config> = Lazy::new(|| Config {
     burst_limit: NonZeroU32::new(5).unwrap(),
     per_second_limit: NonZeroU32::new(10).unwrap(),
     overflow_forced_keys: None,
+    partition_key_overrides: PartitionKeyOverrides::default(),
+    partition_strategy: PartitionStrategy::default(),
+    event_partition_strategies: EventPartitionStrategies::default(),
     kafka: KafkaConfig {
         kafka_producer_linger_ms: 0, // Send messages as soon as possible
         kafka_producer_queue_mib: 10,
@@ -40,6 +46,7 @@ pub static DEFAULT_CONFIG: Lazy<Config> = Lazy::new(|| Config {
         kafka_topic: "events_plugin_ingestion".to_string(),
         kafka_tls: false,
     },
+    processing: ProcessingConfig::default(),
     otel_url: None,
     otel_sampling_rate: 0.0,
     export_prometheus: false,
@@ -1,10 +1,16 @@
+use crate::config::ProcessingConfig;
+use crate::decompression::Codec;
 use crate::token::InvalidTokenReason;
-use axum::http::StatusCode;
+use axum::http::header::{CONTENT_ENCODING, CONTENT_TYPE, RETRY_AFTER, VARY};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Response};
+use flate2::write::GzEncoder;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
+use std::io::Write;
 use thiserror::Error;
+use uuid::Uuid;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct CaptureRequest {
@@ -25,29 +31,138 @@ pub struct CaptureResponse {
     pub status: CaptureResponseCode,
 }
 
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AckStatus {
+    Accepted,
+    Rejected,
+}
+
+/// Per-event acknowledgement, letting clients retry only the rejected events.
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Ack {
+    pub uuid: Option<Uuid>,
+    pub status: AckStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Event rejected by lenient processing, for sinks to keep instead of dropping it.
+#[derive(Debug)]
+pub struct DeadLetter {
+    /// The event as sent, before any normalization
+    pub raw: Value,
+    pub reason: CaptureError,
+    /// Time the request was received at, see `ProcessingContext::now`
+    pub received_at: String,
+}
+
+/// Outcome of the dry-run validation of an event, see `capture::validate_only`.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct EventValidation {
+    pub index: usize,
+    pub valid: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Serialize acknowledgements as newline-delimited JSON.
+pub fn serialize_acks(acks: &[Ack]) -> String {
+    acks.iter()
+        .map(|ack| serde_json::to_string(ack).expect("failed to serialize ack") + "\n")
+        .collect()
+}
+
 #[derive(Error, Debug)]
 pub enum CaptureError {
     #[error("failed to decode request: {0}")]
     RequestDecodingError(String),
     #[error("failed to decode request: {0}")]
     RequestParsingError(#[from] serde_json::Error),
+    #[error("failed to decode request: invalid {0} data")]
+    CorruptCompressedData(Codec),
+    #[error("failed to decode request: invalid {0} encoding")]
+    InvalidBase64(&'static str),
+    #[error("failed to decode request: invalid {0} encoding")]
+    InvalidTextEncoding(&'static str),
+    #[error("failed to decode request: event tuple {0} is not an [event, properties] pair")]
+    MalformedEventTuple(usize),
+    #[error("request took too long to decompress")]
+    DecompressionTimeout,
+    #[error("request exceeds the maximum decompressed size")]
+    DecompressedTooLarge,
+    #[error("request body is truncated")]
+    TruncatedRequestBody,
+    #[error("duplicate key in request: {0}")]
+    DuplicateJsonKey(String),
+    #[error("request holds NaN or Infinity numbers")]
+    NonFiniteNumber,
+    #[error("request holds lone surrogate escapes")]
+    InvalidUnicode,
 
     #[error("request holds no event")]
     EmptyBatch,
     #[error("event submitted with an empty event name")]
     MissingEventName,
+    #[error("event name exceeds the maximum length")]
+    EventNameTooLong,
     #[error("event submitted without a distinct_id")]
     MissingDistinctId,
+    #[error("event submitted with too many $distinct_ids")]
+    TooManyDistinctIds,
+    #[error("event properties are nested too deeply")]
+    PropertiesTooDeep,
+    #[error("event property {0} is out of its allowed range")]
+    PropertyOutOfRange(String),
+    #[error("$merge_dangerously event merging a distinct_id with itself")]
+    InvalidMerge,
+    #[error("event submitted with an invalid offset")]
+    InvalidOffset,
+    #[error("invalid X-PostHog-Event-Time header")]
+    InvalidEventTime,
+    #[error("request sent_at is too far in the future")]
+    InvalidSentAt,
+    #[error("events cannot be ingested at this time, please retry later")]
+    OutsideIngestWindow,
+    #[error("event submitted with a uuid of a disallowed version")]
+    DisallowedUuidVersion,
+    #[error("event submitted by an unsupported {lib} version: {version}")]
+    UnsupportedSdkVersion { lib: String, version: String },
+    #[error("event {event} does not match its schema: {details}")]
+    SchemaViolation { event: String, details: String },
+    #[error("event submitted with property {0} missing from the project allowlist")]
+    UnknownProperty(String),
+    #[error("batch submitted with too many distinct property keys")]
+    TooManyDistinctProperties,
 
     #[error("event submitted without an api_key")]
     NoTokenError,
+    #[error("no api_key found in the event body, the Authorization header or the default token")]
+    MissingToken,
     #[error("batch submitted with inconsistent api_key values")]
     MultipleTokensError,
+    #[error("event submitted as a distinct_id the api_key is not permitted to send as")]
+    OwnershipViolation,
+    #[error("batch submitted with too many distinct api_key values")]
+    TooManyTokens,
+    #[error("events from this origin are not allowed for the api_key")]
+    DisallowedOrigin,
+    #[error("api_key in the Authorization header and body differ")]
+    TokenMismatch,
+    #[error("API key is disabled or unknown")]
+    DisabledToken,
     #[error("API key is not valid: {0}")]
     TokenValidationError(#[from] InvalidTokenReason),
 
+    #[error("request body too large")]
+    RequestTooLarge,
+
     #[error("transient error, please retry")]
     RetryableSinkError,
+    #[error("sink is overloaded, please retry later")]
+    SinkBackpressure,
     #[error("maximum event size exceeded")]
     EventTooBig,
     #[error("invalid event could not be processed")]
@@ -58,29 +173,248 @@ pub enum CaptureError {
 
     #[error("rate limited")]
     RateLimited,
+    #[error("too many concurrent requests for this token, retry in {retry_after} seconds")]
+    TooManyConcurrentRequests { retry_after: u64 },
 }
 
 impl IntoResponse for CaptureError {
     fn into_response(self) -> Response {
+        if let CaptureError::TooManyConcurrentRequests { retry_after } = self {
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(RETRY_AFTER, retry_after.to_string())],
+                self.to_string(),
+            )
+                .into_response();
+        }
         match self {
             CaptureError::RequestDecodingError(_)
             | CaptureError::RequestParsingError(_)
+            | CaptureError::CorruptCompressedData(_)
+            | CaptureError::InvalidBase64(_)
+            | CaptureError::InvalidTextEncoding(_)
+            | CaptureError::MalformedEventTuple(_)
+            | CaptureError::DecompressionTimeout
+            | CaptureError::DecompressedTooLarge
+            | CaptureError::TruncatedRequestBody
+            | CaptureError::DuplicateJsonKey(_)
+            | CaptureError::NonFiniteNumber
+            | CaptureError::InvalidUnicode
             | CaptureError::EmptyBatch
             | CaptureError::MissingEventName
+            | CaptureError::EventNameTooLong
             | CaptureError::MissingDistinctId
+            | CaptureError::TooManyDistinctIds
+            | CaptureError::PropertiesTooDeep
+            | CaptureError::PropertyOutOfRange(_)
+            | CaptureError::InvalidMerge
+            | CaptureError::InvalidOffset
+            | CaptureError::InvalidEventTime
+            | CaptureError::InvalidSentAt
+            | CaptureError::DisallowedUuidVersion
+            | CaptureError::UnsupportedSdkVersion { .. }
+            | CaptureError::SchemaViolation { .. }
+            | CaptureError::UnknownProperty(_)
+            | CaptureError::TooManyDistinctProperties
             | CaptureError::EventTooBig
             | CaptureError::NonRetryableSinkError => (StatusCode::BAD_REQUEST, self.to_string()),
 
             CaptureError::NoTokenError
+            | CaptureError::MissingToken
             | CaptureError::MultipleTokensError
+            | CaptureError::OwnershipViolation
+            | CaptureError::DisallowedOrigin
+            | CaptureError::TooManyTokens
+            | CaptureError::TokenMismatch
+            | CaptureError::DisabledToken
             | CaptureError::TokenValidationError(_) => (StatusCode::UNAUTHORIZED, self.to_string()),
 
-            CaptureError::RetryableSinkError => (StatusCode::SERVICE_UNAVAILABLE, self.to_string()),
+            CaptureError::RetryableSinkError
+            | CaptureError::SinkBackpressure
+            | CaptureError::OutsideIngestWindow => {
+                (StatusCode::SERVICE_UNAVAILABLE, self.to_string())
+            }
+
+            CaptureError::RequestTooLarge => (StatusCode::PAYLOAD_TOO_LARGE, self.to_string()),
 
-            CaptureError::BillingLimit | CaptureError::RateLimited => {
+            CaptureError::BillingLimit
+            | CaptureError::RateLimited
+            | CaptureError::TooManyConcurrentRequests { .. } => {
                 (StatusCode::TOO_MANY_REQUESTS, self.to_string())
             }
         }
         .into_response()
     }
 }
+
+/// Returns whether an Accept-Encoding header value allows gzip, honoring `q=0` exclusions.
+fn accepts_gzip(accept_encoding: &str) -> bool {
+    accept_encoding.split(',').any(|coding| {
+        let mut parts = coding.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        let excluded = parts
+            .any(|param| param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0));
+        (name.eq_ignore_ascii_case("gzip") || name == "*") && !excluded
+    })
+}
+
+/// Gzip a response body if the client accepts it and the body is larger than the configured
+/// threshold. The returned headers and body can be used as an axum response.
+pub fn compress_response(
+    accept_encoding: Option<&str>,
+    body: Vec<u8>,
+    config: &ProcessingConfig,
+) -> (HeaderMap, Vec<u8>) {
+    let mut headers = HeaderMap::new();
+    headers.insert(VARY, HeaderValue::from_static("accept-encoding"));
+
+    if body.len() < config.response_compression_min_bytes
+        || !accept_encoding.is_some_and(accepts_gzip)
+    {
+        return (headers, body);
+    }
+
+    let level = flate2::Compression::new(config.response_compression_level);
+    let mut encoder = GzEncoder::new(Vec::with_capacity(body.len() / 4), level);
+    match encoder.write_all(&body).and_then(|_| encoder.finish()) {
+        Ok(compressed) => {
+            headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+            (headers, compressed)
+        }
+        Err(e) => {
+            tracing::error!("failed to compress response: {}", e);
+            (headers, body)
+        }
+    }
+}
+
+/// Newline-delimited JSON response listing the acks of a batch, gzipped like other responses
+/// when it is large enough: a few acks are not worth compressing, but those of large batches
+/// are.
+pub fn ack_response(
+    accept_encoding: Option<&str>,
+    acks: &[Ack],
+    config: &ProcessingConfig,
+) -> (HeaderMap, Vec<u8>) {
+    let (mut headers, body) =
+        compress_response(accept_encoding, serialize_acks(acks).into_bytes(), config);
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/x-ndjson"),
+    );
+    (headers, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use axum::http::header::{CONTENT_ENCODING, CONTENT_TYPE};
+    use flate2::read::GzDecoder;
+
+    use crate::api::{ack_response, compress_response, serialize_acks, Ack, AckStatus};
+    use crate::config::ProcessingConfig;
+    use crate::utils::uuid_v7;
+
+    #[test]
+    fn compresses_large_responses() {
+        let config = ProcessingConfig::default();
+        let body = "a"
+            .repeat(config.response_compression_min_bytes * 2)
+            .into_bytes();
+
+        let (headers, compressed) =
+            compress_response(Some("br, gzip;q=0.8"), body.clone(), &config);
+        assert_eq!(headers[CONTENT_ENCODING], "gzip");
+        assert!(compressed.len() < body.len());
+
+        let mut decompressed = Vec::new();
+        GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decompressed)
+            .expect("invalid gzip response");
+        assert_eq!(decompressed, body);
+    }
+
+    #[test]
+    fn does_not_compress_small_or_unaccepted_responses() {
+        let config = ProcessingConfig::default();
+        let small = b"{\"status\":1}".to_vec();
+        let (headers, body) = compress_response(Some("gzip"), small.clone(), &config);
+        assert!(headers.get(CONTENT_ENCODING).is_none());
+        assert_eq!(body, small);
+
+        let large = "a"
+            .repeat(config.response_compression_min_bytes * 2)
+            .into_bytes();
+        for accept_encoding in [None, Some("br"), Some("gzip;q=0")] {
+            let (headers, body) = compress_response(accept_encoding, large.clone(), &config);
+            assert!(headers.get(CONTENT_ENCODING).is_none());
+            assert_eq!(body, large);
+        }
+    }
+
+    #[test]
+    fn serializes_acks() {
+        let uuid = uuid_v7();
+        let acks = vec![
+            Ack {
+                uuid: Some(uuid),
+                status: AckStatus::Accepted,
+                reason: None,
+            },
+            Ack {
+                uuid: None,
+                status: AckStatus::Rejected,
+                reason: Some(String::from("event submitted without a distinct_id")),
+            },
+        ];
+
+        let serialized = serialize_acks(&acks);
+        assert_eq!(
+            serialized,
+            format!(
+                "{{\"uuid\":\"{}\",\"status\":\"accepted\"}}\n\
+                 {{\"uuid\":null,\"status\":\"rejected\",\"reason\":\"event submitted without a distinct_id\"}}\n",
+                uuid
+            )
+        );
+        let parsed: Vec<Ack> = serialized
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(parsed, acks);
+    }
+
+    fn accepted_acks(count: usize) -> Vec<Ack> {
+        (0..count)
+            .map(|_| Ack {
+                uuid: Some(uuid_v7()),
+                status: AckStatus::Accepted,
+                reason: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn small_ack_response_is_not_compressed() {
+        let acks = accepted_acks(2);
+        let (headers, body) = ack_response(Some("gzip"), &acks, &ProcessingConfig::default());
+        assert_eq!(headers[CONTENT_TYPE], "application/x-ndjson");
+        assert!(headers.get(CONTENT_ENCODING).is_none());
+        assert_eq!(body, serialize_acks(&acks).into_bytes());
+    }
+
+    #[test]
+    fn large_ack_response_is_compressed() {
+        let acks = accepted_acks(100);
+        let (headers, body) = ack_response(Some("gzip"), &acks, &ProcessingConfig::default());
+        assert_eq!(headers[CONTENT_TYPE], "application/x-ndjson");
+        assert_eq!(headers[CONTENT_ENCODING], "gzip");
+
+        let mut decompressed = String::new();
+        GzDecoder::new(body.as_slice())
+            .read_to_string(&mut decompressed)
+            .expect("invalid gzip response");
+        assert_eq!(decompressed, serialize_acks(&acks));
+    }
+}
@@ -178,18 +178,16 @@ mod tests {
         let limiter = BillingLimiter::new(Duration::microseconds(1), client)
             .expect("Failed to create billing limiter");
 
-        assert_eq!(
-            limiter
+        assert!(
+            !limiter
                 .is_limited("idk it doesn't matter", QuotaResource::Events)
-                .await,
-            false
+                .await
         );
 
-        assert_eq!(
-            limiter
+        assert!(
+            !limiter
                 .is_limited("some_org_hit_limits", QuotaResource::Events)
-                .await,
-            false
+                .await
         );
         assert!(limiter.is_limited("banana", QuotaResource::Events).await);
     }
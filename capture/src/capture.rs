@@ -1,5 +1,4 @@
-use std::collections::HashSet;
-use std::ops::Deref;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
 use bytes::Bytes;
@@ -7,25 +6,105 @@ use bytes::Bytes;
 use axum::Json;
 // TODO: stream this instead
 use axum::extract::{Query, State};
-use axum::http::{HeaderMap, Method};
+use axum::http::{HeaderMap, Method, Uri};
 use axum_client_ip::InsecureClientIp;
-use base64::Engine;
 use metrics::counter;
+use rand::Rng;
+use serde_json::Value;
 
-use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+use time::{Duration, OffsetDateTime};
 use tracing::instrument;
+use uuid::Uuid;
 
 use crate::billing_limits::QuotaResource;
-use crate::event::{Compression, ProcessingContext};
+use crate::config::{
+    DuplicateUuidPolicy, EventPropertyDefaults, MinimumLibVersions, NullDistinctIdPolicy,
+    PathEventNames, ProcessingConfig, PropertyAllowlist, PropertyBounds, PropertyRenames,
+    TimestampFormats, TokenPropertyAllowlists, Ttls, UuidPolicy,
+};
+use crate::decode_metrics::DecodeFailureKind;
+use crate::dedup::DedupKey;
+use crate::event::{Compression, EventOffset, ProcessingContext, TraceParent};
+use crate::multipart::parse_multipart;
+use crate::normalization::{
+    apply_property_defaults, cap_arrays, check_lib_version, clamp_numbers, depth,
+    drop_largest_properties, extract_utm_params, merge_case_colliding_keys, namespace_properties,
+    normalize_booleans, normalize_current_url, normalize_geoip_disable, normalize_lib,
+    normalize_phone_numbers, preserve_raw_lib_version, prune_properties, redact_ip_addresses,
+    rename_properties, strip_elements, strip_empty_properties, truncate_strings, unescape_unicode,
+    CLAMPED_PROPERTIES_PROPERTY, DROPPED_PROPERTIES_PROPERTY, EMPTY_PROPERTIES_REMOVED_PROPERTY,
+    LIB_UNKNOWN_PROPERTY, MERGED_CASE_KEYS_PROPERTY, REDACTED_IP_PROPERTIES_PROPERTY,
+    TRUNCATED_ARRAYS_PROPERTY,
+};
+use crate::ownership::OwnershipValidator;
 use crate::prometheus::report_dropped_events;
-use crate::token::validate_token;
+use crate::schema::{enforce_property_allowlist, limit_distinct_properties};
+use crate::time::{parse_event_timestamp, SystemTime, TimeSource};
+use crate::token::{extract_token_from_auth, token_log_id, validate_token, TokenValidator};
+use crate::user_agent::UserAgentParser;
+use crate::zip::{parse_zip, ZIP_MAGIC_NUMBERS};
 use crate::{
-    api::{CaptureError, CaptureResponse, CaptureResponseCode},
-    event::{EventFormData, EventQuery, ProcessedEvent, RawEvent},
+    api::{
+        Ack, AckStatus, CaptureError, CaptureResponse, CaptureResponseCode, DeadLetter,
+        EventValidation,
+    },
+    event::{EventQuery, ProcessedEvent, RawEvent},
     router, sink,
-    utils::uuid_v7,
+    utils::{new_uuid, uuid_v7_timestamp_millis},
 };
 
+const FEATURE_FLAG_CALLED_EVENT: &str = "$feature_flag_called";
+
+// Summary of the events of a request rejected by processing, see `emit_ingestion_warnings`
+const INGESTION_WARNING_EVENT: &str = "$ingestion_warning";
+const INGESTION_WARNING_DISTINCT_ID: &str = "$capture";
+const MERGE_DANGEROUSLY_EVENT: &str = "$merge_dangerously";
+
+// Flags events given a generated distinct_id by `NullDistinctIdPolicy::Anonymous`
+const DISTINCT_ID_GENERATED_PROPERTY: &str = "$distinct_id_generated";
+
+// Flags events received outside of the configured `ingest_windows`
+const OUTSIDE_INGEST_WINDOW_PROPERTY: &str = "$outside_ingest_window";
+
+// Server-side events applying to several users, see `fan_out_distinct_ids`
+const DISTINCT_IDS_PROPERTY: &str = "$distinct_ids";
+
+// Original name of events renamed by `lowercase_event_names`
+const ORIGINAL_EVENT_PROPERTY: &str = "$original_event";
+
+// Number of identical consecutive events collapsed by `coalesce_duplicates`
+const COALESCED_COUNT_PROPERTY: &str = "$coalesced_count";
+
+// Length in characters of event names truncated to `max_event_name_length`
+const EVENT_NAME_TRUNCATED_PROPERTY: &str = "$event_name_truncated";
+
+// Flags events whose uuid was seen by a previous request, see `check_seen_uuids`
+const DUPLICATE_UUID_PROPERTY: &str = "$duplicate_uuid";
+
+// Drift in milliseconds from now of the time of v7 uuids regenerated by `max_uuid_clock_drift_ms`
+const UUID_CLOCK_DRIFT_PROPERTY: &str = "$uuid_clock_drift";
+
+// Markers set by normalization steps, reported as warnings by `validate_only`
+const WARNING_PROPERTIES: [&str; 10] = [
+    TRUNCATED_ARRAYS_PROPERTY,
+    CLAMPED_PROPERTIES_PROPERTY,
+    DROPPED_PROPERTIES_PROPERTY,
+    LIB_UNKNOWN_PROPERTY,
+    ORIGINAL_EVENT_PROPERTY,
+    EMPTY_PROPERTIES_REMOVED_PROPERTY,
+    EVENT_NAME_TRUNCATED_PROPERTY,
+    REDACTED_IP_PROPERTIES_PROPERTY,
+    UUID_CLOCK_DRIFT_PROPERTY,
+    MERGED_CASE_KEYS_PROPERTY,
+];
+
+// Sent by replay and backfill tooling to set the timestamp of all events of a request
+const EVENT_TIME_HEADER: &str = "x-posthog-event-time";
+
+// W3C trace context header, stamped on events as `$trace_id` and `$span_id`
+const TRACEPARENT_HEADER: &str = "traceparent";
+
 #[instrument(
     skip_all,
     fields(
@@ -41,9 +120,10 @@ use crate::{
 pub async fn event(
     state: State<router::State>,
     InsecureClientIp(ip): InsecureClientIp,
-    meta: Query<EventQuery>,
+    mut meta: Query<EventQuery>,
     headers: HeaderMap,
     method: Method,
+    uri: Uri,
     body: Bytes,
 ) -> Result<Json<CaptureResponse>, CaptureError> {
     // content-type
@@ -56,9 +136,16 @@ pub async fn event(
         .get("content-encoding")
         .map_or("unknown", |v| v.to_str().unwrap_or("unknown"));
 
+    meta.content_encoding = headers
+        .get("content-encoding")
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+
     let comp = match meta.compression {
         None => String::from("unknown"),
         Some(Compression::Gzip) => String::from("gzip"),
+        Some(Compression::Xz) => String::from("xz"),
+        Some(Compression::Deflate) => String::from("deflate"),
         Some(Compression::Unsupported) => String::from("unsupported"),
     };
 
@@ -68,25 +155,77 @@ pub async fn event(
     tracing::Span::current().record("compression", comp.as_str());
     tracing::Span::current().record("method", method.as_str());
 
-    let events = match headers
+    let dedup = state.request_dedup.as_ref().and_then(|cache| {
+        request_dedup_key(&headers, &body, &state.processing).map(|key| (cache, key))
+    });
+    let auth_token = headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(extract_token_from_auth);
+    #[allow(clippy::manual_inspect)]
+    let mut events = match headers
         .get("content-type")
         .map_or("", |v| v.to_str().unwrap_or(""))
     {
         "application/x-www-form-urlencoded" => {
             tracing::Span::current().record("content_type", "application/x-www-form-urlencoded");
 
-            let input: EventFormData = serde_urlencoded::from_bytes(body.deref()).unwrap();
-            let payload = base64::engine::general_purpose::STANDARD
-                .decode(input.data)
-                .unwrap();
-            RawEvent::from_bytes(&meta, payload.into())
+            RawEvent::from_form_data(&meta, body, &state.processing)
+        }
+        ct if ct.starts_with("multipart/form-data") => {
+            tracing::Span::current().record("content_type", "multipart/form-data");
+
+            parse_multipart(ct, &meta, body, &state.processing).map(|parsed| {
+                if !parsed.attachments.is_empty() {
+                    tracing::debug!(attachments = ?parsed.attachments, "ignoring attachments");
+                }
+                parsed.events
+            })
+        }
+        #[cfg(feature = "protobuf")]
+        "application/x-protobuf" => {
+            tracing::Span::current().record("content_type", "application/x-protobuf");
+
+            RawEvent::from_protobuf(&meta, body)
+        }
+        ct if body.starts_with(&ZIP_MAGIC_NUMBERS) => {
+            tracing::Span::current().record("content_type", ct);
+
+            parse_zip(&meta, body, &state.processing).map(|parsed| {
+                if !parsed.errors.is_empty() {
+                    tracing::warn!(errors = ?parsed.errors, "skipping invalid zip entries");
+                }
+                parsed.events
+            })
         }
         ct => {
             tracing::Span::current().record("content_type", ct);
 
-            RawEvent::from_bytes(&meta, body)
+            RawEvent::from_bytes_with(&meta, body, &state.processing)
+        }
+    }
+    .map_err(|err| {
+        // Tokens sent in the body are out of reach when it fails to decode
+        let token = auth_token.as_deref().or(meta.api_key.as_deref());
+        state.decode_failures.record(
+            DecodeFailureKind::of(&err),
+            token.map(token_log_id).as_deref(),
+        );
+        err
+    })?;
+    number_events(&mut events);
+
+    if state.processing.promote_nested_person_updates {
+        for event in events.iter_mut() {
+            event.promote_nested_person_updates();
         }
-    }?;
+    }
+    if state.processing.split_person_properties {
+        events = events
+            .into_iter()
+            .flat_map(RawEvent::split_person_properties)
+            .collect();
+    }
 
     tracing::Span::current().record("batch_size", events.len());
 
@@ -94,12 +233,50 @@ pub async fn event(
         return Err(CaptureError::EmptyBatch);
     }
 
-    let token = extract_and_verify_token(&events).map_err(|err| {
-        report_dropped_events("token_shape_invalid", events.len() as u64);
-        err
-    })?;
+    let mut tokens = TokenCache::new(&events);
+    let tokenless;
+    (events, tokens, tokenless) =
+        reject_tokenless(events, tokens, auth_token.as_deref(), &state.processing);
+    if !tokenless.is_empty() {
+        report_dropped_events("missing_token", tokenless.len() as u64);
+        if events.is_empty() {
+            return Err(CaptureError::MissingToken);
+        }
+    }
+    // inspect_err needs Rust 1.76, the image builds with 1.72
+    #[allow(clippy::manual_inspect)]
+    let token =
+        extract_and_verify_token(&tokens, auth_token, &state.processing).map_err(|err| {
+            report_dropped_events("token_shape_invalid", events.len() as u64);
+            err
+        })?;
+
+    tracing::Span::current().record("token", token_log_id(&token));
+
+    if let Some((cache, key)) = &dedup {
+        if let Some(events) = cache.check(&token, key) {
+            tracing::info!(events, "skipping repeated request");
+            report_dropped_events("duplicate_request", events as u64);
+            return Ok(Json(CaptureResponse {
+                status: CaptureResponseCode::Ok,
+            }));
+        }
+    }
 
-    tracing::Span::current().record("token", &token);
+    (events, tokens) = filter_valid_tokens(
+        events,
+        tokens,
+        &token,
+        state.token_validator.as_ref(),
+        &state.processing,
+    )
+    .await?;
+    check_ownership(&events, &tokens, &token, state.ownership_validator.as_ref()).await?;
+    if events.is_empty() {
+        return Ok(Json(CaptureResponse {
+            status: CaptureResponseCode::Ok,
+        }));
+    }
 
     counter!("capture_events_received_total", events.len() as u64);
 
@@ -113,14 +290,39 @@ pub async fn event(
         }
         None
     });
+    let event_time = event_time_override(&headers, &state.processing)?;
+    let traceparent = headers
+        .get(TRACEPARENT_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
 
     let context = ProcessingContext {
         lib_version: meta.lib_version.clone(),
         sent_at,
+        event_time,
+        trace_id: traceparent_trace_id(traceparent.as_deref()).or_else(current_span_id),
+        traceparent,
+        user_agent: headers
+            .get("user-agent")
+            .and_then(|value| value.to_str().ok())
+            .map(String::from),
+        origin: headers
+            .get("origin")
+            .and_then(|value| value.to_str().ok())
+            .map(String::from),
+        path: Some(uri.path().to_string()),
+        ingest_region: state.processing.ingest_region.clone(),
         token,
         now: state.timesource.current_time(),
+        clock: state.timesource.clone(),
         client_ip: ip.to_string(),
     };
+    check_sent_at(&context, &state.processing)?;
+    check_origin(&context, &state.processing)?;
+    gate_ingest_window(&mut events, &context, &state.processing)?;
+    if state.processing.parse_user_agents {
+        enrich_user_agent(&mut events, &context, state.user_agent_parser.as_ref());
+    }
 
     let billing_limited = state
         .billing
@@ -141,13 +343,61 @@ pub async fn event(
         }));
     }
 
-    tracing::debug!(context=?context, events=?events, "decoded request");
+    // Held until the events are processed and sent
+    let _permit = match &state.concurrency {
+        #[allow(clippy::manual_inspect)]
+        Some(limiter) => Some(limiter.acquire(&context.token).map_err(|err| {
+            report_dropped_events("concurrency_limited", events.len() as u64);
+            err
+        })?),
+        None => None,
+    };
+
+    if state.processing.regenerate_colliding_uuids {
+        regenerate_colliding_uuids(&mut events, state.processing.uuid_policy);
+    }
+    let (events, dropped) = check_seen_uuids(
+        events,
+        state.uuid_seen.as_ref(),
+        state.processing.duplicate_uuids,
+    );
+    if dropped > 0 {
+        report_dropped_events("duplicate_uuid", dropped as u64);
+    }
+    if events.is_empty() {
+        return Ok(Json(CaptureResponse {
+            status: CaptureResponseCode::Ok,
+        }));
+    }
+
+    tracing::debug!(
+        token = token_log_id(&context.token),
+        events = events.len(),
+        "decoded request"
+    );
 
-    if let Err(err) = process_events(state.sink.clone(), &events, &context).await {
-        report_dropped_events("process_events_error", events.len() as u64);
+    let batch_size = events.len();
+    let rejected = tokenless
+        .into_iter()
+        .map(|raw| dead_letter(raw, CaptureError::MissingToken, &context))
+        .collect();
+    if let Err(err) = process_events(
+        state.sink.clone(),
+        events,
+        rejected,
+        &context,
+        &state.processing,
+    )
+    .await
+    {
+        report_dropped_events("process_events_error", batch_size as u64);
         tracing::log::warn!("rejected invalid payload: {}", err);
         return Err(err);
     }
+    // Only once processed, for failed requests to be retried
+    if let Some((cache, key)) = dedup {
+        cache.record(&context.token, key, batch_size);
+    }
 
     Ok(Json(CaptureResponse {
         status: CaptureResponseCode::Ok,
@@ -160,122 +410,1407 @@ pub async fn options() -> Result<Json<CaptureResponse>, CaptureError> {
     }))
 }
 
+/// Key to deduplicate a request on, along with its token: its `X-Request-Id` header, falling
+/// back to the hash of its body if `dedup_request_bodies` is set. None if the request is not to
+/// be deduplicated.
+pub fn request_dedup_key(
+    headers: &HeaderMap,
+    body: &[u8],
+    config: &ProcessingConfig,
+) -> Option<DedupKey> {
+    let request_id = headers
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|id| !id.is_empty());
+    match request_id {
+        Some(id) => Some(DedupKey::RequestId(id.to_string())),
+        None if config.dedup_request_bodies => Some(DedupKey::body(body)),
+        None => None,
+    }
+}
+
+/// Reject the batch if one of its `(token, distinct_id)` pairs is not permitted. Events without
+/// a token of their own are checked with `default_token`, those without a distinct_id are left
+/// to distinct_id resolution. The ids of a `$distinct_ids` array are checked too, as events are
+/// sent as each of them once fanned out. The validator is called once per distinct pair.
+pub async fn check_ownership(
+    events: &[RawEvent],
+    tokens: &TokenCache,
+    default_token: &str,
+    validator: &(dyn OwnershipValidator + Send + Sync),
+) -> Result<(), CaptureError> {
+    let mut checked: HashSet<(String, String)> = HashSet::new();
+    for (index, event) in events.iter().enumerate() {
+        let fanned_out_ids = match event.properties.get(DISTINCT_IDS_PROPERTY) {
+            Some(Value::Array(ids)) => ids.as_slice(),
+            _ => &[],
+        };
+        let distinct_ids =
+            event
+                .extract_distinct_id()
+                .into_iter()
+                .chain(fanned_out_ids.iter().filter_map(|id| match id {
+                    Value::String(id) => Some(id.clone()),
+                    Value::Number(id) => Some(id.to_string()),
+                    _ => None,
+                }));
+        let token = tokens.get(index).unwrap_or(default_token);
+        for distinct_id in distinct_ids {
+            let pair = (token.to_string(), distinct_id);
+            if checked.contains(&pair) {
+                continue;
+            }
+            if !validator.is_permitted(&pair.0, &pair.1).await {
+                tracing::warn!(
+                    token = token_log_id(&pair.0),
+                    distinct_id = pair.1,
+                    "rejecting event sent as a distinct_id of another owner"
+                );
+                report_dropped_events("ownership_violation", events.len() as u64);
+                return Err(CaptureError::OwnershipViolation);
+            }
+            checked.insert(pair);
+        }
+    }
+    Ok(())
+}
+
+/// Record the position of each event within the request, kept by the events derived from it and
+/// through the steps dropping some of them.
+fn number_events(events: &mut [RawEvent]) {
+    for (index, event) in events.iter_mut().enumerate() {
+        event.original_index = index as u32;
+    }
+}
+
+/// Drop events whose token fails validation, or error with DisabledToken if configured to.
+/// Events without a token of their own are checked against `default_token`. The validator is
+/// called once per distinct token. The cache is returned along with the events kept.
+pub async fn filter_valid_tokens(
+    events: Vec<RawEvent>,
+    tokens: TokenCache,
+    default_token: &str,
+    validator: &(dyn TokenValidator + Send + Sync),
+    config: &ProcessingConfig,
+) -> Result<(Vec<RawEvent>, TokenCache), CaptureError> {
+    let mut validity: HashMap<String, bool> = HashMap::new();
+    let mut valid_events = Vec::with_capacity(events.len());
+    let mut valid_tokens = Vec::with_capacity(events.len());
+
+    for (event, event_token) in events.into_iter().zip(tokens.tokens) {
+        let token = event_token.as_deref().unwrap_or(default_token);
+        let valid = match validity.get(token) {
+            Some(valid) => *valid,
+            None => {
+                let valid = validator.is_valid(token).await;
+                validity.insert(token.to_string(), valid);
+                valid
+            }
+        };
+
+        if valid {
+            valid_events.push(event);
+            valid_tokens.push(event_token);
+        } else if config.reject_disabled_tokens {
+            return Err(CaptureError::DisabledToken);
+        } else {
+            report_dropped_events("token_disabled", 1);
+        }
+    }
+
+    Ok((
+        valid_events,
+        TokenCache {
+            tokens: valid_tokens,
+        },
+    ))
+}
+
+/// Flag with `$out_of_order` the events of a batch whose timestamp is earlier than the one of
+/// a previous event by more than `tolerance`, which often points to a client clock glitch.
+/// Events are never dropped, and those without a parseable timestamp are ignored.
+pub fn flag_out_of_order(events: &mut [RawEvent], tolerance: Duration, config: &ProcessingConfig) {
+    let TimestampFormats(formats) = &config.timestamp_formats;
+    let mut latest: Option<OffsetDateTime> = None;
+
+    for event in events.iter_mut() {
+        let Some(timestamp) = event
+            .timestamp
+            .as_deref()
+            .and_then(|value| parse_event_timestamp(value, formats))
+        else {
+            continue;
+        };
+        match latest {
+            Some(latest) if latest - timestamp > tolerance => {
+                event
+                    .properties
+                    .insert(String::from("$out_of_order"), Value::Bool(true));
+            }
+            Some(latest) if latest >= timestamp => {}
+            _ => latest = Some(timestamp),
+        }
+    }
+}
+
+/// Split a batch into live events and historical ones, whose resolved timestamp is older
+/// than `threshold`, such as backfills. Events without a parseable timestamp are live. The
+/// order of the batch is kept within each group.
+pub fn split_by_recency(
+    events: Vec<RawEvent>,
+    threshold: OffsetDateTime,
+    config: &ProcessingConfig,
+) -> (Vec<RawEvent>, Vec<RawEvent>) {
+    let TimestampFormats(formats) = &config.timestamp_formats;
+    events.into_iter().partition(|event| {
+        let timestamp = event
+            .timestamp
+            .as_deref()
+            .and_then(|value| parse_event_timestamp(value, formats));
+        match timestamp {
+            Some(timestamp) => timestamp >= threshold,
+            None => true,
+        }
+    })
+}
+
+/// Collapse runs of consecutive events with the same fingerprint into their first event,
+/// setting `$coalesced_count` to the length of the run. Only consecutive duplicates are
+/// collapsed, keeping it a single pass that preserves the order of the batch.
+pub fn coalesce_duplicates(events: Vec<RawEvent>) -> Vec<RawEvent> {
+    let mut coalesced: Vec<(RawEvent, u64, usize)> = Vec::with_capacity(events.len());
+    for event in events {
+        let fingerprint = event.fingerprint();
+        match coalesced.last_mut() {
+            Some((_, last, count)) if *last == fingerprint => *count += 1,
+            _ => coalesced.push((event, fingerprint, 1)),
+        }
+    }
+    coalesced
+        .into_iter()
+        .map(|(mut event, _, count)| {
+            if count > 1 {
+                event
+                    .properties
+                    .insert(String::from(COALESCED_COUNT_PROPERTY), Value::from(count));
+            }
+            event
+        })
+        .collect()
+}
+
+/// Replace events carrying a `$distinct_ids` array with one copy per id, rejecting events with
+/// more than `max` ids. Copies lose the array and the uuid of the original, each getting its own.
+pub fn fan_out_distinct_ids(
+    events: Vec<RawEvent>,
+    max: usize,
+) -> Result<Vec<RawEvent>, CaptureError> {
+    let mut fanned = Vec::with_capacity(events.len());
+    for mut event in events {
+        let ids = match event.properties.remove(DISTINCT_IDS_PROPERTY) {
+            Some(Value::Array(ids)) => ids,
+            Some(other) => {
+                event
+                    .properties
+                    .insert(String::from(DISTINCT_IDS_PROPERTY), other);
+                fanned.push(event);
+                continue;
+            }
+            None => {
+                fanned.push(event);
+                continue;
+            }
+        };
+        if ids.len() > max {
+            return Err(CaptureError::TooManyDistinctIds);
+        }
+        for id in ids {
+            let distinct_id = match id {
+                Value::String(id) => id,
+                Value::Number(id) => id.to_string(),
+                _ => return Err(CaptureError::MissingDistinctId),
+            };
+            fanned.push(RawEvent {
+                distinct_id: Some(distinct_id),
+                uuid: None,
+                ..event.clone()
+            });
+        }
+    }
+    Ok(fanned)
+}
+
+/// Set the device properties parsed from the User-Agent of the request on its events, keeping
+/// the values set by the client. Session recording snapshots are left untouched.
+pub fn enrich_user_agent(
+    events: &mut [RawEvent],
+    context: &ProcessingContext,
+    parser: &(dyn UserAgentParser + Send + Sync),
+) {
+    let Some(user_agent) = &context.user_agent else {
+        return;
+    };
+    let info = parser.parse(user_agent);
+    for event in events.iter_mut().filter(|event| event.event != "$snapshot") {
+        info.enrich(&mut event.properties);
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum EventAction {
+    Keep,
+    Drop,
+}
+
+/// Escape hatch for embedders: apply custom logic to each event, which can modify it and decide
+/// whether to keep it. Returns the kept events and the number of dropped ones.
+pub fn process_with<F: FnMut(&mut RawEvent) -> EventAction>(
+    events: Vec<RawEvent>,
+    mut f: F,
+) -> (Vec<RawEvent>, usize) {
+    let received = events.len();
+    let kept: Vec<RawEvent> = events
+        .into_iter()
+        .filter_map(|mut event| match f(&mut event) {
+            EventAction::Keep => Some(event),
+            EventAction::Drop => None,
+        })
+        .collect();
+    let dropped = received - kept.len();
+    (kept, dropped)
+}
+
+/// Feature flag evaluations are very high volume, they can be sampled more heavily than other
+/// events. Other events are always kept.
+pub fn keep_sampled(event: &RawEvent, config: &ProcessingConfig) -> bool {
+    if event.event != FEATURE_FLAG_CALLED_EVENT || config.feature_flag_call_sample_rate >= 1.0 {
+        return true;
+    }
+    rand::thread_rng().gen::<f64>() < config.feature_flag_call_sample_rate
+}
+
 #[instrument(skip_all)]
 pub fn process_single_event(
-    event: &RawEvent,
+    mut event: RawEvent,
     context: &ProcessingContext,
+    config: &ProcessingConfig,
 ) -> Result<ProcessedEvent, CaptureError> {
-    let distinct_id = match &event.distinct_id {
-        Some(id) => id,
-        None => match event.properties.get("distinct_id").map(|v| v.as_str()) {
-            Some(Some(id)) => id,
-            _ => return Err(CaptureError::MissingDistinctId),
-        },
+    let started = config
+        .record_processing_duration
+        .then(std::time::Instant::now);
+    let ingest_ts_nanos = context.clock.current_time_nanos();
+    let PropertyAllowlist(sentinels) = &config.distinct_id_sentinels;
+    let mut extracted = event.extract_distinct_id();
+    if extracted.as_ref().is_some_and(|id| sentinels.contains(id)) {
+        tracing::debug!(
+            distinct_id = extracted,
+            "treating sentinel distinct_id as missing"
+        );
+        event.distinct_id = None;
+        extracted = None;
+    }
+    let distinct_id = match (extracted, config.null_distinct_id) {
+        (Some(distinct_id), _) => distinct_id,
+        (None, NullDistinctIdPolicy::Reject) => return Err(CaptureError::MissingDistinctId),
+        (None, NullDistinctIdPolicy::Anonymous) => {
+            let distinct_id = new_uuid(config.uuid_policy).to_string();
+            event.distinct_id = Some(distinct_id.clone());
+            event.properties.insert(
+                String::from(DISTINCT_ID_GENERATED_PROPERTY),
+                Value::Bool(true),
+            );
+            distinct_id
+        }
+        (None, NullDistinctIdPolicy::Passthrough) => String::new(),
     };
     // Limit the size of distinct_id to 200 chars
     let distinct_id: String = match distinct_id.len() {
-        0..=200 => distinct_id.to_owned(),
+        0..=200 => distinct_id,
         _ => distinct_id.chars().take(200).collect(),
     };
+    event.uuid = event.extract_uuid();
+    if let Some(header) = &context.traceparent {
+        match TraceParent::parse(header) {
+            Some(TraceParent { trace_id, span_id }) => {
+                event
+                    .properties
+                    .insert(String::from("$trace_id"), Value::String(trace_id));
+                event
+                    .properties
+                    .insert(String::from("$span_id"), Value::String(span_id));
+            }
+            None => tracing::debug!(header, "ignoring malformed traceparent header"),
+        }
+    }
 
     if event.event.is_empty() {
-        return Err(CaptureError::MissingEventName);
+        let PathEventNames(path_events) = &config.path_event_names;
+        if let Some(name) = context
+            .path
+            .as_deref()
+            .and_then(|path| path_events.get(path.trim_end_matches('/')))
+        {
+            event.event = name.clone();
+        }
+    }
+    if event.event.is_empty() {
+        if !config.lenient_event_name {
+            return Err(CaptureError::MissingEventName);
+        }
+        tracing::warn!(
+            distinct_id,
+            "event submitted without a name, defaulting to {}",
+            config.missing_event_name
+        );
+        event.event = config.missing_event_name.clone();
     }
 
-    let data = serde_json::to_string(&event).map_err(|e| {
-        tracing::error!("failed to encode data field: {}", e);
-        CaptureError::NonRetryableSinkError
-    })?;
+    // Limit the size of the event name, counted in chars as for distinct_id
+    let name_length = event.event.chars().count();
+    if name_length > config.max_event_name_length {
+        if config.strict_event_name_length {
+            return Err(CaptureError::EventNameTooLong);
+        }
+        tracing::warn!(name_length, "truncating event name");
+        event.event = event
+            .event
+            .chars()
+            .take(config.max_event_name_length)
+            .collect();
+        event.properties.insert(
+            String::from(EVENT_NAME_TRUNCATED_PROPERTY),
+            Value::from(name_length),
+        );
+    }
+
+    if config.lowercase_event_names && !event.event.starts_with('$') {
+        let lowercase = event.event.to_lowercase();
+        if lowercase != event.event {
+            let original = std::mem::replace(&mut event.event, lowercase);
+            event.properties.insert(
+                String::from(ORIGINAL_EVENT_PROPERTY),
+                Value::String(original),
+            );
+        }
+    }
+
+    if event.event == MERGE_DANGEROUSLY_EVENT {
+        validate_merge(&event, &distinct_id)?;
+    }
+
+    if config.strict_uuid_version {
+        if let Some(uuid) = event.uuid {
+            if uuid.get_version_num() != config.uuid_policy.version() {
+                return Err(CaptureError::DisallowedUuidVersion);
+            }
+        }
+    }
+
+    if let Some(max_drift) = config.max_uuid_clock_drift_ms {
+        let now_millis = (ingest_ts_nanos / 1_000_000) as i64;
+        let drift = event
+            .uuid
+            .as_ref()
+            .and_then(uuid_v7_timestamp_millis)
+            .map(|millis| millis as i64 - now_millis)
+            .filter(|drift| drift.unsigned_abs() > max_drift);
+        if let Some(drift) = drift {
+            tracing::warn!(
+                distinct_id,
+                drift,
+                "regenerating uuid with a drifting clock"
+            );
+            event.uuid = Some(new_uuid(config.uuid_policy));
+            event
+                .properties
+                .insert(String::from(UUID_CLOCK_DRIFT_PROPERTY), Value::from(drift));
+        }
+    }
+
+    if config.strip_ignored_person_updates && event.strip_ignored_person_updates() {
+        tracing::debug!(
+            distinct_id,
+            "dropped person updates of an event not processing persons"
+        );
+    }
+    let PropertyAllowlist(set_once_keys) = &config.set_once_properties;
+    if !set_once_keys.is_empty() && event.move_to_set_once(set_once_keys) {
+        tracing::debug!(
+            distinct_id,
+            "moved immutable person properties to $set_once"
+        );
+    }
+
+    if config.promote_time_properties {
+        let PropertyAllowlist(keys) = &config.time_properties;
+        let TimestampFormats(formats) = &config.timestamp_formats;
+        if let Some(key) = event.promote_time_property(keys, formats) {
+            tracing::debug!(distinct_id, key, "promoted time property to timestamp");
+        }
+    }
+    if event.timestamp.is_none() {
+        if let Some(event_time) = context.event_time {
+            event.timestamp = event_time.format(&Rfc3339).ok();
+        }
+    }
+
+    // Reject absurd offsets here, instead of letting them skew the timestamp in ingestion,
+    // which only reads milliseconds
+    if let Some(offset) = event.offset_duration()? {
+        event.offset = Some(EventOffset::Millis(offset.whole_milliseconds() as i64));
+    }
+
+    event.strip_invalid_session_id(config.strict_session_id);
+    let timezone = if config.validate_timezones {
+        event.validate_timezone()
+    } else {
+        None
+    };
+
+    // Session recording snapshots are large by nature and must reach ingestion untouched,
+    // they only go through the event size check
+    if event.event != "$snapshot" {
+        let PropertyRenames(renames) = &config.property_renames;
+        rename_properties(&mut event.properties, renames);
+        let EventPropertyDefaults(event_defaults) = &config.event_property_defaults;
+        if let Some(defaults) = event_defaults.get(&event.event) {
+            let applied = apply_property_defaults(&mut event.properties, defaults);
+            if applied > 0 {
+                tracing::debug!(distinct_id, applied, "applied event property defaults");
+            }
+        }
+        if config.merge_case_colliding_keys {
+            let merged = merge_case_colliding_keys(&mut event.properties);
+            if !merged.is_empty() {
+                tracing::debug!(
+                    distinct_id,
+                    ?merged,
+                    "merged property keys differing by case"
+                );
+            }
+        }
+        let TokenPropertyAllowlists(allowlists) = &config.token_property_allowlists;
+        if let Some(allowed) = allowlists.get(&context.token) {
+            let removed = enforce_property_allowlist(
+                &mut event.properties,
+                allowed,
+                config.strict_property_allowlists,
+            )?;
+            if !removed.is_empty() {
+                tracing::debug!(?removed, "stripped properties missing from the allowlist");
+            }
+        }
+        if let Some(namespace) = &config.property_namespace {
+            namespace_properties(&mut event.properties, namespace);
+        }
+        if config.strip_empty_properties {
+            strip_empty_properties(&mut event.properties);
+        }
+        if config.normalize_lib_names {
+            let PropertyAllowlist(known) = &config.known_libraries;
+            normalize_lib(&mut event.properties, known);
+        }
+        let MinimumLibVersions(minimums) = &config.minimum_lib_versions;
+        if !minimums.is_empty() {
+            check_lib_version(&event.properties, minimums, config.strict_lib_versions)?;
+        }
+        // Before the URL loses its stripped params
+        if config.extract_utm_params {
+            let extracted = extract_utm_params(&mut event.properties);
+            if extracted > 0 {
+                tracing::debug!(
+                    distinct_id,
+                    extracted,
+                    "extracted utm params from $current_url"
+                );
+            }
+        }
+        let PropertyAllowlist(stripped_params) = &config.stripped_url_params;
+        if !stripped_params.is_empty() || config.max_current_url_length.is_some() {
+            normalize_current_url(
+                &mut event.properties,
+                stripped_params,
+                config.max_current_url_length,
+            );
+        }
+        if config.preserve_raw_lib_version {
+            preserve_raw_lib_version(&mut event.properties);
+        }
+        // Before truncation, which could leave part of an address behind
+        if config.redact_ip_addresses {
+            let redacted = redact_ip_addresses(&mut event.properties);
+            if !redacted.is_empty() {
+                tracing::debug!(?redacted, "masked ip addresses in properties");
+            }
+        }
+        if let Some(max_depth) = config.max_property_depth {
+            if event.properties.values().any(|v| depth(v) > max_depth) {
+                return Err(CaptureError::PropertiesTooDeep);
+            }
+        }
+        if let Some(max_length) = config.max_property_string_length {
+            for value in event.properties.values_mut() {
+                truncate_strings(value, max_length);
+            }
+        }
+        if config.cap_property_arrays {
+            cap_arrays(&mut event.properties, config.max_property_array_length);
+        }
+        let PropertyAllowlist(escaped_keys) = &config.unescape_unicode_properties;
+        unescape_unicode(&mut event.properties, escaped_keys);
+        let PropertyAllowlist(boolean_keys) = &config.boolean_properties;
+        normalize_booleans(&mut event.properties, boolean_keys);
+        let PropertyAllowlist(phone_keys) = &config.phone_properties;
+        normalize_phone_numbers(
+            &mut event.properties,
+            phone_keys,
+            config.phone_default_calling_code,
+        );
+        if config.normalize_geoip_disable && normalize_geoip_disable(&mut event.properties) {
+            tracing::debug!(distinct_id, "event opted out of GeoIP enrichment");
+        }
+        let PropertyBounds(bounds) = &config.property_bounds;
+        if !bounds.is_empty() {
+            let clamped = clamp_numbers(&mut event.properties, bounds);
+            if let Some(key) = clamped.first().filter(|_| config.strict_property_bounds) {
+                return Err(CaptureError::PropertyOutOfRange(key.clone()));
+            }
+        }
+        if config.prune_feature_flag_calls && event.event == FEATURE_FLAG_CALLED_EVENT {
+            let PropertyAllowlist(allowlist) = &config.feature_flag_call_properties;
+            prune_properties(&mut event.properties, allowlist);
+        }
+        let PropertyAllowlist(stripped_events) = &config.strip_elements_events;
+        if config.strip_elements
+            && stripped_events.contains(&event.event)
+            && strip_elements(&mut event.properties)
+        {
+            tracing::debug!(distinct_id, "stripped autocaptured elements");
+        }
+    }
+
+    let encode = |event: &RawEvent| {
+        serde_json::to_string(event).map_err(|e| {
+            tracing::error!("failed to encode data field: {}", e);
+            CaptureError::NonRetryableSinkError
+        })
+    };
+    let mut data = encode(&event)?;
+    let has_budget = config.event_byte_budget.is_some() || config.properties_byte_budget.is_some();
+    if has_budget && event.event != "$snapshot" {
+        // Only properties can be dropped, the rest of the event is a fixed overhead
+        let properties_len = serde_json::to_vec(&event.properties).map_or(0, |v| v.len());
+        let overhead = data.len().saturating_sub(properties_len);
+        let budget = config
+            .event_byte_budget
+            .map(|budget| budget.saturating_sub(overhead))
+            .into_iter()
+            .chain(config.properties_byte_budget)
+            .min()
+            .filter(|budget| properties_len > *budget);
+        if let Some(budget) = budget {
+            let dropped = drop_largest_properties(&mut event.properties, budget);
+            if !dropped.is_empty() {
+                tracing::debug!(distinct_id, ?dropped, "dropped properties over byte budget");
+                data = encode(&event)?;
+            }
+        }
+    }
+    if config
+        .max_event_size_bytes
+        .is_some_and(|max| data.len() > max)
+    {
+        return Err(CaptureError::EventTooBig);
+    }
+
+    let is_test = event.is_test(&config.test_event_property);
+    let session_id = event
+        .properties
+        .get("$session_id")
+        .and_then(Value::as_str)
+        .map(String::from);
+
+    let expires_at = expires_at(&event, context, config);
+    let processing_duration = started.map(|started| started.elapsed());
+    if let Some(duration) = processing_duration {
+        tracing::debug!(
+            distinct_id,
+            event = event.event,
+            duration_us = duration.as_micros() as u64,
+            "processed event"
+        );
+    }
 
     Ok(ProcessedEvent {
-        uuid: event.uuid.unwrap_or_else(uuid_v7),
+        uuid: event.uuid.unwrap_or_else(|| new_uuid(config.uuid_policy)),
         distinct_id,
         ip: context.client_ip.clone(),
         data,
         now: context.now.clone(),
+        ingest_ts_nanos,
         sent_at: context.sent_at,
         token: context.token.clone(),
+        process_person_profile: event.process_person_profile(),
+        event: event.event,
+        session_id,
+        timezone,
+        seq: 0,
+        trace_id: context
+            .trace_id
+            .clone()
+            .or_else(|| traceparent_trace_id(context.traceparent.as_deref()))
+            .or_else(current_span_id),
+        is_test,
+        ingest_region: context.ingest_region.clone(),
+        processing_duration,
+        original_index: event.original_index,
+        expires_at,
     })
 }
 
-#[instrument(skip_all, fields(events = events.len()))]
-pub fn extract_and_verify_token(events: &[RawEvent]) -> Result<String, CaptureError> {
-    let distinct_tokens: HashSet<Option<String>> = HashSet::from_iter(
-        events
-            .iter()
-            .map(RawEvent::extract_token)
-            .filter(Option::is_some),
-    );
+/// Expiry of the events given a time to live by `event_ttls`, or by `token_ttls` otherwise,
+/// from their resolved timestamp: the one they were sent with, or now shifted by their offset.
+fn expires_at(
+    event: &RawEvent,
+    context: &ProcessingContext,
+    config: &ProcessingConfig,
+) -> Option<OffsetDateTime> {
+    let Ttls(event_ttls) = &config.event_ttls;
+    let Ttls(token_ttls) = &config.token_ttls;
+    let ttl = event_ttls
+        .get(&event.event)
+        .or_else(|| token_ttls.get(&context.token))?;
 
-    return match distinct_tokens.len() {
-        0 => Err(CaptureError::NoTokenError),
-        1 => match distinct_tokens.iter().last() {
-            Some(Some(token)) => {
-                validate_token(token)?;
-                Ok(token.clone())
-            }
-            _ => Err(CaptureError::NoTokenError),
-        },
-        _ => Err(CaptureError::MultipleTokensError),
+    let TimestampFormats(formats) = &config.timestamp_formats;
+    let timestamp = match event
+        .timestamp
+        .as_deref()
+        .and_then(|value| parse_event_timestamp(value, formats))
+    {
+        Some(timestamp) => timestamp,
+        None => {
+            // Offsets were normalized to milliseconds
+            let offset = match event.offset {
+                Some(EventOffset::Millis(millis)) => Duration::milliseconds(millis),
+                _ => Duration::ZERO,
+            };
+            parse_event_timestamp(&context.now, &[])? - offset
+        }
+    };
+    timestamp.checked_add(*ttl)
+}
+
+/// `$merge_dangerously` events merge the person of their `alias` property into the one of
+/// their distinct_id. Self-merges are rejected, a missing alias is only logged.
+fn validate_merge(event: &RawEvent, distinct_id: &str) -> Result<(), CaptureError> {
+    let alias = match event.properties.get("alias") {
+        Some(Value::String(alias)) => alias.clone(),
+        Some(Value::Number(alias)) => alias.to_string(),
+        _ => {
+            tracing::warn!(distinct_id, "$merge_dangerously event without an alias");
+            return Ok(());
+        }
     };
+    if alias == distinct_id {
+        return Err(CaptureError::InvalidMerge);
+    }
+    Ok(())
+}
+
+/// Trace id of a W3C `traceparent` header, shared with the services the request went through.
+/// Preferred over the span id, None when the header is missing or malformed.
+fn traceparent_trace_id(header: Option<&str>) -> Option<String> {
+    header
+        .and_then(TraceParent::parse)
+        .map(|TraceParent { trace_id, .. }| trace_id)
+}
+
+/// Id of the active tracing span, None when no span is active or no subscriber records it.
+/// The handler stamps the request span on the context when the request carries no trace
+/// context, events processed without one get the id of their own processing span.
+/// Span ids are process-local: the subscriber reuses the ids of closed spans, and other
+/// capture instances hand out the same ids, so they only correlate within one process and
+/// for as long as the span is open.
+fn current_span_id() -> Option<String> {
+    tracing::Span::current()
+        .id()
+        .map(|id| format!("{:016x}", id.into_u64()))
 }
 
+/// Some clients reuse the same uuid for semantically different events, which makes
+/// downstream upserts clobber each other. Within a batch, give a fresh uuid to any event
+/// reusing the uuid of an earlier event with a different name or distinct_id.
+/// Genuine duplicates (same name and distinct_id) keep their uuid, to be deduplicated on it.
+/// Uuids are resolved as `process_single_event` does, including those sent in properties.
 #[instrument(skip_all, fields(events = events.len()))]
-pub async fn process_events<'a>(
-    sink: Arc<dyn sink::EventSink + Send + Sync>,
-    events: &'a [RawEvent],
-    context: &'a ProcessingContext,
-) -> Result<(), CaptureError> {
-    let events: Vec<ProcessedEvent> = events
-        .iter()
-        .map(|e| process_single_event(e, context))
-        .collect::<Result<Vec<ProcessedEvent>, CaptureError>>()?;
+pub fn regenerate_colliding_uuids(events: &mut [RawEvent], policy: UuidPolicy) {
+    let mut seen: HashMap<Uuid, (String, Option<String>)> = HashMap::new();
 
-    tracing::debug!(events=?events, "processed {} events", events.len());
+    for event in events.iter_mut() {
+        let Some(uuid) = event.extract_uuid() else {
+            continue;
+        };
+        let distinct_id = event.distinct_id.clone().or_else(|| {
+            event
+                .properties
+                .get("distinct_id")
+                .and_then(Value::as_str)
+                .map(String::from)
+        });
 
-    if events.len() == 1 {
-        sink.send(events[0].clone()).await
-    } else {
-        sink.send_batch(events).await
+        match seen.get(&uuid) {
+            None => {
+                seen.insert(uuid, (event.event.clone(), distinct_id));
+            }
+            Some((name, id)) if *name == event.event && *id == distinct_id => {}
+            Some(_) => {
+                let replacement = new_uuid(policy);
+                tracing::warn!(
+                    %uuid,
+                    %replacement,
+                    "uuid reused by a different event in the batch, regenerating"
+                );
+                counter!("capture_uuid_collisions_total", 1);
+                event.uuid = Some(replacement);
+            }
+        }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::capture::extract_and_verify_token;
-    use crate::event::RawEvent;
-    use serde_json::json;
-    use std::collections::HashMap;
+/// Default `uuid_seen` hook, for embedders not keeping track of the uuids of previous requests.
+pub fn no_uuid_seen(_uuid: &Uuid) -> bool {
+    false
+}
 
-    #[tokio::test]
-    async fn all_events_have_same_token() {
-        let events = vec![
-            RawEvent {
-                token: Some(String::from("hello")),
-                distinct_id: Some("testing".to_string()),
-                uuid: None,
-                event: String::new(),
-                properties: HashMap::new(),
-                timestamp: None,
-                offset: None,
-                set: Default::default(),
-                set_once: Default::default(),
-            },
-            RawEvent {
-                token: None,
-                distinct_id: Some("testing".to_string()),
-                uuid: None,
-                event: String::new(),
-                properties: HashMap::from([(String::from("token"), json!("hello"))]),
+/// Cross-request uniqueness of uuids, for embedders keeping the recent ones in a set, e.g. in
+/// Redis. Events whose uuid `uuid_seen` reports are dropped or flagged with `$duplicate_uuid`
+/// depending on the `policy`, those without a uuid are never duplicates. Uuids sent in
+/// properties count, as resolved by `RawEvent::extract_uuid`. Returns the kept events and the
+/// number of dropped ones.
+pub fn check_seen_uuids(
+    events: Vec<RawEvent>,
+    uuid_seen: impl Fn(&Uuid) -> bool,
+    policy: DuplicateUuidPolicy,
+) -> (Vec<RawEvent>, usize) {
+    process_with(events, |event| {
+        if !event.extract_uuid().as_ref().is_some_and(&uuid_seen) {
+            return EventAction::Keep;
+        }
+        match policy {
+            DuplicateUuidPolicy::Drop => EventAction::Drop,
+            DuplicateUuidPolicy::Flag => {
+                event
+                    .properties
+                    .insert(String::from(DUPLICATE_UUID_PROPERTY), Value::Bool(true));
+                EventAction::Keep
+            }
+        }
+    })
+}
+
+/// Parse the timestamp override header, accepting the same formats as event timestamps.
+pub fn event_time_override(
+    headers: &HeaderMap,
+    config: &ProcessingConfig,
+) -> Result<Option<OffsetDateTime>, CaptureError> {
+    let Some(value) = headers.get(EVENT_TIME_HEADER) else {
+        return Ok(None);
+    };
+    let TimestampFormats(formats) = &config.timestamp_formats;
+    value
+        .to_str()
+        .ok()
+        .and_then(|value| parse_event_timestamp(value, formats))
+        .map(Some)
+        .ok_or(CaptureError::InvalidEventTime)
+}
+
+/// Reject requests whose `sent_at` is more than `max_future_sent_at_ms` ahead of `now`: skew
+/// correction would shift all their events by the same bogus amount. Smaller drifts of the
+/// client clock are accepted.
+pub fn check_sent_at(
+    context: &ProcessingContext,
+    config: &ProcessingConfig,
+) -> Result<(), CaptureError> {
+    let (Some(max_ms), Some(sent_at)) = (config.max_future_sent_at_ms, context.sent_at) else {
+        return Ok(());
+    };
+    let Some(now) = parse_event_timestamp(&context.now, &[]) else {
+        tracing::warn!(
+            now = context.now,
+            "cannot check sent_at, now is not a timestamp"
+        );
+        return Ok(());
+    };
+    let ahead = sent_at - now;
+    if ahead > Duration::milliseconds(max_ms as i64) {
+        tracing::warn!(%sent_at, %now, "rejecting request sent in the future");
+        return Err(CaptureError::InvalidSentAt);
+    }
+    Ok(())
+}
+
+/// Reject browser requests from an origin missing from the `token_allowed_origins` of their
+/// token. Requests without an Origin header, like those of server-side SDKs, are accepted.
+pub fn check_origin(
+    context: &ProcessingContext,
+    config: &ProcessingConfig,
+) -> Result<(), CaptureError> {
+    let (Some(allowed), Some(origin)) = (
+        config.token_allowed_origins.0.get(&context.token),
+        &context.origin,
+    ) else {
+        return Ok(());
+    };
+    let origin = origin.trim().to_ascii_lowercase();
+    if allowed
+        .iter()
+        .any(|pattern| origin_matches(pattern, &origin))
+    {
+        return Ok(());
+    }
+    tracing::warn!(origin, "rejecting request from a disallowed origin");
+    Err(CaptureError::DisallowedOrigin)
+}
+
+/// Whether `origin` is `pattern`, or one of its subdomains if the host of `pattern` starts with
+/// `*.`. Both are expected lowercase.
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+    let Some((scheme, host)) = pattern.split_once("://") else {
+        return pattern == origin;
+    };
+    let Some(domain) = host.strip_prefix("*.") else {
+        return pattern == origin;
+    };
+    origin
+        .strip_prefix(scheme)
+        .and_then(|rest| rest.strip_prefix("://"))
+        .and_then(|rest| rest.strip_suffix(domain))
+        .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.'))
+}
+
+/// Gate requests on the configured `ingest_windows`, at the `now` of the context. Outside of
+/// them, events are flagged with `$outside_ingest_window`, or the request is rejected with
+/// OutsideIngestWindow for clients to retry it later.
+pub fn gate_ingest_window(
+    events: &mut [RawEvent],
+    context: &ProcessingContext,
+    config: &ProcessingConfig,
+) -> Result<(), CaptureError> {
+    if config.ingest_windows.0.is_empty() {
+        return Ok(());
+    }
+    let Some(now) = parse_event_timestamp(&context.now, &[]) else {
+        tracing::warn!(
+            now = context.now,
+            "cannot check ingest windows, now is not a timestamp"
+        );
+        return Ok(());
+    };
+    if config.ingest_windows.allows(now) {
+        return Ok(());
+    }
+    if config.reject_outside_ingest_window {
+        return Err(CaptureError::OutsideIngestWindow);
+    }
+    for event in events.iter_mut() {
+        event.properties.insert(
+            String::from(OUTSIDE_INGEST_WINDOW_PROPERTY),
+            Value::Bool(true),
+        );
+    }
+    Ok(())
+}
+
+/// Resolve the token of a batch. A token passed in the Authorization header takes precedence
+/// over the ones found in the events, that must otherwise all agree. Batches without any token
+/// fall back to the configured `default_token`, or are rejected with MissingToken.
+/// If `reject_token_mismatch` is set, a header token disagreeing with the body is rejected.
+/// Batches holding more than `max_tokens_per_batch` distinct tokens are always rejected.
+#[instrument(skip_all, fields(events = tokens.len()))]
+pub fn extract_and_verify_token(
+    tokens: &TokenCache,
+    auth_token: Option<String>,
+    config: &ProcessingConfig,
+) -> Result<String, CaptureError> {
+    let distinct_tokens = tokens.distinct();
+    if distinct_tokens.len() > config.max_tokens_per_batch {
+        return Err(CaptureError::TooManyTokens);
+    }
+
+    if let Some(token) = auth_token {
+        if config.reject_token_mismatch
+            && distinct_tokens
+                .iter()
+                .any(|body_token| body_token != &token)
+        {
+            return Err(CaptureError::TokenMismatch);
+        }
+        validate_token(&token)?;
+        return Ok(token);
+    }
+
+    return match distinct_tokens.len() {
+        0 => match &config.default_token {
+            Some(token) => {
+                validate_token(token)?;
+                Ok(token.clone())
+            }
+            None => Err(CaptureError::MissingToken),
+        },
+        1 => match distinct_tokens.into_iter().last() {
+            Some(token) => {
+                validate_token(&token)?;
+                Ok(token)
+            }
+            None => Err(CaptureError::NoTokenError),
+        },
+        _ => Err(CaptureError::MultipleTokensError),
+    };
+}
+
+/// Tokens of the events of a request, in the batch order. Each is looked up once with
+/// `RawEvent::extract_token`, and shared by the token validation and ownership checks.
+pub struct TokenCache {
+    tokens: Vec<Option<String>>,
+}
+
+impl TokenCache {
+    pub fn new(events: &[RawEvent]) -> Self {
+        Self::with_extractor(events, RawEvent::extract_token)
+    }
+
+    pub fn with_extractor(
+        events: &[RawEvent],
+        extract: impl FnMut(&RawEvent) -> Option<String>,
+    ) -> Self {
+        Self {
+            tokens: events.iter().map(extract).collect(),
+        }
+    }
+
+    /// Token of the event at `index`, `None` if it has no token of its own.
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.tokens.get(index).and_then(Option::as_deref)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// Distinct tokens of the batch, events without a token being skipped.
+    pub fn distinct(&self) -> HashSet<String> {
+        self.tokens.iter().flatten().cloned().collect()
+    }
+}
+
+/// Distinct tokens of the events of a batch, events without a token being skipped.
+pub fn tokens_in_batch(events: &[RawEvent]) -> HashSet<String> {
+    TokenCache::new(events).distinct()
+}
+
+/// Split off the events without any token: none of their own, no `auth_token` for the request
+/// and no configured `default_token`. They are rejected one by one with MissingToken instead of
+/// failing the batch, see `resolve_token`. Returns the kept events with their tokens, and the
+/// rejected ones.
+pub fn reject_tokenless(
+    events: Vec<RawEvent>,
+    tokens: TokenCache,
+    auth_token: Option<&str>,
+    config: &ProcessingConfig,
+) -> (Vec<RawEvent>, TokenCache, Vec<RawEvent>) {
+    if auth_token.is_some() || config.default_token.is_some() {
+        return (events, tokens, Vec::new());
+    }
+    let mut kept = Vec::with_capacity(events.len());
+    let mut kept_tokens = Vec::with_capacity(events.len());
+    let mut rejected = Vec::new();
+    for (event, token) in events.into_iter().zip(tokens.tokens) {
+        if token.is_some() {
+            kept.push(event);
+            kept_tokens.push(token);
+        } else {
+            rejected.push(event);
+        }
+    }
+    (
+        kept,
+        TokenCache {
+            tokens: kept_tokens,
+        },
+        rejected,
+    )
+}
+
+/// Resolve the token of a single event: its own token, then the request token (from the
+/// Authorization header), then the configured `default_token`.
+pub fn resolve_token(
+    event: &RawEvent,
+    request_token: Option<&str>,
+    config: &ProcessingConfig,
+) -> Result<String, CaptureError> {
+    let token = event
+        .extract_token()
+        .or_else(|| request_token.map(String::from))
+        .or_else(|| config.default_token.clone())
+        .ok_or(CaptureError::MissingToken)?;
+    validate_token(&token)?;
+    Ok(token)
+}
+
+/// Process each event on its own instead of failing the whole batch on the first invalid one.
+/// Returns the valid events, an acknowledgement for each event, in the batch order, and a
+/// dead-letter for each rejected event.
+/// Each event is attributed its own token, see `resolve_token`, the context token standing for
+/// the request token when not empty. Events are processed under their own token.
+pub fn process_events_lenient(
+    events: Vec<RawEvent>,
+    context: &ProcessingContext,
+    config: &ProcessingConfig,
+) -> (Vec<ProcessedEvent>, Vec<Ack>, Vec<DeadLetter>) {
+    let mut processed = Vec::with_capacity(events.len());
+    let mut acks = Vec::with_capacity(events.len());
+    let mut dead_letters = Vec::new();
+
+    let request_token = Some(context.token.as_str()).filter(|token| !token.is_empty());
+    for event in events {
+        let uuid = event.uuid;
+        // Kept apart as processing consumes the event
+        let raw = event.clone();
+        let result = resolve_token(&event, request_token, config).and_then(|token| {
+            let context = ProcessingContext {
+                token,
+                ..context.clone()
+            };
+            process_single_event(event, &context, config)
+        });
+        match result {
+            Ok(event) => {
+                acks.push(Ack {
+                    uuid: Some(event.uuid),
+                    status: AckStatus::Accepted,
+                    reason: None,
+                });
+                processed.push(event);
+            }
+            Err(err) => {
+                acks.push(Ack {
+                    uuid,
+                    status: AckStatus::Rejected,
+                    reason: Some(err.to_string()),
+                });
+                dead_letters.push(dead_letter(raw, err, context));
+            }
+        }
+    }
+
+    (processed, acks, dead_letters)
+}
+
+fn dead_letter(raw: RawEvent, reason: CaptureError, context: &ProcessingContext) -> DeadLetter {
+    DeadLetter {
+        raw: serde_json::to_value(raw).unwrap_or_default(),
+        reason,
+        received_at: context.now.clone(),
+    }
+}
+
+/// Synthetic event summarizing the events of a request rejected by processing, counted by
+/// reason. It is sent under the request token, for the rejections to show up in the project
+/// they were meant for. Returns None if no event was rejected.
+pub fn ingestion_warning(
+    dead_letters: &[DeadLetter],
+    context: &ProcessingContext,
+) -> Option<RawEvent> {
+    if dead_letters.is_empty() {
+        return None;
+    }
+    let mut reasons: HashMap<String, u64> = HashMap::new();
+    for dead_letter in dead_letters {
+        *reasons.entry(dead_letter.reason.to_string()).or_default() += 1;
+    }
+
+    Some(RawEvent {
+        token: Some(context.token.clone()),
+        distinct_id: Some(String::from(INGESTION_WARNING_DISTINCT_ID)),
+        event: String::from(INGESTION_WARNING_EVENT),
+        properties: HashMap::from([
+            (
+                String::from("rejected_events"),
+                Value::from(dead_letters.len()),
+            ),
+            (
+                String::from("reasons"),
+                Value::Object(
+                    reasons
+                        .into_iter()
+                        .map(|(reason, count)| (reason, Value::from(count)))
+                        .collect(),
+                ),
+            ),
+        ]),
+        ..Default::default()
+    })
+}
+
+/// Dry run of the parsing, validation and normalization steps of a request body, reporting the
+/// outcome of each event without ingesting anything. Warnings list the markers set on the event
+/// by normalization steps, such as `$clamped_properties`.
+pub fn validate_only(
+    query: &EventQuery,
+    bytes: Bytes,
+    config: &ProcessingConfig,
+) -> Result<Vec<EventValidation>, CaptureError> {
+    let events = RawEvent::from_bytes_with(query, bytes, config)?;
+    // Markers sent by the client are not warnings
+    let sent_markers: Vec<Vec<&str>> = events
+        .iter()
+        .map(|event| {
+            WARNING_PROPERTIES
+                .into_iter()
+                .filter(|key| event.properties.contains_key(*key))
+                .collect()
+        })
+        .collect();
+    let context = ProcessingContext {
+        lib_version: query.lib_version.clone(),
+        sent_at: None,
+        event_time: None,
+        trace_id: None,
+        traceparent: None,
+        user_agent: None,
+        origin: None,
+        path: None,
+        ingest_region: None,
+        token: String::new(),
+        now: SystemTime {}.current_time(),
+        clock: Arc::new(SystemTime {}),
+        client_ip: String::new(),
+    };
+
+    let (processed, acks, _) = process_events_lenient(events, &context, config);
+    let mut processed = processed.into_iter();
+    let validations = acks
+        .into_iter()
+        .zip(sent_markers)
+        .enumerate()
+        .map(|(index, (ack, sent_markers))| match ack.status {
+            AckStatus::Accepted => {
+                let data = processed
+                    .next()
+                    .and_then(|event| event.data_as_value().ok());
+                let warnings = WARNING_PROPERTIES
+                    .into_iter()
+                    .filter(|key| !sent_markers.contains(key))
+                    .filter(|key| {
+                        data.as_ref()
+                            .is_some_and(|d| d["properties"].get(key).is_some())
+                    })
+                    .map(String::from)
+                    .collect();
+                EventValidation {
+                    index,
+                    valid: true,
+                    warnings,
+                    error: None,
+                }
+            }
+            AckStatus::Rejected => EventValidation {
+                index,
+                valid: false,
+                warnings: Vec::new(),
+                error: ack.reason,
+            },
+        })
+        .collect();
+    Ok(validations)
+}
+
+// Processing of the batch goes on past invalid events, those and the `dead_letters` rejected
+// before processing are reported in an `$ingestion_warning` event sent along the valid ones
+fn process_reporting_rejections(
+    events: Vec<RawEvent>,
+    mut dead_letters: Vec<DeadLetter>,
+    context: &ProcessingContext,
+    config: &ProcessingConfig,
+) -> Vec<ProcessedEvent> {
+    let mut processed = Vec::with_capacity(events.len() + 1);
+    for event in events {
+        // Kept apart as processing consumes the event
+        let raw = event.clone();
+        match process_single_event(event, context, config) {
+            Ok(event) => processed.push(event),
+            Err(err) => dead_letters.push(dead_letter(raw, err, context)),
+        }
+    }
+
+    if let Some(warning) = ingestion_warning(&dead_letters, context) {
+        report_dropped_events("event_rejected", dead_letters.len() as u64);
+        match process_single_event(warning, context, config) {
+            Ok(warning) => processed.push(warning),
+            Err(err) => tracing::warn!("failed to process ingestion warning: {}", err),
+        }
+    }
+    processed
+}
+
+#[instrument(skip_all, fields(events = events.len()))]
+pub async fn process_events<'a>(
+    sink: Arc<dyn sink::EventSink + Send + Sync>,
+    events: Vec<RawEvent>,
+    rejected: Vec<DeadLetter>,
+    context: &'a ProcessingContext,
+    config: &'a ProcessingConfig,
+) -> Result<(), CaptureError> {
+    let mut events = events;
+    if config.fan_out_distinct_ids {
+        events = fan_out_distinct_ids(events, config.max_distinct_id_fan_out)?;
+    }
+    if config.coalesce_duplicate_events {
+        let received = events.len();
+        events = coalesce_duplicates(events);
+        tracing::debug!(received, kept = events.len(), "coalesced duplicate events");
+    }
+    if config.flag_out_of_order_events {
+        let tolerance = Duration::milliseconds(config.out_of_order_tolerance_ms as i64);
+        flag_out_of_order(&mut events, tolerance, config);
+    }
+    if let Some(max) = config.max_distinct_properties_per_batch {
+        let removed =
+            limit_distinct_properties(&mut events, max, config.strict_distinct_properties)?;
+        if !removed.is_empty() {
+            tracing::debug!(
+                ?removed,
+                "stripped rare properties over the batch key limit"
+            );
+        }
+    }
+
+    let received = events.len();
+    let events: Vec<RawEvent> = events
+        .into_iter()
+        .filter(|e| keep_sampled(e, config))
+        .collect();
+    if events.len() < received {
+        report_dropped_events(
+            "feature_flag_call_sampled",
+            (received - events.len()) as u64,
+        );
+    }
+
+    let events = if config.emit_ingestion_warnings {
+        process_reporting_rejections(events, rejected, context, config)
+    } else {
+        events
+            .into_iter()
+            .map(|e| process_single_event(e, context, config))
+            .collect::<Result<Vec<ProcessedEvent>, CaptureError>>()?
+    };
+    if events.is_empty() {
+        return Ok(());
+    }
+
+    tracing::debug!(
+        token = token_log_id(&context.token),
+        uuids = ?events.iter().map(|event| event.uuid).collect::<Vec<_>>(),
+        "processed {} events",
+        events.len()
+    );
+
+    if events.len() == 1 {
+        sink.send(events[0].clone()).await
+    } else {
+        sink.send_batch(events).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::{AckStatus, CaptureError, EventValidation};
+    use crate::capture::{
+        check_origin, check_ownership, check_seen_uuids, check_sent_at, coalesce_duplicates,
+        current_span_id, event_time_override, extract_and_verify_token, fan_out_distinct_ids,
+        filter_valid_tokens, flag_out_of_order, gate_ingest_window, ingestion_warning,
+        keep_sampled, no_uuid_seen, number_events, process_events_lenient,
+        process_reporting_rejections, process_single_event, process_with,
+        regenerate_colliding_uuids, reject_tokenless, request_dedup_key, resolve_token,
+        split_by_recency, tokens_in_batch, traceparent_trace_id, validate_only, EventAction,
+        TokenCache, COALESCED_COUNT_PROPERTY, EVENT_NAME_TRUNCATED_PROPERTY,
+    };
+    use crate::config::{
+        DuplicateUuidPolicy, NullDistinctIdPolicy, ProcessingConfig, Ttls, UuidPolicy,
+    };
+    use crate::dedup::{DedupKey, RequestDedupCache};
+    use crate::event::{EventOffset, EventQuery, ProcessingContext, RawEvent};
+    use crate::ownership::OwnershipValidator;
+    use crate::time::{SystemTime, TimeSource};
+    use crate::token::TokenValidator;
+    use crate::utils::{uuid_v4, uuid_v7};
+    use async_trait::async_trait;
+    use axum::http::HeaderMap;
+    use serde_json::{json, Value};
+    use std::collections::{HashMap, HashSet};
+    use std::sync::{Arc, Mutex};
+    use time::macros::datetime;
+    use uuid::Uuid;
+
+    fn test_context() -> ProcessingContext {
+        ProcessingContext {
+            lib_version: None,
+            sent_at: None,
+            event_time: None,
+            trace_id: None,
+            traceparent: None,
+            user_agent: None,
+            origin: None,
+            path: None,
+            ingest_region: None,
+            token: String::from("token"),
+            now: String::from("2023-09-15T09:15:02.328551+00:00"),
+            clock: Arc::new(FixedTime {
+                time: "2023-09-15T09:15:02.328551+00:00",
+            }),
+            client_ip: String::from("127.0.0.1"),
+        }
+    }
+
+    #[tokio::test]
+    async fn all_events_have_same_token() {
+        let events = vec![
+            RawEvent {
+                token: Some(String::from("hello")),
+                distinct_id: Some("testing".to_string()),
+                uuid: None,
+                event: String::new(),
+                properties: HashMap::new(),
+                timestamp: None,
+                offset: None,
+                set: Default::default(),
+                set_once: Default::default(),
+                original_index: 0,
+            },
+            RawEvent {
+                token: None,
+                distinct_id: Some("testing".to_string()),
+                uuid: None,
+                event: String::new(),
+                properties: HashMap::from([(String::from("token"), json!("hello"))]),
                 timestamp: None,
                 offset: None,
                 set: Default::default(),
                 set_once: Default::default(),
+                original_index: 0,
             },
         ];
 
-        let processed = extract_and_verify_token(&events);
-        assert_eq!(processed.is_ok(), true, "{:?}", processed);
+        let processed =
+            extract_and_verify_token(&TokenCache::new(&events), None, &Default::default());
+        assert!(processed.is_ok(), "{:?}", processed);
     }
 
     #[tokio::test]
@@ -291,6 +1826,7 @@ mod tests {
                 offset: None,
                 set: Default::default(),
                 set_once: Default::default(),
+                original_index: 0,
             },
             RawEvent {
                 token: None,
@@ -302,10 +1838,2159 @@ mod tests {
                 offset: None,
                 set: Default::default(),
                 set_once: Default::default(),
+                original_index: 0,
+            },
+        ];
+
+        let processed =
+            extract_and_verify_token(&TokenCache::new(&events), None, &Default::default());
+        assert!(processed.is_err());
+    }
+
+    #[test]
+    fn nameless_event_is_rejected_by_default() {
+        let event: RawEvent = serde_json::from_value(json!({
+            "distinct_id": "user1",
+            "properties": {"key": "value"}
+        }))
+        .expect("failed to parse nameless event");
+
+        let processed = process_single_event(event, &test_context(), &Default::default());
+        assert!(matches!(processed, Err(CaptureError::MissingEventName)));
+    }
+
+    #[test]
+    fn nameless_event_is_defaulted_in_lenient_mode() {
+        let event: RawEvent = serde_json::from_value(json!({
+            "distinct_id": "user1",
+            "properties": {"key": "value"}
+        }))
+        .expect("failed to parse nameless event");
+        let config = ProcessingConfig {
+            lenient_event_name: true,
+            ..Default::default()
+        };
+
+        let processed = process_single_event(event, &test_context(), &config)
+            .expect("nameless event should be accepted");
+        let data: Value = serde_json::from_str(&processed.data).unwrap();
+        assert_eq!(data["event"], "$unknown");
+        assert_eq!(data["properties"]["key"], "value");
+    }
+
+    #[test]
+    fn processing_duration_recorded_when_enabled() {
+        let processed =
+            process_single_event(event_without_uuid(), &test_context(), &Default::default())
+                .expect("event should be processed");
+        assert_eq!(processed.processing_duration, None);
+
+        let config = ProcessingConfig {
+            record_processing_duration: true,
+            ..Default::default()
+        };
+        let processed = process_single_event(event_without_uuid(), &test_context(), &config)
+            .expect("event should be processed");
+        assert!(processed.processing_duration.is_some());
+        // Debug field, not forwarded to the sinks
+        let value = serde_json::to_value(&processed).unwrap();
+        assert!(value.get("processing_duration").is_none());
+    }
+
+    fn process_with_time_property(event: Value) -> Value {
+        let event: RawEvent = serde_json::from_value(event).unwrap();
+        let config = ProcessingConfig {
+            promote_time_properties: true,
+            ..Default::default()
+        };
+        let processed = process_single_event(event, &test_context(), &config)
+            .expect("event should be processed");
+        serde_json::from_str(&processed.data).unwrap()
+    }
+
+    #[test]
+    fn time_property_promoted_to_timestamp() {
+        let data = process_with_time_property(json!({
+            "event": "pageview",
+            "distinct_id": "user1",
+            "properties": {"time": 1698321600123_i64, "key": "value"}
+        }));
+        assert_eq!(data["timestamp"], "2023-10-26T12:00:00.123Z");
+        assert!(data["properties"].get("time").is_none());
+        assert_eq!(data["properties"]["key"], "value");
+    }
+
+    #[test]
+    fn dollar_time_property_promoted_to_timestamp() {
+        let data = process_with_time_property(json!({
+            "event": "pageview",
+            "distinct_id": "user1",
+            "properties": {"$time": "1698321600"}
+        }));
+        assert_eq!(data["timestamp"], "2023-10-26T12:00:00Z");
+        assert!(data["properties"].get("$time").is_none());
+    }
+
+    #[test]
+    fn time_property_kept_with_timestamp() {
+        let data = process_with_time_property(json!({
+            "event": "pageview",
+            "distinct_id": "user1",
+            "timestamp": "2023-10-26T10:00:00Z",
+            "properties": {"time": 1698321600123_i64}
+        }));
+        assert_eq!(data["timestamp"], "2023-10-26T10:00:00Z");
+        assert_eq!(data["properties"]["time"], 1698321600123_i64);
+    }
+
+    #[test]
+    fn nameless_event_is_named_after_the_path() {
+        let event: RawEvent = serde_json::from_value(json!({
+            "distinct_id": "user1",
+            "properties": {"key": "value"}
+        }))
+        .expect("failed to parse nameless event");
+        let config = ProcessingConfig {
+            path_event_names: "/track/pageview:$pageview,/track/click:click"
+                .parse()
+                .unwrap(),
+            ..Default::default()
+        };
+        let context_at = |path: &str| ProcessingContext {
+            path: Some(path.to_string()),
+            ..test_context()
+        };
+
+        let processed =
+            process_single_event(event.clone(), &context_at("/track/pageview/"), &config)
+                .expect("nameless event should be named");
+        let data: Value = serde_json::from_str(&processed.data).unwrap();
+        assert_eq!(data["event"], "$pageview");
+
+        let named = RawEvent {
+            event: String::from("signup"),
+            ..event.clone()
+        };
+        let processed = process_single_event(named, &context_at("/track/click"), &config).unwrap();
+        assert_eq!(processed.event, "signup");
+
+        let res = process_single_event(event, &context_at("/i/v0/e"), &config);
+        assert!(matches!(res, Err(CaptureError::MissingEventName)));
+    }
+
+    #[test]
+    fn duplicate_uuids_are_kept() {
+        let uuid = uuid_v7();
+        let event = RawEvent {
+            uuid: Some(uuid),
+            distinct_id: Some(String::from("user1")),
+            event: String::from("pageview"),
+            ..Default::default()
+        };
+        let mut events = vec![event.clone(), event];
+
+        regenerate_colliding_uuids(&mut events, UuidPolicy::V7);
+        assert_eq!(events[0].uuid, Some(uuid));
+        assert_eq!(events[1].uuid, Some(uuid));
+    }
+
+    #[test]
+    fn colliding_uuids_are_regenerated() {
+        let uuid = uuid_v7();
+        let mut events = vec![
+            RawEvent {
+                uuid: Some(uuid),
+                distinct_id: Some(String::from("user1")),
+                event: String::from("pageview"),
+                ..Default::default()
+            },
+            RawEvent {
+                uuid: Some(uuid),
+                distinct_id: Some(String::from("user2")),
+                event: String::from("pageview"),
+                ..Default::default()
+            },
+            RawEvent {
+                uuid: Some(uuid),
+                distinct_id: Some(String::from("user1")),
+                event: String::from("pageleave"),
+                ..Default::default()
             },
         ];
 
-        let processed = extract_and_verify_token(&events);
-        assert_eq!(processed.is_err(), true);
+        regenerate_colliding_uuids(&mut events, UuidPolicy::V7);
+        assert_eq!(events[0].uuid, Some(uuid));
+        assert_ne!(events[1].uuid, Some(uuid));
+        assert_ne!(events[2].uuid, Some(uuid));
+        assert_ne!(events[1].uuid, events[2].uuid);
+    }
+
+    #[test]
+    fn colliding_property_uuids_are_regenerated() {
+        let uuid = uuid_v7();
+        let event = |distinct_id: &str| RawEvent {
+            distinct_id: Some(String::from(distinct_id)),
+            event: String::from("pageview"),
+            properties: HashMap::from([(String::from("$insert_id"), json!(uuid.to_string()))]),
+            ..Default::default()
+        };
+        let mut events = vec![event("user1"), event("user1"), event("user2")];
+
+        regenerate_colliding_uuids(&mut events, UuidPolicy::V7);
+        assert_eq!(events[0].extract_uuid(), Some(uuid));
+        assert_eq!(events[1].extract_uuid(), Some(uuid));
+        let replacement = events[2].extract_uuid().unwrap();
+        assert_ne!(replacement, uuid);
+
+        // The replacement wins over the property when the event is processed
+        let processed =
+            process_single_event(events.remove(2), &test_context(), &Default::default()).unwrap();
+        assert_eq!(processed.uuid, replacement);
+    }
+
+    #[test]
+    fn auth_header_token_takes_precedence() {
+        let events = vec![RawEvent {
+            token: Some(String::from("body_token")),
+            distinct_id: Some(String::from("user1")),
+            ..Default::default()
+        }];
+
+        let token = extract_and_verify_token(
+            &TokenCache::new(&events),
+            Some(String::from("header_token")),
+            &Default::default(),
+        );
+        assert_eq!(token.unwrap(), "header_token");
+
+        let token = extract_and_verify_token(&TokenCache::new(&events), None, &Default::default());
+        assert_eq!(token.unwrap(), "body_token");
+    }
+
+    #[test]
+    fn snapshot_events_skip_property_guards() {
+        let config = ProcessingConfig {
+            max_property_string_length: Some(4),
+            max_property_depth: Some(1),
+            max_event_size_bytes: Some(500),
+            ..Default::default()
+        };
+        let event_named = |name: &str| RawEvent {
+            distinct_id: Some(String::from("user1")),
+            event: name.to_string(),
+            properties: HashMap::from([
+                (String::from("$session_id"), json!("session1")),
+                (
+                    String::from("$snapshot_data"),
+                    json!([{"data": "abcdefgh"}]),
+                ),
+            ]),
+            ..Default::default()
+        };
+
+        let processed = process_single_event(event_named("$snapshot"), &test_context(), &config)
+            .expect("snapshot should be accepted");
+        let data: Value = serde_json::from_str(&processed.data).unwrap();
+        assert_eq!(data["properties"]["$snapshot_data"][0]["data"], "abcdefgh");
+        assert_eq!(processed.session_id.as_deref(), Some("session1"));
+
+        let processed = process_single_event(event_named("pageview"), &test_context(), &config);
+        assert!(matches!(processed, Err(CaptureError::PropertiesTooDeep)));
+
+        let config = ProcessingConfig {
+            max_property_depth: None,
+            ..config
+        };
+        let processed = process_single_event(event_named("pageview"), &test_context(), &config)
+            .expect("event should be accepted");
+        let data: Value = serde_json::from_str(&processed.data).unwrap();
+        assert_eq!(data["properties"]["$snapshot_data"][0]["data"], "abcd");
+        assert_eq!(data["properties"]["$session_id"], "sess");
+    }
+
+    #[test]
+    fn snapshot_events_are_size_limited() {
+        let config = ProcessingConfig {
+            max_event_size_bytes: Some(100),
+            ..Default::default()
+        };
+        let event = RawEvent {
+            distinct_id: Some(String::from("user1")),
+            event: String::from("$snapshot"),
+            properties: HashMap::from([(String::from("$snapshot_data"), json!("x".repeat(100)))]),
+            ..Default::default()
+        };
+
+        let processed = process_single_event(event, &test_context(), &config);
+        assert!(matches!(processed, Err(CaptureError::EventTooBig)));
+    }
+
+    #[test]
+    fn token_mismatch_is_rejected() {
+        let config = ProcessingConfig {
+            reject_token_mismatch: true,
+            ..Default::default()
+        };
+        let events = vec![RawEvent {
+            token: Some(String::from("body_token")),
+            distinct_id: Some(String::from("user1")),
+            ..Default::default()
+        }];
+
+        let token = extract_and_verify_token(
+            &TokenCache::new(&events),
+            Some(String::from("body_token")),
+            &config,
+        );
+        assert_eq!(token.unwrap(), "body_token");
+
+        let token = extract_and_verify_token(
+            &TokenCache::new(&events),
+            Some(String::from("other_token")),
+            &config,
+        );
+        assert!(matches!(token, Err(CaptureError::TokenMismatch)));
+    }
+
+    #[test]
+    fn token_mismatch_skipped_for_single_source() {
+        let config = ProcessingConfig {
+            reject_token_mismatch: true,
+            ..Default::default()
+        };
+        let tokenless = vec![RawEvent {
+            distinct_id: Some(String::from("user1")),
+            ..Default::default()
+        }];
+        let token = extract_and_verify_token(
+            &TokenCache::new(&tokenless),
+            Some(String::from("header")),
+            &config,
+        );
+        assert_eq!(token.unwrap(), "header");
+
+        let with_token = vec![RawEvent {
+            token: Some(String::from("body_token")),
+            ..Default::default()
+        }];
+        let token = extract_and_verify_token(&TokenCache::new(&with_token), None, &config);
+        assert_eq!(token.unwrap(), "body_token");
+    }
+
+    /// Accepts tokens starting with "valid", recording the tokens it was called with.
+    #[derive(Default)]
+    struct StubValidator {
+        calls: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl TokenValidator for StubValidator {
+        async fn is_valid(&self, token: &str) -> bool {
+            self.calls.lock().unwrap().push(token.to_string());
+            token.starts_with("valid")
+        }
+    }
+
+    fn events_with_tokens(tokens: &[Option<&str>]) -> Vec<RawEvent> {
+        tokens
+            .iter()
+            .map(|token| RawEvent {
+                token: token.map(String::from),
+                event: String::from("e"),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    /// Permits the distinct_ids prefixed with the token, recording the pairs it was called with.
+    #[derive(Default)]
+    struct StubOwnership {
+        calls: Mutex<Vec<(String, String)>>,
+    }
+
+    #[async_trait]
+    impl OwnershipValidator for StubOwnership {
+        async fn is_permitted(&self, token: &str, distinct_id: &str) -> bool {
+            let pair = (token.to_string(), distinct_id.to_string());
+            self.calls.lock().unwrap().push(pair);
+            distinct_id.starts_with(token)
+        }
+    }
+
+    fn events_with_owners(pairs: &[(Option<&str>, &str)]) -> Vec<RawEvent> {
+        pairs
+            .iter()
+            .map(|(token, distinct_id)| RawEvent {
+                token: token.map(String::from),
+                distinct_id: Some(distinct_id.to_string()),
+                event: String::from("e"),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn permits_owned_distinct_ids() {
+        let validator = StubOwnership::default();
+        let events = events_with_owners(&[
+            (Some("a"), "a_user1"),
+            (None, "a_user2"),
+            (Some("a"), "a_user1"),
+            (Some("b"), "b_user1"),
+        ]);
+
+        check_ownership(&events, &TokenCache::new(&events), "a", &validator)
+            .await
+            .expect("all pairs are permitted");
+        // Once per distinct pair
+        assert_eq!(validator.calls.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn rejects_distinct_ids_of_other_owners() {
+        let validator = StubOwnership::default();
+        let events = events_with_owners(&[(Some("a"), "a_user1"), (None, "b_user1")]);
+
+        assert!(matches!(
+            check_ownership(&events, &TokenCache::new(&events), "a", &validator).await,
+            Err(CaptureError::OwnershipViolation)
+        ));
+        assert_eq!(
+            validator.calls.lock().unwrap().last(),
+            Some(&(String::from("a"), String::from("b_user1")))
+        );
+    }
+
+    #[tokio::test]
+    async fn checks_owners_of_fanned_out_distinct_ids() {
+        let validator = StubOwnership::default();
+        let mut events = events_with_owners(&[(Some("a"), "a_user1")]);
+        events[0]
+            .properties
+            .insert(String::from("$distinct_ids"), json!(["a_user2", "b_user1"]));
+        let fanned = fan_out_distinct_ids(events.clone(), 10).unwrap();
+        assert!(fanned
+            .iter()
+            .any(|event| event.distinct_id.as_deref() == Some("b_user1")));
+
+        assert!(matches!(
+            check_ownership(&events, &TokenCache::new(&events), "a", &validator).await,
+            Err(CaptureError::OwnershipViolation)
+        ));
+        assert_eq!(
+            validator.calls.lock().unwrap().last(),
+            Some(&(String::from("a"), String::from("b_user1")))
+        );
+
+        let mut owned = events_with_owners(&[(Some("a"), "a_user1")]);
+        owned[0]
+            .properties
+            .insert(String::from("$distinct_ids"), json!(["a_user2", "a_user3"]));
+        check_ownership(&owned, &TokenCache::new(&owned), "a", &validator)
+            .await
+            .expect("all fanned out ids are owned");
+    }
+
+    #[tokio::test]
+    async fn original_indices_survive_filtering() {
+        let mut events = events_with_tokens(&[
+            Some("disabled"),
+            Some("valid_a"),
+            Some("disabled"),
+            Some("valid_b"),
+        ]);
+        for event in events.iter_mut() {
+            event.distinct_id = Some(String::from("user1"));
+        }
+        number_events(&mut events);
+
+        let tokens = TokenCache::new(&events);
+        let (events, _) = filter_valid_tokens(
+            events,
+            tokens,
+            "valid_default",
+            &StubValidator::default(),
+            &ProcessingConfig::default(),
+        )
+        .await
+        .unwrap();
+        let indices: Vec<u32> = events
+            .into_iter()
+            .map(|event| {
+                process_single_event(event, &test_context(), &ProcessingConfig::default())
+                    .unwrap()
+                    .original_index
+            })
+            .collect();
+        assert_eq!(indices, vec![1, 3]);
+    }
+
+    #[tokio::test]
+    async fn drops_events_with_disabled_tokens() {
+        let validator = StubValidator::default();
+        let events = events_with_tokens(&[
+            Some("valid_a"),
+            Some("disabled"),
+            None,
+            Some("valid_a"),
+            Some("disabled"),
+        ]);
+
+        let (events, cache) = filter_valid_tokens(
+            events.clone(),
+            TokenCache::new(&events),
+            "valid_default",
+            &validator,
+            &ProcessingConfig::default(),
+        )
+        .await
+        .expect("tokens are dropped, not rejected");
+        let tokens: Vec<Option<String>> = events.iter().map(RawEvent::extract_token).collect();
+        let cached: Vec<Option<String>> = (0..cache.len())
+            .map(|index| cache.get(index).map(String::from))
+            .collect();
+        assert_eq!(cached, tokens);
+        assert_eq!(
+            tokens,
+            vec![
+                Some(String::from("valid_a")),
+                None,
+                Some(String::from("valid_a"))
+            ]
+        );
+
+        // Called once per distinct token
+        assert_eq!(
+            *validator.calls.lock().unwrap(),
+            vec!["valid_a", "disabled", "valid_default"]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_disabled_tokens() {
+        let config = ProcessingConfig {
+            reject_disabled_tokens: true,
+            ..Default::default()
+        };
+        let validator = StubValidator::default();
+
+        let valid = events_with_tokens(&[Some("valid_a"), None]);
+        let tokens = TokenCache::new(&valid);
+        assert!(
+            filter_valid_tokens(valid, tokens, "valid_b", &validator, &config)
+                .await
+                .is_ok()
+        );
+
+        let invalid = events_with_tokens(&[Some("valid_a"), None]);
+        let tokens = TokenCache::new(&invalid);
+        let res = filter_valid_tokens(invalid, tokens, "disabled", &validator, &config).await;
+        assert!(matches!(res, Err(CaptureError::DisabledToken)));
+    }
+
+    #[tokio::test]
+    async fn tokens_are_resolved_once_per_event() {
+        let events = events_with_owners(&[
+            (Some("a"), "a_user1"),
+            (None, "a_user2"),
+            (Some("a"), "a_user3"),
+        ]);
+        let mut lookups = 0;
+        let tokens = TokenCache::with_extractor(&events, |event| {
+            lookups += 1;
+            event.extract_token()
+        });
+
+        let token = extract_and_verify_token(&tokens, None, &ProcessingConfig::default()).unwrap();
+        let (events, tokens) = filter_valid_tokens(
+            events,
+            tokens,
+            &token,
+            &StubValidator::default(),
+            &ProcessingConfig::default(),
+        )
+        .await
+        .unwrap();
+        check_ownership(&events, &tokens, &token, &StubOwnership::default())
+            .await
+            .unwrap();
+        assert_eq!(lookups, 3);
+    }
+
+    fn feature_flag_event(name: &str) -> RawEvent {
+        RawEvent {
+            event: name.to_string(),
+            distinct_id: Some(String::from("user1")),
+            properties: serde_json::from_value(json!({
+                "$feature_flag": "new-onboarding",
+                "$feature_flag_response": true,
+                "$current_url": "https://example.com/",
+                "$browser": "Firefox"
+            }))
+            .unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn prunes_feature_flag_called_properties() {
+        let config = ProcessingConfig {
+            prune_feature_flag_calls: true,
+            ..Default::default()
+        };
+
+        let processed = process_single_event(
+            feature_flag_event("$feature_flag_called"),
+            &test_context(),
+            &config,
+        )
+        .unwrap();
+        let data: Value = serde_json::from_str(&processed.data).unwrap();
+        assert_eq!(
+            data["properties"],
+            json!({"$feature_flag": "new-onboarding", "$feature_flag_response": true})
+        );
+
+        let processed =
+            process_single_event(feature_flag_event("$pageview"), &test_context(), &config)
+                .unwrap();
+        let data: Value = serde_json::from_str(&processed.data).unwrap();
+        assert_eq!(data["properties"]["$browser"], json!("Firefox"));
+        assert_eq!(
+            data["properties"]["$current_url"],
+            json!("https://example.com/")
+        );
+    }
+
+    fn autocapture_event() -> RawEvent {
+        RawEvent {
+            event: String::from("$autocapture"),
+            distinct_id: Some(String::from("user1")),
+            properties: HashMap::from([
+                (String::from("$event_type"), json!("click")),
+                (String::from("$elements"), json!([{"tag_name": "button"}])),
+                (
+                    String::from("$elements_chain"),
+                    json!("button:nth-child=\"1\""),
+                ),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn strips_autocapture_elements() {
+        let config = ProcessingConfig {
+            strip_elements: true,
+            ..Default::default()
+        };
+
+        let processed =
+            process_single_event(autocapture_event(), &test_context(), &config).unwrap();
+        let data: Value = serde_json::from_str(&processed.data).unwrap();
+        assert_eq!(
+            data["properties"],
+            json!({"$event_type": "click", "$elements_stripped": true})
+        );
+    }
+
+    #[test]
+    fn keeps_autocapture_elements_by_default() {
+        let processed = process_single_event(
+            autocapture_event(),
+            &test_context(),
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        let data: Value = serde_json::from_str(&processed.data).unwrap();
+        assert_eq!(
+            data["properties"]["$elements"],
+            json!([{"tag_name": "button"}])
+        );
+        assert!(data["properties"].get("$elements_stripped").is_none());
+    }
+
+    #[test]
+    fn samples_feature_flag_called_events() {
+        let config = ProcessingConfig {
+            feature_flag_call_sample_rate: 0.0,
+            ..Default::default()
+        };
+        assert!(!keep_sampled(
+            &feature_flag_event("$feature_flag_called"),
+            &config
+        ));
+        assert!(keep_sampled(&feature_flag_event("$pageview"), &config));
+
+        let default = ProcessingConfig::default();
+        assert!(keep_sampled(
+            &feature_flag_event("$feature_flag_called"),
+            &default
+        ));
+    }
+
+    fn event_without_uuid() -> RawEvent {
+        RawEvent {
+            event: String::from("pageview"),
+            distinct_id: Some(String::from("user1")),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn stamps_expiry_of_events_with_a_ttl() {
+        let config = ProcessingConfig {
+            event_ttls: "experiment_viewed:P7D".parse().unwrap(),
+            token_ttls: "token:PT1H,other:P1D".parse().unwrap(),
+            ..Default::default()
+        };
+        let process = |event: RawEvent, token: &str| {
+            let context = ProcessingContext {
+                token: String::from(token),
+                ..test_context()
+            };
+            process_single_event(event, &context, &config).unwrap()
+        };
+
+        let experiment = RawEvent {
+            event: String::from("experiment_viewed"),
+            timestamp: Some(String::from("2023-09-14T10:00:00Z")),
+            ..event_without_uuid()
+        };
+        let processed = process(experiment, "token");
+        assert_eq!(
+            processed.expires_at,
+            Some(datetime!(2023-09-21 10:00:00 UTC))
+        );
+        let value = serde_json::to_value(&processed).unwrap();
+        assert_eq!(value["expires_at"], json!("2023-09-21T10:00:00Z"));
+
+        // From now shifted by the offset, without a timestamp
+        let pageview = RawEvent {
+            offset: Some(EventOffset::Millis(2_328)),
+            ..event_without_uuid()
+        };
+        let processed = process(pageview, "token");
+        assert_eq!(
+            processed.expires_at,
+            Some(datetime!(2023-09-15 10:15:00.000551 UTC))
+        );
+
+        let processed = process(event_without_uuid(), "unconfigured");
+        assert_eq!(processed.expires_at, None);
+        let value = serde_json::to_value(&processed).unwrap();
+        assert!(value.get("expires_at").is_none());
+
+        assert!("experiment_viewed:P1M".parse::<Ttls>().is_err());
+    }
+
+    #[test]
+    fn drops_or_flags_seen_uuids() {
+        let (seen, fresh) = (uuid_v7(), uuid_v7());
+        let events = vec![
+            RawEvent {
+                uuid: Some(seen),
+                ..event_without_uuid()
+            },
+            RawEvent {
+                uuid: Some(fresh),
+                ..event_without_uuid()
+            },
+            event_without_uuid(),
+        ];
+        let uuid_seen = |uuid: &Uuid| *uuid == seen;
+
+        let (kept, dropped) =
+            check_seen_uuids(events.clone(), uuid_seen, DuplicateUuidPolicy::Drop);
+        assert_eq!(dropped, 1);
+        let uuids: Vec<Option<Uuid>> = kept.iter().map(|e| e.uuid).collect();
+        assert_eq!(uuids, vec![Some(fresh), None]);
+
+        let (kept, dropped) =
+            check_seen_uuids(events.clone(), uuid_seen, DuplicateUuidPolicy::Flag);
+        assert_eq!(dropped, 0);
+        let flagged: Vec<bool> = kept
+            .iter()
+            .map(|e| e.properties.contains_key("$duplicate_uuid"))
+            .collect();
+        assert_eq!(flagged, vec![true, false, false]);
+        assert_eq!(kept[0].properties["$duplicate_uuid"], json!(true));
+
+        let (kept, dropped) = check_seen_uuids(events, no_uuid_seen, DuplicateUuidPolicy::Drop);
+        assert_eq!((kept.len(), dropped), (3, 0));
+    }
+
+    #[test]
+    fn checks_uuids_sent_in_properties() {
+        let seen = uuid_v7();
+        let mut event = event_without_uuid();
+        event
+            .properties
+            .insert(String::from("uuid"), json!(seen.to_string()));
+        let uuid_seen = |uuid: &Uuid| *uuid == seen;
+
+        let (kept, dropped) = check_seen_uuids(vec![event], uuid_seen, DuplicateUuidPolicy::Drop);
+        assert_eq!((kept.len(), dropped), (0, 1));
+    }
+
+    #[test]
+    fn generated_uuid_versions() {
+        let processed = process_single_event(
+            event_without_uuid(),
+            &test_context(),
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(processed.uuid.get_version_num(), 7);
+
+        let config = ProcessingConfig {
+            uuid_policy: UuidPolicy::V4,
+            ..Default::default()
+        };
+        let processed =
+            process_single_event(event_without_uuid(), &test_context(), &config).unwrap();
+        assert_eq!(processed.uuid.get_version_num(), 4);
+        assert_eq!(processed.uuid.get_variant(), uuid::Variant::RFC4122);
+    }
+
+    #[test]
+    fn strict_uuid_version() {
+        let config = ProcessingConfig {
+            uuid_policy: UuidPolicy::V4,
+            strict_uuid_version: true,
+            ..Default::default()
+        };
+        let with_uuid = |uuid| RawEvent {
+            uuid: Some(uuid),
+            ..event_without_uuid()
+        };
+
+        let res = process_single_event(with_uuid(uuid_v7()), &test_context(), &config);
+        assert!(matches!(res, Err(CaptureError::DisallowedUuidVersion)));
+
+        let uuid = uuid_v4();
+        let processed = process_single_event(with_uuid(uuid), &test_context(), &config).unwrap();
+        assert_eq!(processed.uuid, uuid);
+
+        // Client uuids are not checked by default
+        let res = process_single_event(
+            with_uuid(uuid_v7()),
+            &test_context(),
+            &ProcessingConfig {
+                uuid_policy: UuidPolicy::V4,
+                ..Default::default()
+            },
+        );
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn uuid_clock_drift() {
+        let config = ProcessingConfig {
+            max_uuid_clock_drift_ms: Some(60_000),
+            ..Default::default()
+        };
+        // v7 uuid generated at the given offset from the test context time
+        let v7_at = |offset_ms: i64| {
+            let millis = (1694769302328 + offset_ms) as u64;
+            let mut bytes = *uuid_v7().as_bytes();
+            bytes[..6].copy_from_slice(&millis.to_be_bytes()[2..]);
+            uuid::Uuid::from_bytes(bytes)
+        };
+        let process = |uuid| {
+            let event = RawEvent {
+                uuid: Some(uuid),
+                ..event_without_uuid()
+            };
+            let processed = process_single_event(event, &test_context(), &config).unwrap();
+            let data: Value = serde_json::from_str(&processed.data).unwrap();
+            (
+                processed.uuid,
+                data["properties"]["$uuid_clock_drift"].clone(),
+            )
+        };
+
+        let uuid = v7_at(-30_000);
+        assert_eq!(process(uuid), (uuid, Value::Null));
+
+        let uuid = v7_at(3_600_000);
+        let (regenerated, drift) = process(uuid);
+        assert_ne!(regenerated, uuid);
+        assert_eq!(drift, Value::from(3_600_000));
+
+        let uuid = uuid_v4();
+        assert_eq!(process(uuid), (uuid, Value::Null));
+    }
+
+    #[test]
+    fn too_many_tokens_in_batch() {
+        let config = ProcessingConfig {
+            max_tokens_per_batch: 3,
+            ..Default::default()
+        };
+        let batch = |tokens: usize| -> Vec<RawEvent> {
+            (0..10)
+                .map(|i| RawEvent {
+                    token: Some(format!("token{}", i % tokens)),
+                    event: String::from("e"),
+                    ..Default::default()
+                })
+                .collect()
+        };
+        let auth = || Some(String::from("header_token"));
+
+        let token = extract_and_verify_token(&TokenCache::new(&batch(1)), None, &config).unwrap();
+        assert_eq!(token, "token0");
+        assert!(extract_and_verify_token(&TokenCache::new(&batch(3)), auth(), &config).is_ok());
+
+        let res = extract_and_verify_token(&TokenCache::new(&batch(4)), auth(), &config);
+        assert!(matches!(res, Err(CaptureError::TooManyTokens)));
+        let res = extract_and_verify_token(&TokenCache::new(&batch(4)), None, &config);
+        assert!(matches!(res, Err(CaptureError::TooManyTokens)));
+    }
+
+    #[test]
+    fn process_with_mutates_events() {
+        let events = vec![event_without_uuid(), event_without_uuid()];
+
+        let (events, dropped) = process_with(events, |event| {
+            event
+                .properties
+                .insert(String::from("$lib"), json!("embedder"));
+            EventAction::Keep
+        });
+        assert_eq!(dropped, 0);
+        assert_eq!(events.len(), 2);
+        assert!(events
+            .iter()
+            .all(|e| e.properties["$lib"] == json!("embedder")));
+    }
+
+    #[test]
+    fn process_with_drops_events() {
+        let events: Vec<RawEvent> = ["$pageview", "$autocapture", "$pageview"]
+            .iter()
+            .map(|name| RawEvent {
+                event: name.to_string(),
+                ..Default::default()
+            })
+            .collect();
+
+        let (events, dropped) = process_with(events, |event| match event.event.as_str() {
+            "$autocapture" => EventAction::Drop,
+            _ => EventAction::Keep,
+        });
+        assert_eq!(dropped, 1);
+        assert!(events.iter().all(|e| e.event == "$pageview"));
+    }
+
+    fn events_at(timestamps: &[&str]) -> Vec<RawEvent> {
+        timestamps
+            .iter()
+            .map(|timestamp| RawEvent {
+                event: String::from("e"),
+                timestamp: Some(timestamp.to_string()),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn out_of_order(events: &[RawEvent]) -> Vec<bool> {
+        events
+            .iter()
+            .map(|e| e.properties.contains_key("$out_of_order"))
+            .collect()
+    }
+
+    #[test]
+    fn in_order_batch() {
+        let mut events = events_at(&[
+            "2023-10-26T12:00:00Z",
+            "2023-10-26T12:00:01Z",
+            // Within tolerance
+            "2023-10-26T12:00:00.500Z",
+            "invalid",
+            "2023-10-26T12:00:05Z",
+        ]);
+        flag_out_of_order(
+            &mut events,
+            time::Duration::seconds(1),
+            &ProcessingConfig::default(),
+        );
+        assert_eq!(out_of_order(&events), vec![false; 5]);
+    }
+
+    #[test]
+    fn out_of_order_batch() {
+        let mut events = events_at(&[
+            "2023-10-26T12:00:00Z",
+            "2023-10-26T12:10:00Z",
+            "2023-10-26T12:00:01Z",
+            "2023-10-26T12:10:01Z",
+        ]);
+        flag_out_of_order(
+            &mut events,
+            time::Duration::seconds(1),
+            &ProcessingConfig::default(),
+        );
+        assert_eq!(out_of_order(&events), vec![false, false, true, false]);
+    }
+
+    #[test]
+    fn lenient_processing_acks_each_event() {
+        let uuid = uuid_v7();
+        let events = vec![
+            RawEvent {
+                uuid: Some(uuid),
+                ..event_without_uuid()
+            },
+            RawEvent {
+                uuid: Some(uuid_v7()),
+                distinct_id: None,
+                ..event_without_uuid()
+            },
+            event_without_uuid(),
+        ];
+
+        let (processed, acks, _) =
+            process_events_lenient(events, &test_context(), &ProcessingConfig::default());
+        assert_eq!(processed.len(), 2);
+        let statuses: Vec<&AckStatus> = acks.iter().map(|ack| &ack.status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                &AckStatus::Accepted,
+                &AckStatus::Rejected,
+                &AckStatus::Accepted
+            ]
+        );
+        assert_eq!(acks[0].uuid, Some(uuid));
+        assert_eq!(
+            acks[1].reason.as_deref(),
+            Some("event submitted without a distinct_id")
+        );
+        // Generated uuids are acknowledged
+        assert_eq!(acks[2].uuid, Some(processed[1].uuid));
+    }
+
+    #[test]
+    fn lenient_processing_dead_letters_rejected_events() {
+        let with_token = |distinct_id: Option<&str>| RawEvent {
+            token: Some(String::from("body")),
+            distinct_id: distinct_id.map(String::from),
+            ..event_without_uuid()
+        };
+        let events = vec![
+            with_token(None),
+            with_token(Some("user1")),
+            event_without_uuid(),
+        ];
+        let raw: Vec<Value> = events
+            .iter()
+            .map(|event| serde_json::to_value(event).unwrap())
+            .collect();
+        let context = ProcessingContext {
+            token: String::new(),
+            ..test_context()
+        };
+
+        let (processed, _, dead_letters) =
+            process_events_lenient(events, &context, &ProcessingConfig::default());
+        assert_eq!(processed.len(), 1);
+        assert_eq!(dead_letters.len(), 2);
+        assert!(matches!(
+            dead_letters[0].reason,
+            CaptureError::MissingDistinctId
+        ));
+        assert_eq!(dead_letters[0].raw, raw[0]);
+        assert!(matches!(dead_letters[1].reason, CaptureError::MissingToken));
+        assert_eq!(dead_letters[1].raw, raw[2]);
+        assert_eq!(dead_letters[1].received_at, context.now);
+    }
+
+    #[test]
+    fn ingestion_warning_summarizes_rejections() {
+        let context = test_context();
+        let (_, _, dead_letters) = process_events_lenient(
+            vec![RawEvent {
+                distinct_id: None,
+                ..event_without_uuid()
+            }],
+            &context,
+            &ProcessingConfig::default(),
+        );
+        assert!(ingestion_warning(&[], &context).is_none());
+
+        let warning = ingestion_warning(&dead_letters, &context).unwrap();
+        assert_eq!(warning.event, "$ingestion_warning");
+        assert_eq!(warning.token, Some(context.token.clone()));
+        assert_eq!(warning.properties["rejected_events"], json!(1));
+        assert_eq!(
+            warning.properties["reasons"],
+            json!({ CaptureError::MissingDistinctId.to_string(): 1 })
+        );
+    }
+
+    #[test]
+    fn ingestion_warning_sent_only_on_rejections() {
+        let config = ProcessingConfig {
+            emit_ingestion_warnings: true,
+            ..Default::default()
+        };
+        let context = test_context();
+        let with_distinct_id = |distinct_id: Option<&str>| RawEvent {
+            distinct_id: distinct_id.map(String::from),
+            ..event_without_uuid()
+        };
+
+        let processed = process_reporting_rejections(
+            vec![
+                with_distinct_id(Some("user1")),
+                with_distinct_id(Some("user2")),
+            ],
+            Vec::new(),
+            &context,
+            &config,
+        );
+        let names: Vec<&str> = processed.iter().map(|e| e.event.as_str()).collect();
+        assert_eq!(names, vec!["pageview", "pageview"]);
+
+        let processed = process_reporting_rejections(
+            vec![with_distinct_id(None), with_distinct_id(Some("user2"))],
+            Vec::new(),
+            &context,
+            &config,
+        );
+        let names: Vec<&str> = processed.iter().map(|e| e.event.as_str()).collect();
+        assert_eq!(names, vec!["pageview", "$ingestion_warning"]);
+        assert_eq!(processed[1].token, context.token);
+        let data: Value = serde_json::from_str(&processed[1].data).unwrap();
+        assert_eq!(data["properties"]["rejected_events"], json!(1));
+        assert_eq!(
+            data["properties"]["reasons"],
+            json!({ CaptureError::MissingDistinctId.to_string(): 1 })
+        );
+    }
+
+    #[test]
+    fn missing_token_permutations() {
+        let tokenless = events_with_tokens(&[None, None]);
+        let with_default = ProcessingConfig {
+            default_token: Some(String::from("default")),
+            ..Default::default()
+        };
+
+        let token =
+            extract_and_verify_token(&TokenCache::new(&tokenless), None, &Default::default());
+        assert!(matches!(token, Err(CaptureError::MissingToken)));
+        let token = extract_and_verify_token(&TokenCache::new(&tokenless), None, &with_default);
+        assert_eq!(token.unwrap(), "default");
+        let token = extract_and_verify_token(
+            &TokenCache::new(&tokenless),
+            Some(String::from("header")),
+            &with_default,
+        );
+        assert_eq!(token.unwrap(), "header");
+        let with_body = events_with_tokens(&[Some("body"), None]);
+        let token = extract_and_verify_token(&TokenCache::new(&with_body), None, &with_default);
+        assert_eq!(token.unwrap(), "body");
+
+        let event = &tokenless[0];
+        let token = resolve_token(event, None, &Default::default());
+        assert!(matches!(token, Err(CaptureError::MissingToken)));
+        assert_eq!(
+            resolve_token(event, None, &with_default).unwrap(),
+            "default"
+        );
+        assert_eq!(
+            resolve_token(event, Some("header"), &with_default).unwrap(),
+            "header"
+        );
+        assert_eq!(
+            resolve_token(&with_body[0], Some("header"), &with_default).unwrap(),
+            "body"
+        );
+    }
+
+    #[test]
+    fn lenient_processing_attributes_missing_tokens() {
+        let events = vec![
+            RawEvent {
+                token: Some(String::from("body")),
+                ..event_without_uuid()
+            },
+            event_without_uuid(),
+        ];
+        let context = ProcessingContext {
+            token: String::new(),
+            ..test_context()
+        };
+
+        let config = ProcessingConfig {
+            token_ttls: "body:P1D".parse().unwrap(),
+            ..Default::default()
+        };
+
+        let (processed, acks, _) = process_events_lenient(events, &context, &config);
+        assert_eq!(processed.len(), 1);
+        assert_eq!(processed[0].token, "body");
+        // Token-keyed steps see the token of the event
+        assert_eq!(
+            processed[0].expires_at,
+            Some(datetime!(2023-09-16 09:15:02.328551 UTC))
+        );
+        assert_eq!(acks[1].status, AckStatus::Rejected);
+        assert_eq!(acks[1].reason, Some(CaptureError::MissingToken.to_string()));
+    }
+
+    #[test]
+    fn rejects_tokenless_events_one_by_one() {
+        let events = events_with_tokens(&[Some("body"), None, Some("body")]);
+        let tokens = TokenCache::new(&events);
+
+        let (kept, kept_tokens, rejected) =
+            reject_tokenless(events.clone(), tokens, None, &ProcessingConfig::default());
+        assert_eq!(kept.len(), 2);
+        assert_eq!(kept_tokens.len(), 2);
+        assert_eq!(kept_tokens.get(1), Some("body"));
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].extract_token(), None);
+
+        // Events fall back to the request token, then to the default one
+        let (kept, _, rejected) = reject_tokenless(
+            events.clone(),
+            TokenCache::new(&events),
+            Some("header"),
+            &Default::default(),
+        );
+        assert_eq!((kept.len(), rejected.len()), (3, 0));
+        let with_default = ProcessingConfig {
+            default_token: Some(String::from("default")),
+            ..Default::default()
+        };
+        let (kept, _, rejected) = reject_tokenless(
+            events.clone(),
+            TokenCache::new(&events),
+            None,
+            &with_default,
+        );
+        assert_eq!((kept.len(), rejected.len()), (3, 0));
+    }
+
+    #[test]
+    fn event_time_header_fills_missing_timestamps() {
+        let context = ProcessingContext {
+            event_time: Some(datetime!(2023-01-02 03:04:05 UTC)),
+            ..test_context()
+        };
+        let timestamp_of = |event: RawEvent| {
+            let processed =
+                process_single_event(event, &context, &ProcessingConfig::default()).unwrap();
+            let data: Value = serde_json::from_str(&processed.data).unwrap();
+            data["timestamp"].clone()
+        };
+
+        assert_eq!(
+            timestamp_of(event_without_uuid()),
+            json!("2023-01-02T03:04:05Z")
+        );
+        let timestamped = RawEvent {
+            timestamp: Some(String::from("2024-05-06T07:08:09Z")),
+            ..event_without_uuid()
+        };
+        assert_eq!(timestamp_of(timestamped), json!("2024-05-06T07:08:09Z"));
+    }
+
+    #[test]
+    fn parses_event_time_header() {
+        let config = ProcessingConfig::default();
+        let mut headers = HeaderMap::new();
+        assert_eq!(event_time_override(&headers, &config).unwrap(), None);
+
+        headers.insert(
+            "X-PostHog-Event-Time",
+            "2023-01-02T03:04:05Z".parse().unwrap(),
+        );
+        assert_eq!(
+            event_time_override(&headers, &config).unwrap(),
+            Some(datetime!(2023-01-02 03:04:05 UTC))
+        );
+
+        headers.insert("X-PostHog-Event-Time", "yesterday".parse().unwrap());
+        assert!(matches!(
+            event_time_override(&headers, &config),
+            Err(CaptureError::InvalidEventTime)
+        ));
+    }
+
+    #[test]
+    fn repeated_request_id_is_skipped() {
+        let config = ProcessingConfig::default();
+        let cache = RequestDedupCache::new(std::time::Duration::from_secs(60));
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Request-Id", "batch-42".parse().unwrap());
+
+        let key = request_dedup_key(&headers, b"[]", &config).unwrap();
+        assert_eq!(key, DedupKey::RequestId(String::from("batch-42")));
+        assert_eq!(cache.check("token", &key), None);
+        cache.record("token", key, 2);
+
+        // A retry with the same id is skipped, even if the client re-encoded its body
+        let retry = request_dedup_key(&headers, b"[ ]", &config).unwrap();
+        assert_eq!(cache.check("token", &retry), Some(2));
+    }
+
+    #[test]
+    fn request_ids_of_other_tokens_are_processed() {
+        let config = ProcessingConfig::default();
+        let cache = RequestDedupCache::new(std::time::Duration::from_secs(60));
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Request-Id", "batch-42".parse().unwrap());
+        cache.record(
+            "token_a",
+            request_dedup_key(&headers, b"[]", &config).unwrap(),
+            2,
+        );
+
+        // Another project, or a proxy stamping its own ids, may send the same id
+        let key = request_dedup_key(&headers, b"[]", &config).unwrap();
+        assert_eq!(cache.check("token_b", &key), None);
+        cache.record("token_b", key.clone(), 1);
+        assert_eq!(cache.check("token_a", &key), Some(2));
+        assert_eq!(cache.check("token_b", &key), Some(1));
+    }
+
+    #[test]
+    fn fresh_request_id_is_processed() {
+        let mut config = ProcessingConfig::default();
+        let cache = RequestDedupCache::new(std::time::Duration::from_secs(60));
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Request-Id", "batch-42".parse().unwrap());
+        cache.record(
+            "token",
+            request_dedup_key(&headers, b"[]", &config).unwrap(),
+            2,
+        );
+
+        headers.insert("X-Request-Id", "batch-43".parse().unwrap());
+        let fresh = request_dedup_key(&headers, b"[]", &config).unwrap();
+        assert_eq!(cache.check("token", &fresh), None);
+
+        // Without the header, bodies are only deduplicated if configured to
+        let headers = HeaderMap::new();
+        assert_eq!(request_dedup_key(&headers, b"[]", &config), None);
+        config.dedup_request_bodies = true;
+        assert_eq!(
+            request_dedup_key(&headers, b"[]", &config),
+            Some(DedupKey::body(b"[]"))
+        );
+    }
+
+    #[test]
+    fn strict_property_bounds_reject_events() {
+        let mut config = ProcessingConfig {
+            property_bounds: "revenue:0:100".parse().unwrap(),
+            ..Default::default()
+        };
+        let event = RawEvent {
+            properties: HashMap::from([(String::from("revenue"), json!(1e9))]),
+            ..event_without_uuid()
+        };
+
+        let processed = process_single_event(event.clone(), &test_context(), &config).unwrap();
+        let data: Value = serde_json::from_str(&processed.data).unwrap();
+        assert_eq!(data["properties"]["revenue"], json!(100.0));
+        assert_eq!(
+            data["properties"]["$clamped_properties"],
+            json!(["revenue"])
+        );
+
+        config.strict_property_bounds = true;
+        let res = process_single_event(event, &test_context(), &config);
+        assert!(matches!(res, Err(CaptureError::PropertyOutOfRange(key)) if key == "revenue"));
+    }
+
+    #[test]
+    fn captures_the_current_span_id() {
+        let processed =
+            process_single_event(event_without_uuid(), &test_context(), &Default::default());
+        assert_eq!(processed.unwrap().trace_id, None);
+
+        tracing::subscriber::with_default(tracing_subscriber::registry(), || {
+            let span = tracing::info_span!("request");
+            let _entered = span.enter();
+            let expected = format!("{:016x}", span.id().unwrap().into_u64());
+            assert_eq!(current_span_id(), Some(expected.clone()));
+
+            let context = ProcessingContext {
+                trace_id: current_span_id(),
+                ..test_context()
+            };
+            let processed =
+                process_single_event(event_without_uuid(), &context, &Default::default());
+            assert_eq!(processed.unwrap().trace_id, Some(expected));
+
+            // Falls back to the span processing the event
+            let processed =
+                process_single_event(event_without_uuid(), &test_context(), &Default::default());
+            assert!(processed.unwrap().trace_id.is_some());
+        });
+    }
+
+    #[test]
+    fn prefers_the_traceparent_trace_id() {
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        assert_eq!(
+            traceparent_trace_id(Some(traceparent)).as_deref(),
+            Some("4bf92f3577b34da6a3ce929d0e0e4736")
+        );
+        assert_eq!(traceparent_trace_id(Some("00-4bf92f35")), None);
+        assert_eq!(traceparent_trace_id(None), None);
+
+        tracing::subscriber::with_default(tracing_subscriber::registry(), || {
+            let span = tracing::info_span!("request");
+            let _entered = span.enter();
+
+            let context = ProcessingContext {
+                traceparent: Some(String::from(traceparent)),
+                ..test_context()
+            };
+            let processed =
+                process_single_event(event_without_uuid(), &context, &Default::default());
+            assert_eq!(
+                processed.unwrap().trace_id.as_deref(),
+                Some("4bf92f3577b34da6a3ce929d0e0e4736")
+            );
+
+            // A malformed header falls back to the span processing the event
+            let context = ProcessingContext {
+                traceparent: Some(String::from("00-4bf92f35")),
+                ..test_context()
+            };
+            let processed =
+                process_single_event(event_without_uuid(), &context, &Default::default());
+            let trace_id = processed.unwrap().trace_id.expect("no span id");
+            assert_eq!(trace_id.len(), 16);
+        });
+    }
+
+    #[test]
+    fn stamps_traceparent_ids() {
+        let properties_with = |traceparent: &str| {
+            let context = ProcessingContext {
+                traceparent: Some(String::from(traceparent)),
+                ..test_context()
+            };
+            let processed =
+                process_single_event(event_without_uuid(), &context, &Default::default()).unwrap();
+            let data: Value = serde_json::from_str(&processed.data).unwrap();
+            data["properties"].clone()
+        };
+
+        let properties = properties_with("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01");
+        assert_eq!(
+            properties["$trace_id"],
+            json!("4bf92f3577b34da6a3ce929d0e0e4736")
+        );
+        assert_eq!(properties["$span_id"], json!("00f067aa0ba902b7"));
+
+        let properties = properties_with("00-4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(properties.get("$trace_id"), None);
+        assert_eq!(properties.get("$span_id"), None);
+    }
+
+    #[test]
+    fn iso_offsets_are_sent_as_milliseconds() {
+        let event = RawEvent {
+            offset: Some(EventOffset::Iso(String::from("PT2.5S"))),
+            ..event_without_uuid()
+        };
+
+        let processed = process_single_event(event, &test_context(), &Default::default()).unwrap();
+        let data: Value = serde_json::from_str(&processed.data).unwrap();
+        assert_eq!(data["offset"], json!(2500));
+    }
+
+    #[test]
+    fn lists_tokens_in_batch() {
+        let events = events_with_tokens(&[Some("a"), None, Some("b"), Some("a")]);
+        assert_eq!(
+            tokens_in_batch(&events),
+            HashSet::from([String::from("a"), String::from("b")])
+        );
+        assert!(tokens_in_batch(&events_with_tokens(&[None])).is_empty());
+    }
+
+    #[test]
+    fn byte_budget_drops_largest_properties() {
+        let config = ProcessingConfig {
+            event_byte_budget: Some(300),
+            ..Default::default()
+        };
+        let event = RawEvent {
+            properties: HashMap::from([
+                (String::from("small"), json!("a")),
+                (String::from("large"), json!("b".repeat(400))),
+            ]),
+            ..event_without_uuid()
+        };
+
+        let processed = process_single_event(event, &test_context(), &config).unwrap();
+        assert!(processed.data.len() <= 300);
+        let data: Value = serde_json::from_str(&processed.data).unwrap();
+        assert_eq!(data["properties"]["small"], json!("a"));
+        assert_eq!(data["properties"]["$dropped_properties"], json!(["large"]));
+    }
+
+    #[test]
+    fn properties_byte_budget_drops_largest_properties() {
+        let config = ProcessingConfig {
+            properties_byte_budget: Some(150),
+            max_event_size_bytes: Some(1000),
+            ..Default::default()
+        };
+        let event = RawEvent {
+            properties: HashMap::from([
+                (String::from("small"), json!("a")),
+                (String::from("medium"), json!("b".repeat(50))),
+                (String::from("large"), json!("c".repeat(200))),
+            ]),
+            ..event_without_uuid()
+        };
+
+        // The event is under the size cap, only its properties are over their own budget
+        let processed = process_single_event(event.clone(), &test_context(), &config).unwrap();
+        let data: Value = serde_json::from_str(&processed.data).unwrap();
+        assert!(data["properties"].to_string().len() <= 150);
+        assert_eq!(data["properties"]["medium"], json!("b".repeat(50)));
+        assert_eq!(data["properties"]["$dropped_properties"], json!(["large"]));
+
+        // Both budgets apply, the tightest one wins
+        let config = ProcessingConfig {
+            event_byte_budget: Some(120),
+            ..config
+        };
+        let processed = process_single_event(event, &test_context(), &config).unwrap();
+        assert!(processed.data.len() <= 120);
+        let data: Value = serde_json::from_str(&processed.data).unwrap();
+        assert_eq!(
+            data["properties"]["$dropped_properties"][1],
+            json!("medium")
+        );
+    }
+
+    #[test]
+    fn merge_dangerously_validation() {
+        let merge = |properties: Value| RawEvent {
+            event: String::from("$merge_dangerously"),
+            properties: serde_json::from_value(properties).unwrap(),
+            ..event_without_uuid()
+        };
+        let process = |event| process_single_event(event, &test_context(), &Default::default());
+
+        assert!(process(merge(json!({"alias": "user2"}))).is_ok());
+        assert!(matches!(
+            process(merge(json!({"alias": "user1"}))),
+            Err(CaptureError::InvalidMerge)
+        ));
+        // Only warned about
+        assert!(process(merge(json!({}))).is_ok());
+    }
+
+    #[test]
+    fn lowercases_event_names() {
+        let config = ProcessingConfig {
+            lowercase_event_names: true,
+            ..Default::default()
+        };
+        let event_named = |name: &str| RawEvent {
+            event: name.to_string(),
+            ..event_without_uuid()
+        };
+        let process = |event| {
+            let processed = process_single_event(event, &test_context(), &config).unwrap();
+            let data: Value = serde_json::from_str(&processed.data).unwrap();
+            (processed.event, data["properties"].clone())
+        };
+
+        let (name, properties) = process(event_named("Signed Up"));
+        assert_eq!(name, "signed up");
+        assert_eq!(properties["$original_event"], json!("Signed Up"));
+
+        let (name, properties) = process(event_named("signed up"));
+        assert_eq!(name, "signed up");
+        assert!(properties.get("$original_event").is_none());
+
+        let (name, properties) = process(event_named("$AutoCapture"));
+        assert_eq!(name, "$AutoCapture");
+        assert!(properties.get("$original_event").is_none());
+    }
+
+    #[test]
+    fn checks_origins_against_the_token_allowlist() {
+        let config = ProcessingConfig {
+            token_allowed_origins:
+                "token:https://example.com,https://*.example.org; other:http://localhost:8000"
+                    .parse()
+                    .unwrap(),
+            ..Default::default()
+        };
+        let from = |origin: &str| ProcessingContext {
+            origin: Some(origin.to_string()),
+            ..test_context()
+        };
+
+        for allowed in [
+            "https://example.com",
+            "HTTPS://Example.com",
+            "https://app.example.org",
+            "https://eu.app.example.org",
+        ] {
+            assert!(check_origin(&from(allowed), &config).is_ok(), "{}", allowed);
+        }
+        for disallowed in [
+            "https://evil.com",
+            "http://example.com",
+            "https://app.example.com",
+            "https://example.org",
+            "https://notexample.org",
+            "http://app.example.org",
+            "http://localhost:8000",
+        ] {
+            assert!(
+                matches!(
+                    check_origin(&from(disallowed), &config),
+                    Err(CaptureError::DisallowedOrigin)
+                ),
+                "{}",
+                disallowed
+            );
+        }
+
+        // Requests without an Origin, and tokens without a list, are accepted
+        assert!(check_origin(&test_context(), &config).is_ok());
+        let unlisted = ProcessingContext {
+            token: String::from("unlisted"),
+            ..from("https://evil.com")
+        };
+        assert!(check_origin(&unlisted, &config).is_ok());
+        assert!(check_origin(&from("https://evil.com"), &ProcessingConfig::default()).is_ok());
+    }
+
+    #[test]
+    fn rejects_future_sent_at() {
+        // now is 2023-09-15T09:15:02.328551+00:00
+        let sent_at = |sent_at| ProcessingContext {
+            sent_at: Some(sent_at),
+            ..test_context()
+        };
+        let config = ProcessingConfig {
+            max_future_sent_at_ms: Some(60_000),
+            ..Default::default()
+        };
+
+        for in_window in [
+            datetime!(2023-09-15 09:15:00 UTC),
+            datetime!(2023-09-15 09:16:00 UTC),
+            datetime!(2023-01-01 00:00:00 UTC),
+        ] {
+            assert!(check_sent_at(&sent_at(in_window), &config).is_ok());
+        }
+        let far_future = sent_at(datetime!(2023-09-15 10:00:00 UTC));
+        assert!(matches!(
+            check_sent_at(&far_future, &config),
+            Err(CaptureError::InvalidSentAt)
+        ));
+
+        // Off by default
+        assert!(check_sent_at(&far_future, &ProcessingConfig::default()).is_ok());
+        assert!(check_sent_at(&test_context(), &config).is_ok());
+    }
+
+    // Stands for the clock the handler reads now from
+    struct FixedTime {
+        time: &'static str,
+    }
+
+    impl TimeSource for FixedTime {
+        fn current_time(&self) -> String {
+            self.time.to_string()
+        }
+    }
+
+    #[test]
+    fn ingest_timestamps_do_not_go_backwards() {
+        let context = ProcessingContext {
+            now: SystemTime {}.current_time(),
+            clock: Arc::new(SystemTime {}),
+            ..test_context()
+        };
+        let (processed, _, _) = process_events_lenient(
+            vec![event_without_uuid(), event_without_uuid()],
+            &context,
+            &Default::default(),
+        );
+        let [first, second] = processed.as_slice() else {
+            panic!("events should be processed");
+        };
+        assert!(first.ingest_ts_nanos > 0);
+        assert!(second.ingest_ts_nanos >= first.ingest_ts_nanos);
+
+        // Clocks without their own resolution are parsed
+        let clock = FixedTime {
+            time: "2023-10-26T12:00:00.000000123Z",
+        };
+        assert_eq!(clock.current_time_nanos(), 1698321600000000123);
+    }
+
+    #[test]
+    fn gates_ingest_windows() {
+        let mut config = ProcessingConfig {
+            ingest_windows: "mon-fri 09:00-17:00; sat 10:00-12:00".parse().unwrap(),
+            ..Default::default()
+        };
+        let context_at = |time| ProcessingContext {
+            now: FixedTime { time }.current_time(),
+            ..test_context()
+        };
+        let gate = |time, config: &ProcessingConfig| {
+            let mut events = vec![event_without_uuid()];
+            gate_ingest_window(&mut events, &context_at(time), config)
+                .map(|_| events[0].properties.contains_key("$outside_ingest_window"))
+        };
+
+        // 2023-09-15 is a Friday
+        for in_window in [
+            "2023-09-15T09:00:00Z",
+            "2023-09-15T16:59:59Z",
+            "2023-09-15T12:00:00+02:00",
+            "2023-09-16T11:00:00Z",
+        ] {
+            assert!(!gate(in_window, &config).unwrap(), "{}", in_window);
+        }
+        for out_of_window in [
+            "2023-09-15T17:00:00Z",
+            "2023-09-15T08:00:00Z",
+            "2023-09-16T12:30:00Z",
+            "2023-09-17T11:00:00Z",
+        ] {
+            assert!(gate(out_of_window, &config).unwrap(), "{}", out_of_window);
+        }
+
+        config.reject_outside_ingest_window = true;
+        assert!(matches!(
+            gate("2023-09-17T11:00:00Z", &config),
+            Err(CaptureError::OutsideIngestWindow)
+        ));
+        assert!(gate("2023-09-15T10:00:00Z", &config).is_ok());
+
+        // Always allowed by default
+        assert!(!gate("2023-09-17T11:00:00Z", &ProcessingConfig::default()).unwrap());
+    }
+
+    #[test]
+    fn splits_batch_by_recency() {
+        let event_at = |name: &str, timestamp: Option<&str>| RawEvent {
+            event: name.to_string(),
+            timestamp: timestamp.map(String::from),
+            ..event_without_uuid()
+        };
+        let events = vec![
+            event_at("old1", Some("2023-01-01T00:00:00Z")),
+            event_at("live1", Some("2023-10-26T12:00:00Z")),
+            event_at("untimed", None),
+            event_at("old2", Some("2023-10-25T23:59:59Z")),
+            event_at("unparseable", Some("yesterday")),
+            event_at("live2", Some("2023-10-26T00:00:00Z")),
+        ];
+
+        let (live, historical) = split_by_recency(
+            events,
+            datetime!(2023-10-26 00:00:00 UTC),
+            &ProcessingConfig::default(),
+        );
+        let names = |events: Vec<RawEvent>| -> Vec<String> {
+            events.into_iter().map(|e| e.event).collect()
+        };
+        assert_eq!(
+            names(live),
+            vec!["live1", "untimed", "unparseable", "live2"]
+        );
+        assert_eq!(names(historical), vec!["old1", "old2"]);
+    }
+
+    #[test]
+    fn coalesces_consecutive_duplicates() {
+        let event_named = |name: &str| RawEvent {
+            event: name.to_string(),
+            timestamp: Some(String::from("2023-10-26T12:00:00Z")),
+            ..event_without_uuid()
+        };
+        let events = vec![
+            event_named("$autocapture"),
+            event_named("$autocapture"),
+            event_named("$autocapture"),
+            event_named("$pageview"),
+        ];
+
+        let coalesced = coalesce_duplicates(events);
+        let summary: Vec<(&str, Option<&Value>)> = coalesced
+            .iter()
+            .map(|e| (e.event.as_str(), e.properties.get(COALESCED_COUNT_PROPERTY)))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![("$autocapture", Some(&json!(3))), ("$pageview", None)]
+        );
+    }
+
+    #[test]
+    fn keeps_interleaved_duplicates() {
+        let event_named = |name: &str| RawEvent {
+            event: name.to_string(),
+            ..event_without_uuid()
+        };
+        let names = ["a", "b", "a", "b"];
+        let events = names.iter().map(|name| event_named(name)).collect();
+
+        let coalesced = coalesce_duplicates(events);
+        let kept: Vec<&str> = coalesced.iter().map(|e| e.event.as_str()).collect();
+        assert_eq!(kept, names);
+        assert!(coalesced
+            .iter()
+            .all(|e| !e.properties.contains_key(COALESCED_COUNT_PROPERTY)));
+    }
+
+    #[test]
+    fn strips_malformed_session_ids() {
+        let event_with = |session_id: Value| {
+            let mut event = event_without_uuid();
+            event
+                .properties
+                .insert(String::from("$session_id"), session_id);
+            event
+        };
+
+        let processed = process_single_event(
+            event_with(json!("session1")),
+            &test_context(),
+            &Default::default(),
+        )
+        .unwrap();
+        assert_eq!(processed.session_id.as_deref(), Some("session1"));
+
+        let processed =
+            process_single_event(event_with(json!("")), &test_context(), &Default::default())
+                .unwrap();
+        assert_eq!(processed.session_id, None);
+        let data: Value = serde_json::from_str(&processed.data).unwrap();
+        assert!(data["properties"].get("$session_id").is_none());
+
+        let processed =
+            process_single_event(event_without_uuid(), &test_context(), &Default::default())
+                .unwrap();
+        assert_eq!(processed.session_id, None);
+    }
+
+    #[test]
+    fn surfaces_validated_timezone() {
+        let config = ProcessingConfig {
+            validate_timezones: true,
+            ..Default::default()
+        };
+        let event_in = |zone: &str| {
+            let mut event = event_without_uuid();
+            event
+                .properties
+                .insert(String::from("$timezone"), json!(zone));
+            event
+        };
+
+        let processed = process_single_event(event_in("Asia/Tokyo"), &test_context(), &config);
+        assert_eq!(processed.unwrap().timezone.as_deref(), Some("Asia/Tokyo"));
+
+        let processed = process_single_event(event_in("Tokyo"), &test_context(), &config).unwrap();
+        assert_eq!(processed.timezone, None);
+        let data: Value = serde_json::from_str(&processed.data).unwrap();
+        assert_eq!(data["properties"]["$timezone_invalid"], json!(true));
+
+        let processed =
+            process_single_event(event_in("Asia/Tokyo"), &test_context(), &Default::default());
+        assert_eq!(processed.unwrap().timezone, None);
+    }
+
+    #[test]
+    fn limits_event_name_length() {
+        let mut config = ProcessingConfig {
+            max_event_name_length: 5,
+            ..Default::default()
+        };
+        let event_named = |name: &str| RawEvent {
+            event: name.to_string(),
+            ..event_without_uuid()
+        };
+        let process = |event, config: &ProcessingConfig| {
+            let processed = process_single_event(event, &test_context(), config).unwrap();
+            let data: Value = serde_json::from_str(&processed.data).unwrap();
+            (processed.event, data["properties"].clone())
+        };
+
+        for name in ["été", "ééééé"] {
+            let (truncated, properties) = process(event_named(name), &config);
+            assert_eq!(truncated, name);
+            assert!(properties.get(EVENT_NAME_TRUNCATED_PROPERTY).is_none());
+        }
+
+        let (truncated, properties) = process(event_named("éééééé"), &config);
+        assert_eq!(truncated, "ééééé");
+        assert_eq!(properties[EVENT_NAME_TRUNCATED_PROPERTY], json!(6));
+
+        config.strict_event_name_length = true;
+        let (name, _) = process(event_named("ééééé"), &config);
+        assert_eq!(name, "ééééé");
+        let res = process_single_event(event_named("éééééé"), &test_context(), &config);
+        assert!(matches!(res, Err(CaptureError::EventNameTooLong)));
+    }
+
+    #[test]
+    fn validate_only_reports_each_event() {
+        let config = ProcessingConfig {
+            cap_property_arrays: true,
+            max_property_array_length: 2,
+            ..Default::default()
+        };
+        let query = EventQuery {
+            api_key: Some(String::from("token")),
+            ..Default::default()
+        };
+        let body = json!([
+            {"event": "valid", "distinct_id": "user1"},
+            {"event": "warned", "distinct_id": "user1", "properties": {"list": [1, 2, 3]}},
+            {"event": "invalid"}
+        ]);
+
+        let validations = validate_only(&query, body.to_string().into(), &config).unwrap();
+        assert_eq!(
+            validations,
+            vec![
+                EventValidation {
+                    index: 0,
+                    valid: true,
+                    warnings: vec![],
+                    error: None,
+                },
+                EventValidation {
+                    index: 1,
+                    valid: true,
+                    warnings: vec![String::from("$truncated_arrays")],
+                    error: None,
+                },
+                EventValidation {
+                    index: 2,
+                    valid: false,
+                    warnings: vec![],
+                    error: Some(CaptureError::MissingDistinctId.to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn applies_event_property_defaults() {
+        let config = ProcessingConfig {
+            event_property_defaults: "$pageview:$page_category=unknown,$depth=0".parse().unwrap(),
+            ..Default::default()
+        };
+        let properties_of = |event: &str, properties: Value| {
+            let event = RawEvent {
+                event: String::from(event),
+                properties: serde_json::from_value(properties).unwrap(),
+                ..event_without_uuid()
+            };
+            let processed = process_single_event(event, &test_context(), &config).unwrap();
+            let data: Value = serde_json::from_str(&processed.data).unwrap();
+            data["properties"].clone()
+        };
+
+        let properties = properties_of("$pageview", json!({}));
+        assert_eq!(properties["$page_category"], json!("unknown"));
+        assert_eq!(properties["$depth"], json!(0));
+
+        // Client values are kept, even null ones
+        let properties = properties_of(
+            "$pageview",
+            json!({"$page_category": "docs", "$depth": null}),
+        );
+        assert_eq!(properties["$page_category"], json!("docs"));
+        assert_eq!(properties["$depth"], Value::Null);
+        assert!(properties.as_object().unwrap().contains_key("$depth"));
+
+        let properties = properties_of("signup", json!({}));
+        assert!(properties.get("$page_category").is_none());
+        assert!(properties.get("$depth").is_none());
+    }
+
+    #[test]
+    fn enforces_token_property_allowlists() {
+        let mut config = ProcessingConfig {
+            token_property_allowlists: "token:plan;other:color".parse().unwrap(),
+            ..Default::default()
+        };
+        let event = RawEvent {
+            properties: HashMap::from([
+                (String::from("plan"), json!("pro")),
+                (String::from("color"), json!("red")),
+                (String::from("$browser"), json!("Firefox")),
+            ]),
+            ..event_without_uuid()
+        };
+
+        let processed = process_single_event(event.clone(), &test_context(), &config).unwrap();
+        let data: Value = serde_json::from_str(&processed.data).unwrap();
+        assert_eq!(data["properties"]["plan"], "pro");
+        assert_eq!(data["properties"]["$browser"], "Firefox");
+        assert!(data["properties"].get("color").is_none());
+
+        let unconfigured = ProcessingContext {
+            token: String::from("open"),
+            ..test_context()
+        };
+        let processed = process_single_event(event.clone(), &unconfigured, &config).unwrap();
+        let data: Value = serde_json::from_str(&processed.data).unwrap();
+        assert_eq!(data["properties"]["color"], "red");
+
+        config.strict_property_allowlists = true;
+        let res = process_single_event(event, &test_context(), &config);
+        assert!(matches!(res, Err(CaptureError::UnknownProperty(key)) if key == "color"));
+    }
+
+    #[test]
+    fn fans_out_distinct_ids() {
+        let event = RawEvent {
+            uuid: Some(uuid_v7()),
+            distinct_id: None,
+            properties: HashMap::from([
+                (String::from("$distinct_ids"), json!(["user1", "user2"])),
+                (String::from("plan"), json!("pro")),
+            ]),
+            ..event_without_uuid()
+        };
+
+        let events = fan_out_distinct_ids(vec![event, event_without_uuid()], 10).unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].distinct_id.as_deref(), Some("user1"));
+        assert_eq!(events[1].distinct_id.as_deref(), Some("user2"));
+        for event in &events[..2] {
+            assert_eq!(event.uuid, None);
+            assert_eq!(event.properties["plan"], json!("pro"));
+            assert!(!event.properties.contains_key("$distinct_ids"));
+        }
+        assert_eq!(events[2].distinct_id.as_deref(), Some("user1"));
+        assert!(events[2].properties.is_empty());
+    }
+
+    #[test]
+    fn caps_distinct_id_fan_out() {
+        let event = RawEvent {
+            properties: HashMap::from([(
+                String::from("$distinct_ids"),
+                json!(["user1", "user2", "user3"]),
+            )]),
+            ..event_without_uuid()
+        };
+
+        assert_eq!(
+            fan_out_distinct_ids(vec![event.clone()], 3).unwrap().len(),
+            3
+        );
+        assert!(matches!(
+            fan_out_distinct_ids(vec![event], 2),
+            Err(CaptureError::TooManyDistinctIds)
+        ));
+    }
+
+    #[test]
+    fn null_distinct_id_policies() {
+        let event: RawEvent = serde_json::from_value(json!({
+            "event": "pageview",
+            "distinct_id": null,
+            "properties": {"key": "value"}
+        }))
+        .unwrap();
+        let mut config = ProcessingConfig::default();
+
+        let res = process_single_event(event.clone(), &test_context(), &config);
+        assert!(matches!(res, Err(CaptureError::MissingDistinctId)));
+
+        config.null_distinct_id = NullDistinctIdPolicy::Anonymous;
+        let processed = process_single_event(event.clone(), &test_context(), &config).unwrap();
+        assert!(uuid::Uuid::parse_str(&processed.distinct_id).is_ok());
+        let data: Value = serde_json::from_str(&processed.data).unwrap();
+        assert_eq!(data["distinct_id"], processed.distinct_id.as_str());
+        assert_eq!(data["properties"]["$distinct_id_generated"], true);
+
+        config.null_distinct_id = NullDistinctIdPolicy::Passthrough;
+        let processed = process_single_event(event, &test_context(), &config).unwrap();
+        assert_eq!(processed.distinct_id, "");
+        let data: Value = serde_json::from_str(&processed.data).unwrap();
+        assert!(data.get("distinct_id").is_none());
+        assert!(data["properties"].get("$distinct_id_generated").is_none());
+    }
+
+    #[test]
+    fn sentinel_distinct_ids_are_missing() {
+        let mut config = ProcessingConfig {
+            distinct_id_sentinels: "anonymous,$anonymous".parse().unwrap(),
+            ..Default::default()
+        };
+        let with_id = |distinct_id: &str| RawEvent {
+            distinct_id: Some(String::from(distinct_id)),
+            ..event_without_uuid()
+        };
+
+        let res = process_single_event(with_id("$anonymous"), &test_context(), &config);
+        assert!(matches!(res, Err(CaptureError::MissingDistinctId)));
+
+        config.null_distinct_id = NullDistinctIdPolicy::Anonymous;
+        let processed =
+            process_single_event(with_id("anonymous"), &test_context(), &config).unwrap();
+        assert!(uuid::Uuid::parse_str(&processed.distinct_id).is_ok());
+        let data: Value = serde_json::from_str(&processed.data).unwrap();
+        assert_eq!(data["distinct_id"], processed.distinct_id.as_str());
+        assert_eq!(data["properties"]["$distinct_id_generated"], true);
+
+        let processed =
+            process_single_event(with_id("anonymous_user"), &test_context(), &config).unwrap();
+        assert_eq!(processed.distinct_id, "anonymous_user");
+
+        // No sentinel by default
+        let processed =
+            process_single_event(with_id("anonymous"), &test_context(), &Default::default())
+                .unwrap();
+        assert_eq!(processed.distinct_id, "anonymous");
+    }
+
+    #[test]
+    fn extracts_utm_params_before_stripping_them() {
+        let config = ProcessingConfig {
+            extract_utm_params: true,
+            stripped_url_params: "utm_source".parse().unwrap(),
+            ..Default::default()
+        };
+        let event = RawEvent {
+            properties: HashMap::from([(
+                String::from("$current_url"),
+                json!("https://example.com/?utm_source=ads&id=3"),
+            )]),
+            ..event_without_uuid()
+        };
+
+        let processed = process_single_event(event, &test_context(), &config).unwrap();
+        let data: Value = serde_json::from_str(&processed.data).unwrap();
+        assert_eq!(data["properties"]["$utm_source"], json!("ads"));
+        assert_eq!(
+            data["properties"]["$current_url"],
+            json!("https://example.com/?id=3")
+        );
     }
 }
@@ -0,0 +1,136 @@
+/// A single misbehaving client sending many large requests at once can keep most workers busy
+/// decoding and processing them, starving the other projects. Cap the number of requests of a
+/// token processed at the same time, asking the client to retry the extra ones shortly.
+///
+/// Tokens are tracked with one semaphore each, forgotten once they have been idle for a while
+/// so that the map stays bounded by the number of recently active tokens.
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::api::CaptureError;
+
+struct TokenSlots {
+    semaphore: Arc<Semaphore>,
+    last_used: Instant,
+}
+
+#[derive(Clone)]
+pub struct ConcurrencyLimiter {
+    limit: usize,
+    retry_after: Duration,
+    idle_timeout: Duration,
+    slots: Arc<DashMap<String, TokenSlots>>,
+    last_eviction: Arc<Mutex<Instant>>,
+}
+
+/// Slot of an in-flight request, released when dropped.
+pub struct ConcurrencyPermit {
+    _permit: OwnedSemaphorePermit,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(limit: usize, retry_after: Duration, idle_timeout: Duration) -> Self {
+        ConcurrencyLimiter {
+            limit,
+            retry_after,
+            idle_timeout,
+            slots: Arc::new(DashMap::new()),
+            last_eviction: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Take one of the slots of the token, to hold while its request is processed.
+    pub fn acquire(&self, token: &str) -> Result<ConcurrencyPermit, CaptureError> {
+        self.evict_idle_periodically();
+
+        let semaphore = {
+            let mut slots = self
+                .slots
+                .entry(token.to_string())
+                .or_insert_with(|| TokenSlots {
+                    semaphore: Arc::new(Semaphore::new(self.limit)),
+                    last_used: Instant::now(),
+                });
+            slots.last_used = Instant::now();
+            slots.semaphore.clone()
+        };
+
+        semaphore
+            .try_acquire_owned()
+            .map(|permit| ConcurrencyPermit { _permit: permit })
+            .map_err(|_| CaptureError::TooManyConcurrentRequests {
+                retry_after: self.retry_after.as_secs(),
+            })
+    }
+
+    /// Forget the tokens without in-flight requests that have been idle for longer than the
+    /// idle timeout.
+    pub fn evict_idle(&self) {
+        let now = Instant::now();
+        self.slots.retain(|_, slots| {
+            slots.semaphore.available_permits() < self.limit
+                || now.duration_since(slots.last_used) < self.idle_timeout
+        });
+    }
+
+    pub fn tracked_tokens(&self) -> usize {
+        self.slots.len()
+    }
+
+    // Run from the request path at most once per idle timeout, instead of in a background task
+    fn evict_idle_periodically(&self) {
+        let Ok(mut last_eviction) = self.last_eviction.try_lock() else {
+            return;
+        };
+        if last_eviction.elapsed() >= self.idle_timeout {
+            *last_eviction = Instant::now();
+            drop(last_eviction);
+            self.evict_idle();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::api::CaptureError;
+    use crate::concurrency_limits::ConcurrencyLimiter;
+
+    #[test]
+    fn rejects_requests_over_the_limit() {
+        let limiter = ConcurrencyLimiter::new(2, Duration::from_secs(1), Duration::from_secs(60));
+
+        let first = limiter.acquire("token").expect("first slot");
+        let _second = limiter.acquire("token").expect("second slot");
+        assert!(matches!(
+            limiter.acquire("token"),
+            Err(CaptureError::TooManyConcurrentRequests { retry_after: 1 })
+        ));
+        // Other tokens have their own slots
+        assert!(limiter.acquire("other").is_ok());
+
+        drop(first);
+        assert!(limiter.acquire("token").is_ok());
+    }
+
+    #[test]
+    fn evicts_idle_tokens() {
+        let limiter = ConcurrencyLimiter::new(1, Duration::from_secs(1), Duration::ZERO);
+
+        let in_flight = limiter.acquire("busy").unwrap();
+        drop(limiter.acquire("idle").unwrap());
+        assert_eq!(limiter.tracked_tokens(), 2);
+
+        limiter.evict_idle();
+        assert_eq!(limiter.tracked_tokens(), 1);
+        assert!(limiter.acquire("busy").is_err());
+
+        drop(in_flight);
+        limiter.evict_idle();
+        assert_eq!(limiter.tracked_tokens(), 0);
+    }
+}
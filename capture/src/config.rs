@@ -1,6 +1,18 @@
-use std::{net::SocketAddr, num::NonZeroU32};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    num::NonZeroU32,
+    str::FromStr,
+};
 
 use envconfig::Envconfig;
+use serde_json::Value;
+use time::format_description::{self, OwnedFormatItem};
+use time::{Duration, OffsetDateTime, Time, UtcOffset, Weekday};
+
+use crate::decompression::Codec;
+use crate::normalization::LibVersion;
+use crate::time::parse_iso_duration;
 
 #[derive(Envconfig, Clone)]
 pub struct Config {
@@ -21,9 +33,20 @@ pub struct Config {
 
     pub overflow_forced_keys: Option<String>, // Coma-delimited keys
 
+    #[envconfig(default = "")]
+    pub partition_key_overrides: PartitionKeyOverrides, // Comma-delimited token:salt pairs
+
+    #[envconfig(default = "distinct_id")]
+    pub partition_strategy: PartitionStrategy, // token or distinct_id, see ProcessedEvent::key_with
+    #[envconfig(default = "")]
+    pub event_partition_strategies: EventPartitionStrategies, // Comma-delimited event:strategy pairs
+
     #[envconfig(nested = true)]
     pub kafka: KafkaConfig,
 
+    #[envconfig(nested = true)]
+    pub processing: ProcessingConfig,
+
     #[envconfig(default = "1.0")]
     pub otel_sampling_rate: f64,
 
@@ -46,3 +69,772 @@ pub struct KafkaConfig {
     #[envconfig(default = "false")]
     pub kafka_tls: bool,
 }
+
+#[derive(Envconfig, Clone, Debug)]
+pub struct ProcessingConfig {
+    #[envconfig(default = "false")]
+    pub lenient_event_name: bool, // Accept events without a name instead of rejecting them
+    #[envconfig(default = "$unknown")]
+    pub missing_event_name: String, // Event name given to nameless events in lenient mode
+    #[envconfig(default = "")]
+    pub path_event_names: PathEventNames, // Comma-delimited path:event names of nameless events
+    #[envconfig(default = "false")]
+    pub lowercase_event_names: bool, // Lowercase names of non-reserved events, keeping $original_event
+    #[envconfig(default = "200")]
+    pub max_event_name_length: usize, // Longer event names are truncated, in characters
+    #[envconfig(default = "false")]
+    pub strict_event_name_length: bool, // Reject events with longer names instead of truncating them
+    #[envconfig(default = "reject")]
+    pub null_distinct_id: NullDistinctIdPolicy, // reject, anonymous or passthrough
+    #[envconfig(default = "")]
+    pub distinct_id_sentinels: PropertyAllowlist, // Comma-delimited ids handled as missing, e.g. anonymous
+    #[envconfig(default = "v7")]
+    pub uuid_policy: UuidPolicy, // Version of the uuids generated for events without one, v7 or v4
+    #[envconfig(default = "false")]
+    pub validate_timezones: bool, // Flag $timezone values that are not IANA names with $timezone_invalid
+    #[envconfig(default = "false")]
+    pub strict_session_id: bool, // Strip $session_id values that are not uuids, not only empty ones
+    #[envconfig(default = "false")]
+    pub strict_uuid_version: bool, // Reject events whose uuid is not of the policy's version
+    #[envconfig(default = "false")]
+    pub emit_ingestion_warnings: bool, // Send the valid events of a batch, and a summary of the rejected ones
+    pub max_uuid_clock_drift_ms: Option<u64>, // Regenerate v7 uuids whose time is further from now
+    #[envconfig(default = "false")]
+    pub regenerate_colliding_uuids: bool, // Replace uuids reused by different events of a batch
+    #[envconfig(default = "flag")]
+    pub duplicate_uuids: DuplicateUuidPolicy, // drop or flag events whose uuid was seen by another request
+    pub decompression_timeout_ms: Option<u64>, // Time budget for decompressing a request body
+    #[envconfig(default = "67108864")]
+    pub max_compressed_bytes: usize, // Maximum size of a request body before decompression
+    pub max_decompressed_bytes: Option<u64>,   // Maximum size of a request body once decompressed
+    pub max_total_decompressed_bytes: Option<u64>, // Maximum of all the gzip streams in a request
+    #[envconfig(default = "false")]
+    pub reject_on_gzip_size_hint: bool, // Reject gzip bodies whose footer announces a size over the max
+    #[envconfig(default = "2")]
+    pub max_gzip_layers: usize, // Decompress bodies gzipped several times, up to this many times
+    #[envconfig(default = "false")]
+    pub stream_gzip_json: bool, // Parse gzip bodies while decompressing them, without buffering
+    #[envconfig(default = "3")]
+    pub max_content_encodings: usize, // Longest Content-Encoding chain decoded
+    #[envconfig(default = "gzip,xz,deflate,deflate-raw")]
+    pub codec_preference: CodecPreference, // Comma-delimited, order deflate bodies are inflated in
+    #[envconfig(default = "100")]
+    pub max_zip_entries: usize, // Maximum number of files in a zip archive body
+    #[envconfig(default = "0.0")]
+    pub payload_log_sample_rate: f64, // Share of decoded payloads logged at debug level, read at startup only
+    #[envconfig(default = "1024")]
+    pub payload_log_max_bytes: usize, // Logged payloads are cut to this many bytes, read at startup only
+    #[envconfig(default = "false")]
+    pub stamp_detected_compression: bool, // Set $detected_compression to gzip or none on events
+    #[envconfig(default = "false")]
+    pub strip_ignored_person_updates: bool, // Drop $set and $set_once when persons are not processed
+    #[envconfig(default = "false")]
+    pub promote_nested_person_updates: bool, // Move $set and $set_once properties to the top level
+    #[envconfig(default = "")]
+    pub set_once_properties: PropertyAllowlist, // Comma-delimited person properties moved from $set to $set_once
+    #[envconfig(default = "false")]
+    pub split_person_properties: bool, // Move $set and $set_once updates to separate $set events
+    #[envconfig(default = "1024")]
+    pub response_compression_min_bytes: usize, // Smaller response bodies are not compressed
+    #[envconfig(default = "6")]
+    pub response_compression_level: u32, // Gzip level from 0 (none) to 9 (best)
+    #[envconfig(default = "allow")]
+    pub duplicate_json_keys: DuplicateKeyPolicy, // allow, warn or reject
+    #[envconfig(default = "reject")]
+    pub non_finite_numbers: NonFinitePolicy, // reject, or null to replace NaN and Infinity
+    #[envconfig(default = "false")]
+    pub lenient_stringified_properties: bool, // Empty properties stringified as a non-object instead of rejecting them
+    #[envconfig(default = "false")]
+    pub strict_unicode: bool, // Reject lone surrogate escapes instead of replacing them
+
+    pub max_property_string_length: Option<usize>, // Longer property strings are truncated
+    pub max_property_depth: Option<usize>, // Events with deeper nested properties are rejected
+    #[envconfig(default = "false")]
+    pub cap_property_arrays: bool, // Truncate array properties longer than the max below
+    #[envconfig(default = "1000")]
+    pub max_property_array_length: usize,
+    pub max_event_size_bytes: Option<usize>, // Larger serialized events are rejected
+    pub event_byte_budget: Option<usize>,    // Larger events lose their largest properties first
+    pub properties_byte_budget: Option<usize>, // Same, for the serialized properties on their own
+
+    #[envconfig(default = "50")]
+    pub max_tokens_per_batch: usize, // Batches with more distinct tokens are rejected
+    pub max_concurrent_requests_per_token: Option<usize>, // Extra requests get a 429
+    #[envconfig(default = "1")]
+    pub concurrency_retry_after_secs: u64,
+    #[envconfig(default = "60")]
+    pub concurrency_idle_eviction_secs: u64, // Idle tokens are forgotten after this long
+    #[envconfig(default = "false")]
+    pub reject_token_mismatch: bool, // Reject requests whose header and body tokens differ
+    #[envconfig(default = "false")]
+    pub reject_disabled_tokens: bool, // Error on tokens failing validation instead of dropping
+    pub default_token: Option<String>, // Token of events found without one in body or header
+
+    #[envconfig(default = "")]
+    pub timestamp_formats: TimestampFormats, // Semicolon-delimited `time` format descriptions
+    #[envconfig(default = "false")]
+    pub promote_time_properties: bool, // Set missing timestamps from a time property, removing it
+    #[envconfig(default = "$time,time")]
+    pub time_properties: PropertyAllowlist, // Comma-delimited, tried in lexicographic order
+    pub request_dedup_ttl_secs: Option<u64>, // Skip requests repeating an X-Request-Id seen this recently
+    #[envconfig(default = "false")]
+    pub dedup_request_bodies: bool, // Without X-Request-Id, skip requests repeating a recent body
+    pub max_future_sent_at_ms: Option<u64>, // Reject requests whose sent_at is further ahead of now
+    #[envconfig(default = "")]
+    pub ingest_windows: IngestWindows, // Semicolon-delimited UTC windows, e.g. mon-fri 09:00-17:00
+    #[envconfig(default = "false")]
+    pub reject_outside_ingest_window: bool, // Reject instead of setting $outside_ingest_window
+    #[envconfig(default = "false")]
+    pub fan_out_distinct_ids: bool, // Copy events once per id of their $distinct_ids array
+    #[envconfig(default = "100")]
+    pub max_distinct_id_fan_out: usize, // Events listing more ids are rejected
+    #[envconfig(default = "false")]
+    pub coalesce_duplicate_events: bool, // Collapse runs of identical events, setting $coalesced_count
+    #[envconfig(default = "false")]
+    pub flag_out_of_order_events: bool, // Set $out_of_order on events older than previous ones
+    #[envconfig(default = "5000")]
+    pub out_of_order_tolerance_ms: u64,
+
+    #[envconfig(default = "")]
+    pub property_renames: PropertyRenames, // Coma-delimited from:to pairs, applied in order
+    #[envconfig(default = "false")]
+    pub merge_case_colliding_keys: bool, // Merge property keys only differing by case, like URL and url
+    #[envconfig(default = "")]
+    pub unescape_unicode_properties: PropertyAllowlist, // Comma-delimited keys of double-escaped strings
+    #[envconfig(default = "")]
+    pub boolean_properties: PropertyAllowlist, // Comma-delimited keys of boolean-like strings
+    #[envconfig(default = "")]
+    pub phone_properties: PropertyAllowlist, // Comma-delimited keys of phone numbers normalized to E.164
+    pub phone_default_calling_code: Option<u16>, // Given to national numbers, e.g. 44 for GB
+    #[envconfig(default = "")]
+    pub property_bounds: PropertyBounds, // Coma-delimited key:min:max numeric ranges
+    #[envconfig(default = "false")]
+    pub strict_property_bounds: bool, // Reject out of range values instead of clamping them
+    #[envconfig(default = "")]
+    pub token_property_allowlists: TokenPropertyAllowlists, // Semicolon-delimited token:key,key lists
+    #[envconfig(default = "")]
+    pub token_allowed_origins: TokenAllowedOrigins, // Semicolon-delimited token:origin,origin lists
+    #[envconfig(default = "")]
+    pub event_ttls: Ttls, // Comma-delimited event:duration pairs, stamping events with expires_at
+    #[envconfig(default = "")]
+    pub token_ttls: Ttls, // Comma-delimited token:duration pairs, for events without an event_ttls one
+    #[envconfig(default = "")]
+    pub event_property_defaults: EventPropertyDefaults, // Semicolon-delimited event:key=value,key=value lists
+    #[envconfig(default = "false")]
+    pub strict_property_allowlists: bool, // Reject unknown properties instead of stripping them
+    pub max_distinct_properties_per_batch: Option<usize>, // Rarest keys over the limit are removed
+    #[envconfig(default = "false")]
+    pub strict_distinct_properties: bool, // Reject batches over the limit instead of stripping keys
+
+    #[envconfig(default = "1.0")]
+    pub feature_flag_call_sample_rate: f64, // Share of $feature_flag_called events kept
+    #[envconfig(default = "false")]
+    pub prune_feature_flag_calls: bool, // Only keep allowlisted $feature_flag_called properties
+    #[envconfig(default = "$feature_flag,$feature_flag_response")]
+    pub feature_flag_call_properties: PropertyAllowlist, // Comma-delimited
+    #[envconfig(default = "false")]
+    pub strip_elements: bool, // Remove $elements and $elements_chain from the events below
+    #[envconfig(default = "$autocapture")]
+    pub strip_elements_events: PropertyAllowlist, // Comma-delimited event names
+
+    pub ingest_region: Option<String>, // Stamped on events, to tell capture regions apart
+
+    #[envconfig(default = "false")]
+    pub parse_user_agents: bool, // Set missing $browser, $os and $device_type from the User-Agent
+
+    #[envconfig(default = "$test")]
+    pub test_event_property: String, // Boolean property marking QA events, set as is_test
+
+    #[envconfig(default = "false")]
+    pub normalize_lib_names: bool, // Lowercase $lib and set $lib_unknown for unknown libraries
+    #[envconfig(
+        default = "web,posthog-js-lite,posthog-node,posthog-python,posthog-ruby,posthog-go,posthog-php,posthog-java,posthog-ios,posthog-android,posthog-flutter,posthog-react-native,posthog-rs"
+    )]
+    pub known_libraries: PropertyAllowlist, // Comma-delimited lowercase $lib values
+    #[envconfig(default = "")]
+    pub minimum_lib_versions: MinimumLibVersions, // Comma-delimited lib:version, older SDKs are rejected
+    #[envconfig(default = "false")]
+    pub strict_lib_versions: bool, // Also reject restricted libs sending unparseable versions
+
+    pub property_namespace: Option<String>, // Prefix added to non-reserved property keys, e.g. crm.
+
+    #[envconfig(default = "false")]
+    pub extract_utm_params: bool, // Copy the utm_ params of $current_url to $utm_ properties
+    #[envconfig(default = "")]
+    pub stripped_url_params: PropertyAllowlist, // Comma-delimited query params removed from $current_url
+    pub max_current_url_length: Option<usize>, // Longer $current_url values are truncated
+
+    #[envconfig(default = "false")]
+    pub strip_empty_properties: bool, // Remove non-reserved properties with empty values
+
+    #[envconfig(default = "false")]
+    pub normalize_geoip_disable: bool, // Coerce $geoip_disable to true, removing false and invalid flags
+
+    #[envconfig(default = "false")]
+    pub redact_ip_addresses: bool, // Mask IPv4 and IPv6 addresses found in string properties
+
+    #[envconfig(default = "false")]
+    pub preserve_raw_lib_version: bool, // Keep $lib_version__raw when LibVersion parsing is lossy
+
+    #[envconfig(default = "false")]
+    pub record_processing_duration: bool, // Time the processing of each event, for investigations
+}
+
+impl Default for ProcessingConfig {
+    fn default() -> Self {
+        Self::init_from_hashmap(&HashMap::new()).expect("invalid default processing config")
+    }
+}
+
+/// How to handle JSON objects holding the same key several times, serde_json keeping the last
+/// value silently.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplicateKeyPolicy {
+    Allow,
+    Warn,
+    Reject,
+}
+
+impl FromStr for DuplicateKeyPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "allow" => Ok(Self::Allow),
+            "warn" => Ok(Self::Warn),
+            "reject" => Ok(Self::Reject),
+            _ => Err(format!("unknown duplicate key policy: {}", s)),
+        }
+    }
+}
+
+/// Compression codecs in preference order, for bodies that decode with several of them. Gzip
+/// and xz magic numbers are trusted over the `compression` query param, so it only orders the
+/// zlib wrapped (`deflate`) and raw (`deflate-raw`) readings of deflate bodies. zstd and brotli
+/// have no decoder here and are rejected.
+#[derive(Clone, Debug, Default)]
+pub struct CodecPreference(pub Vec<Codec>);
+
+impl CodecPreference {
+    /// Position of `codec` in the list, codecs missing from it coming after the listed ones.
+    pub fn rank(&self, codec: Codec) -> usize {
+        self.0
+            .iter()
+            .position(|preferred| *preferred == codec)
+            .unwrap_or(usize::MAX)
+    }
+}
+
+impl FromStr for CodecPreference {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|codec| !codec.is_empty())
+            .map(|codec| match codec.to_lowercase().as_str() {
+                "gzip" => Ok(Codec::Gzip),
+                "xz" => Ok(Codec::Xz),
+                "deflate" => Ok(Codec::Deflate),
+                "deflate-raw" => Ok(Codec::RawDeflate),
+                _ => Err(format!("unsupported compression codec: {}", codec)),
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Extra formats accepted for event timestamps, after RFC3339 and epochs.
+#[derive(Clone, Debug, Default)]
+pub struct TimestampFormats(pub Vec<OwnedFormatItem>);
+
+impl FromStr for TimestampFormats {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(';')
+            .map(str::trim)
+            .filter(|description| !description.is_empty())
+            .map(|description| {
+                format_description::parse(description)
+                    .map(|items| OwnedFormatItem::from(items.as_slice()))
+                    .map_err(|e| format!("invalid timestamp format {}: {}", description, e))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Weekly schedule of the times events may be ingested at, in UTC. Each window covers some
+/// days of the week, `*`, a range such as `mon-fri` or a list such as `sat,sun`, and a time
+/// range within the day such as `09:00-17:30`. Windows crossing midnight must be split in two.
+/// An empty schedule allows any time.
+#[derive(Clone, Debug, Default)]
+pub struct IngestWindows(pub Vec<IngestWindow>);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IngestWindow {
+    pub days: Vec<Weekday>,
+    pub start: Time,
+    pub end: Time,
+}
+
+impl IngestWindows {
+    pub fn allows(&self, at: OffsetDateTime) -> bool {
+        let at = at.to_offset(UtcOffset::UTC);
+        self.0.is_empty()
+            || self.0.iter().any(|window| {
+                window.days.contains(&at.weekday())
+                    && at.time() >= window.start
+                    && at.time() < window.end
+            })
+    }
+}
+
+impl FromStr for IngestWindows {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(';')
+            .map(str::trim)
+            .filter(|window| !window.is_empty())
+            .map(|window| {
+                let invalid = || format!("invalid ingest window: {}", window);
+                let (days, times) = window.split_once(' ').ok_or_else(invalid)?;
+                let (start, end) = times.trim().split_once('-').ok_or_else(invalid)?;
+                let (start, end) = (parse_time(start), parse_time(end));
+                match (parse_weekdays(days), start, end) {
+                    (Some(days), Some(start), Some(end)) if start < end => {
+                        Ok(IngestWindow { days, start, end })
+                    }
+                    _ => Err(invalid()),
+                }
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+const WEEKDAYS: [(&str, Weekday); 7] = [
+    ("mon", Weekday::Monday),
+    ("tue", Weekday::Tuesday),
+    ("wed", Weekday::Wednesday),
+    ("thu", Weekday::Thursday),
+    ("fri", Weekday::Friday),
+    ("sat", Weekday::Saturday),
+    ("sun", Weekday::Sunday),
+];
+
+fn parse_weekdays(days: &str) -> Option<Vec<Weekday>> {
+    let index = |day: &str| {
+        WEEKDAYS
+            .iter()
+            .position(|(name, _)| day.trim().eq_ignore_ascii_case(name))
+    };
+    if days.trim() == "*" {
+        return Some(WEEKDAYS.iter().map(|(_, day)| *day).collect());
+    }
+    let mut weekdays = Vec::new();
+    for part in days.split(',') {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (index(first)?, index(last)?),
+            None => (index(part)?, index(part)?),
+        };
+        if first > last {
+            return None;
+        }
+        weekdays.extend(WEEKDAYS[first..=last].iter().map(|(_, day)| *day));
+    }
+    Some(weekdays)
+}
+
+// HH:MM, with 24:00 for the end of the day
+fn parse_time(time: &str) -> Option<Time> {
+    let (hours, minutes) = time.trim().split_once(':')?;
+    let (hours, minutes) = (hours.parse::<u8>().ok()?, minutes.parse::<u8>().ok()?);
+    match (hours, minutes) {
+        (24, 0) => Some(Time::MIDNIGHT - time::Duration::NANOSECOND),
+        _ => Time::from_hms(hours, minutes, 0).ok(),
+    }
+}
+
+/// v7 uuids embed their generation time, deployments not wanting to leak it can use v4 instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UuidPolicy {
+    V7,
+    V4,
+}
+
+impl UuidPolicy {
+    pub fn version(&self) -> usize {
+        match self {
+            Self::V7 => 7,
+            Self::V4 => 4,
+        }
+    }
+}
+
+impl FromStr for UuidPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "v7" => Ok(Self::V7),
+            "v4" => Ok(Self::V4),
+            _ => Err(format!("unknown uuid policy: {}", s)),
+        }
+    }
+}
+
+/// How to handle events whose distinct_id is null, or missing from both the event and its
+/// properties.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NullDistinctIdPolicy {
+    /// Reject them with `CaptureError::MissingDistinctId`
+    Reject,
+    /// Give them a generated id, flagged with `$distinct_id_generated`
+    Anonymous,
+    /// Send them with an empty distinct_id, for ingestion to handle
+    Passthrough,
+}
+
+impl FromStr for NullDistinctIdPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "reject" => Ok(Self::Reject),
+            "anonymous" => Ok(Self::Anonymous),
+            "passthrough" => Ok(Self::Passthrough),
+            _ => Err(format!("unknown null distinct_id policy: {}", s)),
+        }
+    }
+}
+
+/// How to handle events whose uuid the embedder reports as seen by a previous request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplicateUuidPolicy {
+    Drop,
+    /// Keep them, flagged with `$duplicate_uuid`
+    Flag,
+}
+
+impl FromStr for DuplicateUuidPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "drop" => Ok(Self::Drop),
+            "flag" => Ok(Self::Flag),
+            _ => Err(format!("unknown duplicate uuid policy: {}", s)),
+        }
+    }
+}
+
+/// What events are keyed on when producing them to Kafka, see `ProcessedEvent::key_with`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PartitionStrategy {
+    /// `token`, co-locating all the events of a project
+    Token,
+    /// `token:distinct_id`, keeping the events of each user ordered
+    #[default]
+    DistinctId,
+}
+
+impl FromStr for PartitionStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "token" => Ok(Self::Token),
+            "distinct_id" => Ok(Self::DistinctId),
+            _ => Err(format!("unknown partition strategy: {}", s)),
+        }
+    }
+}
+
+/// Partition strategies of some event names, overriding the default one.
+#[derive(Clone, Debug, Default)]
+pub struct EventPartitionStrategies(pub HashMap<String, PartitionStrategy>);
+
+impl FromStr for EventPartitionStrategies {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            // Split from the end, event names may hold colons
+            .map(|pair| match pair.rsplit_once(':') {
+                Some((event, strategy)) if !event.trim().is_empty() => {
+                    Ok((event.trim().to_string(), strategy.parse()?))
+                }
+                _ => Err(format!("invalid event partition strategy: {}", pair)),
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Fixed partition key salts of some tokens, see ProcessedEvent::key_with.
+#[derive(Clone, Debug, Default)]
+pub struct PartitionKeyOverrides(pub HashMap<String, String>);
+
+impl FromStr for PartitionKeyOverrides {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| match pair.split_once(':') {
+                Some((token, salt)) if !token.trim().is_empty() && !salt.trim().is_empty() => {
+                    Ok((token.trim().to_string(), salt.trim().to_string()))
+                }
+                _ => Err(format!("invalid partition key override: {}", pair)),
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Time to live of events keyed by event name or token, as ISO-8601 durations such as `P30D`.
+#[derive(Clone, Debug, Default)]
+pub struct Ttls(pub HashMap<String, Duration>);
+
+impl FromStr for Ttls {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            // Split from the end, event names may hold colons
+            .map(|pair| match pair.rsplit_once(':') {
+                Some((key, ttl)) if !key.trim().is_empty() => match parse_iso_duration(ttl) {
+                    Some(ttl) if ttl.is_positive() => Ok((key.trim().to_string(), ttl)),
+                    _ => Err(format!("invalid time to live: {}", pair)),
+                },
+                _ => Err(format!("invalid time to live: {}", pair)),
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// How to handle the NaN and Infinity literals that some broken serializers emit, which are
+/// not valid JSON.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NonFinitePolicy {
+    Reject,
+    Null,
+}
+
+impl FromStr for NonFinitePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "reject" => Ok(Self::Reject),
+            "null" => Ok(Self::Null),
+            _ => Err(format!("unknown non-finite number policy: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PropertyAllowlist(pub HashSet<String>);
+
+impl FromStr for PropertyAllowlist {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(
+            s.split(',')
+                .map(str::trim)
+                .filter(|key| !key.is_empty())
+                .map(String::from)
+                .collect(),
+        ))
+    }
+}
+
+/// Property keys accepted for some tokens, other keys being unknown. `$` properties are always
+/// accepted, and tokens without a list accept any property.
+#[derive(Clone, Debug, Default)]
+pub struct TokenPropertyAllowlists(pub HashMap<String, HashSet<String>>);
+
+impl FromStr for TokenPropertyAllowlists {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(';')
+            .map(str::trim)
+            .filter(|list| !list.is_empty())
+            .map(|list| match list.split_once(':') {
+                Some((token, keys)) if !token.trim().is_empty() => {
+                    let PropertyAllowlist(keys) = keys.parse()?;
+                    Ok((token.trim().to_string(), keys))
+                }
+                _ => Err(format!("invalid token property allowlist: {}", list)),
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Origins browser requests are accepted from for some tokens, like
+/// `phc_abc:https://example.com,https://*.example.com`. A `*.` host prefix matches any subdomain,
+/// and tokens without a list accept any origin.
+#[derive(Clone, Debug, Default)]
+pub struct TokenAllowedOrigins(pub HashMap<String, HashSet<String>>);
+
+impl FromStr for TokenAllowedOrigins {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(';')
+            .map(str::trim)
+            .filter(|list| !list.is_empty())
+            .map(|list| match list.split_once(':') {
+                Some((token, origins)) if !token.trim().is_empty() => {
+                    let PropertyAllowlist(origins) = origins.to_ascii_lowercase().parse()?;
+                    Ok((token.trim().to_string(), origins))
+                }
+                _ => Err(format!("invalid token allowed origins: {}", list)),
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Properties set on some events when they are missing, keyed by event name. Values are read as
+/// JSON when valid, as strings otherwise, so `$pageview:$page_category=unknown,$depth=0` sets a
+/// string and a number.
+#[derive(Clone, Debug, Default)]
+pub struct EventPropertyDefaults(pub HashMap<String, HashMap<String, Value>>);
+
+impl FromStr for EventPropertyDefaults {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(';')
+            .map(str::trim)
+            .filter(|list| !list.is_empty())
+            .map(|list| match list.split_once(':') {
+                Some((event, defaults)) if !event.trim().is_empty() => defaults
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|pair| !pair.is_empty())
+                    .map(|pair| match pair.split_once('=') {
+                        Some((key, value)) if !key.trim().is_empty() => {
+                            let value = serde_json::from_str(value.trim())
+                                .unwrap_or_else(|_| Value::String(value.trim().to_string()));
+                            Ok((key.trim().to_string(), value))
+                        }
+                        _ => Err(format!("invalid event property default: {}", pair)),
+                    })
+                    .collect::<Result<_, _>>()
+                    .map(|defaults| (event.trim().to_string(), defaults)),
+                _ => Err(format!("invalid event property defaults: {}", list)),
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Names of the nameless events sent to some request paths, for pixel-style integrations
+/// tracking through the URL. Trailing slashes are ignored.
+#[derive(Clone, Debug, Default)]
+pub struct PathEventNames(pub HashMap<String, String>);
+
+impl FromStr for PathEventNames {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| match pair.split_once(':') {
+                Some((path, event)) if path.starts_with('/') && !event.is_empty() => {
+                    Ok((path.trim_end_matches('/').to_string(), event.to_string()))
+                }
+                _ => Err(format!("invalid path event name: {}", pair)),
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PropertyRenames(pub Vec<(String, String)>);
+
+impl FromStr for PropertyRenames {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| match pair.split_once(':') {
+                Some((from, to)) if !from.is_empty() && !to.is_empty() => {
+                    Ok((from.to_string(), to.to_string()))
+                }
+                _ => Err(format!("invalid property rename: {}", pair)),
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Oldest accepted `$lib_version` of some client libraries, keyed by lowercase `$lib`. Libraries
+/// without a minimum are not restricted.
+#[derive(Clone, Debug, Default)]
+pub struct MinimumLibVersions(pub HashMap<String, LibVersion>);
+
+impl FromStr for MinimumLibVersions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|minimum| !minimum.is_empty())
+            .map(|minimum| match minimum.split_once(':') {
+                Some((lib, version)) if !lib.trim().is_empty() => {
+                    match LibVersion::parse(version.trim()) {
+                        Some(version) => Ok((lib.trim().to_lowercase(), version)),
+                        None => Err(format!("invalid minimum lib version: {}", minimum)),
+                    }
+                }
+                _ => Err(format!("invalid minimum lib version: {}", minimum)),
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PropertyBounds(pub HashMap<String, (f64, f64)>);
+
+impl FromStr for PropertyBounds {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|bound| !bound.is_empty())
+            .map(|bound| {
+                // Split from the end, keys may hold colons
+                let mut parts = bound.rsplitn(3, ':');
+                let (max, min, key) = (parts.next(), parts.next(), parts.next());
+                match (key, min.map(str::parse::<f64>), max.map(str::parse::<f64>)) {
+                    (Some(key), Some(Ok(min)), Some(Ok(max))) if !key.is_empty() && min <= max => {
+                        Ok((key.to_string(), (min, max)))
+                    }
+                    _ => Err(format!("invalid property bound: {}", bound)),
+                }
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
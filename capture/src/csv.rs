@@ -0,0 +1,231 @@
+// Decoding of CSV exports, used to replay historical events
+
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::api::CaptureError;
+use crate::event::RawEvent;
+
+/// Columns of a CSV export holding the event fields, by header name.
+#[derive(Clone, Debug, Default)]
+pub struct CsvMapping {
+    pub event: String,
+    pub distinct_id: String,
+    pub token: Option<String>,
+    pub timestamp: Option<String>,
+    /// Columns turned into properties, all the unmapped ones when None
+    pub properties: Option<Vec<String>>,
+}
+
+impl RawEvent {
+    /// Decodes a CSV export with a header row, one event per row. Fields may be quoted, with
+    /// `""` escaping quotes. Property values that parse as numbers are sent as numbers, and
+    /// empty fields are skipped.
+    pub fn from_csv(bytes: &[u8], mapping: &CsvMapping) -> Result<Vec<RawEvent>, CaptureError> {
+        let payload = std::str::from_utf8(bytes).map_err(|e| {
+            tracing::error!("failed to decode csv: {}", e);
+            CaptureError::InvalidTextEncoding("csv")
+        })?;
+        let mut records = parse_records(payload.trim_start_matches('\u{feff}'))?.into_iter();
+        let header = records.next().unwrap_or_default();
+
+        let column = |name: &str| {
+            header.iter().position(|h| h == name).ok_or_else(|| {
+                CaptureError::RequestDecodingError(format!("missing csv column {name}"))
+            })
+        };
+        let event_column = column(&mapping.event)?;
+        let distinct_id_column = column(&mapping.distinct_id)?;
+        let token_column = mapping.token.as_deref().map(column).transpose()?;
+        let timestamp_column = mapping.timestamp.as_deref().map(column).transpose()?;
+        let property_columns: Vec<usize> = match &mapping.properties {
+            Some(names) => names
+                .iter()
+                .map(|name| column(name))
+                .collect::<Result<_, _>>()?,
+            None => {
+                let mapped = [
+                    Some(event_column),
+                    Some(distinct_id_column),
+                    token_column,
+                    timestamp_column,
+                ];
+                (0..header.len())
+                    .filter(|i| !mapped.contains(&Some(*i)))
+                    .collect()
+            }
+        };
+
+        records
+            .enumerate()
+            .map(|(row, fields)| {
+                if fields.len() != header.len() {
+                    return Err(CaptureError::RequestDecodingError(format!(
+                        "csv row {} has {} fields instead of {}",
+                        row + 1,
+                        fields.len(),
+                        header.len()
+                    )));
+                }
+                let non_empty = |column: Option<usize>| {
+                    column
+                        .map(|i| fields[i].clone())
+                        .filter(|value| !value.is_empty())
+                };
+                let properties: HashMap<String, Value> = property_columns
+                    .iter()
+                    .filter(|i| !fields[**i].is_empty())
+                    .map(|i| (header[*i].clone(), infer_value(&fields[*i])))
+                    .collect();
+                Ok(RawEvent {
+                    event: fields[event_column].clone(),
+                    distinct_id: non_empty(Some(distinct_id_column)),
+                    token: non_empty(token_column),
+                    timestamp: non_empty(timestamp_column),
+                    properties,
+                    ..Default::default()
+                })
+            })
+            .collect()
+    }
+}
+
+fn infer_value(field: &str) -> Value {
+    if let Ok(integer) = field.parse::<i64>() {
+        return Value::from(integer);
+    }
+    match field.parse::<f64>() {
+        Ok(float) if float.is_finite() => Value::from(float),
+        _ => Value::String(field.to_string()),
+    }
+}
+
+/// Splits a CSV document into records of fields. Quoted fields may hold separators, quotes
+/// escaped as `""` and line breaks. Blank lines are skipped.
+fn parse_records(payload: &str) -> Result<Vec<Vec<String>>, CaptureError> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = payload.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            ('"', true) => quoted = false,
+            ('"', false) if field.is_empty() => quoted = true,
+            (',', false) => record.push(std::mem::take(&mut field)),
+            ('\r', false) if chars.peek() == Some(&'\n') => {}
+            ('\n', false) => {
+                record.push(std::mem::take(&mut field));
+                if record.len() > 1 || !record[0].is_empty() {
+                    records.push(std::mem::take(&mut record));
+                } else {
+                    record.clear();
+                }
+            }
+            (c, _) => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(CaptureError::RequestDecodingError(String::from(
+            "unterminated csv quoted field",
+        )));
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::api::CaptureError;
+    use crate::csv::{parse_records, CsvMapping};
+    use crate::event::RawEvent;
+
+    fn mapping() -> CsvMapping {
+        CsvMapping {
+            event: String::from("event"),
+            distinct_id: String::from("user"),
+            token: Some(String::from("token")),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn decodes_simple_csv() {
+        let body = "event,user,token,price,plan\n\
+                    purchase,user1,tok,12.5,pro\n\
+                    refund,user2,tok,-3,\n";
+
+        let events = RawEvent::from_csv(body.as_bytes(), &mapping()).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].event, "purchase");
+        assert_eq!(events[0].distinct_id.as_deref(), Some("user1"));
+        assert_eq!(events[0].token.as_deref(), Some("tok"));
+        assert_eq!(events[0].properties["price"], json!(12.5));
+        assert_eq!(events[0].properties["plan"], json!("pro"));
+        assert_eq!(events[1].properties["price"], json!(-3));
+        assert!(!events[1].properties.contains_key("plan"));
+        assert!(!events[0].properties.contains_key("token"));
+    }
+
+    #[test]
+    fn decodes_quoted_fields() {
+        let body = "event,user,token,comment\r\n\
+                    \"signed up\",user1,tok,\"says \"\"hi\"\", then\nleaves\"\r\n";
+
+        let events = RawEvent::from_csv(body.as_bytes(), &mapping()).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event, "signed up");
+        assert_eq!(
+            events[0].properties["comment"],
+            json!("says \"hi\", then\nleaves")
+        );
+    }
+
+    #[test]
+    fn selects_property_columns() {
+        let body = "event,user,a,b\ne,u,1,2\n";
+        let mapping = CsvMapping {
+            token: None,
+            properties: Some(vec![String::from("b")]),
+            ..mapping()
+        };
+
+        let events = RawEvent::from_csv(body.as_bytes(), &mapping).unwrap();
+        assert_eq!(events[0].properties.len(), 1);
+        assert_eq!(events[0].properties["b"], json!(2));
+    }
+
+    #[test]
+    fn rejects_malformed_csv() {
+        for invalid in [
+            "event,user,token\ne,u\n",
+            "event,user\ne,u\n",
+            "event,user,token\n\"e,u,t\n",
+        ] {
+            let res = RawEvent::from_csv(invalid.as_bytes(), &mapping());
+            assert!(
+                matches!(res, Err(CaptureError::RequestDecodingError(_))),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn skips_blank_lines() {
+        assert_eq!(
+            parse_records("a,b\n\n1,2").unwrap(),
+            vec![vec!["a", "b"], vec!["1", "2"]]
+        );
+    }
+}
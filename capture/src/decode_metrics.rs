@@ -0,0 +1,214 @@
+// Breakdown of the request bodies failing to decode, for operators
+//
+// Failures are recorded where the handler dispatches bodies to their parser, which covers the
+// error paths of every parser, and classified from the variant of the error they return. The
+// default recorder ignores them, embedders can forward them to their metrics system.
+use std::sync::Mutex;
+
+use crate::api::CaptureError;
+use crate::decompression::Codec;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeFailureKind {
+    Gzip,
+    Xz,
+    Deflate,
+    Base64,
+    Json,
+    Utf8,
+    /// Compressed data ending early
+    Truncated,
+    /// Size and decompression time limits
+    Limit,
+    Other,
+}
+
+impl DecodeFailureKind {
+    /// Kind of a decoding error, from its variant.
+    pub fn of(error: &CaptureError) -> Self {
+        match error {
+            CaptureError::CorruptCompressedData(Codec::Gzip) => Self::Gzip,
+            CaptureError::CorruptCompressedData(Codec::Xz) => Self::Xz,
+            CaptureError::CorruptCompressedData(Codec::Deflate | Codec::RawDeflate) => {
+                Self::Deflate
+            }
+            CaptureError::InvalidBase64(_) => Self::Base64,
+            CaptureError::RequestParsingError(_)
+            | CaptureError::MalformedEventTuple(_)
+            | CaptureError::DuplicateJsonKey(_)
+            | CaptureError::NonFiniteNumber => Self::Json,
+            CaptureError::InvalidTextEncoding(_) | CaptureError::InvalidUnicode => Self::Utf8,
+            CaptureError::TruncatedRequestBody => Self::Truncated,
+            CaptureError::RequestTooLarge
+            | CaptureError::DecompressedTooLarge
+            | CaptureError::DecompressionTimeout => Self::Limit,
+            _ => Self::Other,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Xz => "xz",
+            Self::Deflate => "deflate",
+            Self::Base64 => "base64",
+            Self::Json => "json",
+            Self::Utf8 => "utf8",
+            Self::Truncated => "truncated",
+            Self::Limit => "limit",
+            Self::Other => "other",
+        }
+    }
+}
+
+pub trait DecodeFailureMetrics {
+    /// `token` is the hashed token of the request, see `token_log_id`, when sent outside of the
+    /// body.
+    fn record(&self, kind: DecodeFailureKind, token: Option<&str>);
+}
+
+/// Default recorder, ignoring failures.
+#[derive(Clone, Default)]
+pub struct NoopDecodeFailureMetrics {}
+
+impl DecodeFailureMetrics for NoopDecodeFailureMetrics {
+    fn record(&self, _kind: DecodeFailureKind, _token: Option<&str>) {}
+}
+
+/// Keeps the failures it records, for tests.
+#[derive(Default)]
+pub struct RecordedDecodeFailures {
+    pub failures: Mutex<Vec<(DecodeFailureKind, Option<String>)>>,
+}
+
+impl DecodeFailureMetrics for RecordedDecodeFailures {
+    fn record(&self, kind: DecodeFailureKind, token: Option<&str>) {
+        self.failures
+            .lock()
+            .unwrap()
+            .push((kind, token.map(String::from)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use bytes::Bytes;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use serde_json::json;
+
+    use crate::api::CaptureError;
+    use crate::config::ProcessingConfig;
+    use crate::decode_metrics::{
+        DecodeFailureKind, DecodeFailureMetrics, NoopDecodeFailureMetrics, RecordedDecodeFailures,
+    };
+    use crate::decompression::Codec;
+    use crate::event::{EventQuery, RawEvent};
+
+    fn failure_kind(body: Vec<u8>, config: &ProcessingConfig) -> DecodeFailureKind {
+        let err = RawEvent::from_bytes_with(&EventQuery::default(), Bytes::from(body), config)
+            .expect_err("body decoded");
+        DecodeFailureKind::of(&err)
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn classifies_decode_failures() {
+        let config = ProcessingConfig::default();
+        let event = json!({"event": "pageview", "distinct_id": "user1"}).to_string();
+
+        let mut corrupt = gzip(event.as_bytes());
+        let middle = corrupt.len() / 2;
+        corrupt[middle] ^= 0xff;
+        assert_eq!(failure_kind(corrupt, &config), DecodeFailureKind::Gzip);
+
+        let compressed = gzip(event.as_bytes());
+        let truncated = compressed[..compressed.len() - 10].to_vec();
+        assert_eq!(
+            failure_kind(truncated, &config),
+            DecodeFailureKind::Truncated
+        );
+
+        let nested = json!({"data": "not base64!"}).to_string();
+        assert_eq!(
+            failure_kind(nested.into_bytes(), &config),
+            DecodeFailureKind::Base64
+        );
+
+        let invalid_json = b"{\"event\": \"pageview\",".to_vec();
+        assert_eq!(failure_kind(invalid_json, &config), DecodeFailureKind::Json);
+
+        let invalid_utf8 = vec![b'{', 0xff, 0xfe, b'}'];
+        assert_eq!(failure_kind(invalid_utf8, &config), DecodeFailureKind::Utf8);
+
+        let config = ProcessingConfig {
+            max_compressed_bytes: 10,
+            ..Default::default()
+        };
+        assert_eq!(
+            failure_kind(event.into_bytes(), &config),
+            DecodeFailureKind::Limit
+        );
+    }
+
+    #[test]
+    fn classifies_from_variants() {
+        // Messages are for clients, kinds must not depend on their wording
+        for (err, kind) in [
+            (
+                CaptureError::CorruptCompressedData(Codec::Gzip),
+                DecodeFailureKind::Gzip,
+            ),
+            (
+                CaptureError::CorruptCompressedData(Codec::Xz),
+                DecodeFailureKind::Xz,
+            ),
+            (
+                CaptureError::CorruptCompressedData(Codec::Deflate),
+                DecodeFailureKind::Deflate,
+            ),
+            (
+                CaptureError::InvalidBase64("data field"),
+                DecodeFailureKind::Base64,
+            ),
+            (
+                CaptureError::InvalidTextEncoding("csv"),
+                DecodeFailureKind::Utf8,
+            ),
+            (
+                CaptureError::MalformedEventTuple(0),
+                DecodeFailureKind::Json,
+            ),
+            (
+                CaptureError::RequestDecodingError(String::from("invalid gzip data")),
+                DecodeFailureKind::Other,
+            ),
+        ] {
+            assert_eq!(DecodeFailureKind::of(&err), kind, "{}", err);
+        }
+    }
+
+    #[test]
+    fn records_failures() {
+        let recorder = RecordedDecodeFailures::default();
+        recorder.record(DecodeFailureKind::Gzip, Some("cbf29ce4"));
+        recorder.record(DecodeFailureKind::Json, None);
+        NoopDecodeFailureMetrics {}.record(DecodeFailureKind::Json, None);
+
+        assert_eq!(
+            *recorder.failures.lock().unwrap(),
+            vec![
+                (DecodeFailureKind::Gzip, Some(String::from("cbf29ce4"))),
+                (DecodeFailureKind::Json, None)
+            ]
+        );
+        assert_eq!(DecodeFailureKind::Base64.as_str(), "base64");
+    }
+}
@@ -0,0 +1,1144 @@
+// Decoding of compressed request bodies
+
+use std::fmt;
+use std::io::{BufReader, ErrorKind, Read};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use flate2::read::{DeflateDecoder, MultiGzDecoder, ZlibDecoder};
+use serde::de::DeserializeOwned;
+
+use crate::api::CaptureError;
+use crate::config::ProcessingConfig;
+
+// Decompressed bytes read between two checks of the time budget
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+
+pub static GZIP_MAGIC_NUMBERS: [u8; 3] = [0x1f, 0x8b, 8];
+pub static XZ_MAGIC_NUMBERS: [u8; 6] = [0xfd, b'7', b'z', b'X', b'Z', 0];
+
+// Upper bound of the buffer allocated upfront from a size hint, clients control the hint
+const MAX_PREALLOCATION: u64 = 16 * 1024 * 1024;
+
+/// Time budget for decompressing a request body, unlimited unless `decompression_timeout_ms`
+/// is set.
+pub fn decompression_budget(config: &ProcessingConfig) -> Duration {
+    config
+        .decompression_timeout_ms
+        .map_or(Duration::MAX, Duration::from_millis)
+}
+
+/// Size limit of a decompressed request body, within the `remaining_total` left to the request.
+/// Unlimited unless `max_decompressed_bytes` is set.
+pub fn decompressed_size_limit(config: &ProcessingConfig, remaining_total: u64) -> u64 {
+    config
+        .max_decompressed_bytes
+        .map_or(remaining_total, |max| max.min(remaining_total))
+}
+
+/// Uncompressed size announced by the ISIZE footer of a gzip stream: its last 4 bytes, the size
+/// modulo 2^32, little-endian. The footer is sent by the client and not verified until the end
+/// of decompression, and only covers the last member of multi-member streams.
+pub fn gzip_size_hint(bytes: &[u8]) -> Option<u64> {
+    if !bytes.starts_with(&GZIP_MAGIC_NUMBERS) || bytes.len() < 18 {
+        // Too short for a header and footer
+        return None;
+    }
+    let footer: [u8; 4] = bytes[bytes.len() - 4..].try_into().ok()?;
+    Some(u64::from(u32::from_le_bytes(footer)))
+}
+
+/// Broken proxy chains sometimes compress bodies twice: layers are decompressed as long as the
+/// output starts with the gzip magic numbers, up to `max_gzip_layers`. The time budget and size
+/// limit apply to all the layers combined, and to all the members of multi-member streams.
+pub fn decompress_gzip(bytes: Bytes, config: &ProcessingConfig) -> Result<String, CaptureError> {
+    let mut unbounded = u64::MAX;
+    decompress_gzip_within(bytes, config, &mut unbounded)
+}
+
+/// Same as `decompress_gzip`, also bounded by the `remaining_total` bytes left to the request
+/// the stream is part of. The decompressed size is subtracted from it.
+pub fn decompress_gzip_within(
+    bytes: Bytes,
+    config: &ProcessingConfig,
+    remaining_total: &mut u64,
+) -> Result<String, CaptureError> {
+    let payload = decompress_gzip_bytes(&bytes, config, remaining_total)?;
+    String::from_utf8(payload).map_err(|e| {
+        tracing::error!("failed to decode gzip payload: {}", e);
+        CaptureError::InvalidTextEncoding("body")
+    })
+}
+
+fn decompress_gzip_bytes(
+    bytes: &[u8],
+    config: &ProcessingConfig,
+    remaining_total: &mut u64,
+) -> Result<Vec<u8>, CaptureError> {
+    let start = Instant::now();
+    let budget = decompression_budget(config);
+    let max_bytes = decompressed_size_limit(config, *remaining_total);
+    let mut remaining_bytes = max_bytes;
+
+    if config.reject_on_gzip_size_hint {
+        if let Some(hint) = gzip_size_hint(bytes).filter(|hint| *hint > remaining_bytes) {
+            tracing::error!(hint, "gzip footer announces a body over the size limit");
+            return Err(CaptureError::DecompressedTooLarge);
+        }
+    }
+
+    let mut payload = decompress_layer(bytes, budget, remaining_bytes)?;
+    for _ in 1..config.max_gzip_layers {
+        if !payload.starts_with(&GZIP_MAGIC_NUMBERS) {
+            break;
+        }
+        tracing::warn!("request body is compressed several times");
+        remaining_bytes = remaining_bytes.saturating_sub(payload.len() as u64);
+        let remaining_budget = budget.saturating_sub(start.elapsed());
+        payload = decompress_layer(&payload, remaining_budget, remaining_bytes)?;
+    }
+    remaining_bytes = remaining_bytes.saturating_sub(payload.len() as u64);
+    *remaining_total = remaining_total.saturating_sub(max_bytes - remaining_bytes);
+    Ok(payload)
+}
+
+/// Decompress the concatenated xz streams of a body, within the time budget and size limits of
+/// `decompress_gzip_within`. Without the `xz` feature, xz bodies are rejected.
+pub fn decompress_xz_within(
+    bytes: &[u8],
+    config: &ProcessingConfig,
+    remaining_total: &mut u64,
+) -> Result<String, CaptureError> {
+    let budget = decompression_budget(config);
+    let max_bytes = decompressed_size_limit(config, *remaining_total);
+    let payload = decompress_xz_bytes(bytes, budget, max_bytes)?;
+    *remaining_total = remaining_total.saturating_sub(payload.len() as u64);
+    String::from_utf8(payload).map_err(|e| {
+        tracing::error!("failed to decode xz payload: {}", e);
+        CaptureError::InvalidTextEncoding("body")
+    })
+}
+
+#[cfg(feature = "xz")]
+fn decompress_xz_bytes(
+    bytes: &[u8],
+    budget: Duration,
+    max_bytes: u64,
+) -> Result<Vec<u8>, CaptureError> {
+    let mut input = InputTracker {
+        inner: bytes,
+        exhausted: false,
+    };
+    match read_bounded(
+        xz2::read::XzDecoder::new_multi_decoder(&mut input),
+        Codec::Xz,
+        budget,
+        max_bytes,
+        0,
+    ) {
+        Err(CaptureError::CorruptCompressedData(_)) if input.exhausted => {
+            tracing::error!("xz stream is truncated");
+            Err(CaptureError::TruncatedRequestBody)
+        }
+        res => res,
+    }
+}
+
+#[cfg(not(feature = "xz"))]
+fn decompress_xz_bytes(
+    _bytes: &[u8],
+    _budget: Duration,
+    _max_bytes: u64,
+) -> Result<Vec<u8>, CaptureError> {
+    tracing::error!("rejecting xz body, capture is built without the xz feature");
+    Err(CaptureError::RequestDecodingError(String::from(
+        "xz data is not supported",
+    )))
+}
+
+/// Parses the JSON held by a gzip stream while decompressing it, without buffering the
+/// decompressed payload. The time budget and size limits of `decompress_gzip_within` apply,
+/// but only one layer is decompressed: payloads that are not plain JSON fail to parse, for
+/// callers to fall back to the buffered path.
+pub fn parse_gzip_json_within<T: DeserializeOwned>(
+    bytes: &[u8],
+    config: &ProcessingConfig,
+    remaining_total: &mut u64,
+) -> Result<T, CaptureError> {
+    let budget = decompression_budget(config);
+    let max_bytes = decompressed_size_limit(config, *remaining_total);
+    if config.reject_on_gzip_size_hint {
+        if let Some(hint) = gzip_size_hint(bytes).filter(|hint| *hint > max_bytes) {
+            tracing::error!(hint, "gzip footer announces a body over the size limit");
+            return Err(CaptureError::DecompressedTooLarge);
+        }
+    }
+
+    // serde_json reads byte by byte, the buffer keeps the limits checked once per chunk
+    let mut reader = BufReader::with_capacity(
+        READ_CHUNK_SIZE,
+        BoundedReader {
+            inner: MultiGzDecoder::new(bytes),
+            start: Instant::now(),
+            budget,
+            max_bytes,
+            total: 0,
+            failure: None,
+        },
+    );
+    let parsed = {
+        let mut deserializer = serde_json::Deserializer::from_reader(&mut reader);
+        T::deserialize(&mut deserializer).and_then(|value| deserializer.end().map(|_| value))
+    };
+    let bounded = reader.into_inner();
+    match (parsed, bounded.failure) {
+        (_, Some(failure)) => Err(failure),
+        (Err(e), None) => Err(CaptureError::RequestParsingError(e)),
+        (Ok(value), None) => {
+            *remaining_total = remaining_total.saturating_sub(bounded.total);
+            Ok(value)
+        }
+    }
+}
+
+/// Enforces the limits of `read_bounded` on a reader consumed by someone else, keeping the
+/// reason of the failure for it not to be lost in an opaque io::Error.
+struct BoundedReader<R> {
+    inner: R,
+    start: Instant,
+    budget: Duration,
+    max_bytes: u64,
+    total: u64,
+    failure: Option<CaptureError>,
+}
+
+impl<R: Read> BoundedReader<R> {
+    fn fail(&mut self, failure: CaptureError) -> std::io::Error {
+        self.failure = Some(failure);
+        ErrorKind::Other.into()
+    }
+}
+
+impl<R: Read> Read for BoundedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = match self.inner.read(buf) {
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => return Err(e),
+            Err(e) => {
+                let failure = read_error(e, Codec::Gzip, self.total);
+                return Err(self.fail(failure));
+            }
+        };
+        self.total = self.total.saturating_add(read as u64);
+        if self.total > self.max_bytes {
+            tracing::error!(
+                max_bytes = self.max_bytes,
+                "decompressed body exceeds the size limit"
+            );
+            return Err(self.fail(CaptureError::DecompressedTooLarge));
+        }
+        if self.start.elapsed() > self.budget {
+            tracing::error!(
+                read = self.total,
+                "decompression exceeded its {:?} budget",
+                self.budget
+            );
+            return Err(self.fail(CaptureError::DecompressionTimeout));
+        }
+        Ok(read)
+    }
+}
+
+/// Compression of a request body, sniffed from its magic numbers or declared by the
+/// `compression` query param.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    Gzip,
+    Xz,
+    // zlib wrapped, as for the deflate content coding
+    Deflate,
+    // Deflate stream without the zlib wrapper, sent by some clients as deflate
+    RawDeflate,
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Codec::Gzip => "gzip",
+            Codec::Xz => "xz",
+            Codec::Deflate => "deflate",
+            Codec::RawDeflate => "deflate-raw",
+        })
+    }
+}
+
+/// Pick the codec a body is decompressed with. Gzip and xz magic numbers are trusted over the
+/// declared codec, and bodies without them are read as they are, as they are often declared
+/// compressed when they are not. Deflate streams have no magic numbers, declared deflate bodies
+/// are inflated with `decompress_deflate_within`.
+pub fn pick_codec(bytes: &[u8], declared: Option<Codec>) -> Option<Codec> {
+    if bytes.starts_with(&GZIP_MAGIC_NUMBERS) {
+        Some(Codec::Gzip)
+    } else if bytes.starts_with(&XZ_MAGIC_NUMBERS) {
+        Some(Codec::Xz)
+    } else if let Some(Codec::Deflate | Codec::RawDeflate) = declared {
+        Some(Codec::Deflate)
+    } else {
+        None
+    }
+}
+
+/// Inflate a deflate body, which may be zlib wrapped or raw. Some bodies are valid as both, the
+/// readings are tried in `codec_preference` order, the second one only if the data is invalid
+/// for the first. Limits are those of `decompress_gzip_within`.
+pub fn decompress_deflate_within(
+    bytes: &[u8],
+    config: &ProcessingConfig,
+    remaining_total: &mut u64,
+) -> Result<String, CaptureError> {
+    let payload = inflate_deflate_bytes(bytes, config, remaining_total)?;
+    String::from_utf8(payload).map_err(|e| {
+        tracing::error!("failed to decode deflate payload: {}", e);
+        CaptureError::InvalidTextEncoding("body")
+    })
+}
+
+fn inflate_deflate_bytes(
+    bytes: &[u8],
+    config: &ProcessingConfig,
+    remaining_total: &mut u64,
+) -> Result<Vec<u8>, CaptureError> {
+    let (preferred, other) = if config.codec_preference.rank(Codec::RawDeflate)
+        < config.codec_preference.rank(Codec::Deflate)
+    {
+        (Codec::RawDeflate, Codec::Deflate)
+    } else {
+        (Codec::Deflate, Codec::RawDeflate)
+    };
+    let inflate = |codec: Codec, remaining_total: &mut u64| match codec {
+        Codec::RawDeflate => decompress_raw_deflate_within(bytes, config, remaining_total),
+        _ => decompress_zlib_within(bytes, config, remaining_total),
+    };
+    match inflate(preferred, remaining_total) {
+        // The size and time limits apply to both readings, only invalid data is retried
+        Err(CaptureError::CorruptCompressedData(_) | CaptureError::TruncatedRequestBody) => {
+            tracing::debug!("deflate body is not valid {}, trying {}", preferred, other);
+            inflate(other, remaining_total)
+        }
+        res => res,
+    }
+}
+
+fn decompress_raw_deflate_within(
+    bytes: &[u8],
+    config: &ProcessingConfig,
+    remaining_total: &mut u64,
+) -> Result<Vec<u8>, CaptureError> {
+    let max_bytes = decompressed_size_limit(config, *remaining_total);
+    let budget = decompression_budget(config);
+    let inflated = inflate_raw(bytes, budget, max_bytes)?;
+    *remaining_total = remaining_total.saturating_sub(inflated.len() as u64);
+    Ok(inflated)
+}
+
+/// Inflate a zlib wrapped deflate stream, within the time budget and size limits of
+/// `decompress_gzip_within`.
+pub fn decompress_zlib_within(
+    bytes: &[u8],
+    config: &ProcessingConfig,
+    remaining_total: &mut u64,
+) -> Result<Vec<u8>, CaptureError> {
+    let max_bytes = decompressed_size_limit(config, *remaining_total);
+    let budget = decompression_budget(config);
+    let inflated = read_bounded(
+        ZlibDecoder::new(bytes),
+        Codec::Deflate,
+        budget,
+        max_bytes,
+        0,
+    )?;
+    *remaining_total = remaining_total.saturating_sub(inflated.len() as u64);
+    Ok(inflated)
+}
+
+/// Coding of a `Content-Encoding` header value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Identity,
+    Gzip,
+    Deflate,
+}
+
+/// Parses a `Content-Encoding` header, listing the codings in the order they were applied:
+/// `br, gzip` is brotli compressed, then gzipped. Errors on unsupported codings and chains
+/// longer than `max_content_encodings`.
+pub fn parse_content_encoding(
+    header: &str,
+    config: &ProcessingConfig,
+) -> Result<Vec<ContentEncoding>, CaptureError> {
+    let codings: Vec<&str> = header
+        .split(',')
+        .map(str::trim)
+        .filter(|coding| !coding.is_empty())
+        .collect();
+    if codings.len() > config.max_content_encodings {
+        tracing::error!(header, "too many content encodings");
+        return Err(CaptureError::RequestDecodingError(String::from(
+            "too many content encodings",
+        )));
+    }
+    codings
+        .into_iter()
+        .map(|coding| match coding.to_ascii_lowercase().as_str() {
+            "identity" => Ok(ContentEncoding::Identity),
+            "gzip" | "x-gzip" => Ok(ContentEncoding::Gzip),
+            "deflate" => Ok(ContentEncoding::Deflate),
+            _ => {
+                tracing::error!(coding, "unsupported content encoding");
+                Err(CaptureError::RequestDecodingError(format!(
+                    "unsupported content encoding {coding}"
+                )))
+            }
+        })
+        .collect()
+}
+
+/// Undoes the `encodings` of a body, as parsed by `parse_content_encoding`, outermost first.
+/// Gzip layers are only decompressed when the data starts with the gzip magic numbers: bodies
+/// wrongly declared as gzipped are common, and several gzip layers are decompressed at once.
+/// The size limit applies to each layer, all of them counting towards `remaining_total`.
+pub fn decode_content(
+    bytes: Bytes,
+    encodings: &[ContentEncoding],
+    config: &ProcessingConfig,
+    remaining_total: &mut u64,
+) -> Result<Bytes, CaptureError> {
+    let mut payload = bytes;
+    for encoding in encodings.iter().rev() {
+        payload = match encoding {
+            ContentEncoding::Identity => payload,
+            ContentEncoding::Gzip if payload.starts_with(&GZIP_MAGIC_NUMBERS) => {
+                decompress_gzip_bytes(&payload, config, remaining_total)?.into()
+            }
+            ContentEncoding::Gzip => payload,
+            ContentEncoding::Deflate => {
+                decompress_zlib_within(&payload, config, remaining_total)?.into()
+            }
+        };
+    }
+    Ok(payload)
+}
+
+/// Inflate a raw deflate stream, as held by zip archives, within the time `budget` and
+/// `max_bytes`.
+pub fn inflate_raw(
+    bytes: &[u8],
+    budget: Duration,
+    max_bytes: u64,
+) -> Result<Vec<u8>, CaptureError> {
+    read_bounded(
+        DeflateDecoder::new(bytes),
+        Codec::RawDeflate,
+        budget,
+        max_bytes,
+        0,
+    )
+}
+
+fn decompress_layer(
+    bytes: &[u8],
+    budget: Duration,
+    max_bytes: u64,
+) -> Result<Vec<u8>, CaptureError> {
+    let mut input = InputTracker {
+        inner: bytes,
+        exhausted: false,
+    };
+
+    let capacity = gzip_size_hint(bytes)
+        .unwrap_or(0)
+        .min(max_bytes)
+        .min(MAX_PREALLOCATION);
+    match read_bounded(
+        MultiGzDecoder::new(&mut input),
+        Codec::Gzip,
+        budget,
+        max_bytes,
+        capacity as usize,
+    ) {
+        // The deflate decoder reports truncated and corrupt streams the same way. If it failed
+        // after reading all the input, more was expected: the body is incomplete.
+        Err(CaptureError::CorruptCompressedData(_)) if input.exhausted => {
+            tracing::error!("gzip stream is truncated");
+            Err(CaptureError::TruncatedRequestBody)
+        }
+        res => res,
+    }
+}
+
+/// Records whether the wrapped reader reached its end.
+struct InputTracker<R> {
+    inner: R,
+    exhausted: bool,
+}
+
+impl<R: Read> Read for InputTracker<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if read == 0 && !buf.is_empty() {
+            self.exhausted = true;
+        }
+        Ok(read)
+    }
+}
+
+/// Decompression can be CPU-expensive on pathological payloads, even when they are small.
+/// Instead of spawning a watchdog, we read in chunks and check the elapsed time in between,
+/// aborting once the budget is exceeded.
+/// Sizes are tracked as u64 and checked before growing the output, so that memory use stays
+/// bounded by `max_bytes` on 32-bit targets too. `capacity` bytes are reserved upfront.
+fn read_bounded<R: Read>(
+    mut reader: R,
+    codec: Codec,
+    budget: Duration,
+    max_bytes: u64,
+    capacity: usize,
+) -> Result<Vec<u8>, CaptureError> {
+    let start = Instant::now();
+    let mut chunk = [0u8; READ_CHUNK_SIZE];
+    let mut payload = Vec::new();
+    if payload.try_reserve_exact(capacity).is_err() {
+        tracing::warn!(capacity, "failed to preallocate decompression buffer");
+    }
+    let mut total: u64 = 0;
+
+    loop {
+        let read = match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(read_error(e, codec, total)),
+        };
+
+        total = total.saturating_add(read as u64);
+        if total > max_bytes {
+            tracing::error!(max_bytes, "decompressed body exceeds the size limit");
+            return Err(CaptureError::DecompressedTooLarge);
+        }
+        if payload.try_reserve(read).is_err() {
+            tracing::error!(read = payload.len(), "failed to grow decompression buffer");
+            return Err(CaptureError::DecompressedTooLarge);
+        }
+        payload.extend_from_slice(&chunk[..read]);
+
+        if start.elapsed() > budget {
+            tracing::error!(
+                read = payload.len(),
+                "decompression exceeded its {:?} budget",
+                budget
+            );
+            return Err(CaptureError::DecompressionTimeout);
+        }
+    }
+
+    Ok(payload)
+}
+
+fn read_error(e: std::io::Error, codec: Codec, read: u64) -> CaptureError {
+    // The stream ended before its end marker, the body is incomplete
+    if e.kind() == ErrorKind::UnexpectedEof {
+        tracing::error!(read, "{} stream is truncated: {}", codec, e);
+        return CaptureError::TruncatedRequestBody;
+    }
+    tracing::error!("failed to decode {}: {}", codec, e);
+    CaptureError::CorruptCompressedData(codec)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use base64::Engine;
+    use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+
+    use crate::api::CaptureError;
+    use crate::config::ProcessingConfig;
+    use crate::decompression::{
+        decode_content, decompress_deflate_within, decompress_gzip, decompress_gzip_within,
+        decompress_xz_within, decompressed_size_limit, decompression_budget, gzip_size_hint,
+        inflate_deflate_bytes, parse_content_encoding, parse_gzip_json_within, pick_codec,
+        read_bounded, Codec, ContentEncoding, GZIP_MAGIC_NUMBERS, READ_CHUNK_SIZE,
+    };
+
+    // Two pageview events, compressed by `xz` with its default options
+    const XZ_PAGEVIEWS: &str = "/Td6WFoAAATm1rRGAgAhARYAAAB0L+Wj4ABWADVdAC2ewEZT8FgOdOXX2e4hW9mHaBMMXrAVpKwd2R9V4xnPf0cxnc80dy3k4FicJjhWSs4E9ifgAAAAAOqCydL28LwaAAFRV2kdKXYftvN9AQAAAAAEWVo=";
+
+    /// Yields one byte per read, sleeping before each one.
+    struct SlowReader {
+        remaining: usize,
+        delay: Duration,
+    }
+
+    impl Read for SlowReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.remaining == 0 {
+                return Ok(0);
+            }
+            sleep(self.delay);
+            self.remaining -= 1;
+            buf[0] = b'a';
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn slow_decoder_hits_budget() {
+        let reader = SlowReader {
+            remaining: 100,
+            delay: Duration::from_millis(5),
+        };
+
+        let res = read_bounded(reader, Codec::Gzip, Duration::from_millis(20), u64::MAX, 0);
+        assert!(matches!(res, Err(CaptureError::DecompressionTimeout)));
+    }
+
+    #[test]
+    fn decompression_budget_is_opt_in() {
+        let config = ProcessingConfig::default();
+        assert_eq!(decompression_budget(&config), Duration::MAX);
+
+        let config = ProcessingConfig {
+            decompression_timeout_ms: Some(20),
+            ..Default::default()
+        };
+        assert_eq!(decompression_budget(&config), Duration::from_millis(20));
+    }
+
+    #[test]
+    fn decompressed_size_limit_is_opt_in() {
+        let config = ProcessingConfig::default();
+        assert_eq!(decompressed_size_limit(&config, u64::MAX), u64::MAX);
+        assert_eq!(decompressed_size_limit(&config, 100), 100);
+
+        let config = ProcessingConfig {
+            max_decompressed_bytes: Some(50),
+            ..Default::default()
+        };
+        assert_eq!(decompressed_size_limit(&config, u64::MAX), 50);
+        assert_eq!(decompressed_size_limit(&config, 10), 10);
+    }
+
+    #[test]
+    fn decoder_within_budget() {
+        let reader = SlowReader {
+            remaining: 3,
+            delay: Duration::from_millis(1),
+        };
+
+        let res = read_bounded(reader, Codec::Gzip, Duration::from_secs(10), u64::MAX, 0);
+        assert_eq!(res.unwrap(), b"aaa");
+    }
+
+    fn gzip(payload: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(payload).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn multi_chunk_payload() {
+        let payload: String = (0..READ_CHUNK_SIZE * 5 + 17)
+            .map(|i| char::from(b'a' + (i % 26) as u8))
+            .collect();
+
+        let res = decompress_gzip(
+            gzip(payload.as_bytes()).into(),
+            &ProcessingConfig::default(),
+        );
+        assert_eq!(res.unwrap(), payload);
+    }
+
+    #[test]
+    fn payload_over_size_limit() {
+        let payload = vec![b'a'; READ_CHUNK_SIZE * 3];
+        let config = ProcessingConfig {
+            max_decompressed_bytes: Some((READ_CHUNK_SIZE * 2) as u64),
+            ..Default::default()
+        };
+
+        let res = decompress_gzip(gzip(&payload).into(), &config);
+        assert!(matches!(res, Err(CaptureError::DecompressedTooLarge)));
+    }
+
+    #[test]
+    fn truncated_gzip_member() {
+        let payload = vec![b'a'; READ_CHUNK_SIZE * 2];
+        let compressed = gzip(&payload);
+
+        // Cut in the deflate stream, then in the trailer
+        for cut in [compressed.len() / 2, compressed.len() - 4] {
+            let truncated = compressed[..cut].to_vec();
+            let res = decompress_gzip(truncated.into(), &ProcessingConfig::default());
+            assert!(matches!(res, Err(CaptureError::TruncatedRequestBody)));
+        }
+    }
+
+    #[test]
+    fn streamed_json_parse() {
+        let payload = serde_json::json!([{
+            "event": "pageview",
+            "properties": {"key": "a".repeat(READ_CHUNK_SIZE * 3)}
+        }]);
+        let compressed = gzip(payload.to_string().as_bytes());
+        let mut remaining_total = u64::MAX;
+
+        let parsed: serde_json::Value = parse_gzip_json_within(
+            &compressed,
+            &ProcessingConfig::default(),
+            &mut remaining_total,
+        )
+        .unwrap();
+        assert_eq!(parsed, payload);
+        assert_eq!(u64::MAX - remaining_total, payload.to_string().len() as u64);
+    }
+
+    #[test]
+    fn streamed_json_parse_errors() {
+        let parse = |bytes: &[u8], config: &ProcessingConfig| {
+            parse_gzip_json_within::<serde_json::Value>(bytes, config, &mut u64::MAX.clone())
+        };
+        let payload = format!("[\"{}\"]", "a".repeat(READ_CHUNK_SIZE * 3));
+        let compressed = gzip(payload.as_bytes());
+
+        let small = ProcessingConfig {
+            max_decompressed_bytes: Some((READ_CHUNK_SIZE * 2) as u64),
+            ..Default::default()
+        };
+        assert!(matches!(
+            parse(&compressed, &small),
+            Err(CaptureError::DecompressedTooLarge)
+        ));
+        assert!(matches!(
+            parse(&compressed[..compressed.len() - 4], &Default::default()),
+            Err(CaptureError::TruncatedRequestBody)
+        ));
+        // Parse errors keep the position reported by serde_json
+        match parse(&gzip(b"[1, 2,\n 3 oops]"), &Default::default()) {
+            Err(CaptureError::RequestParsingError(e)) => assert_eq!((e.line(), e.column()), (2, 4)),
+            _ => panic!("expected a parsing error"),
+        }
+        assert!(matches!(
+            parse(&gzip(b"[1] [2]"), &Default::default()),
+            Err(CaptureError::RequestParsingError(_))
+        ));
+    }
+
+    #[test]
+    fn corrupt_gzip_data() {
+        let mut compressed = gzip(b"some event payload");
+        // Flip bytes of the deflate stream, keeping the gzip header intact
+        for byte in compressed[10..].iter_mut() {
+            *byte ^= 0xff;
+        }
+
+        let res = decompress_gzip(compressed.into(), &ProcessingConfig::default());
+        assert!(matches!(
+            res,
+            Err(CaptureError::CorruptCompressedData(Codec::Gzip))
+        ));
+    }
+
+    #[cfg(feature = "xz")]
+    fn xz_pageviews() -> Vec<u8> {
+        base64::engine::general_purpose::STANDARD
+            .decode(XZ_PAGEVIEWS)
+            .unwrap()
+    }
+
+    #[cfg(feature = "xz")]
+    fn decompress_xz(bytes: &[u8], config: &ProcessingConfig) -> Result<String, CaptureError> {
+        let mut unbounded = u64::MAX;
+        decompress_xz_within(bytes, config, &mut unbounded)
+    }
+
+    #[test]
+    #[cfg(feature = "xz")]
+    fn xz_payload() {
+        let mut remaining_total = 1000;
+        let res = decompress_xz_within(
+            &xz_pageviews(),
+            &ProcessingConfig::default(),
+            &mut remaining_total,
+        );
+        let payload = res.unwrap();
+        assert_eq!(
+            payload,
+            r#"[{"event":"pageview","distinct_id":"user1"},{"event":"pageview","distinct_id":"user2"}]"#
+        );
+        assert_eq!(remaining_total, 1000 - payload.len() as u64);
+    }
+
+    #[test]
+    #[cfg(feature = "xz")]
+    fn truncated_xz_body() {
+        let compressed = xz_pageviews();
+
+        // Cut in the block header, the LZMA2 data, the index, then the stream footer
+        for cut in [16, 40, compressed.len() - 20, compressed.len() - 4] {
+            let res = decompress_xz(&compressed[..cut], &ProcessingConfig::default());
+            assert!(
+                matches!(res, Err(CaptureError::TruncatedRequestBody)),
+                "{cut}"
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "xz")]
+    fn corrupt_xz_data() {
+        let mut compressed = xz_pageviews();
+        // Flip a byte of the LZMA2 data, keeping the headers intact
+        compressed[40] ^= 0xff;
+
+        let res = decompress_xz(&compressed, &ProcessingConfig::default());
+        assert!(matches!(
+            res,
+            Err(CaptureError::CorruptCompressedData(Codec::Xz))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "xz")]
+    fn xz_over_size_limit() {
+        let config = ProcessingConfig {
+            max_decompressed_bytes: Some(16),
+            ..Default::default()
+        };
+
+        let res = decompress_xz(&xz_pageviews(), &config);
+        assert!(matches!(res, Err(CaptureError::DecompressedTooLarge)));
+    }
+
+    #[cfg(feature = "xz")]
+    fn xz(data: &[u8], stream: xz2::stream::Stream) -> Vec<u8> {
+        let mut encoder = xz2::write::XzEncoder::new_stream(Vec::new(), stream);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    // Pseudo-random bytes, incompressible enough for LZMA2 to store them as uncompressed chunks
+    #[cfg(feature = "xz")]
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state: u64 = 0x2545f4914f6cdd1d;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    #[cfg(feature = "xz")]
+    fn xz_round_trips() {
+        use xz2::stream::{Check, MtStreamBuilder, Stream};
+
+        let decode = |bytes: &[u8]| {
+            super::decompress_xz_bytes(bytes, Duration::from_secs(10), u64::MAX).unwrap()
+        };
+        // Uncompressed chunks, then matches 1.5 MiB behind for the repeated noise
+        let mut data = noise(1536 * 1024);
+        data.extend_from_within(..256 * 1024);
+        data.extend(b"pageview ".repeat(20_000));
+
+        for (name, check) in [
+            ("none", Check::None),
+            ("crc32", Check::Crc32),
+            ("crc64", Check::Crc64),
+            ("sha256", Check::Sha256),
+        ] {
+            let single_block = xz(&data, Stream::new_easy_encoder(6, check).unwrap());
+            assert!(decode(&single_block) == data, "{}", name);
+        }
+
+        let blocks = MtStreamBuilder::new()
+            .threads(1)
+            .block_size(256 * 1024)
+            .check(Check::Crc64)
+            .encoder()
+            .unwrap();
+        let multi_block = xz(&data, blocks);
+        assert!(decode(&multi_block) == data);
+
+        // Concatenated streams, and stream padding
+        let stream = || Stream::new_easy_encoder(6, Check::Crc64).unwrap();
+        let mut concatenated = xz(b"first stream, ", stream());
+        concatenated.extend(xz(b"second stream", stream()));
+        concatenated.extend([0; 8]);
+        assert_eq!(decode(&concatenated), b"first stream, second stream");
+        concatenated.push(0);
+        assert!(
+            super::decompress_xz_bytes(&concatenated, Duration::from_secs(10), u64::MAX).is_err()
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "xz")]
+    fn corrupt_xz_check() {
+        use xz2::stream::{Check, Stream};
+
+        let mut compressed = xz(
+            b"pageview",
+            Stream::new_easy_encoder(6, Check::Sha256).unwrap(),
+        );
+        // The check ends the block, before the index and the stream footer
+        let check_end = compressed.len() - 12 - 12;
+        compressed[check_end - 1] ^= 0xff;
+
+        let res = decompress_xz(&compressed, &ProcessingConfig::default());
+        assert!(matches!(
+            res,
+            Err(CaptureError::CorruptCompressedData(Codec::Xz))
+        ));
+    }
+
+    #[test]
+    #[cfg(not(feature = "xz"))]
+    fn xz_needs_its_feature() {
+        let mut unbounded = u64::MAX;
+        let res = decompress_xz_within(
+            &super::XZ_MAGIC_NUMBERS,
+            &ProcessingConfig::default(),
+            &mut unbounded,
+        );
+        assert!(matches!(res, Err(CaptureError::RequestDecodingError(_))));
+    }
+
+    #[test]
+    fn double_gzip() {
+        let payload = String::from(r#"{"event": "pageview"}"#);
+        let twice = gzip(&gzip(payload.as_bytes()));
+
+        let res = decompress_gzip(twice.clone().into(), &ProcessingConfig::default());
+        assert_eq!(res.unwrap(), payload);
+
+        // Layers past the limit are not decompressed
+        let config = ProcessingConfig {
+            max_gzip_layers: 1,
+            ..Default::default()
+        };
+        let res = decompress_gzip(twice.into(), &config);
+        assert!(matches!(res, Err(CaptureError::InvalidTextEncoding(_))));
+    }
+
+    #[test]
+    fn size_limit_applies_to_all_layers() {
+        let payload = vec![b'a'; READ_CHUNK_SIZE * 4];
+        let inner = gzip(&payload);
+        let config = ProcessingConfig {
+            max_decompressed_bytes: Some((payload.len() + inner.len() - 1) as u64),
+            ..Default::default()
+        };
+
+        let res = decompress_gzip(gzip(&inner).into(), &config);
+        assert!(matches!(res, Err(CaptureError::DecompressedTooLarge)));
+    }
+
+    #[test]
+    fn decompresses_all_members() {
+        let mut members = gzip(b"first,");
+        members.extend(gzip(b"second"));
+
+        let res = decompress_gzip(members.into(), &ProcessingConfig::default());
+        assert_eq!(res.unwrap(), "first,second");
+    }
+
+    #[test]
+    fn total_limit_applies_across_streams() {
+        let member = gzip(&[b'a'; READ_CHUNK_SIZE]);
+        let config = ProcessingConfig::default();
+
+        let mut remaining_total = (READ_CHUNK_SIZE * 3) as u64;
+        for _ in 0..2 {
+            let res = decompress_gzip_within(member.clone().into(), &config, &mut remaining_total);
+            assert!(res.is_ok());
+        }
+        assert_eq!(remaining_total, READ_CHUNK_SIZE as u64);
+
+        // Each member is small, but they sum over what is left
+        let members = [member.clone(), member].concat();
+        let res = decompress_gzip_within(members.into(), &config, &mut remaining_total);
+        assert!(matches!(res, Err(CaptureError::DecompressedTooLarge)));
+    }
+
+    #[test]
+    fn parses_content_encoding_chains() {
+        let config = ProcessingConfig::default();
+        assert_eq!(
+            parse_content_encoding("deflate, GZIP", &config).unwrap(),
+            vec![ContentEncoding::Deflate, ContentEncoding::Gzip]
+        );
+        assert_eq!(
+            parse_content_encoding("identity", &config).unwrap(),
+            vec![ContentEncoding::Identity]
+        );
+        assert!(parse_content_encoding("", &config).unwrap().is_empty());
+
+        // Not built with a brotli decoder
+        let res = parse_content_encoding("br, gzip", &config);
+        assert!(matches!(res, Err(CaptureError::RequestDecodingError(_))));
+
+        let config = ProcessingConfig {
+            max_content_encodings: 2,
+            ..Default::default()
+        };
+        let res = parse_content_encoding("gzip, gzip, gzip", &config);
+        assert!(matches!(res, Err(CaptureError::RequestDecodingError(_))));
+    }
+
+    #[test]
+    fn magic_numbers_win_over_the_declared_codec() {
+        let xz = base64::engine::general_purpose::STANDARD
+            .decode(XZ_PAGEVIEWS)
+            .unwrap();
+        assert_eq!(pick_codec(&xz, Some(Codec::Gzip)), Some(Codec::Xz));
+        assert_eq!(pick_codec(&gzip(b"{}"), Some(Codec::Xz)), Some(Codec::Gzip));
+        assert_eq!(
+            pick_codec(&gzip(b"{}"), Some(Codec::Deflate)),
+            Some(Codec::Gzip)
+        );
+        // Bodies declared compressed often are not
+        assert_eq!(pick_codec(b"{}", Some(Codec::Gzip)), None);
+        assert_eq!(pick_codec(b"{}", None), None);
+        // Deflate streams have no magic numbers
+        assert_eq!(
+            pick_codec(b"x\x9c", Some(Codec::Deflate)),
+            Some(Codec::Deflate)
+        );
+    }
+
+    #[test]
+    fn inflates_zlib_and_raw_deflate() {
+        let payload = br#"{"event": "pageview"}"#;
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(payload).unwrap();
+        let mut raw = DeflateEncoder::new(Vec::new(), Compression::default());
+        raw.write_all(payload).unwrap();
+
+        for body in [zlib.finish().unwrap(), raw.finish().unwrap()] {
+            for preference in ["deflate,deflate-raw", "deflate-raw,deflate"] {
+                let config = ProcessingConfig {
+                    codec_preference: preference.parse().unwrap(),
+                    ..Default::default()
+                };
+                let res = decompress_deflate_within(&body, &config, &mut u64::MAX.clone());
+                assert_eq!(res.unwrap().as_bytes(), &payload[..], "{preference}");
+            }
+        }
+
+        let res = decompress_deflate_within(
+            b"not deflate",
+            &ProcessingConfig::default(),
+            &mut u64::MAX.clone(),
+        );
+        assert!(matches!(res, Err(CaptureError::CorruptCompressedData(_))));
+    }
+
+    #[test]
+    fn codec_preference_orders_ambiguous_deflate() {
+        fn adler32(data: &[u8]) -> u32 {
+            let (mut a, mut b) = (1u32, 0u32);
+            for byte in data {
+                a = (a + *byte as u32) % 65521;
+                b = (b + a) % 65521;
+            }
+            (b << 16) | a
+        }
+
+        // 78 01 is a zlib header, holding a final stored block of 65278 bytes. Read as raw
+        // deflate, 78 is a stored block, of 257 bytes from the header of the zlib one, and a
+        // second stored block starts within the zlib data, running to the end of the body.
+        let mut stored = vec![b'z'; 65278];
+        let raw_len: u16 = 65022;
+        stored[255] = 0x01;
+        stored[256..258].copy_from_slice(&raw_len.to_le_bytes());
+        stored[258..260].copy_from_slice(&(!raw_len).to_le_bytes());
+        let mut body = vec![0x78, 0x01, 0x01, 0xfe, 0xfe, 0x01, 0x01];
+        body.extend_from_slice(&stored);
+        body.extend_from_slice(&adler32(&stored).to_be_bytes());
+
+        let inflate = |preference: &str| {
+            let config = ProcessingConfig {
+                codec_preference: preference.parse().unwrap(),
+                ..Default::default()
+            };
+            inflate_deflate_bytes(&body, &config, &mut u64::MAX.clone()).unwrap()
+        };
+        assert_eq!(inflate("gzip,xz,deflate,deflate-raw"), stored);
+        let raw = inflate("deflate-raw,deflate");
+        assert_eq!(raw.len(), 257 + raw_len as usize);
+        assert_eq!(raw[..2], [0x01, 0x01]);
+    }
+
+    #[test]
+    fn decodes_content_encoding_chain() {
+        let payload = br#"{"event": "pageview"}"#;
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(payload).unwrap();
+        let chained = gzip(&encoder.finish().unwrap());
+        let encodings = [ContentEncoding::Deflate, ContentEncoding::Gzip];
+        let config = ProcessingConfig::default();
+
+        let mut remaining_total = u64::MAX;
+        let res = decode_content(
+            chained.clone().into(),
+            &encodings,
+            &config,
+            &mut remaining_total,
+        );
+        assert_eq!(res.unwrap(), &payload[..]);
+
+        // Sizes of all the layers add up
+        let mut remaining_total = payload.len() as u64 + 10;
+        let res = decode_content(chained.into(), &encodings, &config, &mut remaining_total);
+        assert!(matches!(res, Err(CaptureError::DecompressedTooLarge)));
+    }
+
+    #[test]
+    fn gzip_footer_size_hint() {
+        for len in [0, 1, READ_CHUNK_SIZE * 3 + 7] {
+            let compressed = gzip(&vec![b'a'; len]);
+            assert_eq!(gzip_size_hint(&compressed), Some(len as u64));
+        }
+        assert_eq!(gzip_size_hint(b"not gzip at all, but long enough"), None);
+        assert_eq!(gzip_size_hint(&GZIP_MAGIC_NUMBERS), None);
+
+        // Only the last member is covered
+        let mut members = gzip(&[b'a'; 100]);
+        members.extend(gzip(&[b'b'; 10]));
+        assert_eq!(gzip_size_hint(&members), Some(10));
+    }
+
+    #[test]
+    fn rejects_on_size_hint() {
+        let payload = vec![b'a'; READ_CHUNK_SIZE];
+        let mut compressed = gzip(&payload);
+        let config = ProcessingConfig {
+            max_decompressed_bytes: Some(payload.len() as u64),
+            reject_on_gzip_size_hint: true,
+            ..Default::default()
+        };
+        assert!(decompress_gzip(compressed.clone().into(), &config).is_ok());
+
+        // Announce a larger body, rejected without being decompressed
+        let footer = compressed.len() - 4;
+        compressed[footer..].copy_from_slice(&(payload.len() as u32 + 1).to_le_bytes());
+        let res = decompress_gzip(compressed.into(), &config);
+        assert!(matches!(res, Err(CaptureError::DecompressedTooLarge)));
+    }
+}
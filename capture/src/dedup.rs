@@ -0,0 +1,165 @@
+/// At-least-once pipelines in front of capture may deliver the same request several times,
+/// for instance when their acknowledgement was lost. Remember the requests processed recently,
+/// keyed on the `X-Request-Id` clients send for their retries or on a hash of their raw body,
+/// so that callers can short-circuit a re-delivery instead of producing its events again.
+/// Requests are remembered per token, as ids are only unique among the clients of a project.
+///
+/// This is distinct from the per-event dedup done by ingestion on uuids: a re-delivered
+/// request is skipped as a whole, once its token is known.
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+
+use crate::utils::fnv1a;
+
+/// What identifies a request: the `X-Request-Id` sent by the client for its retries, or the
+/// hash of the body. Ids and hashes never match each other.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DedupKey {
+    RequestId(String),
+    Body(u64),
+}
+
+impl DedupKey {
+    pub fn body(body: &[u8]) -> Self {
+        DedupKey::Body(fnv1a(body))
+    }
+}
+
+struct ProcessedRequest {
+    events: usize,
+    processed_at: Instant,
+}
+
+#[derive(Clone)]
+pub struct RequestDedupCache {
+    ttl: Duration,
+    requests: Arc<DashMap<(String, DedupKey), ProcessedRequest>>,
+    last_eviction: Arc<Mutex<Instant>>,
+}
+
+impl RequestDedupCache {
+    pub fn new(ttl: Duration) -> Self {
+        RequestDedupCache {
+            ttl,
+            requests: Arc::new(DashMap::new()),
+            last_eviction: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Number of events produced by the identical request of `token` processed within the TTL,
+    /// if any.
+    pub fn check(&self, token: &str, key: &DedupKey) -> Option<usize> {
+        self.evict_expired_periodically();
+        let request = self.requests.get(&(token.to_string(), key.clone()))?;
+        (request.processed_at.elapsed() < self.ttl).then_some(request.events)
+    }
+
+    /// Remember a processed request of `token` and the number of events it produced.
+    pub fn record(&self, token: &str, key: DedupKey, events: usize) {
+        self.requests.insert(
+            (token.to_string(), key),
+            ProcessedRequest {
+                events,
+                processed_at: Instant::now(),
+            },
+        );
+    }
+
+    /// Forget the requests processed longer than the TTL ago, to be called periodically.
+    pub fn evict_expired(&self) {
+        self.requests
+            .retain(|_, request| request.processed_at.elapsed() < self.ttl);
+    }
+
+    // Run from the request path at most once per TTL, instead of in a background task
+    fn evict_expired_periodically(&self) {
+        let Ok(mut last_eviction) = self.last_eviction.try_lock() else {
+            return;
+        };
+        if last_eviction.elapsed() >= self.ttl {
+            *last_eviction = Instant::now();
+            drop(last_eviction);
+            self.evict_expired();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::dedup::{DedupKey, RequestDedupCache};
+
+    #[test]
+    fn first_seen_request() {
+        let cache = RequestDedupCache::new(Duration::from_secs(60));
+        assert_eq!(
+            cache.check("token", &DedupKey::body(b"{\"event\":\"first\"}")),
+            None
+        );
+
+        cache.record("token", DedupKey::body(b"{\"event\":\"first\"}"), 1);
+        assert_eq!(
+            cache.check("token", &DedupKey::body(b"{\"event\":\"second\"}")),
+            None
+        );
+    }
+
+    #[test]
+    fn redelivered_request() {
+        let body = b"[{\"event\":\"first\"},{\"event\":\"second\"}]";
+        let cache = RequestDedupCache::new(Duration::from_secs(60));
+        cache.record("token", DedupKey::body(body), 2);
+
+        assert_eq!(cache.check("token", &DedupKey::body(body)), Some(2));
+        assert_eq!(
+            cache.check("token", &DedupKey::body(body.as_ref())),
+            Some(2)
+        );
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn repeated_request_id() {
+        let cache = RequestDedupCache::new(Duration::from_secs(60));
+        let request_id = DedupKey::RequestId(String::from("retry-1"));
+        assert_eq!(cache.check("token", &request_id), None);
+
+        cache.record("token", request_id.clone(), 3);
+        assert_eq!(cache.check("token", &request_id), Some(3));
+        let fresh = DedupKey::RequestId(String::from("retry-2"));
+        assert_eq!(cache.check("token", &fresh), None);
+        // Ids are not mixed up with body hashes
+        assert_eq!(cache.check("token", &DedupKey::body(b"retry-1")), None);
+    }
+
+    #[test]
+    fn requests_are_scoped_by_token() {
+        let cache = RequestDedupCache::new(Duration::from_secs(60));
+        let request_id = DedupKey::RequestId(String::from("retry-1"));
+        cache.record("token", request_id.clone(), 3);
+
+        assert_eq!(cache.check("other_token", &request_id), None);
+        assert_eq!(cache.check("token", &request_id), Some(3));
+    }
+
+    #[test]
+    fn expired_requests_are_forgotten() {
+        let cache = RequestDedupCache::new(Duration::ZERO);
+        cache.record("token", DedupKey::body(b"body"), 1);
+        assert_eq!(cache.check("token", &DedupKey::body(b"body")), None);
+
+        cache.evict_expired();
+        assert!(cache.is_empty());
+    }
+}
@@ -1,15 +1,31 @@
-use std::collections::HashMap;
-use std::io::prelude::*;
-
-use bytes::{Buf, Bytes};
-use flate2::read::GzDecoder;
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use time::OffsetDateTime;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+
+use base64::Engine;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use rand::Rng;
+use serde::de::{MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+use time::format_description::well_known::Rfc3339;
+use time::format_description::OwnedFormatItem;
+use time::{Duration, OffsetDateTime};
 use tracing::instrument;
 use uuid::Uuid;
 
 use crate::api::CaptureError;
+use crate::config::{
+    DuplicateKeyPolicy, EventPartitionStrategies, NonFinitePolicy, PartitionStrategy,
+    ProcessingConfig,
+};
+use crate::decompression::{
+    decode_content, decompress_deflate_within, decompress_gzip_within, decompress_xz_within,
+    parse_content_encoding, parse_gzip_json_within, pick_codec, Codec, GZIP_MAGIC_NUMBERS,
+};
+use crate::normalization::{replace_lone_surrogates, replace_non_finite};
+use crate::time::{is_iana_zone_name, parse_event_timestamp, parse_iso_duration, TimeSource};
+use crate::utils::{coerce_bool, fnv1a};
 
 #[derive(Deserialize, Default)]
 pub enum Compression {
@@ -18,6 +34,23 @@ pub enum Compression {
 
     #[serde(rename = "gzip", alias = "gzip-js")]
     Gzip,
+
+    #[serde(rename = "xz")]
+    Xz,
+
+    #[serde(rename = "deflate")]
+    Deflate,
+}
+
+impl Compression {
+    fn codec(&self) -> Option<Codec> {
+        match self {
+            Compression::Unsupported => None,
+            Compression::Gzip => Some(Codec::Gzip),
+            Compression::Xz => Some(Codec::Xz),
+            Compression::Deflate => Some(Codec::Deflate),
+        }
+    }
 }
 
 #[derive(Deserialize, Default)]
@@ -29,6 +62,33 @@ pub struct EventQuery {
 
     #[serde(alias = "_")]
     pub sent_at: Option<i64>,
+
+    // Legacy clients send the token in the query string instead of the body
+    #[serde(alias = "token")]
+    pub api_key: Option<String>,
+
+    // Content-Encoding header of the request, set by the handler
+    #[serde(skip)]
+    pub content_encoding: Option<String>,
+}
+
+/// Recoverable oddities found while decoding a request body, see
+/// `RawEvent::from_bytes_with_warnings`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseWarning {
+    /// The decoded body started with a UTF-8 byte order mark, that was removed
+    ByteOrderMarkStripped,
+    /// The body was gzip compressed while the compression query param said otherwise, or the
+    /// other way around. The body is decoded as detected.
+    CompressionMismatch { detected_gzip: bool },
+}
+
+/// Milliseconds between the event and `sent_at`, some SDKs sending an ISO-8601 duration instead.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum EventOffset {
+    Millis(i64),
+    Iso(String),
 }
 
 #[derive(Debug, Deserialize)]
@@ -36,7 +96,7 @@ pub struct EventFormData {
     pub data: String,
 }
 
-#[derive(Default, Debug, Deserialize, Serialize)]
+#[derive(Clone, Default, Debug, Deserialize, Serialize)]
 pub struct RawEvent {
     #[serde(
         alias = "$token",
@@ -47,36 +107,156 @@ pub struct RawEvent {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub distinct_id: Option<String>,
     pub uuid: Option<Uuid>,
-    pub event: String,
     #[serde(default)]
+    pub event: String,
+    #[serde(default, deserialize_with = "deserialize_properties")]
     pub properties: HashMap<String, Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub timestamp: Option<String>, // Passed through if provided, parsed by ingestion
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub offset: Option<i64>, // Passed through if provided, parsed by ingestion
+    pub offset: Option<EventOffset>, // Normalized to milliseconds, parsed by ingestion
     #[serde(rename = "$set", skip_serializing_if = "Option::is_none")]
     pub set: Option<HashMap<String, Value>>,
     #[serde(rename = "$set_once", skip_serializing_if = "Option::is_none")]
     pub set_once: Option<HashMap<String, Value>>,
+    // Position of the event within its request, see `number_events`
+    #[serde(skip)]
+    pub original_index: u32,
+}
+
+// Set on events whose properties were sent as a JSON string. Holds the string until checked by
+// `check_stringified_properties` when it is not an object.
+const STRINGIFIED_PROPERTIES_MARKER: &str = "$properties_were_stringified";
+
+/// Some misconfigured SDKs send properties as a stringified JSON object, parse it back. Other
+/// strings are kept under the marker, for decoding to reject or empty them depending on the
+/// configuration.
+fn deserialize_properties<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, Value>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Properties {
+        Object(HashMap<String, Value>),
+        Stringified(String),
+    }
+
+    match Properties::deserialize(deserializer)? {
+        Properties::Object(properties) => Ok(properties),
+        Properties::Stringified(raw) => {
+            let mut properties = match serde_json::from_str::<HashMap<String, Value>>(&raw) {
+                Ok(properties) => properties,
+                Err(_) => HashMap::from([(
+                    STRINGIFIED_PROPERTIES_MARKER.to_string(),
+                    Value::String(raw),
+                )]),
+            };
+            properties
+                .entry(STRINGIFIED_PROPERTIES_MARKER.to_string())
+                .or_insert(Value::Bool(true));
+            Ok(properties)
+        }
+    }
 }
 
-static GZIP_MAGIC_NUMBERS: [u8; 3] = [0x1f, 0x8b, 8];
+// Clients queue events while offline, but not for years
+const MAX_EVENT_OFFSET: Duration = Duration::days(365);
+
+// Compression sniffed from the request body, gzip or none, for debugging SDKs
+const DETECTED_COMPRESSION_PROPERTY: &str = "$detected_compression";
+
+// Set on events whose `$timezone` is not an IANA zone name
+const TIMEZONE_INVALID_PROPERTY: &str = "$timezone_invalid";
+
+// Prepended by some Windows tooling, not valid at the start of a JSON document
+const BYTE_ORDER_MARK: char = '\u{feff}';
+
+// Property holding base64(gzip(json)) properties, see `inflate_properties`
+const COMPRESSED_PROPERTIES_PROPERTY: &str = "$compressed_properties";
+
+// A nested `data` payload is decoded once, but must itself hold events
+const MAX_DATA_NESTING: usize = 1;
 
 #[derive(Deserialize)]
 #[serde(untagged)]
 enum RawRequest {
     /// Batch of events
     Batch(Vec<RawEvent>),
+    /// Batch of events sharing a top-level token, must be tried before One
+    Wrapped(WrappedBatch),
+    /// Base64 encoded payload wrapped in a JSON object, must be tried before One
+    Nested(NestedData),
     /// Single event
     One(Box<RawEvent>),
+    /// Batch of `[event, properties]` tuples sent by very old SDKs, validated by `events`
+    Tuples(Vec<Vec<Value>>),
+}
+
+/// The `EventFormData` shape sent as a JSON body, `data` holding a base64(gzip(json)) payload.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NestedData {
+    data: String,
+    #[allow(dead_code)]
+    compression: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct WrappedBatch {
+    batch: Vec<RawEvent>,
+    #[serde(alias = "$token", alias = "token")]
+    api_key: Option<String>,
+    // Sent once for the whole batch to save bandwidth, merged into the properties of each event
+    #[serde(default)]
+    common_properties: HashMap<String, Value>,
 }
 
 impl RawRequest {
-    pub fn events(self) -> Vec<RawEvent> {
-        match self {
+    pub fn events(self) -> Result<Vec<RawEvent>, CaptureError> {
+        let events = match self {
             RawRequest::Batch(events) => events,
+            RawRequest::Wrapped(WrappedBatch {
+                mut batch,
+                api_key,
+                common_properties,
+            }) => {
+                // Properties of the event win, a common `token` property is used by events
+                // without a token of their own
+                for event in batch.iter_mut() {
+                    for (key, value) in &common_properties {
+                        event
+                            .properties
+                            .entry(key.clone())
+                            .or_insert_with(|| value.clone());
+                    }
+                }
+                if let Some(token) = api_key {
+                    // Events carrying their own token keep it
+                    for event in batch.iter_mut() {
+                        if event.extract_token().is_none() {
+                            event.token = Some(token.clone());
+                        }
+                    }
+                }
+                batch
+            }
+            // Decoded by RawEvent::from_bytes_with
+            RawRequest::Nested(_) => Vec::new(),
             RawRequest::One(event) => vec![*event],
-        }
+            RawRequest::Tuples(tuples) => tuples
+                .into_iter()
+                .enumerate()
+                .map(|(index, tuple)| match <[Value; 2]>::try_from(tuple) {
+                    Ok([Value::String(event), Value::Object(properties)]) => Ok(RawEvent {
+                        event,
+                        properties: properties.into_iter().collect(),
+                        ..Default::default()
+                    }),
+                    _ => Err(CaptureError::MalformedEventTuple(index)),
+                })
+                .collect::<Result<_, _>>()?,
+        };
+        Ok(events)
     }
 }
 
@@ -86,27 +266,654 @@ impl RawEvent {
     /// fail due to it being missing when the body is compressed.
     /// Instead of trusting the parameter, we peek at the payload's first three bytes to
     /// detect gzip, fallback to uncompressed utf8 otherwise.
+    pub fn from_bytes(query: &EventQuery, bytes: Bytes) -> Result<Vec<RawEvent>, CaptureError> {
+        Self::from_bytes_with(query, bytes, &ProcessingConfig::default())
+    }
+
+    /// Same as `from_bytes`, for a body delivered as several frames. The frames are copied
+    /// once into a buffer of their total size, a single frame is not copied.
+    pub fn from_chunks(
+        query: &EventQuery,
+        chunks: impl IntoIterator<Item = Bytes>,
+    ) -> Result<Vec<RawEvent>, CaptureError> {
+        let mut chunks: Vec<Bytes> = chunks.into_iter().collect();
+        let body = if chunks.len() == 1 {
+            chunks.remove(0)
+        } else {
+            let len = chunks.iter().map(Bytes::len).sum();
+            let mut body = BytesMut::with_capacity(len);
+            for chunk in chunks {
+                body.put(chunk);
+            }
+            body.freeze()
+        };
+        Self::from_bytes(query, body)
+    }
+
+    /// Same as `from_bytes`, with the decoding limits set in `config`.
+    pub fn from_bytes_with(
+        query: &EventQuery,
+        bytes: Bytes,
+        config: &ProcessingConfig,
+    ) -> Result<Vec<RawEvent>, CaptureError> {
+        Self::from_bytes_with_warnings(query, bytes, config).map(|(events, _)| events)
+    }
+
+    /// Same as `from_bytes_with`, also returning the warnings raised while decoding the body,
+    /// for callers to report them.
     #[instrument(skip_all)]
-    pub fn from_bytes(_query: &EventQuery, bytes: Bytes) -> Result<Vec<RawEvent>, CaptureError> {
+    pub fn from_bytes_with_warnings(
+        query: &EventQuery,
+        bytes: Bytes,
+        config: &ProcessingConfig,
+    ) -> Result<(Vec<RawEvent>, Vec<ParseWarning>), CaptureError> {
+        // Checked before decompression, that has its own limit
+        if bytes.len() > config.max_compressed_bytes {
+            return Err(CaptureError::RequestTooLarge);
+        }
+        let mut remaining_total = config.max_total_decompressed_bytes.unwrap_or(u64::MAX);
+        let mut warnings = Vec::new();
+        let events =
+            Self::decode_payload(query, bytes, config, 0, &mut remaining_total, &mut warnings)?;
+        Ok((events, warnings))
+    }
+
+    /// Decompress the payload with `codec`, if any, and parse it as a whole.
+    fn parse_payload(
+        bytes: Bytes,
+        codec: Option<Codec>,
+        config: &ProcessingConfig,
+        remaining_total: &mut u64,
+        warnings: &mut Vec<ParseWarning>,
+    ) -> Result<RawRequest, CaptureError> {
+        let mut payload = match codec {
+            Some(Codec::Gzip) => decompress_gzip_within(bytes, config, remaining_total)?,
+            Some(Codec::Xz) => decompress_xz_within(&bytes, config, remaining_total)?,
+            Some(Codec::Deflate | Codec::RawDeflate) => {
+                decompress_deflate_within(&bytes, config, remaining_total)?
+            }
+            None => String::from_utf8(bytes.into()).map_err(|e| {
+                tracing::error!("failed to decode body: {}", e);
+                CaptureError::InvalidTextEncoding("body")
+            })?,
+        };
+        // Some clients pad the body with whitespace, which must not hide the mark. It is only
+        // trimmed once decoded, binary bodies are detected on their first bytes
+        let mark_at = payload.len() - payload.trim_start().len();
+        if payload[mark_at..].starts_with(BYTE_ORDER_MARK) {
+            tracing::warn!("stripping byte order mark from body");
+            payload.drain(..mark_at + BYTE_ORDER_MARK.len_utf8());
+            warnings.push(ParseWarning::ByteOrderMarkStripped);
+        }
+
+        let payload = match strip_jsonp(&payload) {
+            Some(inner) => inner.to_string(),
+            None => payload,
+        };
+
+        if let Some(logged) = sampled_payload(&payload, config) {
+            tracing::debug!(json = logged, len = payload.len(), "decoded event data");
+        }
+        check_duplicate_keys(&payload, config.duplicate_json_keys)?;
+        let request = match serde_json::from_str::<RawRequest>(&payload) {
+            Ok(request) => request,
+            // Only look for lone surrogates, NaN and Infinity literals when parsing fails, to
+            // keep the happy path fast
+            Err(err) => {
+                let mut repaired = None;
+                if let Some(replaced) = replace_lone_surrogates(&payload) {
+                    if config.strict_unicode {
+                        return Err(CaptureError::InvalidUnicode);
+                    }
+                    tracing::warn!("replaced lone surrogate escapes");
+                    repaired = Some(replaced);
+                }
+                if let Some(replaced) = replace_non_finite(repaired.as_deref().unwrap_or(&payload))
+                {
+                    if config.non_finite_numbers == NonFinitePolicy::Reject {
+                        return Err(CaptureError::NonFiniteNumber);
+                    }
+                    tracing::warn!("replaced NaN or Infinity numbers with null");
+                    repaired = Some(replaced);
+                }
+                match repaired {
+                    Some(repaired) => serde_json::from_str::<RawRequest>(&repaired)?,
+                    None => return Err(err.into()),
+                }
+            }
+        };
+        Ok(request)
+    }
+
+    /// `remaining_total` is the decompressed size left to the request, shared by the body,
+    /// nested data and compressed properties.
+    fn decode_payload(
+        query: &EventQuery,
+        bytes: Bytes,
+        config: &ProcessingConfig,
+        nesting: usize,
+        remaining_total: &mut u64,
+        warnings: &mut Vec<ParseWarning>,
+    ) -> Result<Vec<RawEvent>, CaptureError> {
         tracing::debug!(len = bytes.len(), "decoding new event");
 
-        let payload = if bytes.starts_with(&GZIP_MAGIC_NUMBERS) {
-            let mut d = GzDecoder::new(bytes.reader());
-            let mut s = String::new();
-            d.read_to_string(&mut s).map_err(|e| {
-                tracing::error!("failed to decode gzip: {}", e);
-                CaptureError::RequestDecodingError(String::from("invalid gzip data"))
-            })?;
-            s
+        let gzipped = bytes.starts_with(&GZIP_MAGIC_NUMBERS);
+        // Nested data carries its own compression field
+        if nesting == 0 {
+            let declared_gzip = matches!(query.compression, Some(Compression::Gzip));
+            if gzipped != declared_gzip {
+                tracing::warn!(gzipped, "compression query param does not match the body");
+                warnings.push(ParseWarning::CompressionMismatch {
+                    detected_gzip: gzipped,
+                });
+            }
+        }
+        let bytes = match query.content_encoding.as_deref() {
+            Some(header) if nesting == 0 => {
+                let encodings = parse_content_encoding(header, config)?;
+                decode_content(bytes, &encodings, config, remaining_total)?
+            }
+            _ => bytes,
+        };
+        // Nested data carries its own compression field
+        let declared = match &query.compression {
+            Some(compression) if nesting == 0 => compression.codec(),
+            _ => None,
+        };
+        let codec = pick_codec(&bytes, declared);
+        let streamed = if config.stream_gzip_json
+            && codec == Some(Codec::Gzip)
+            && config.duplicate_json_keys == DuplicateKeyPolicy::Allow
+        {
+            let mut streamed_total = *remaining_total;
+            match parse_gzip_json_within::<RawRequest>(&bytes, config, &mut streamed_total) {
+                Ok(request) => {
+                    *remaining_total = streamed_total;
+                    Some(request)
+                }
+                // Byte order marks, JSONP, non-finite numbers and nested gzip layers are only
+                // handled by the buffered path
+                Err(CaptureError::RequestParsingError(e)) => {
+                    tracing::debug!("falling back to buffered parsing: {}", e);
+                    None
+                }
+                Err(e) => return Err(e),
+            }
         } else {
-            String::from_utf8(bytes.into()).map_err(|e| {
-                tracing::error!("failed to decode body: {}", e);
-                CaptureError::RequestDecodingError(String::from("invalid body encoding"))
-            })?
+            None
+        };
+        let request = match streamed {
+            Some(request) => request,
+            None => Self::parse_payload(bytes, codec, config, remaining_total, warnings)?,
+        };
+        if let RawRequest::Nested(nested) = request {
+            if nesting >= MAX_DATA_NESTING {
+                return Err(CaptureError::RequestDecodingError(String::from(
+                    "nested data field holds another nested data field",
+                )));
+            }
+            let payload = base64::engine::general_purpose::STANDARD
+                .decode(nested.data.trim())
+                .map_err(|e| {
+                    tracing::error!("failed to decode nested data: {}", e);
+                    CaptureError::InvalidBase64("data field")
+                })?;
+            return Self::decode_payload(
+                query,
+                payload.into(),
+                config,
+                nesting + 1,
+                remaining_total,
+                warnings,
+            );
+        }
+
+        let mut events = request.events()?;
+        for event in events.iter_mut() {
+            event.check_stringified_properties(config)?;
+            event.inflate_properties(config, remaining_total)?;
+        }
+        if config.stamp_detected_compression {
+            let compression = if gzipped { "gzip" } else { "none" };
+            for event in events.iter_mut() {
+                event.properties.insert(
+                    String::from(DETECTED_COMPRESSION_PROPERTY),
+                    Value::from(compression),
+                );
+            }
+        }
+
+        if let Some(token) = &query.api_key {
+            for event in events.iter_mut() {
+                if event.extract_token().is_none() {
+                    event.token = Some(token.clone());
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    /// Reject events whose properties were stringified as something else than an object, or
+    /// empty their properties if `lenient_stringified_properties` is set, keeping the marker.
+    fn check_stringified_properties(
+        &mut self,
+        config: &ProcessingConfig,
+    ) -> Result<(), CaptureError> {
+        let Some(Value::String(raw)) = self.properties.get(STRINGIFIED_PROPERTIES_MARKER) else {
+            return Ok(());
+        };
+        if !config.lenient_stringified_properties {
+            tracing::error!(
+                length = raw.len(),
+                "rejecting properties stringified as a non-object"
+            );
+            return Err(CaptureError::RequestParsingError(serde::de::Error::custom(
+                "properties must be a JSON object",
+            )));
+        }
+        self.properties =
+            HashMap::from([(STRINGIFIED_PROPERTIES_MARKER.to_string(), Value::Bool(true))]);
+        Ok(())
+    }
+
+    /// Some SDKs compress the properties of large events, sending them as a base64(gzip(json))
+    /// string under the `$compressed_properties` property. Decompress them into `properties`,
+    /// properties sent in the clear taking precedence.
+    fn inflate_properties(
+        &mut self,
+        config: &ProcessingConfig,
+        remaining_total: &mut u64,
+    ) -> Result<(), CaptureError> {
+        let Some(Value::String(blob)) = self.properties.remove(COMPRESSED_PROPERTIES_PROPERTY)
+        else {
+            return Ok(());
+        };
+        let compressed = base64::engine::general_purpose::STANDARD
+            .decode(blob)
+            .map_err(|e| {
+                tracing::error!("failed to decode compressed properties: {}", e);
+                CaptureError::InvalidBase64("compressed properties")
+            })?;
+        if !compressed.starts_with(&GZIP_MAGIC_NUMBERS) {
+            tracing::error!("compressed properties are not gzip compressed");
+            return Err(CaptureError::CorruptCompressedData(Codec::Gzip));
+        }
+        let payload = decompress_gzip_within(compressed.into(), config, remaining_total)?;
+        let properties: HashMap<String, Value> = serde_json::from_str(&payload).map_err(|e| {
+            tracing::error!("failed to parse compressed properties: {}", e);
+            CaptureError::RequestParsingError(e)
+        })?;
+        for (key, value) in properties {
+            self.properties.entry(key).or_insert(value);
+        }
+        Ok(())
+    }
+
+    /// Decodes a stream of JSON events, each prefixed by its length as a 4 bytes big-endian
+    /// integer, as sent by internal producers. Errors on a truncated frame.
+    pub fn from_length_prefixed(mut bytes: Bytes) -> Result<Vec<RawEvent>, CaptureError> {
+        let mut events = Vec::new();
+        while bytes.has_remaining() {
+            if bytes.remaining() < 4 {
+                return Err(CaptureError::RequestDecodingError(String::from(
+                    "truncated frame length",
+                )));
+            }
+            let len = bytes.get_u32() as usize;
+            if bytes.remaining() < len {
+                return Err(CaptureError::RequestDecodingError(String::from(
+                    "truncated frame",
+                )));
+            }
+            let frame = bytes.split_to(len);
+            events.push(serde_json::from_slice(&frame)?);
+        }
+        Ok(events)
+    }
+
+    /// Lazily decodes an uncompressed JSON body, a single event object or an array of events,
+    /// yielding events as they are parsed instead of collecting them. The iteration stops
+    /// after the first error.
+    pub fn stream_from_bytes(bytes: &[u8]) -> EventStream<'_> {
+        EventStream {
+            bytes,
+            position: 0,
+            state: StreamState::Start,
+        }
+    }
+
+    /// Same as `from_bytes`, also returning the original payload for forwarding it untouched.
+    /// `Bytes` is reference counted: the returned value shares the request buffer instead of
+    /// copying it, and keeps it allocated until dropped, including the compressed data.
+    pub fn from_bytes_preserving(
+        query: &EventQuery,
+        bytes: Bytes,
+    ) -> Result<(Vec<RawEvent>, Bytes), CaptureError> {
+        let events = Self::from_bytes(query, bytes.clone())?;
+        Ok((events, bytes))
+    }
+
+    /// Decodes a form-encoded body, as sent by the legacy `/e/` endpoint, carrying the
+    /// base64 encoded payload in its `data` field.
+    pub fn from_form_data(
+        query: &EventQuery,
+        body: Bytes,
+        config: &ProcessingConfig,
+    ) -> Result<Vec<RawEvent>, CaptureError> {
+        let input: EventFormData = serde_urlencoded::from_bytes(&body).map_err(|e| {
+            tracing::error!("failed to decode form data: {}", e);
+            CaptureError::RequestDecodingError(String::from("missing data field"))
+        })?;
+        let payload = base64::engine::general_purpose::STANDARD
+            .decode(input.data.trim())
+            .map_err(|e| {
+                tracing::error!("failed to decode form data: {}", e);
+                CaptureError::InvalidBase64("data field")
+            })?;
+        Self::from_bytes_with(query, payload.into(), config)
+    }
+
+    /// Some SDKs send person property updates as `$set` and `$set_once` objects in the
+    /// properties. Move them to the top-level fields, keys already set there taking
+    /// precedence. Returns whether updates were moved, other values are left in place.
+    pub fn promote_nested_person_updates(&mut self) -> bool {
+        let mut promoted = false;
+        for (key, target) in [("$set", &mut self.set), ("$set_once", &mut self.set_once)] {
+            let Some(Value::Object(nested)) = self.properties.get(key) else {
+                continue;
+            };
+            let updates = target.get_or_insert_with(HashMap::new);
+            for (name, value) in nested {
+                updates.entry(name.clone()).or_insert_with(|| value.clone());
+            }
+            self.properties.remove(key);
+            promoted = true;
+        }
+        promoted
+    }
+
+    /// Person properties such as `initial_referrer` must only be set once: move the `keys`
+    /// updates of `$set` to `$set_once`, values already in `$set_once` taking precedence. Both
+    /// the top-level fields and the objects nested in the properties are handled. Returns
+    /// whether updates were moved.
+    pub fn move_to_set_once(&mut self, keys: &HashSet<String>) -> bool {
+        let mut moved = false;
+        if let Some(set) = self.set.as_mut() {
+            for key in keys {
+                if let Some(value) = set.remove(key) {
+                    let set_once = self.set_once.get_or_insert_with(HashMap::new);
+                    set_once.entry(key.clone()).or_insert(value);
+                    moved = true;
+                }
+            }
+        }
+
+        // Nested updates are left alone if `$set_once` is not an object
+        if !matches!(
+            self.properties.get("$set_once"),
+            None | Some(Value::Object(_))
+        ) {
+            return moved;
+        }
+        let Some(Value::Object(set)) = self.properties.get_mut("$set") else {
+            return moved;
+        };
+        let updates: Vec<(String, Value)> = keys
+            .iter()
+            .filter_map(|key| Some((key.clone(), set.remove(key)?)))
+            .collect();
+        if updates.is_empty() {
+            return moved;
+        }
+        if let Value::Object(set_once) = self
+            .properties
+            .entry(String::from("$set_once"))
+            .or_insert_with(|| Value::Object(Map::new()))
+        {
+            for (key, value) in updates {
+                set_once.entry(key).or_insert(value);
+            }
+        }
+        true
+    }
+
+    /// Some clients send the event time as a property, such as epoch milliseconds in `time`,
+    /// instead of the top-level timestamp. Without a timestamp, move the first of the `keys`
+    /// holding a parseable time to it as RFC3339, keys being tried in lexicographic order.
+    /// Returns the promoted key, unparseable values are left in place.
+    pub fn promote_time_property(
+        &mut self,
+        keys: &HashSet<String>,
+        formats: &[OwnedFormatItem],
+    ) -> Option<String> {
+        if self.timestamp.is_some() {
+            return None;
+        }
+        let mut keys: Vec<&String> = keys.iter().collect();
+        keys.sort();
+        for key in keys {
+            let value = match self.properties.get(key) {
+                Some(Value::String(value)) => value.clone(),
+                Some(Value::Number(value)) => value.to_string(),
+                _ => continue,
+            };
+            let Some(timestamp) = parse_event_timestamp(&value, formats)
+                .and_then(|timestamp| timestamp.format(&Rfc3339).ok())
+            else {
+                continue;
+            };
+            self.timestamp = Some(timestamp);
+            self.properties.remove(key);
+            return Some(key.clone());
+        }
+        None
+    }
+
+    /// Older SDKs piggyback person property updates on regular events through `$set` and
+    /// `$set_once`. Split these into the bare event, followed by a synthetic `$set` event
+    /// carrying the updates. `$identify` and `$set` events are returned untouched.
+    pub fn split_person_properties(mut self) -> Vec<RawEvent> {
+        if self.event == "$identify" || self.event == "$set" {
+            return vec![self];
+        }
+        if self.set.is_none() && self.set_once.is_none() {
+            return vec![self];
+        }
+
+        // Carry over the properties used for token and distinct_id resolution
+        let properties = ["token", "distinct_id"]
+            .into_iter()
+            .filter_map(|key| Some((key.to_string(), self.properties.get(key)?.clone())))
+            .collect();
+        let person_event = RawEvent {
+            token: self.token.clone(),
+            distinct_id: self.distinct_id.clone(),
+            uuid: None,
+            event: String::from("$set"),
+            properties,
+            timestamp: self.timestamp.clone(),
+            offset: self.offset.clone(),
+            set: self.set.take(),
+            set_once: self.set_once.take(),
+            original_index: self.original_index,
+        };
+
+        vec![self, person_event]
+    }
+
+    /// Clients set `$process_person_profile` to false on events that must not update person
+    /// profiles. Defaults to true when absent or not a boolean.
+    pub fn process_person_profile(&self) -> bool {
+        self.properties
+            .get("$process_person_profile")
+            .and_then(coerce_bool)
+            .unwrap_or(true)
+    }
+
+    /// Whether the event must go through person processing. That is, unless
+    /// `$process_person_profile` is false:
+    /// - `$identify`, `$set`, `$create_alias` and `$merge_dangerously` events
+    /// - events carrying `$set` or `$set_once` updates, top-level or in their properties
+    pub fn affects_person(&self) -> bool {
+        if !self.process_person_profile() {
+            return false;
+        }
+        matches!(
+            self.event.as_str(),
+            "$identify" | "$set" | "$create_alias" | "$merge_dangerously"
+        ) || self.set.is_some()
+            || self.set_once.is_some()
+            || ["$set", "$set_once"]
+                .iter()
+                .any(|key| self.properties.contains_key(*key))
+    }
+
+    /// QA traffic is marked with a boolean `marker` property, see `test_event_property`.
+    /// Defaults to false when absent or not a boolean.
+    pub fn is_test(&self, marker: &str) -> bool {
+        self.properties
+            .get(marker)
+            .and_then(coerce_bool)
+            .unwrap_or(false)
+    }
+
+    /// Events with `$process_person_profile` set to false don't update person profiles, remove
+    /// the `$set` and `$set_once` updates they carry. Returns whether updates were removed.
+    pub fn strip_ignored_person_updates(&mut self) -> bool {
+        if self.process_person_profile() {
+            return false;
+        }
+        let mut stripped = self.set.take().is_some() | self.set_once.take().is_some();
+        for key in ["$set", "$set_once"] {
+            stripped |= self.properties.remove(key).is_some();
+        }
+        stripped
+    }
+
+    /// Remove the `$session_id` property unless it is a non-empty string, and a uuid when
+    /// `strict` is set. Returns whether it was removed.
+    pub fn strip_invalid_session_id(&mut self, strict: bool) -> bool {
+        let valid = match self.properties.get("$session_id") {
+            None => return false,
+            Some(Value::String(id)) if strict => Uuid::parse_str(id).is_ok(),
+            Some(Value::String(id)) => !id.is_empty(),
+            Some(_) => false,
+        };
+        if !valid {
+            let session_id = self.properties.remove("$session_id");
+            tracing::warn!(?session_id, "stripping malformed $session_id");
+        }
+        !valid
+    }
+
+    /// Returns the `$timezone` property if it is an IANA zone name, such as `Europe/Paris`.
+    /// Other values are kept, flagged with `$timezone_invalid`.
+    pub fn validate_timezone(&mut self) -> Option<String> {
+        let valid = match self.properties.get("$timezone") {
+            None => return None,
+            Some(Value::String(zone)) if is_iana_zone_name(zone) => Some(zone.clone()),
+            Some(_) => None,
+        };
+        if valid.is_none() {
+            self.properties
+                .insert(String::from(TIMEZONE_INVALID_PROPERTY), Value::Bool(true));
+        }
+        valid
+    }
+
+    /// Serialized size in bytes of each top-level property value, largest first. Helps finding
+    /// the properties bloating an event.
+    pub fn property_sizes(&self) -> Vec<(String, usize)> {
+        let mut sizes: Vec<(String, usize)> = self
+            .properties
+            .iter()
+            .map(|(key, value)| (key.clone(), value.to_string().len()))
+            .collect();
+        sizes.sort_unstable_by(|(ka, a), (kb, b)| b.cmp(a).then_with(|| ka.cmp(kb)));
+        sizes
+    }
+
+    /// The `offset` field is the number of milliseconds between the event and `sent_at`,
+    /// used by ingestion to correct client clock skew. Validate it and return it as a Duration.
+    pub fn offset_duration(&self) -> Result<Option<Duration>, CaptureError> {
+        let offset = match &self.offset {
+            None => return Ok(None),
+            Some(EventOffset::Millis(millis)) => Duration::milliseconds(*millis),
+            Some(EventOffset::Iso(value)) => {
+                parse_iso_duration(value).ok_or(CaptureError::InvalidOffset)?
+            }
         };
+        if offset.is_negative() || offset > MAX_EVENT_OFFSET {
+            return Err(CaptureError::InvalidOffset);
+        }
+        Ok(Some(offset))
+    }
+
+    /// Stable hash of the semantically meaningful parts of the event, used for content-based
+    /// dedup. Computed with FNV-1a, so it does not change across processes or releases.
+    pub fn fingerprint(&self) -> u64 {
+        // serde_json maps are sorted by key, making the serialization canonical
+        let properties: serde_json::Map<String, Value> = self
+            .properties
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        let canonical =
+            serde_json::to_vec(&(&self.event, &self.distinct_id, properties, &self.timestamp))
+                .expect("serializing json values cannot fail");
+
+        fnv1a(&canonical)
+    }
+
+    /// Resolve the values shared by a batch on the event itself: missing timestamp, token and
+    /// `$lib_version` property are filled from the context. Calling it again is a no-op.
+    pub fn apply_context(&mut self, ctx: &ProcessingContext) {
+        if self.timestamp.is_none() {
+            self.timestamp = match ctx
+                .event_time
+                .or(ctx.sent_at)
+                .map(|sent_at| sent_at.format(&Rfc3339))
+            {
+                Some(Ok(sent_at)) => Some(sent_at),
+                _ => Some(ctx.now.clone()),
+            };
+        }
+        if let Some(lib_version) = &ctx.lib_version {
+            self.properties
+                .entry(String::from("$lib_version"))
+                .or_insert_with(|| Value::String(lib_version.clone()));
+        }
+        if self.extract_token().is_none() {
+            self.token = Some(ctx.token.clone());
+        }
+    }
+
+    /// Resolve the uuid from the top-level field, falling back to the `$insert_id` then `uuid`
+    /// properties used by some SDKs. Invalid property values are skipped.
+    pub fn extract_uuid(&self) -> Option<Uuid> {
+        if self.uuid.is_some() {
+            return self.uuid;
+        }
+        ["$insert_id", "uuid"].iter().find_map(|key| {
+            let uuid = self.properties.get(*key)?.as_str()?.parse().ok()?;
+            tracing::debug!(%uuid, key, "using uuid found in properties");
+            Some(uuid)
+        })
+    }
 
-        tracing::debug!(json = payload, "decoded event data");
-        Ok(serde_json::from_str::<RawRequest>(&payload)?.events())
+    /// Resolve the distinct_id from the top-level field, falling back to the `distinct_id` then
+    /// `$user_id` properties. Numeric ids are converted to strings.
+    pub fn extract_distinct_id(&self) -> Option<String> {
+        if let Some(distinct_id) = &self.distinct_id {
+            return Some(distinct_id.clone());
+        }
+        ["distinct_id", "$user_id"]
+            .iter()
+            .find_map(|key| match self.properties.get(*key) {
+                Some(Value::String(id)) => Some(id.clone()),
+                Some(Value::Number(id)) => Some(id.to_string()),
+                _ => None,
+            })
     }
 
     pub fn extract_token(&self) -> Option<String> {
@@ -121,16 +928,285 @@ impl RawEvent {
     }
 }
 
-#[derive(Debug)]
+/// Keys found several times in a JSON object, at any depth of the document.
+struct DuplicateKeys(Vec<String>);
+
+impl<'de> Deserialize<'de> for DuplicateKeys {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(DuplicateKeysVisitor)
+    }
+}
+
+struct DuplicateKeysVisitor;
+
+impl<'de> Visitor<'de> for DuplicateKeysVisitor {
+    type Value = DuplicateKeys;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("any JSON value")
+    }
+
+    fn visit_bool<E>(self, _: bool) -> Result<Self::Value, E> {
+        Ok(DuplicateKeys(vec![]))
+    }
+
+    fn visit_i64<E>(self, _: i64) -> Result<Self::Value, E> {
+        Ok(DuplicateKeys(vec![]))
+    }
+
+    fn visit_u64<E>(self, _: u64) -> Result<Self::Value, E> {
+        Ok(DuplicateKeys(vec![]))
+    }
+
+    fn visit_f64<E>(self, _: f64) -> Result<Self::Value, E> {
+        Ok(DuplicateKeys(vec![]))
+    }
+
+    fn visit_str<E>(self, _: &str) -> Result<Self::Value, E> {
+        Ok(DuplicateKeys(vec![]))
+    }
+
+    fn visit_unit<E>(self) -> Result<Self::Value, E> {
+        Ok(DuplicateKeys(vec![]))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut found = vec![];
+        while let Some(DuplicateKeys(keys)) = seq.next_element()? {
+            found.extend(keys);
+        }
+        Ok(DuplicateKeys(found))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut seen = HashSet::new();
+        let mut found = vec![];
+        while let Some(key) = map.next_key::<String>()? {
+            let DuplicateKeys(keys) = map.next_value()?;
+            found.extend(keys);
+            if seen.contains(&key) {
+                found.push(key);
+            } else {
+                seen.insert(key);
+            }
+        }
+        Ok(DuplicateKeys(found))
+    }
+}
+
+/// Detect keys duplicated within any object of the payload (event objects, their properties
+/// and nested values), which serde_json would otherwise resolve by keeping the last value.
+fn check_duplicate_keys(payload: &str, policy: DuplicateKeyPolicy) -> Result<(), CaptureError> {
+    if policy == DuplicateKeyPolicy::Allow {
+        return Ok(());
+    }
+    // Invalid JSON is reported by the actual parsing
+    let Ok(DuplicateKeys(keys)) = serde_json::from_str(payload) else {
+        return Ok(());
+    };
+
+    match (keys.first(), policy) {
+        (None, _) => Ok(()),
+        (Some(key), DuplicateKeyPolicy::Reject) => Err(CaptureError::DuplicateJsonKey(key.clone())),
+        (Some(_), _) => {
+            tracing::warn!(keys = ?keys, "duplicate keys in request payload");
+            Ok(())
+        }
+    }
+}
+
+// Longer callback names are not generated by JSONP clients
+const MAX_JSONP_CALLBACK_LENGTH: usize = 128;
+
+/// Legacy pixel clients wrap payloads in a JSONP callback, `callback({...})`. Returns the
+/// wrapped JSON, or None if the payload is not wrapped. The callback must be a dotted path of
+/// JavaScript identifiers, such as `jQuery123_456` or `posthog.cb`.
+fn strip_jsonp(payload: &str) -> Option<&str> {
+    let payload = payload.trim();
+    if payload.starts_with(['{', '[']) {
+        return None;
+    }
+    let payload = payload.strip_suffix(';').unwrap_or(payload).trim_end();
+    let (callback, rest) = payload.split_once('(')?;
+    let inner = rest.strip_suffix(')')?;
+
+    let callback = callback.trim();
+    let is_identifier = |name: &str| {
+        name.chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '$')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+    };
+    if callback.len() > MAX_JSONP_CALLBACK_LENGTH || !callback.split('.').all(is_identifier) {
+        return None;
+    }
+    Some(inner)
+}
+
+/// Trace and parent span ids of a W3C `traceparent` header, `00-<trace_id>-<span_id>-<flags>`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TraceParent {
+    pub trace_id: String,
+    pub span_id: String,
+}
+
+impl TraceParent {
+    /// Returns None if the header is malformed, or holds the invalid all-zero ids.
+    pub fn parse(header: &str) -> Option<Self> {
+        let is_id = |id: &str, len: usize| {
+            id.len() == len
+                && id
+                    .bytes()
+                    .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+                && id.bytes().any(|b| b != b'0')
+        };
+        let mut parts = header.trim().split('-');
+        let (version, trace_id, span_id, flags) =
+            (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        // Version 00 has exactly four fields, later versions may append more
+        let valid = version.len() == 2
+            && version != "ff"
+            && version.bytes().all(|b| b.is_ascii_hexdigit())
+            && (version != "00" || parts.next().is_none())
+            && is_id(trace_id, 32)
+            && is_id(span_id, 16)
+            && flags.len() == 2
+            && flags.bytes().all(|b| b.is_ascii_hexdigit());
+        valid.then(|| Self {
+            trace_id: trace_id.to_string(),
+            span_id: span_id.to_string(),
+        })
+    }
+}
+
+/// Payloads hold personal data: only a `payload_log_sample_rate` share of them is logged, cut
+/// to `payload_log_max_bytes` on a char boundary. Returns the part to log, if any.
+fn sampled_payload<'a>(payload: &'a str, config: &ProcessingConfig) -> Option<&'a str> {
+    if config.payload_log_sample_rate <= 0.0
+        || rand::thread_rng().gen::<f64>() >= config.payload_log_sample_rate
+    {
+        return None;
+    }
+    let mut end = payload.len().min(config.payload_log_max_bytes);
+    while !payload.is_char_boundary(end) {
+        end -= 1;
+    }
+    Some(&payload[..end])
+}
+
+/// Iterator returned by `RawEvent::stream_from_bytes`.
+pub struct EventStream<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    state: StreamState,
+}
+
+enum StreamState {
+    Start,
+    Array { first: bool },
+    Done,
+}
+
+impl EventStream<'_> {
+    fn invalid(reason: &str) -> CaptureError {
+        tracing::error!("failed to stream body: {}", reason);
+        CaptureError::RequestDecodingError(String::from(reason))
+    }
+
+    /// Returns the next non-whitespace byte, without consuming it.
+    fn peek(&mut self) -> Option<u8> {
+        while let Some(byte) = self.bytes.get(self.position) {
+            if !byte.is_ascii_whitespace() {
+                return Some(*byte);
+            }
+            self.position += 1;
+        }
+        None
+    }
+
+    fn parse_event(&mut self) -> Result<RawEvent, CaptureError> {
+        let mut events =
+            serde_json::Deserializer::from_slice(&self.bytes[self.position..]).into_iter();
+        let event = events
+            .next()
+            .ok_or_else(|| Self::invalid("unexpected end of body"))??;
+        self.position += events.byte_offset();
+        Ok(event)
+    }
+
+    fn expect_end(&mut self) -> Result<(), CaptureError> {
+        self.state = StreamState::Done;
+        match self.peek() {
+            None => Ok(()),
+            Some(_) => Err(Self::invalid("trailing data after the events")),
+        }
+    }
+
+    fn advance(&mut self) -> Result<Option<RawEvent>, CaptureError> {
+        match self.state {
+            StreamState::Done => Ok(None),
+            StreamState::Start => match self.peek() {
+                Some(b'{') => {
+                    let event = self.parse_event()?;
+                    self.expect_end()?;
+                    Ok(Some(event))
+                }
+                Some(b'[') => {
+                    self.position += 1;
+                    self.state = StreamState::Array { first: true };
+                    self.advance()
+                }
+                _ => Err(Self::invalid("body is not an event object or array")),
+            },
+            StreamState::Array { first } => {
+                match self.peek() {
+                    Some(b']') => {
+                        self.position += 1;
+                        return self.expect_end().map(|_| None);
+                    }
+                    Some(b',') if !first => self.position += 1,
+                    _ if !first => return Err(Self::invalid("expected , or ] after an event")),
+                    _ => {}
+                }
+                self.state = StreamState::Array { first: false };
+                self.parse_event().map(Some)
+            }
+        }
+    }
+}
+
+impl Iterator for EventStream<'_> {
+    type Item = Result<RawEvent, CaptureError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.advance();
+        if next.is_err() {
+            self.state = StreamState::Done;
+        }
+        next.transpose()
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct ProcessingContext {
     pub lib_version: Option<String>,
     pub sent_at: Option<OffsetDateTime>,
+    pub event_time: Option<OffsetDateTime>, // Timestamp override of the request
+    pub trace_id: Option<String>,           // Defaults to the traceparent, else the tracing span
+    pub traceparent: Option<String>,        // W3C trace context header of the request
+    pub user_agent: Option<String>,
+    pub origin: Option<String>, // Origin header of browser requests
+    pub path: Option<String>,   // Path the request was sent to
+    pub ingest_region: Option<String>,
     pub token: String,
     pub now: String,
+    pub clock: Arc<dyn TimeSource + Send + Sync>, // Clock of now, read for the ingest_ts_nanos of each event
     pub client_ip: String,
 }
 
-#[derive(Clone, Default, Debug, Serialize, Eq, PartialEq)]
+#[derive(Clone, Debug, Serialize, Eq, PartialEq)]
 pub struct ProcessedEvent {
     pub uuid: Uuid,
     pub distinct_id: String,
@@ -140,39 +1216,1976 @@ pub struct ProcessedEvent {
     #[serde(with = "time::serde::rfc3339::option")]
     pub sent_at: Option<OffsetDateTime>,
     pub token: String,
+    #[serde(skip_serializing_if = "is_true")]
+    pub process_person_profile: bool,
+    // Used for partitioning, already part of data
+    #[serde(skip)]
+    pub event: String,
+    #[serde(skip)]
+    pub session_id: Option<String>,
+    // Validated IANA zone of the client, for local time computations, already part of data
+    #[serde(skip)]
+    pub timezone: Option<String>,
+    // Per-key ingestion order, 0 when not stamped by a SequenceAllocator
+    #[serde(skip_serializing_if = "is_zero")]
+    pub seq: u64,
+    // Trace id of the request's traceparent, else the id of the tracing span the event was
+    // processed in, which is only unique within this process, for end-to-end correlation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+    // QA traffic, for sinks to route to a sandbox
+    #[serde(skip_serializing_if = "is_false")]
+    pub is_test: bool,
+    // Region or node of the capture instance that handled the event
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ingest_region: Option<String>,
+    // Time spent processing the event, only recorded when enabled for investigations
+    #[serde(skip)]
+    pub processing_duration: Option<std::time::Duration>,
+    // Nanoseconds since the epoch at ingestion, for precise ordering independent of now
+    #[serde(skip_serializing_if = "is_zero_nanos")]
+    pub ingest_ts_nanos: i128,
+    // Position of the event within its request, for reconciliation against client acks
+    pub original_index: u32,
+    // Time after which the event is to be deleted downstream, see `event_ttls`
+    #[serde(
+        with = "time::serde::rfc3339::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub expires_at: Option<OffsetDateTime>,
 }
 
-impl ProcessedEvent {
-    pub fn key(&self) -> String {
-        format!("{}:{}", self.token, self.distinct_id)
-    }
+fn is_true(value: &bool) -> bool {
+    *value
 }
 
-#[cfg(test)]
-mod tests {
-    use super::Compression;
-    use base64::Engine as _;
-    use bytes::Bytes;
+fn is_false(value: &bool) -> bool {
+    !*value
+}
 
-    use super::{EventQuery, RawEvent};
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
 
-    #[test]
-    fn decode_bytes() {
-        let horrible_blob = "H4sIAAAAAAAAA31T207cMBD9lSrikSy+5bIrVX2g4oWWUlEqBEKRY08Sg4mD4+xCEf/e8XLZBSGeEp+ZOWOfmXPxkMAS+pAskp1BtmBBLiHZTQbvBvDBwJgsHpIdh5/kp1Rffp18OcMwAtUS/GhcjwFKZjSbkYjX3q1G8AgeGA+Nu4ughqVRUIX7ATDwHcbr4IYYUJP32LyavMVAF8Kw2NuzTknbuTEsSkIIHlvTf+vhLnzdizUxgslvs2JgkKHr5U1s8VS0dZ/NZSnlW7CVfTvhs7EG+vT0JJaMygP0VQem7bDTvBAbcGV06JAkIwTBpYHV4Hx4zS1FJH+FX7IFj7A1NbZZQR2b4GFbwFlWzFjETY/XCpXRiN538yt/S9mdnm7bSa+lDCY+kOalKDJGs/msZMVuos0YTK+e62hZciHqes7LnDcpoVmTg+TAaqnKMhWUaaa4TllBoCDpJn2uYK3k87xeyFjZFHWdzxmdq5Q0IstBzRXlDMiHbM/5kgnerKfs+tFZqHAolQflvDZ9W0Evawu6wveiENVoND4s+Ami2jBGZbayn/42g3xblizX4skp4FYMYfJQoSQf8DfSjrGBVMEsoWpArpMbK1vc8ItLDG1j1SDvrZM6muBxN/Eg7U1cVFw70KmyRl13bhqjYeBGGrtuFqWTSzzF/q8tRyvV9SfxHXQLoBuidXY0ekeF+KQnNCqgHXaIy7KJBncNERk6VUFhhB33j8zv5uhQ/rCTvbq9/9seH5Pj3Bf/TsuzYf9g2j+3h9N6yZ8Vfpmx4KSguSY5S0lOqc5LmgmhidoMmOaixoFvktFKOo9kK9Nrt3rPxViWk5RwIhtJykZzXohP2DjmZ08+bnH/4B1fkUnGSp2SMmNlIYTguS5ga//eERZZTSVeD8cWPTMGeTMgHSOMpyRLGftDyUKwBV9b6Dx5vPwPzQHjFwsFAAA=";
-        let decoded_horrible_blob = base64::engine::general_purpose::STANDARD
-            .decode(horrible_blob)
-            .unwrap();
+fn is_zero_nanos(value: &i128) -> bool {
+    *value == 0
+}
 
-        let bytes = Bytes::from(decoded_horrible_blob);
-        let events = RawEvent::from_bytes(
-            &EventQuery {
-                compression: Some(Compression::Gzip),
-                lib_version: None,
-                sent_at: None,
-            },
+/// Binary representation of a ProcessedEvent. bincode is not self-describing: unlike the JSON
+/// one, it must hold every field and never skip any.
+#[cfg(feature = "bincode")]
+#[derive(Serialize, Deserialize)]
+struct BinaryEvent {
+    uuid: Uuid,
+    distinct_id: String,
+    ip: String,
+    data: String,
+    now: String,
+    // Encoded by the time crate as a compact tuple of its components, offset included
+    sent_at: Option<OffsetDateTime>,
+    token: String,
+    process_person_profile: bool,
+    event: String,
+    session_id: Option<String>,
+    timezone: Option<String>,
+    seq: u64,
+    trace_id: Option<String>,
+    is_test: bool,
+    ingest_region: Option<String>,
+    processing_duration: Option<std::time::Duration>,
+    ingest_ts_nanos: i128,
+    original_index: u32,
+    expires_at: Option<OffsetDateTime>,
+}
+
+impl Default for ProcessedEvent {
+    fn default() -> Self {
+        Self {
+            uuid: Uuid::default(),
+            distinct_id: String::default(),
+            ip: String::default(),
+            data: String::default(),
+            now: String::default(),
+            sent_at: None,
+            token: String::default(),
+            process_person_profile: true,
+            event: String::default(),
+            session_id: None,
+            timezone: None,
+            seq: 0,
+            trace_id: None,
+            is_test: false,
+            ingest_region: None,
+            processing_duration: None,
+            ingest_ts_nanos: 0,
+            original_index: 0,
+            expires_at: None,
+        }
+    }
+}
+
+#[cfg(feature = "bincode")]
+impl ProcessedEvent {
+    /// Compact binary encoding, faster to produce and parse than JSON.
+    pub fn to_bincode(&self) -> Result<Vec<u8>, bincode::Error> {
+        let ProcessedEvent {
+            uuid,
+            distinct_id,
+            ip,
+            data,
+            now,
+            sent_at,
+            token,
+            process_person_profile,
+            event,
+            session_id,
+            timezone,
+            seq,
+            trace_id,
+            is_test,
+            ingest_region,
+            processing_duration,
+            ingest_ts_nanos,
+            original_index,
+            expires_at,
+        } = self.clone();
+        bincode::serialize(&BinaryEvent {
+            uuid,
+            distinct_id,
+            ip,
+            data,
+            now,
+            sent_at,
+            token,
+            process_person_profile,
+            event,
+            session_id,
+            timezone,
+            seq,
+            trace_id,
+            is_test,
+            ingest_region,
+            processing_duration,
+            ingest_ts_nanos,
+            original_index,
+            expires_at,
+        })
+    }
+
+    pub fn from_bincode(bytes: &[u8]) -> Result<Self, bincode::Error> {
+        let BinaryEvent {
+            uuid,
+            distinct_id,
+            ip,
+            data,
+            now,
+            sent_at,
+            token,
+            process_person_profile,
+            event,
+            session_id,
+            timezone,
+            seq,
+            trace_id,
+            is_test,
+            ingest_region,
+            processing_duration,
+            ingest_ts_nanos,
+            original_index,
+            expires_at,
+        } = bincode::deserialize(bytes)?;
+        Ok(ProcessedEvent {
+            uuid,
+            distinct_id,
+            ip,
+            data,
+            now,
+            sent_at,
+            token,
+            process_person_profile,
+            event,
+            session_id,
+            timezone,
+            seq,
+            trace_id,
+            is_test,
+            ingest_region,
+            processing_duration,
+            ingest_ts_nanos,
+            original_index,
+            expires_at,
+        })
+    }
+}
+
+impl ProcessedEvent {
+    /// Parse the serialized `data` field. This parses the whole string on every call, use
+    /// `lazy_data` to read it several times.
+    pub fn data_as_value(&self) -> Result<Value, serde_json::Error> {
+        serde_json::from_str(&self.data)
+    }
+
+    /// Borrowing view over `data` that parses it on first access only.
+    pub fn lazy_data(&self) -> LazyData<'_> {
+        LazyData {
+            event: self,
+            value: OnceLock::new(),
+        }
+    }
+
+    /// Compare the content of two events, ignoring the fields that differ between two
+    /// captures of the same event: `uuid` (also in `data`), `now`, `ip`, `sent_at` and the
+    /// stamps of the capture instance. `data` is compared as JSON, not byte for byte.
+    pub fn content_eq(&self, other: &ProcessedEvent) -> bool {
+        let content = |event: &ProcessedEvent| {
+            event.data_as_value().map(|mut data| {
+                if let Some(data) = data.as_object_mut() {
+                    data.remove("uuid");
+                }
+                data
+            })
+        };
+        let same_data = match (content(self), content(other)) {
+            (Ok(data), Ok(other_data)) => data == other_data,
+            _ => self.data == other.data,
+        };
+        same_data
+            && self.token == other.token
+            && self.distinct_id == other.distinct_id
+            && self.event == other.event
+    }
+
+    pub fn key(&self) -> String {
+        format!("{}:{}", self.token, self.distinct_id)
+    }
+
+    /// Same as `key()`, but tokens present in `overrides` are keyed on `token:salt` instead
+    /// of `token:distinct_id`, pinning all their events to a single partition.
+    ///
+    /// This changes ordering guarantees for overridden tokens: events are totally ordered
+    /// across the whole token instead of per distinct_id, but they all share one partition
+    /// and one overflow bucket in the PartitionLimiter. Once that bucket is exhausted, the
+    /// events of the whole token are spread randomly and lose ordering altogether.
+    ///
+    /// Other events are keyed following the strategy of their name in `strategies`, falling
+    /// back to `default`. Events keyed on their token alone share the ordering and overflow
+    /// caveats of overridden tokens, and are not ordered with the other events of their
+    /// distinct_id, which land on different partitions.
+    ///
+    /// `$snapshot` events are keyed on `token:session_id` when they carry a session id and
+    /// have no strategy of their own, to keep the snapshots of a recording ordered without
+    /// adding to the distinct_id's key.
+    pub fn key_with(
+        &self,
+        overrides: &HashMap<String, String>,
+        strategies: &EventPartitionStrategies,
+        default: PartitionStrategy,
+    ) -> String {
+        if let Some(salt) = overrides.get(&self.token) {
+            return format!("{}:{}", self.token, salt);
+        }
+        let strategy = match strategies.0.get(&self.event) {
+            Some(strategy) => *strategy,
+            None => match (self.event.as_str(), &self.session_id) {
+                ("$snapshot", Some(session_id)) => return format!("{}:{}", self.token, session_id),
+                _ => default,
+            },
+        };
+        match strategy {
+            PartitionStrategy::Token => self.token.clone(),
+            PartitionStrategy::DistinctId => self.key(),
+        }
+    }
+}
+
+pub struct LazyData<'a> {
+    event: &'a ProcessedEvent,
+    value: OnceLock<Value>,
+}
+
+impl LazyData<'_> {
+    pub fn get(&self) -> Result<&Value, serde_json::Error> {
+        if let Some(value) = self.value.get() {
+            return Ok(value);
+        }
+        let value = self.event.data_as_value()?;
+        Ok(self.value.get_or_init(|| value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Compression;
+    use crate::api::CaptureError;
+    use crate::config::{
+        CodecPreference, DuplicateKeyPolicy, EventPartitionStrategies, EventPropertyDefaults,
+        NonFinitePolicy, PartitionKeyOverrides, PartitionStrategy, ProcessingConfig,
+    };
+    use crate::decompression::Codec;
+    use base64::Engine as _;
+    use bytes::Bytes;
+    use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+    use flate2::Compression as GzCompression;
+    use serde_json::{json, Value};
+    use std::collections::{HashMap, HashSet};
+    use std::io::Write;
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    use time::macros::datetime;
+    use uuid::Uuid;
+
+    use crate::time::SystemTime;
+    use crate::utils::uuid_v7;
+
+    use super::{
+        sampled_payload, EventOffset, EventQuery, ParseWarning, ProcessedEvent, ProcessingContext,
+        RawEvent, TraceParent,
+    };
+
+    const HORRIBLE_BLOB: &str = "H4sIAAAAAAAAA31T207cMBD9lSrikSy+5bIrVX2g4oWWUlEqBEKRY08Sg4mD4+xCEf/e8XLZBSGeEp+ZOWOfmXPxkMAS+pAskp1BtmBBLiHZTQbvBvDBwJgsHpIdh5/kp1Rffp18OcMwAtUS/GhcjwFKZjSbkYjX3q1G8AgeGA+Nu4ughqVRUIX7ATDwHcbr4IYYUJP32LyavMVAF8Kw2NuzTknbuTEsSkIIHlvTf+vhLnzdizUxgslvs2JgkKHr5U1s8VS0dZ/NZSnlW7CVfTvhs7EG+vT0JJaMygP0VQem7bDTvBAbcGV06JAkIwTBpYHV4Hx4zS1FJH+FX7IFj7A1NbZZQR2b4GFbwFlWzFjETY/XCpXRiN538yt/S9mdnm7bSa+lDCY+kOalKDJGs/msZMVuos0YTK+e62hZciHqes7LnDcpoVmTg+TAaqnKMhWUaaa4TllBoCDpJn2uYK3k87xeyFjZFHWdzxmdq5Q0IstBzRXlDMiHbM/5kgnerKfs+tFZqHAolQflvDZ9W0Evawu6wveiENVoND4s+Ami2jBGZbayn/42g3xblizX4skp4FYMYfJQoSQf8DfSjrGBVMEsoWpArpMbK1vc8ItLDG1j1SDvrZM6muBxN/Eg7U1cVFw70KmyRl13bhqjYeBGGrtuFqWTSzzF/q8tRyvV9SfxHXQLoBuidXY0ekeF+KQnNCqgHXaIy7KJBncNERk6VUFhhB33j8zv5uhQ/rCTvbq9/9seH5Pj3Bf/TsuzYf9g2j+3h9N6yZ8Vfpmx4KSguSY5S0lOqc5LmgmhidoMmOaixoFvktFKOo9kK9Nrt3rPxViWk5RwIhtJykZzXohP2DjmZ08+bnH/4B1fkUnGSp2SMmNlIYTguS5ga//eERZZTSVeD8cWPTMGeTMgHSOMpyRLGftDyUKwBV9b6Dx5vPwPzQHjFwsFAAA=";
+
+    #[test]
+    fn decode_bytes() {
+        let decoded_horrible_blob = base64::engine::general_purpose::STANDARD
+            .decode(HORRIBLE_BLOB)
+            .unwrap();
+
+        let bytes = Bytes::from(decoded_horrible_blob);
+        let events = RawEvent::from_bytes(
+            &EventQuery {
+                compression: Some(Compression::Gzip),
+                lib_version: None,
+                sent_at: None,
+                api_key: None,
+                content_encoding: None,
+            },
             bytes,
         );
 
         assert!(events.is_ok());
     }
+
+    #[test]
+    #[cfg(feature = "xz")]
+    fn decode_xz_bytes() {
+        // `[{"event":"pageview","distinct_id":"user1"},{"event":"pageview","distinct_id":"user2"}]`
+        let compressed = base64::engine::general_purpose::STANDARD
+            .decode("/Td6WFoAAATm1rRGAgAhARYAAAB0L+Wj4ABWADVdAC2ewEZT8FgOdOXX2e4hW9mHaBMMXrAVpKwd2R9V4xnPf0cxnc80dy3k4FicJjhWSs4E9ifgAAAAAOqCydL28LwaAAFRV2kdKXYftvN9AQAAAAAEWVo=")
+            .unwrap();
+        let query = EventQuery {
+            compression: Some(Compression::Xz),
+            ..Default::default()
+        };
+
+        let events = RawEvent::from_bytes(&query, Bytes::from(compressed)).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].event, "pageview");
+        assert_eq!(events[1].extract_distinct_id().as_deref(), Some("user2"));
+    }
+
+    #[test]
+    #[cfg(feature = "xz")]
+    fn magic_numbers_win_over_declared_compression() {
+        // The xz body of `decode_xz_bytes`, declared gzipped
+        let compressed = base64::engine::general_purpose::STANDARD
+            .decode("/Td6WFoAAATm1rRGAgAhARYAAAB0L+Wj4ABWADVdAC2ewEZT8FgOdOXX2e4hW9mHaBMMXrAVpKwd2R9V4xnPf0cxnc80dy3k4FicJjhWSs4E9ifgAAAAAOqCydL28LwaAAFRV2kdKXYftvN9AQAAAAAEWVo=")
+            .unwrap();
+        let query = EventQuery {
+            compression: Some(Compression::Gzip),
+            ..Default::default()
+        };
+        for preference in ["gzip,xz,deflate", "xz,gzip"] {
+            let config = ProcessingConfig {
+                codec_preference: preference.parse().unwrap(),
+                ..Default::default()
+            };
+            let events =
+                RawEvent::from_bytes_with(&query, Bytes::from(compressed.clone()), &config)
+                    .unwrap();
+            assert_eq!(events.len(), 2);
+            assert_eq!(events[1].extract_distinct_id().as_deref(), Some("user2"));
+        }
+    }
+
+    #[test]
+    fn codec_preference_breaks_ties() {
+        let payload = json!([{"event": "e", "distinct_id": "user1"}]).to_string();
+        let mut zlib = ZlibEncoder::new(Vec::new(), GzCompression::default());
+        zlib.write_all(payload.as_bytes()).unwrap();
+        let mut raw = DeflateEncoder::new(Vec::new(), GzCompression::default());
+        raw.write_all(payload.as_bytes()).unwrap();
+        let query = EventQuery {
+            compression: Some(Compression::Deflate),
+            ..Default::default()
+        };
+
+        // Deflate bodies may be zlib wrapped or not, both readings are tried in either order
+        for body in [zlib.finish().unwrap(), raw.finish().unwrap()] {
+            for preference in ["deflate,deflate-raw", "deflate-raw,deflate"] {
+                let config = ProcessingConfig {
+                    codec_preference: preference.parse().unwrap(),
+                    ..Default::default()
+                };
+                let events =
+                    RawEvent::from_bytes_with(&query, Bytes::from(body.clone()), &config).unwrap();
+                assert_eq!(events[0].extract_distinct_id().as_deref(), Some("user1"));
+            }
+        }
+
+        assert!("gzip,zstd".parse::<CodecPreference>().is_err());
+    }
+
+    #[test]
+    fn streamed_gzip_parse_matches_buffered() {
+        let bytes = Bytes::from(
+            base64::engine::general_purpose::STANDARD
+                .decode(HORRIBLE_BLOB)
+                .unwrap(),
+        );
+        let query = EventQuery {
+            compression: Some(Compression::Gzip),
+            ..Default::default()
+        };
+        let streaming = ProcessingConfig {
+            stream_gzip_json: true,
+            ..Default::default()
+        };
+
+        let buffered = RawEvent::from_bytes(&query, bytes.clone()).expect("buffered parse");
+        let streamed =
+            RawEvent::from_bytes_with(&query, bytes, &streaming).expect("streamed parse");
+        assert!(!streamed.is_empty());
+        assert_eq!(
+            serde_json::to_value(&streamed).unwrap(),
+            serde_json::to_value(&buffered).unwrap()
+        );
+    }
+
+    fn key_with_defaults(event: &ProcessedEvent, overrides: &HashMap<String, String>) -> String {
+        event.key_with(
+            overrides,
+            &EventPartitionStrategies::default(),
+            PartitionStrategy::default(),
+        )
+    }
+
+    #[test]
+    fn key_with_overridden_token() {
+        let overrides = HashMap::from([(String::from("hot_token"), String::from("pinned"))]);
+        let event = ProcessedEvent {
+            token: String::from("hot_token"),
+            distinct_id: String::from("user1"),
+            ..Default::default()
+        };
+
+        assert_eq!(key_with_defaults(&event, &overrides), "hot_token:pinned");
+    }
+
+    #[test]
+    fn key_with_regular_token() {
+        let overrides = HashMap::from([(String::from("hot_token"), String::from("pinned"))]);
+        let event = ProcessedEvent {
+            token: String::from("other_token"),
+            distinct_id: String::from("user1"),
+            ..Default::default()
+        };
+
+        assert_eq!(key_with_defaults(&event, &overrides), "other_token:user1");
+        assert_eq!(key_with_defaults(&event, &overrides), event.key());
+    }
+
+    #[test]
+    fn key_with_snapshot_session() {
+        let event = ProcessedEvent {
+            token: String::from("token"),
+            distinct_id: String::from("user1"),
+            event: String::from("$snapshot"),
+            session_id: Some(String::from("session1")),
+            ..Default::default()
+        };
+        assert_eq!(key_with_defaults(&event, &HashMap::new()), "token:session1");
+
+        let event = ProcessedEvent {
+            session_id: None,
+            ..event
+        };
+        assert_eq!(key_with_defaults(&event, &HashMap::new()), "token:user1");
+    }
+
+    #[test]
+    fn key_with_event_strategy() {
+        let strategies = EventPartitionStrategies::from_str("$exception:token").unwrap();
+        let exception = ProcessedEvent {
+            token: String::from("token"),
+            distinct_id: String::from("user1"),
+            event: String::from("$exception"),
+            ..Default::default()
+        };
+        let pageview = ProcessedEvent {
+            event: String::from("$pageview"),
+            ..exception.clone()
+        };
+
+        let key =
+            |event: &ProcessedEvent, default| event.key_with(&HashMap::new(), &strategies, default);
+        assert_eq!(key(&exception, PartitionStrategy::DistinctId), "token");
+        assert_eq!(key(&pageview, PartitionStrategy::DistinctId), "token:user1");
+        assert_eq!(key(&pageview, PartitionStrategy::Token), "token");
+
+        // Token overrides still apply to events with their own strategy
+        let overrides = HashMap::from([(String::from("token"), String::from("pinned"))]);
+        assert_eq!(
+            exception.key_with(&overrides, &strategies, PartitionStrategy::DistinctId),
+            "token:pinned"
+        );
+    }
+
+    #[test]
+    fn parses_event_partition_strategies() {
+        let EventPartitionStrategies(strategies) =
+            EventPartitionStrategies::from_str(" $exception:token, ns:event:distinct_id,").unwrap();
+        assert_eq!(strategies.len(), 2);
+        assert_eq!(strategies["$exception"], PartitionStrategy::Token);
+        assert_eq!(strategies["ns:event"], PartitionStrategy::DistinctId);
+
+        for invalid in ["$exception", "$exception:random", ":token"] {
+            assert!(
+                EventPartitionStrategies::from_str(invalid).is_err(),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn parses_partition_key_overrides() {
+        let PartitionKeyOverrides(overrides) =
+            PartitionKeyOverrides::from_str(" hot_token:pinned, other:a:b,").unwrap();
+        assert_eq!(overrides.len(), 2);
+        assert_eq!(overrides["hot_token"], "pinned");
+        assert_eq!(overrides["other"], "a:b");
+
+        // A typo must not silently send a token back to default partitioning
+        for invalid in [
+            "hot_token",
+            "hot_token:",
+            ":pinned",
+            "hot_token:pinned,other",
+        ] {
+            assert!(
+                PartitionKeyOverrides::from_str(invalid).is_err(),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn parses_event_property_defaults() {
+        let EventPropertyDefaults(defaults) = EventPropertyDefaults::from_str(
+            "$pageview:$page_category=unknown, $depth=0; signup:plan=\"1\"",
+        )
+        .unwrap();
+        assert_eq!(defaults.len(), 2);
+        assert_eq!(defaults["$pageview"]["$page_category"], json!("unknown"));
+        assert_eq!(defaults["$pageview"]["$depth"], json!(0));
+        assert_eq!(defaults["signup"]["plan"], json!("1"));
+
+        for invalid in [
+            "$pageview",
+            ":key=value",
+            "$pageview:key",
+            "$pageview:=value",
+        ] {
+            assert!(
+                EventPropertyDefaults::from_str(invalid).is_err(),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn moves_immutable_properties_to_set_once() {
+        let keys = HashSet::from([String::from("initial_referrer"), String::from("first_seen")]);
+        let mut event = RawEvent {
+            set: Some(HashMap::from([
+                (String::from("initial_referrer"), json!("google.com")),
+                (String::from("first_seen"), json!("today")),
+                (String::from("plan"), json!("pro")),
+            ])),
+            set_once: Some(HashMap::from([(
+                String::from("first_seen"),
+                json!("yesterday"),
+            )])),
+            ..Default::default()
+        };
+
+        assert!(event.move_to_set_once(&keys));
+        assert_eq!(json!(event.set), json!({"plan": "pro"}));
+        assert_eq!(
+            json!(event.set_once),
+            json!({"initial_referrer": "google.com", "first_seen": "yesterday"})
+        );
+        assert!(!event.move_to_set_once(&keys));
+
+        let mut nested = RawEvent {
+            properties: serde_json::from_value(json!({
+                "$set": {"initial_referrer": "google.com", "plan": "pro"}
+            }))
+            .unwrap(),
+            ..Default::default()
+        };
+        assert!(nested.move_to_set_once(&keys));
+        assert_eq!(nested.properties["$set"], json!({"plan": "pro"}));
+        assert_eq!(
+            nested.properties["$set_once"],
+            json!({"initial_referrer": "google.com"})
+        );
+        assert_eq!(nested.set_once, None);
+    }
+
+    #[test]
+    fn split_person_properties_from_event() {
+        let event = RawEvent {
+            token: Some(String::from("token")),
+            distinct_id: Some(String::from("user1")),
+            event: String::from("signed_up"),
+            properties: HashMap::from([(String::from("plan"), json!("free"))]),
+            set: Some(HashMap::from([(String::from("email"), json!("a@b.c"))])),
+            set_once: Some(HashMap::from([(
+                String::from("first_seen"),
+                json!("today"),
+            )])),
+            ..Default::default()
+        };
+
+        let events = event.split_person_properties();
+        assert_eq!(events.len(), 2);
+
+        assert_eq!(events[0].event, "signed_up");
+        assert_eq!(events[0].properties["plan"], "free");
+        assert!(events[0].set.is_none());
+        assert!(events[0].set_once.is_none());
+
+        assert_eq!(events[1].event, "$set");
+        assert_eq!(events[1].token.as_deref(), Some("token"));
+        assert_eq!(events[1].distinct_id.as_deref(), Some("user1"));
+        assert!(events[1].properties.is_empty());
+        assert_eq!(events[1].set.as_ref().unwrap()["email"], "a@b.c");
+        assert_eq!(events[1].set_once.as_ref().unwrap()["first_seen"], "today");
+    }
+
+    #[test]
+    fn promote_nested_person_updates() {
+        let mut nested = RawEvent {
+            properties: HashMap::from([
+                (String::from("$set"), json!({"email": "a@b.c"})),
+                (String::from("$set_once"), json!({"first_seen": "today"})),
+                (String::from("plan"), json!("pro")),
+            ]),
+            ..Default::default()
+        };
+        assert!(nested.promote_nested_person_updates());
+        assert_eq!(
+            nested.set,
+            Some(HashMap::from([(String::from("email"), json!("a@b.c"))]))
+        );
+        assert_eq!(
+            nested.set_once,
+            Some(HashMap::from([(
+                String::from("first_seen"),
+                json!("today")
+            )]))
+        );
+        assert_eq!(nested.properties.len(), 1);
+
+        let top_level_set = HashMap::from([(String::from("email"), json!("a@b.c"))]);
+        let mut top_level = RawEvent {
+            set: Some(top_level_set.clone()),
+            ..Default::default()
+        };
+        assert!(!top_level.promote_nested_person_updates());
+        assert_eq!(top_level.set, Some(top_level_set));
+        assert_eq!(top_level.set_once, None);
+    }
+
+    #[test]
+    fn promote_nested_person_updates_keeps_top_level_values() {
+        let mut event = RawEvent {
+            set: Some(HashMap::from([(String::from("email"), json!("top@b.c"))])),
+            properties: HashMap::from([
+                (
+                    String::from("$set"),
+                    json!({"email": "nested@b.c", "name": "Ada"}),
+                ),
+                (String::from("$set_once"), json!("not an object")),
+            ]),
+            ..Default::default()
+        };
+
+        assert!(event.promote_nested_person_updates());
+        assert_eq!(
+            event.set,
+            Some(HashMap::from([
+                (String::from("email"), json!("top@b.c")),
+                (String::from("name"), json!("Ada")),
+            ]))
+        );
+        assert!(!event.properties.contains_key("$set"));
+        assert_eq!(event.properties["$set_once"], json!("not an object"));
+        assert_eq!(event.set_once, None);
+    }
+
+    #[test]
+    fn split_person_properties_without_sets() {
+        let event = RawEvent {
+            distinct_id: Some(String::from("user1")),
+            event: String::from("pageview"),
+            ..Default::default()
+        };
+
+        let events = event.split_person_properties();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event, "pageview");
+
+        let identify = RawEvent {
+            event: String::from("$identify"),
+            set: Some(HashMap::from([(String::from("email"), json!("a@b.c"))])),
+            ..Default::default()
+        };
+        let events = identify.split_person_properties();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].set.is_some());
+    }
+
+    #[test]
+    fn uuid_property_fallbacks() {
+        let top_level = crate::utils::uuid_v7();
+        let insert_id = crate::utils::uuid_v7();
+        let property = crate::utils::uuid_v7();
+        let event_with = |uuid, properties: serde_json::Value| RawEvent {
+            uuid,
+            properties: serde_json::from_value(properties).unwrap(),
+            ..Default::default()
+        };
+
+        let both = json!({"$insert_id": insert_id, "uuid": property});
+        assert_eq!(
+            event_with(Some(top_level), both.clone()).extract_uuid(),
+            Some(top_level)
+        );
+        assert_eq!(event_with(None, both).extract_uuid(), Some(insert_id));
+        assert_eq!(
+            event_with(None, json!({"uuid": property})).extract_uuid(),
+            Some(property)
+        );
+        assert_eq!(
+            event_with(None, json!({"$insert_id": "not-a-uuid", "uuid": property})).extract_uuid(),
+            Some(property)
+        );
+        assert_eq!(
+            event_with(None, json!({"$insert_id": "not-a-uuid"})).extract_uuid(),
+            None
+        );
+    }
+
+    #[test]
+    fn process_person_profile_flag() {
+        let event_with = |value| RawEvent {
+            properties: HashMap::from([(String::from("$process_person_profile"), value)]),
+            ..Default::default()
+        };
+
+        assert!(event_with(json!(true)).process_person_profile());
+        assert!(!event_with(json!(false)).process_person_profile());
+        assert!(event_with(json!("true")).process_person_profile());
+        assert!(!event_with(json!("False")).process_person_profile());
+        assert!(event_with(json!(0)).process_person_profile());
+        assert!(RawEvent::default().process_person_profile());
+    }
+
+    #[test]
+    fn person_affecting_events() {
+        let event_named = |name: &str| RawEvent {
+            event: name.to_string(),
+            ..Default::default()
+        };
+        for name in ["$identify", "$set", "$create_alias", "$merge_dangerously"] {
+            assert!(event_named(name).affects_person(), "{name}");
+        }
+        assert!(!event_named("$pageview").affects_person());
+
+        let updates = Some(HashMap::from([(String::from("plan"), json!("pro"))]));
+        let with_set = RawEvent {
+            set: updates.clone(),
+            ..event_named("$pageview")
+        };
+        assert!(with_set.affects_person());
+        let with_set_once = RawEvent {
+            set_once: updates,
+            ..event_named("$pageview")
+        };
+        assert!(with_set_once.affects_person());
+        for key in ["$set", "$set_once"] {
+            let mut event = event_named("$pageview");
+            event
+                .properties
+                .insert(key.to_string(), json!({"plan": "pro"}));
+            assert!(event.affects_person(), "{key}");
+        }
+
+        let mut opted_out = event_named("$identify");
+        opted_out
+            .properties
+            .insert(String::from("$process_person_profile"), json!(false));
+        assert!(!opted_out.affects_person());
+    }
+
+    #[test]
+    fn session_id_validation() {
+        let event_with = |value| RawEvent {
+            properties: HashMap::from([(String::from("$session_id"), value)]),
+            ..Default::default()
+        };
+        let uuid = "018c3e8b-6f5a-7b3c-8d9e-0a1b2c3d4e5f";
+
+        for strict in [false, true] {
+            let mut event = event_with(json!(uuid));
+            assert!(!event.strip_invalid_session_id(strict));
+            assert_eq!(event.properties["$session_id"], json!(uuid));
+
+            let mut absent = RawEvent::default();
+            assert!(!absent.strip_invalid_session_id(strict));
+
+            for malformed in [json!(""), json!(12), json!(null), json!({"id": uuid})] {
+                let mut event = event_with(malformed);
+                assert!(event.strip_invalid_session_id(strict));
+                assert!(!event.properties.contains_key("$session_id"));
+            }
+        }
+
+        let mut event = event_with(json!("session1"));
+        assert!(!event.strip_invalid_session_id(false));
+        assert!(event.strip_invalid_session_id(true));
+    }
+
+    #[test]
+    fn timezone_validation() {
+        let event_with = |value| RawEvent {
+            properties: HashMap::from([(String::from("$timezone"), value)]),
+            ..Default::default()
+        };
+
+        let mut event = event_with(json!("Europe/Paris"));
+        assert_eq!(event.validate_timezone().as_deref(), Some("Europe/Paris"));
+        assert!(!event.properties.contains_key("$timezone_invalid"));
+
+        for invalid in [json!("Paris, France"), json!(2)] {
+            let mut event = event_with(invalid.clone());
+            assert_eq!(event.validate_timezone(), None);
+            assert_eq!(event.properties["$timezone"], invalid);
+            assert_eq!(event.properties["$timezone_invalid"], json!(true));
+        }
+
+        let mut event = RawEvent::default();
+        assert_eq!(event.validate_timezone(), None);
+        assert!(event.properties.is_empty());
+    }
+
+    #[test]
+    fn test_event_marker() {
+        let event_with = |value| RawEvent {
+            properties: HashMap::from([(String::from("$test"), value)]),
+            ..Default::default()
+        };
+
+        assert!(event_with(json!(true)).is_test("$test"));
+        assert!(event_with(json!("TRUE")).is_test("$test"));
+        assert!(!event_with(json!(false)).is_test("$test"));
+        assert!(!event_with(json!("false")).is_test("$test"));
+        assert!(!event_with(json!(true)).is_test("$qa"));
+        assert!(!RawEvent::default().is_test("$test"));
+
+        let value = serde_json::to_value(ProcessedEvent::default()).unwrap();
+        assert!(value.get("is_test").is_none());
+    }
+
+    #[test]
+    fn ingest_region_serialized_when_set() {
+        let value = serde_json::to_value(ProcessedEvent::default()).unwrap();
+        assert!(value.get("ingest_region").is_none());
+
+        let event = ProcessedEvent {
+            ingest_region: Some(String::from("us-east-1")),
+            ..Default::default()
+        };
+        let value = serde_json::to_value(&event).unwrap();
+        assert_eq!(value["ingest_region"], "us-east-1");
+    }
+
+    #[test]
+    fn process_person_profile_serialized_when_false() {
+        let event = ProcessedEvent::default();
+        let value = serde_json::to_value(&event).unwrap();
+        assert!(value.get("process_person_profile").is_none());
+
+        let event = ProcessedEvent {
+            process_person_profile: false,
+            ..Default::default()
+        };
+        let value = serde_json::to_value(&event).unwrap();
+        assert_eq!(value["process_person_profile"], false);
+    }
+
+    #[test]
+    fn data_as_value_matches_properties() {
+        let raw = RawEvent {
+            distinct_id: Some(String::from("user1")),
+            event: String::from("pageview"),
+            properties: HashMap::from([
+                (String::from("$current_url"), json!("https://posthog.com")),
+                (String::from("nested"), json!({"a": [1, 2]})),
+            ]),
+            ..Default::default()
+        };
+        let event = ProcessedEvent {
+            data: serde_json::to_string(&raw).unwrap(),
+            ..Default::default()
+        };
+
+        let value = event.data_as_value().expect("failed to parse data");
+        assert_eq!(value["event"], "pageview");
+        assert_eq!(value["properties"], json!(raw.properties));
+
+        let lazy = event.lazy_data();
+        assert_eq!(lazy.get().unwrap(), &value);
+        assert!(std::ptr::eq(lazy.get().unwrap(), lazy.get().unwrap()));
+    }
+
+    #[test]
+    fn content_eq_ignores_volatile_fields() {
+        let event = |uuid: Uuid, properties: Value| {
+            let raw = RawEvent {
+                uuid: Some(uuid),
+                distinct_id: Some(String::from("user1")),
+                event: String::from("pageview"),
+                properties: serde_json::from_value(properties).unwrap(),
+                ..Default::default()
+            };
+            ProcessedEvent {
+                uuid,
+                distinct_id: String::from("user1"),
+                data: serde_json::to_string(&raw).unwrap(),
+                token: String::from("token"),
+                event: String::from("pageview"),
+                ..Default::default()
+            }
+        };
+        let first = event(uuid_v7(), json!({"a": 1, "b": 2}));
+        let later = ProcessedEvent {
+            now: String::from("2023-10-26T12:00:05Z"),
+            ip: String::from("10.0.0.1"),
+            ..event(uuid_v7(), json!({"b": 2, "a": 1}))
+        };
+        assert_ne!(first, later);
+        assert!(first.content_eq(&later));
+
+        assert!(!first.content_eq(&event(uuid_v7(), json!({"a": 2, "b": 2}))));
+        let other_user = ProcessedEvent {
+            distinct_id: String::from("user2"),
+            ..first.clone()
+        };
+        assert!(!first.content_eq(&other_user));
+    }
+
+    #[test]
+    fn decode_wrapped_batch() {
+        let body = json!({
+            "api_key": "shared_token",
+            "batch": [
+                {"event": "first", "distinct_id": "user1"},
+                {"event": "second", "distinct_id": "user1", "token": "own_token"},
+                {"event": "third", "distinct_id": "user1", "properties": {"token": "prop_token"}},
+            ]
+        });
+
+        let events = RawEvent::from_bytes(&EventQuery::default(), body.to_string().into())
+            .expect("failed to decode wrapped batch");
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].extract_token().as_deref(), Some("shared_token"));
+        assert_eq!(events[1].extract_token().as_deref(), Some("own_token"));
+        assert_eq!(events[2].extract_token().as_deref(), Some("prop_token"));
+    }
+
+    #[test]
+    fn decode_wrapped_batch_with_common_properties() {
+        let body = json!({
+            "common_properties": {"$lib": "web", "plan": "free", "token": "common_token"},
+            "batch": [
+                {"event": "first", "distinct_id": "user1", "properties": {"$current_url": "/"}},
+                {"event": "second", "distinct_id": "user1", "properties": {"plan": "pro"}},
+                {"event": "third", "distinct_id": "user1", "token": "own_token"},
+            ]
+        });
+
+        let events = RawEvent::from_bytes(&EventQuery::default(), body.to_string().into())
+            .expect("failed to decode wrapped batch");
+        assert_eq!(events.len(), 3);
+        assert_eq!(
+            json!(events[0].properties),
+            json!({"$lib": "web", "plan": "free", "token": "common_token", "$current_url": "/"})
+        );
+        assert_eq!(events[1].properties["plan"], json!("pro"));
+        assert_eq!(events[1].properties["$lib"], json!("web"));
+        assert_eq!(events[1].extract_token().as_deref(), Some("common_token"));
+        assert_eq!(events[2].extract_token().as_deref(), Some("own_token"));
+    }
+
+    #[test]
+    fn decode_wrapped_batch_without_token() {
+        let body = json!({
+            "batch": [{"event": "first", "distinct_id": "user1"}]
+        });
+
+        let events = RawEvent::from_bytes(&EventQuery::default(), body.to_string().into())
+            .expect("failed to decode wrapped batch");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event, "first");
+        assert_eq!(events[0].extract_token(), None);
+    }
+
+    #[test]
+    fn decode_tuple_batch() {
+        let body = json!([
+            ["pageview", {"distinct_id": "user1", "token": "tuple_token"}],
+            ["click", {"distinct_id": "user2", "$current_url": "/home"}],
+        ]);
+
+        let events = RawEvent::from_bytes(&EventQuery::default(), body.to_string().into())
+            .expect("failed to decode tuple batch");
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].event, "pageview");
+        assert_eq!(events[0].extract_distinct_id().as_deref(), Some("user1"));
+        assert_eq!(events[0].extract_token().as_deref(), Some("tuple_token"));
+        assert_eq!(events[1].event, "click");
+        assert_eq!(events[1].properties["$current_url"], "/home");
+    }
+
+    #[test]
+    fn reject_malformed_tuple() {
+        for (body, index) in [
+            (json!([["pageview", {}], ["click"]]), 1),
+            (json!([["pageview", {}], [{}, "click"]]), 1),
+            (json!([["pageview", {}, {}]]), 0),
+        ] {
+            let res = RawEvent::from_bytes(&EventQuery::default(), body.to_string().into());
+            match res {
+                Err(CaptureError::MalformedEventTuple(i)) => assert_eq!(i, index, "{}", body),
+                _ => panic!("expected a malformed tuple error for {}", body),
+            }
+        }
+    }
+
+    #[test]
+    fn decode_legacy_form_with_query_token() {
+        let payload = json!({"event": "legacy", "distinct_id": "user1"}).to_string();
+        let data = base64::engine::general_purpose::STANDARD.encode(payload);
+        let body = serde_urlencoded::to_string([("data", data)]).unwrap();
+        let query = EventQuery {
+            api_key: Some(String::from("query_token")),
+            ..Default::default()
+        };
+
+        let events = RawEvent::from_form_data(&query, body.into(), &ProcessingConfig::default())
+            .expect("failed to decode legacy form");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event, "legacy");
+        assert_eq!(events[0].extract_token(), Some(String::from("query_token")));
+    }
+
+    fn nested_data(payload: &str) -> String {
+        let mut encoder = GzEncoder::new(Vec::new(), GzCompression::default());
+        encoder.write_all(payload.as_bytes()).unwrap();
+        let data = base64::engine::general_purpose::STANDARD.encode(encoder.finish().unwrap());
+        json!({"data": data, "compression": "gzip-js"}).to_string()
+    }
+
+    #[test]
+    fn decode_nested_data() {
+        let payload = json!([
+            {"event": "first", "distinct_id": "user1"},
+            {"event": "second", "distinct_id": "user1"}
+        ]);
+
+        let events = RawEvent::from_bytes(
+            &EventQuery::default(),
+            nested_data(&payload.to_string()).into(),
+        )
+        .expect("failed to decode nested data");
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].event, "second");
+
+        // An event with a data property is not nested data
+        let event = json!({"event": "e", "distinct_id": "user1", "data": "aGVsbG8="});
+        let events = RawEvent::from_bytes(&EventQuery::default(), event.to_string().into())
+            .expect("failed to decode event");
+        assert_eq!(events[0].event, "e");
+    }
+
+    #[test]
+    fn nested_data_is_decoded_once() {
+        let payload = json!({"event": "e", "distinct_id": "user1"}).to_string();
+        let twice = nested_data(&nested_data(&payload));
+
+        let res = RawEvent::from_bytes(&EventQuery::default(), twice.into());
+        assert!(matches!(res, Err(CaptureError::RequestDecodingError(_))));
+
+        let invalid = json!({"data": "not base64!"}).to_string();
+        let res = RawEvent::from_bytes(&EventQuery::default(), invalid.into());
+        assert!(matches!(res, Err(CaptureError::InvalidBase64(_))));
+    }
+
+    #[test]
+    fn stamps_detected_compression() {
+        let payload = json!({"event": "e", "distinct_id": "user1"}).to_string();
+        let mut encoder = GzEncoder::new(Vec::new(), GzCompression::default());
+        encoder.write_all(payload.as_bytes()).unwrap();
+        let gzipped = Bytes::from(encoder.finish().unwrap());
+        let config = ProcessingConfig {
+            stamp_detected_compression: true,
+            ..Default::default()
+        };
+
+        let events =
+            RawEvent::from_bytes_with(&EventQuery::default(), gzipped.clone(), &config).unwrap();
+        assert_eq!(events[0].properties["$detected_compression"], json!("gzip"));
+        let events =
+            RawEvent::from_bytes_with(&EventQuery::default(), payload.into(), &config).unwrap();
+        assert_eq!(events[0].properties["$detected_compression"], json!("none"));
+
+        // Off by default
+        let events = RawEvent::from_bytes(&EventQuery::default(), gzipped).unwrap();
+        assert!(!events[0].properties.contains_key("$detected_compression"));
+    }
+
+    #[test]
+    fn reports_parse_warnings() {
+        let payload = json!({"event": "e", "distinct_id": "user1"}).to_string();
+        let config = ProcessingConfig::default();
+        let gzip_query = EventQuery {
+            compression: Some(Compression::Gzip),
+            ..Default::default()
+        };
+
+        let (events, warnings) = RawEvent::from_bytes_with_warnings(
+            &EventQuery::default(),
+            payload.clone().into(),
+            &config,
+        )
+        .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(warnings, vec![]);
+
+        let bom = format!("\u{feff}{payload}");
+        let (events, warnings) =
+            RawEvent::from_bytes_with_warnings(&EventQuery::default(), bom.into(), &config)
+                .unwrap();
+        assert_eq!(events[0].event, "e");
+        assert_eq!(warnings, vec![ParseWarning::ByteOrderMarkStripped]);
+
+        let (events, warnings) =
+            RawEvent::from_bytes_with_warnings(&gzip_query, payload.clone().into(), &config)
+                .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            warnings,
+            vec![ParseWarning::CompressionMismatch {
+                detected_gzip: false
+            }]
+        );
+
+        let mut encoder = GzEncoder::new(Vec::new(), GzCompression::default());
+        encoder.write_all(payload.as_bytes()).unwrap();
+        let gzipped = Bytes::from(encoder.finish().unwrap());
+        let (_, warnings) =
+            RawEvent::from_bytes_with_warnings(&gzip_query, gzipped.clone(), &config).unwrap();
+        assert_eq!(warnings, vec![]);
+        let (_, warnings) =
+            RawEvent::from_bytes_with_warnings(&EventQuery::default(), gzipped, &config).unwrap();
+        assert_eq!(
+            warnings,
+            vec![ParseWarning::CompressionMismatch {
+                detected_gzip: true
+            }]
+        );
+    }
+
+    fn streamed_names(body: &str) -> Vec<String> {
+        RawEvent::stream_from_bytes(body.as_bytes())
+            .map(|event| event.unwrap().event)
+            .collect()
+    }
+
+    #[test]
+    fn streams_single_object() {
+        let body = r#"
+            {"event": "only", "distinct_id": "u"}  "#;
+        assert_eq!(streamed_names(body), vec!["only"]);
+    }
+
+    #[test]
+    fn streams_array() {
+        let body = r#" [{"event": "first", "distinct_id": "u"},
+            {"event": "second", "properties": {"list": [1, "]"]}} ]"#;
+        assert_eq!(streamed_names(body), vec!["first", "second"]);
+    }
+
+    #[test]
+    fn streams_empty_array() {
+        assert!(streamed_names("[]").is_empty());
+        assert!(streamed_names(" [ \n ] ").is_empty());
+    }
+
+    #[test]
+    fn streaming_stops_on_error() {
+        for invalid in [
+            "",
+            "12",
+            r#"[{"event": "a"},]"#,
+            r#"[{"event": "a"} {"event": "b"}]"#,
+            r#"[{"event": "a"}"#,
+            r#"{"event": "a"} {"event": "b"}"#,
+        ] {
+            let mut stream = RawEvent::stream_from_bytes(invalid.as_bytes());
+            let results: Vec<_> = stream.by_ref().collect();
+            assert!(results.last().unwrap().is_err(), "{invalid}");
+            assert!(stream.next().is_none());
+        }
+    }
+
+    #[test]
+    fn decodes_declared_content_encoding() {
+        let payload = json!({"event": "e", "distinct_id": "u"}).to_string();
+        let mut deflate = ZlibEncoder::new(Vec::new(), GzCompression::default());
+        deflate.write_all(payload.as_bytes()).unwrap();
+        let mut gzip = GzEncoder::new(Vec::new(), GzCompression::default());
+        gzip.write_all(&deflate.finish().unwrap()).unwrap();
+        let body = Bytes::from(gzip.finish().unwrap());
+
+        let query = EventQuery {
+            content_encoding: Some(String::from("deflate, gzip")),
+            ..Default::default()
+        };
+        let events = RawEvent::from_bytes(&query, body.clone()).unwrap();
+        assert_eq!(events[0].event, "e");
+
+        // The inner layer is not sniffed
+        let res = RawEvent::from_bytes(&EventQuery::default(), body);
+        assert!(matches!(res, Err(CaptureError::InvalidTextEncoding(_))));
+
+        let query = EventQuery {
+            content_encoding: Some(String::from("br")),
+            ..Default::default()
+        };
+        let res = RawEvent::from_bytes(&query, payload.into());
+        assert!(matches!(res, Err(CaptureError::RequestDecodingError(_))));
+    }
+
+    #[test]
+    fn samples_logged_payloads() {
+        let payload = r#"{"event": "été"}"#;
+        assert_eq!(sampled_payload(payload, &ProcessingConfig::default()), None);
+
+        let mut config = ProcessingConfig {
+            payload_log_sample_rate: 1.0,
+            payload_log_max_bytes: 100,
+            ..Default::default()
+        };
+        assert_eq!(sampled_payload(payload, &config), Some(payload));
+
+        config.payload_log_max_bytes = 13;
+        assert_eq!(sampled_payload(payload, &config), Some(r#"{"event": "é"#));
+        // Cut within a two bytes é
+        config.payload_log_max_bytes = 12;
+        assert_eq!(sampled_payload(payload, &config), Some(r#"{"event": ""#));
+        config.payload_log_max_bytes = 15;
+        assert_eq!(sampled_payload(payload, &config), Some(r#"{"event": "ét"#));
+    }
+
+    fn length_prefixed(frames: &[&str]) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        for frame in frames {
+            buffer.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            buffer.extend_from_slice(frame.as_bytes());
+        }
+        buffer
+    }
+
+    #[test]
+    fn decode_length_prefixed_frames() {
+        let buffer = length_prefixed(&[
+            r#"{"event": "first", "distinct_id": "user1"}"#,
+            r#"{"event": "second", "distinct_id": "user2"}"#,
+        ]);
+
+        let events = RawEvent::from_length_prefixed(buffer.into()).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].event, "first");
+        assert_eq!(events[1].distinct_id.as_deref(), Some("user2"));
+        assert!(RawEvent::from_length_prefixed(Bytes::new())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn truncated_length_prefixed_frames() {
+        let buffer = length_prefixed(&[r#"{"event": "first", "distinct_id": "user1"}"#]);
+
+        let truncated = Bytes::from(buffer[..buffer.len() - 1].to_vec());
+        let res = RawEvent::from_length_prefixed(truncated);
+        assert!(matches!(res, Err(CaptureError::RequestDecodingError(_))));
+
+        let mut trailing = buffer.clone();
+        trailing.extend_from_slice(&[0, 0]);
+        let res = RawEvent::from_length_prefixed(trailing.into());
+        assert!(matches!(res, Err(CaptureError::RequestDecodingError(_))));
+    }
+
+    #[test]
+    fn request_size_limit() {
+        let body = json!({"event": "e", "distinct_id": "user1"}).to_string();
+        let config = ProcessingConfig {
+            max_compressed_bytes: body.len(),
+            ..Default::default()
+        };
+        let res = RawEvent::from_bytes_with(&EventQuery::default(), body.clone().into(), &config);
+        assert!(res.is_ok());
+
+        let config = ProcessingConfig {
+            max_compressed_bytes: body.len() - 1,
+            ..Default::default()
+        };
+        let res = RawEvent::from_bytes_with(&EventQuery::default(), body.into(), &config);
+        assert!(matches!(res, Err(CaptureError::RequestTooLarge)));
+    }
+
+    #[test]
+    fn decode_jsonp() {
+        let events = RawEvent::from_bytes(
+            &EventQuery::default(),
+            r#"callback({"event": "e", "distinct_id": "user1"});"#.into(),
+        )
+        .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event, "e");
+
+        let events = RawEvent::from_bytes(
+            &EventQuery::default(),
+            r#" jQuery123_456.cb([{"event": "a", "distinct_id": "u"}, {"event": "b", "distinct_id": "u"}]) "#.into(),
+        )
+        .unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].event, "b");
+
+        for invalid in [
+            r#"alert(1);cb({"event": "e"})"#,
+            r#"1cb({"event": "e"})"#,
+            r#"cb({"event": "e"}"#,
+            r#"({"event": "e"})"#,
+        ] {
+            let res = RawEvent::from_bytes(&EventQuery::default(), invalid.into());
+            assert!(res.is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn decode_padded_bodies() {
+        let event = r#"{"event": "e", "distinct_id": "user1"}"#;
+        let decode = |body: String| {
+            RawEvent::from_bytes_with_warnings(
+                &EventQuery::default(),
+                body.into(),
+                &ProcessingConfig::default(),
+            )
+        };
+
+        for body in [
+            format!("{event}\n"),
+            format!("{event} \r\n\t"),
+            format!("cb({event});\n"),
+            format!("{}\n", nested_data(&format!("{event}\n"))),
+        ] {
+            let (events, warnings) = decode(body.clone()).expect(&body);
+            assert_eq!(events[0].event, "e", "{body}");
+            assert_eq!(warnings, vec![], "{body}");
+        }
+
+        for body in [
+            format!("\n  {event}"),
+            format!("\r\n\tcb({event})"),
+            format!(" \n{}", nested_data(event)),
+        ] {
+            let (events, _) = decode(body.clone()).expect(&body);
+            assert_eq!(events[0].event, "e", "{body}");
+        }
+        let (events, warnings) = decode(format!("\n\u{feff}{event}")).unwrap();
+        assert_eq!(events[0].event, "e");
+        assert_eq!(warnings, vec![ParseWarning::ByteOrderMarkStripped]);
+
+        // Trailing whitespace inside a base64 data field
+        let data = base64::engine::general_purpose::STANDARD.encode(event);
+        let body = json!({ "data": format!("{data}\n") }).to_string();
+        let (events, _) = decode(body).unwrap();
+        assert_eq!(events[0].event, "e");
+
+        // Gzipped bodies are left untouched, and padding within them is trimmed once inflated
+        let mut encoder = GzEncoder::new(Vec::new(), GzCompression::default());
+        encoder.write_all(format!(" {event}\n").as_bytes()).unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let events = RawEvent::from_bytes(&EventQuery::default(), gzipped.into()).unwrap();
+        assert_eq!(events[0].event, "e");
+    }
+
+    #[test]
+    fn decode_compressed_properties() {
+        let mut encoder = GzEncoder::new(Vec::new(), GzCompression::default());
+        encoder
+            .write_all(
+                json!({"large": "a".repeat(100), "url": "compressed"})
+                    .to_string()
+                    .as_bytes(),
+            )
+            .unwrap();
+        let blob = base64::engine::general_purpose::STANDARD.encode(encoder.finish().unwrap());
+        let body = json!([
+            {"event": "compressed", "distinct_id": "u", "properties": {
+                "$compressed_properties": blob, "url": "plain"
+            }},
+            {"event": "plain", "distinct_id": "u", "properties": {"url": "plain"}}
+        ]);
+
+        let events = RawEvent::from_bytes(&EventQuery::default(), body.to_string().into()).unwrap();
+        assert_eq!(events[0].properties["large"], json!("a".repeat(100)));
+        assert_eq!(events[0].properties["url"], json!("plain"));
+        assert!(!events[0].properties.contains_key("$compressed_properties"));
+        assert_eq!(
+            events[1].properties,
+            HashMap::from([(String::from("url"), json!("plain"))])
+        );
+
+        let body = json!({"event": "e", "distinct_id": "u", "properties": {
+            "$compressed_properties": "bm90IGd6aXA="
+        }});
+        let res = RawEvent::from_bytes(&EventQuery::default(), body.to_string().into());
+        assert!(matches!(
+            res,
+            Err(CaptureError::CorruptCompressedData(Codec::Gzip))
+        ));
+    }
+
+    #[test]
+    fn total_decompressed_size_limit() {
+        let gzip = |payload: &[u8]| {
+            let mut encoder = GzEncoder::new(Vec::new(), GzCompression::default());
+            encoder.write_all(payload).unwrap();
+            encoder.finish().unwrap()
+        };
+        let config = ProcessingConfig {
+            max_total_decompressed_bytes: Some(1000),
+            ..Default::default()
+        };
+
+        // A JSON array split over many small gzip members
+        let event = json!({"event": "e", "distinct_id": "u", "properties": {"a": "a".repeat(50)}});
+        let mut body = gzip(b"[");
+        for _ in 0..8 {
+            body.extend(gzip(format!("{event},").as_bytes()));
+        }
+        body.extend(gzip(event.to_string().as_bytes()));
+        body.extend(gzip(b"]"));
+        let events =
+            RawEvent::from_bytes_with(&EventQuery::default(), body.clone().into(), &config);
+        assert_eq!(events.unwrap().len(), 9);
+
+        for _ in 0..10 {
+            body.extend(gzip(b" "));
+            body.extend(gzip(&[b' '; 100]));
+        }
+        let res = RawEvent::from_bytes_with(&EventQuery::default(), body.into(), &config);
+        assert!(matches!(res, Err(CaptureError::DecompressedTooLarge)));
+
+        // Compressed properties count towards the same limit
+        let blob = base64::engine::general_purpose::STANDARD.encode(gzip(
+            json!({"large": "a".repeat(400)}).to_string().as_bytes(),
+        ));
+        let compressed = json!({"event": "e", "distinct_id": "u", "properties": {
+            "$compressed_properties": blob
+        }});
+        let body = json!([compressed, compressed]).to_string();
+        let events = RawEvent::from_bytes_with(&EventQuery::default(), body.into(), &config);
+        assert_eq!(events.unwrap().len(), 2);
+        let body = json!([compressed, compressed, compressed]).to_string();
+        let res = RawEvent::from_bytes_with(&EventQuery::default(), body.into(), &config);
+        assert!(matches!(res, Err(CaptureError::DecompressedTooLarge)));
+    }
+
+    #[test]
+    fn decode_chunked_gzip_body() {
+        let mut encoder = GzEncoder::new(Vec::new(), GzCompression::default());
+        encoder
+            .write_all(br#"[{"event": "first", "distinct_id": "u"}, {"event": "second", "distinct_id": "u"}]"#)
+            .unwrap();
+        let body = Bytes::from(encoder.finish().unwrap());
+        let chunks: Vec<Bytes> = body.chunks(7).map(Bytes::copy_from_slice).collect();
+        assert!(chunks.len() > 3);
+
+        let events = RawEvent::from_chunks(&EventQuery::default(), chunks).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].event, "second");
+
+        let events = RawEvent::from_chunks(&EventQuery::default(), [body]).unwrap();
+        assert_eq!(events.len(), 2);
+    }
+
+    #[test]
+    fn query_token_does_not_override_body_token() {
+        let body = json!([
+            {"event": "first", "distinct_id": "user1", "api_key": "body_token"},
+            {"event": "second", "distinct_id": "user1"}
+        ]);
+        let query: EventQuery = serde_urlencoded::from_str("api_key=query_token").unwrap();
+
+        let events = RawEvent::from_bytes(&query, body.to_string().into()).unwrap();
+        assert_eq!(events[0].extract_token(), Some(String::from("body_token")));
+        assert_eq!(events[1].extract_token(), Some(String::from("query_token")));
+    }
+
+    #[test]
+    fn decode_form_without_data() {
+        let res = RawEvent::from_form_data(
+            &EventQuery::default(),
+            Bytes::from("other=1"),
+            &ProcessingConfig::default(),
+        );
+        assert!(matches!(res, Err(CaptureError::RequestDecodingError(_))));
+    }
+
+    #[test]
+    fn property_sizes_are_sorted() {
+        let event = RawEvent {
+            properties: HashMap::from([
+                (String::from("small"), json!(1)),
+                (String::from("large"), json!("x".repeat(100))),
+                (String::from("medium"), json!(["abc", "def"])),
+            ]),
+            ..Default::default()
+        };
+
+        let sizes = event.property_sizes();
+        assert_eq!(
+            sizes,
+            vec![
+                (String::from("large"), 102),
+                (String::from("medium"), 13),
+                (String::from("small"), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_property_keys() {
+        let body = r#"{"event": "e", "distinct_id": "id", "properties": {"a": 1, "b": 2, "a": 3}}"#;
+        let config = |policy| ProcessingConfig {
+            duplicate_json_keys: policy,
+            ..Default::default()
+        };
+
+        let events = RawEvent::from_bytes_with(
+            &EventQuery::default(),
+            body.into(),
+            &config(DuplicateKeyPolicy::Warn),
+        )
+        .expect("duplicate keys should only warn");
+        assert_eq!(events[0].properties["a"], 3);
+
+        let res = RawEvent::from_bytes_with(
+            &EventQuery::default(),
+            body.into(),
+            &config(DuplicateKeyPolicy::Reject),
+        );
+        assert!(matches!(res, Err(CaptureError::DuplicateJsonKey(key)) if key == "a"));
+    }
+
+    #[test]
+    fn duplicate_event_keys() {
+        let body = r#"[{"event": "e", "distinct_id": "one", "distinct_id": "two"}]"#;
+        let config = ProcessingConfig {
+            duplicate_json_keys: DuplicateKeyPolicy::Reject,
+            ..Default::default()
+        };
+
+        let res = RawEvent::from_bytes_with(&EventQuery::default(), body.into(), &config);
+        assert!(matches!(res, Err(CaptureError::DuplicateJsonKey(key)) if key == "distinct_id"));
+
+        let body =
+            r#"[{"event": "e", "distinct_id": "one", "properties": {"distinct_id": "two"}}]"#;
+        let res = RawEvent::from_bytes_with(&EventQuery::default(), body.into(), &config);
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn offset_duration_validation() {
+        let event_with = |offset| RawEvent {
+            offset,
+            ..Default::default()
+        };
+        let millis = |millis| Some(EventOffset::Millis(millis));
+        let iso = |value: &str| Some(EventOffset::Iso(value.to_string()));
+
+        assert_eq!(event_with(None).offset_duration().unwrap(), None);
+        assert_eq!(
+            event_with(millis(1500)).offset_duration().unwrap(),
+            Some(time::Duration::milliseconds(1500))
+        );
+        assert_eq!(
+            event_with(iso("PT1.5S")).offset_duration().unwrap(),
+            Some(time::Duration::milliseconds(1500))
+        );
+        for invalid in [
+            millis(-10),
+            millis(i64::MAX),
+            iso("5 seconds"),
+            iso("P400D"),
+        ] {
+            assert!(matches!(
+                event_with(invalid).offset_duration(),
+                Err(CaptureError::InvalidOffset)
+            ));
+        }
+    }
+
+    #[test]
+    fn decode_offsets() {
+        let body = json!([
+            {"event": "e", "distinct_id": "user1", "offset": 1500},
+            {"event": "e", "distinct_id": "user1", "offset": "PT5S"}
+        ]);
+        let events = RawEvent::from_bytes(&EventQuery::default(), body.to_string().into()).unwrap();
+        assert_eq!(events[0].offset, Some(EventOffset::Millis(1500)));
+        assert_eq!(
+            events[1].offset,
+            Some(EventOffset::Iso(String::from("PT5S")))
+        );
+    }
+
+    #[test]
+    fn fingerprint_is_stable() {
+        let event = |properties: serde_json::Value| RawEvent {
+            event: String::from("pageview"),
+            distinct_id: Some(String::from("user1")),
+            properties: serde_json::from_value(properties).unwrap(),
+            timestamp: Some(String::from("2023-10-26T12:00:00Z")),
+            ..Default::default()
+        };
+        let first = event(json!({"a": 1, "b": {"y": true, "x": "nested"}}));
+        let same = event(json!({"b": {"x": "nested", "y": true}, "a": 1}));
+        let changed = event(json!({"a": 2, "b": {"y": true, "x": "nested"}}));
+
+        assert_eq!(first.fingerprint(), same.fingerprint());
+        assert_ne!(first.fingerprint(), changed.fingerprint());
+
+        // Values must not depend on the process, pin one
+        assert_eq!(RawEvent::default().fingerprint(), 0x8f7a344c176989ad);
+    }
+
+    fn test_context() -> ProcessingContext {
+        ProcessingContext {
+            lib_version: Some(String::from("1.2.3")),
+            sent_at: Some(datetime!(2023-10-26 12:00:00 UTC)),
+            event_time: None,
+            trace_id: None,
+            traceparent: None,
+            user_agent: None,
+            origin: None,
+            path: None,
+            ingest_region: None,
+            token: String::from("context_token"),
+            now: String::from("2023-10-26T12:00:05Z"),
+            clock: Arc::new(SystemTime {}),
+            client_ip: String::from("127.0.0.1"),
+        }
+    }
+
+    #[test]
+    fn parses_traceparent() {
+        assert_eq!(
+            TraceParent::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+            Some(TraceParent {
+                trace_id: String::from("4bf92f3577b34da6a3ce929d0e0e4736"),
+                span_id: String::from("00f067aa0ba902b7"),
+            })
+        );
+
+        for malformed in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e47-00f067aa0ba902b7-01",
+        ] {
+            assert_eq!(TraceParent::parse(malformed), None, "{malformed}");
+        }
+    }
+
+    #[test]
+    fn apply_context_fills_missing_fields() {
+        let mut event = RawEvent {
+            event: String::from("pageview"),
+            ..Default::default()
+        };
+        event.apply_context(&test_context());
+
+        assert_eq!(event.timestamp.as_deref(), Some("2023-10-26T12:00:00Z"));
+        assert_eq!(event.extract_token(), Some(String::from("context_token")));
+        assert_eq!(event.properties["$lib_version"], json!("1.2.3"));
+
+        let applied = event.clone();
+        event.apply_context(&test_context());
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::to_value(&applied).unwrap()
+        );
+    }
+
+    #[test]
+    fn apply_context_keeps_existing_fields() {
+        let mut event = RawEvent {
+            event: String::from("pageview"),
+            timestamp: Some(String::from("2023-10-26T11:00:00Z")),
+            properties: serde_json::from_value(json!({
+                "token": "event_token",
+                "$lib_version": "0.9.0"
+            }))
+            .unwrap(),
+            ..Default::default()
+        };
+        let mut context = test_context();
+        context.sent_at = None;
+        event.apply_context(&context);
+
+        assert_eq!(event.timestamp.as_deref(), Some("2023-10-26T11:00:00Z"));
+        assert_eq!(event.token, None);
+        assert_eq!(event.extract_token(), Some(String::from("event_token")));
+        assert_eq!(event.properties["$lib_version"], json!("0.9.0"));
+
+        let mut event = RawEvent::default();
+        event.apply_context(&context);
+        assert_eq!(event.timestamp.as_deref(), Some("2023-10-26T12:00:05Z"));
+    }
+
+    #[test]
+    fn extract_distinct_id_sources() {
+        let event = |distinct_id: Option<&str>, properties: serde_json::Value| RawEvent {
+            distinct_id: distinct_id.map(String::from),
+            properties: serde_json::from_value(properties).unwrap(),
+            ..Default::default()
+        };
+
+        let top_level = event(
+            Some("top"),
+            json!({"distinct_id": "prop", "$user_id": "user"}),
+        );
+        assert_eq!(top_level.extract_distinct_id(), Some(String::from("top")));
+
+        let property = event(None, json!({"distinct_id": "prop", "$user_id": "user"}));
+        assert_eq!(property.extract_distinct_id(), Some(String::from("prop")));
+
+        let user_id = event(None, json!({"$user_id": "user"}));
+        assert_eq!(user_id.extract_distinct_id(), Some(String::from("user")));
+
+        let numeric = event(None, json!({"distinct_id": 42}));
+        assert_eq!(numeric.extract_distinct_id(), Some(String::from("42")));
+
+        let invalid = event(None, json!({"distinct_id": null, "$user_id": 1.5}));
+        assert_eq!(invalid.extract_distinct_id(), Some(String::from("1.5")));
+
+        assert_eq!(event(None, json!({})).extract_distinct_id(), None);
+    }
+
+    #[test]
+    fn decode_properties() {
+        let decode = |properties: serde_json::Value| {
+            let body = json!({"event": "e", "distinct_id": "user1", "properties": properties});
+            RawEvent::from_bytes(&EventQuery::default(), body.to_string().into())
+        };
+
+        let events = decode(json!({"a": 1})).unwrap();
+        assert_eq!(
+            events[0].properties,
+            HashMap::from([("a".into(), json!(1))])
+        );
+
+        let events = decode(json!("{\"a\": 1}")).unwrap();
+        assert_eq!(events[0].properties["a"], json!(1));
+        assert_eq!(
+            events[0].properties["$properties_were_stringified"],
+            json!(true)
+        );
+
+        assert!(decode(json!("[1, 2]")).is_err());
+        assert!(decode(json!("not json")).is_err());
+
+        let config = ProcessingConfig {
+            lenient_stringified_properties: true,
+            ..Default::default()
+        };
+        for stringified in ["[1, 2]", "not json"] {
+            let body = json!({"event": "e", "distinct_id": "user1", "properties": stringified});
+            let events =
+                RawEvent::from_bytes_with(&EventQuery::default(), body.to_string().into(), &config)
+                    .unwrap();
+            assert_eq!(
+                events[0].properties,
+                HashMap::from([("$properties_were_stringified".into(), json!(true))])
+            );
+        }
+        // Objects are parsed back in both modes
+        let body = json!({"event": "e", "distinct_id": "user1", "properties": "{\"a\": 1}"});
+        let events =
+            RawEvent::from_bytes_with(&EventQuery::default(), body.to_string().into(), &config)
+                .unwrap();
+        assert_eq!(events[0].properties["a"], json!(1));
+    }
+
+    #[test]
+    fn non_finite_numbers() {
+        let payload = r#"{"event": "e", "distinct_id": "user1", "properties": {"a": NaN, "b": 1}}"#;
+        let res = RawEvent::from_bytes(&EventQuery::default(), Bytes::from(payload));
+        assert!(matches!(res, Err(CaptureError::NonFiniteNumber)));
+
+        let config = ProcessingConfig {
+            non_finite_numbers: NonFinitePolicy::Null,
+            ..Default::default()
+        };
+        let events =
+            RawEvent::from_bytes_with(&EventQuery::default(), Bytes::from(payload), &config)
+                .unwrap();
+        assert_eq!(events[0].properties["a"], json!(null));
+        assert_eq!(events[0].properties["b"], json!(1));
+
+        let clean = r#"{"event": "e", "distinct_id": "user1", "properties": {"a": 1.5}}"#;
+        let events =
+            RawEvent::from_bytes_with(&EventQuery::default(), Bytes::from(clean), &config).unwrap();
+        assert_eq!(events[0].properties["a"], json!(1.5));
+
+        let invalid = r#"{"event": "e", "properties": {"a": }}"#;
+        let res = RawEvent::from_bytes(&EventQuery::default(), Bytes::from(invalid));
+        assert!(matches!(res, Err(CaptureError::RequestParsingError(_))));
+    }
+
+    #[test]
+    fn lone_surrogates() {
+        let payload = r#"{"event": "e", "distinct_id": "user1", "properties": {"a": "x\ud800", "b": "\ud83d\ude00"}}"#;
+        let events = RawEvent::from_bytes(&EventQuery::default(), Bytes::from(payload)).unwrap();
+        assert_eq!(events[0].properties["a"], json!("x\u{fffd}"));
+        assert_eq!(events[0].properties["b"], json!("😀"));
+
+        let config = ProcessingConfig {
+            strict_unicode: true,
+            ..Default::default()
+        };
+        let res = RawEvent::from_bytes_with(&EventQuery::default(), Bytes::from(payload), &config);
+        assert!(matches!(res, Err(CaptureError::InvalidUnicode)));
+
+        // Surrogate pairs are valid in strict mode
+        let emoji =
+            r#"{"event": "e", "distinct_id": "user1", "properties": {"b": "\ud83d\ude00"}}"#;
+        let events =
+            RawEvent::from_bytes_with(&EventQuery::default(), Bytes::from(emoji), &config).unwrap();
+        assert_eq!(events[0].properties["b"], json!("😀"));
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_round_trip() {
+        let event = ProcessedEvent {
+            uuid: crate::utils::uuid_v7(),
+            distinct_id: String::from("user1"),
+            ip: String::from("127.0.0.1"),
+            data: String::from(r#"{"event":"$snapshot"}"#),
+            now: String::from("2023-10-26T12:00:05Z"),
+            sent_at: Some(datetime!(2023-10-26 14:00:00.123 +02:00)),
+            token: String::from("token"),
+            process_person_profile: false,
+            event: String::from("$snapshot"),
+            session_id: Some(String::from("session")),
+            timezone: Some(String::from("Europe/Paris")),
+            seq: 42,
+            trace_id: Some(String::from("0000000000000001")),
+            is_test: true,
+            ingest_region: Some(String::from("eu-west-1")),
+            processing_duration: Some(std::time::Duration::from_micros(12)),
+            ingest_ts_nanos: 1698321605123456789,
+            original_index: 3,
+            expires_at: Some(datetime!(2023-11-25 12:00:05 UTC)),
+        };
+
+        let encoded = event.to_bincode().expect("failed to encode event");
+        let decoded = ProcessedEvent::from_bincode(&encoded).expect("failed to decode event");
+        assert_eq!(decoded, event);
+        assert_eq!(
+            decoded.sent_at.unwrap().offset(),
+            event.sent_at.unwrap().offset()
+        );
+
+        assert!(ProcessedEvent::from_bincode(&encoded[..10]).is_err());
+    }
+
+    #[test]
+    fn strip_ignored_person_updates() {
+        let event = |process_person_profile: bool| RawEvent {
+            event: String::from("pageview"),
+            properties: serde_json::from_value(json!({
+                "$process_person_profile": process_person_profile,
+                "$set": {"plan": "free"},
+                "url": "https://example.com"
+            }))
+            .unwrap(),
+            set: Some(HashMap::from([("email".into(), json!("a@b.c"))])),
+            set_once: Some(HashMap::from([("first_seen".into(), json!("today"))])),
+            ..Default::default()
+        };
+
+        let mut anonymous = event(false);
+        assert!(anonymous.strip_ignored_person_updates());
+        assert!(anonymous.set.is_none());
+        assert!(anonymous.set_once.is_none());
+        assert!(!anonymous.properties.contains_key("$set"));
+        assert_eq!(anonymous.properties["url"], json!("https://example.com"));
+        assert!(!anonymous.strip_ignored_person_updates());
+
+        let mut identified = event(true);
+        assert!(!identified.strip_ignored_person_updates());
+        assert!(identified.set.is_some());
+        assert!(identified.set_once.is_some());
+        assert!(identified.properties.contains_key("$set"));
+    }
+
+    #[test]
+    fn from_bytes_preserving_returns_input() {
+        let mut encoder = GzEncoder::new(Vec::new(), GzCompression::default());
+        encoder
+            .write_all(br#"{"event": "e", "distinct_id": "user1"}"#)
+            .unwrap();
+        let input = Bytes::from(encoder.finish().unwrap());
+
+        let (events, original) =
+            RawEvent::from_bytes_preserving(&EventQuery::default(), input.clone()).unwrap();
+        assert_eq!(events[0].event, "e");
+        assert_eq!(original, input);
+        // Not copied
+        assert_eq!(original.as_ptr(), input.as_ptr());
+    }
 }
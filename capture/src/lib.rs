@@ -1,15 +1,32 @@
 pub mod api;
 pub mod billing_limits;
 pub mod capture;
+pub mod concurrency_limits;
 pub mod config;
+pub mod csv;
+pub mod decode_metrics;
+pub mod decompression;
+pub mod dedup;
 pub mod event;
 pub mod health;
+pub mod multipart;
+pub mod normalization;
+pub mod ownership;
 pub mod partition_limits;
 pub mod prometheus;
+#[cfg(feature = "protobuf")]
+pub mod protobuf;
+pub mod pseudonymize;
 pub mod redis;
 pub mod router;
+pub mod rudderstack;
+pub mod schema;
+pub mod sequence;
 pub mod server;
 pub mod sink;
+pub mod snowplow;
 pub mod time;
 pub mod token;
+pub mod user_agent;
 pub mod utils;
+pub mod zip;
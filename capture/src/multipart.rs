@@ -0,0 +1,222 @@
+// Decoding of multipart/form-data bodies, used by mobile SDKs uploading attachments along
+// with their events
+
+use base64::Engine;
+use bytes::Bytes;
+
+use crate::api::CaptureError;
+use crate::config::ProcessingConfig;
+use crate::event::{EventQuery, RawEvent};
+
+// Name of the part holding the events
+const EVENTS_PART: &str = "events";
+
+pub struct MultipartEvents {
+    pub events: Vec<RawEvent>,
+    /// Names of the other parts, which are not ingested
+    pub attachments: Vec<String>,
+}
+
+struct Part<'a> {
+    name: String,
+    body: &'a [u8],
+}
+
+/// Extract the events from a multipart body. The events part holds either JSON, possibly
+/// gzipped, or base64 encoded data.
+pub fn parse_multipart(
+    content_type: &str,
+    query: &EventQuery,
+    body: Bytes,
+    config: &ProcessingConfig,
+) -> Result<MultipartEvents, CaptureError> {
+    let boundary = boundary(content_type).ok_or_else(|| {
+        CaptureError::RequestDecodingError(String::from("missing multipart boundary"))
+    })?;
+    let parts = split_parts(&body, boundary.as_bytes())?;
+
+    let mut events = None;
+    let mut attachments = vec![];
+    for part in parts {
+        if part.name == EVENTS_PART && events.is_none() {
+            events = Some(decode_events(query, part.body, config)?);
+        } else {
+            attachments.push(part.name);
+        }
+    }
+
+    match events {
+        Some(events) => Ok(MultipartEvents {
+            events,
+            attachments,
+        }),
+        None => Err(CaptureError::RequestDecodingError(String::from(
+            "missing events part",
+        ))),
+    }
+}
+
+fn decode_events(
+    query: &EventQuery,
+    body: &[u8],
+    config: &ProcessingConfig,
+) -> Result<Vec<RawEvent>, CaptureError> {
+    let start = body.iter().position(|b| !b.is_ascii_whitespace());
+    let end = body.iter().rposition(|b| !b.is_ascii_whitespace());
+    let trimmed = match (start, end) {
+        (Some(start), Some(end)) => &body[start..=end],
+        _ => &[],
+    };
+    if trimmed.starts_with(b"{") || trimmed.starts_with(b"[") {
+        return RawEvent::from_bytes_with(query, Bytes::copy_from_slice(trimmed), config);
+    }
+    // Gzipped payloads are sniffed by from_bytes
+    let payload = base64::engine::general_purpose::STANDARD
+        .decode(trimmed)
+        .unwrap_or_else(|_| body.to_vec());
+    RawEvent::from_bytes_with(query, payload.into(), config)
+}
+
+fn boundary(content_type: &str) -> Option<String> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.trim().split_once('=')?;
+        key.eq_ignore_ascii_case("boundary")
+            .then(|| value.trim_matches('"').to_string())
+            .filter(|boundary| !boundary.is_empty())
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|position| position + from)
+}
+
+fn split_parts<'a>(body: &'a [u8], boundary: &[u8]) -> Result<Vec<Part<'a>>, CaptureError> {
+    let invalid = || CaptureError::RequestDecodingError(String::from("invalid multipart body"));
+    let delimiter = [b"--", boundary].concat();
+    let next_delimiter = [b"\r\n--", boundary].concat();
+
+    let mut parts = vec![];
+    let mut position = find(body, &delimiter, 0).ok_or_else(invalid)? + delimiter.len();
+    loop {
+        let rest = body.get(position..).ok_or_else(invalid)?;
+        if rest.starts_with(b"--") {
+            return Ok(parts);
+        }
+        let headers_start = position + rest.strip_prefix(b"\r\n").map_or(0, |_| 2);
+        let headers_end = find(body, b"\r\n\r\n", headers_start).ok_or_else(invalid)?;
+        let body_end = find(body, &next_delimiter, headers_end + 4).ok_or_else(invalid)?;
+
+        let headers = String::from_utf8_lossy(&body[headers_start..headers_end]);
+        parts.push(Part {
+            name: part_name(&headers).unwrap_or_default(),
+            body: &body[headers_end + 4..body_end],
+        });
+        position = body_end + next_delimiter.len();
+    }
+}
+
+/// Name of a part, from its Content-Disposition header.
+fn part_name(headers: &str) -> Option<String> {
+    let disposition = headers
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-disposition"))?
+        .1;
+    disposition.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.trim().split_once('=')?;
+        (key == "name").then(|| value.trim_matches('"').to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use base64::Engine;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    use crate::api::CaptureError;
+    use crate::config::ProcessingConfig;
+    use crate::event::EventQuery;
+    use crate::multipart::parse_multipart;
+
+    const CONTENT_TYPE: &str = "multipart/form-data; boundary=\"xYzZY\"";
+
+    fn multipart_body(events: &[u8]) -> Vec<u8> {
+        [
+            b"--xYzZY\r\n".as_slice(),
+            b"Content-Disposition: form-data; name=\"events\"\r\n",
+            b"Content-Type: application/json\r\n\r\n",
+            events,
+            b"\r\n--xYzZY\r\n",
+            b"Content-Disposition: form-data; name=\"screenshot\"; filename=\"bug.png\"\r\n",
+            b"Content-Type: image/png\r\n\r\n",
+            &[0x89, b'P', b'N', b'G', 0xff, 0x00, b'\r', b'\n'],
+            b"\r\n--xYzZY--\r\n",
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn events_and_file_parts() {
+        let events = br#"[{"event": "bug_report", "distinct_id": "user1", "api_key": "token"}]"#;
+        let body = multipart_body(events);
+
+        let parsed = parse_multipart(
+            CONTENT_TYPE,
+            &EventQuery::default(),
+            body.into(),
+            &ProcessingConfig::default(),
+        )
+        .expect("failed to parse multipart body");
+        assert_eq!(parsed.events.len(), 1);
+        assert_eq!(parsed.events[0].event, "bug_report");
+        assert_eq!(parsed.attachments, vec!["screenshot"]);
+    }
+
+    #[test]
+    fn base64_gzip_events_part() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(br#"{"event": "bug_report", "distinct_id": "user1"}"#)
+            .unwrap();
+        let encoded = base64::engine::general_purpose::STANDARD.encode(encoder.finish().unwrap());
+        let body = multipart_body(encoded.as_bytes());
+
+        let parsed = parse_multipart(
+            CONTENT_TYPE,
+            &EventQuery::default(),
+            body.into(),
+            &ProcessingConfig::default(),
+        )
+        .expect("failed to parse multipart body");
+        assert_eq!(parsed.events[0].event, "bug_report");
+    }
+
+    #[test]
+    fn missing_events_part() {
+        let body =
+            b"--xYzZY\r\nContent-Disposition: form-data; name=\"other\"\r\n\r\n{}\r\n--xYzZY--";
+
+        let res = parse_multipart(
+            CONTENT_TYPE,
+            &EventQuery::default(),
+            body.as_slice().into(),
+            &ProcessingConfig::default(),
+        );
+        assert!(matches!(res, Err(CaptureError::RequestDecodingError(_))));
+
+        let res = parse_multipart(
+            "multipart/form-data",
+            &EventQuery::default(),
+            body.as_slice().into(),
+            &ProcessingConfig::default(),
+        );
+        assert!(matches!(res, Err(CaptureError::RequestDecodingError(_))));
+    }
+}
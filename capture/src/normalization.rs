@@ -0,0 +1,1505 @@
+// Guards and normalization steps applied to event properties before they are serialized
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use serde_json::Value;
+
+use crate::api::CaptureError;
+
+// Property recording the original length of arrays truncated by `cap_arrays`
+pub const TRUNCATED_ARRAYS_PROPERTY: &str = "$truncated_arrays";
+
+// Property listing the keys whose values were clamped by `clamp_numbers`
+pub const CLAMPED_PROPERTIES_PROPERTY: &str = "$clamped_properties";
+
+// Property listing the keys removed by `drop_largest_properties`
+pub const DROPPED_PROPERTIES_PROPERTY: &str = "$dropped_properties";
+
+// Property flagging events whose `$lib` is not a known library, see `normalize_lib`
+pub const LIB_UNKNOWN_PROPERTY: &str = "$lib_unknown";
+
+// Property recording how many properties `strip_empty_properties` removed
+pub const EMPTY_PROPERTIES_REMOVED_PROPERTY: &str = "$empty_properties_removed";
+
+// Property listing the keys whose values had IP addresses masked by `redact_ip_addresses`
+pub const REDACTED_IP_PROPERTIES_PROPERTY: &str = "$redacted_ip_properties";
+
+// Replacement of the IP addresses masked by `redact_ip_addresses`
+const IP_MASK: &str = "[redacted]";
+
+// Property flagging events whose DOM elements were removed by `strip_elements`
+pub const ELEMENTS_STRIPPED_PROPERTY: &str = "$elements_stripped";
+
+// Flag of events opting out of GeoIP enrichment, see `normalize_geoip_disable`
+pub const GEOIP_DISABLE_PROPERTY: &str = "$geoip_disable";
+
+// Properties holding the DOM elements clicked on by autocaptured events
+const ELEMENTS_PROPERTIES: [&str; 2] = ["$elements", "$elements_chain"];
+
+// Property keeping the sent `$lib_version` when `LibVersion` cannot represent it exactly
+pub const LIB_VERSION_RAW_PROPERTY: &str = "$lib_version__raw";
+
+/// Truncate strings longer than `max_chars` characters, at any depth. Returns the number of
+/// strings that were truncated.
+pub fn truncate_strings(value: &mut Value, max_chars: usize) -> usize {
+    match value {
+        Value::String(s) => match s.char_indices().nth(max_chars) {
+            Some((boundary, _)) => {
+                s.truncate(boundary);
+                1
+            }
+            None => 0,
+        },
+        Value::Array(values) => values
+            .iter_mut()
+            .map(|v| truncate_strings(v, max_chars))
+            .sum(),
+        Value::Object(map) => map
+            .values_mut()
+            .map(|v| truncate_strings(v, max_chars))
+            .sum(),
+        _ => 0,
+    }
+}
+
+/// Nesting depth of a value: 0 for scalars, 1 for a flat array or object, and so on.
+pub fn depth(value: &Value) -> usize {
+    match value {
+        Value::Array(values) => 1 + values.iter().map(depth).max().unwrap_or(0),
+        Value::Object(map) => 1 + map.values().map(depth).max().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+/// Truncate top-level array properties to `max_len` elements, recording the original length
+/// of each truncated array under `$truncated_arrays`. Arrays nested in other values are left
+/// untouched.
+pub fn cap_arrays(properties: &mut HashMap<String, Value>, max_len: usize) {
+    let mut truncated = serde_json::Map::new();
+    for (key, value) in properties.iter_mut() {
+        if let Value::Array(values) = value {
+            if values.len() > max_len {
+                truncated.insert(key.clone(), Value::from(values.len()));
+                values.truncate(max_len);
+            }
+        }
+    }
+    if !truncated.is_empty() {
+        properties.insert(
+            TRUNCATED_ARRAYS_PROPERTY.to_string(),
+            Value::Object(truncated),
+        );
+    }
+}
+
+/// Remove the properties holding an empty string, null, an empty array or an empty object,
+/// recording their count in `$empty_properties_removed`. Reserved `$` properties are kept.
+/// Returns the number of properties removed.
+pub fn strip_empty_properties(properties: &mut HashMap<String, Value>) -> usize {
+    let before = properties.len();
+    properties.retain(|key, value| {
+        key.starts_with('$')
+            || !match value {
+                Value::Null => true,
+                Value::String(s) => s.is_empty(),
+                Value::Array(values) => values.is_empty(),
+                Value::Object(map) => map.is_empty(),
+                Value::Bool(_) | Value::Number(_) => false,
+            }
+    });
+    let removed = before - properties.len();
+    if removed > 0 {
+        properties.insert(
+            EMPTY_PROPERTIES_REMOVED_PROPERTY.to_string(),
+            Value::from(removed),
+        );
+    }
+    removed
+}
+
+/// Clamp numeric properties into their `(min, max)` range of `bounds`, listing the clamped keys
+/// in `$clamped_properties`. Returns the clamped keys, sorted. Integers stay integers when the
+/// bound they are clamped to is one.
+pub fn clamp_numbers(
+    properties: &mut HashMap<String, Value>,
+    bounds: &HashMap<String, (f64, f64)>,
+) -> Vec<String> {
+    let mut clamped = Vec::new();
+    for (key, (min, max)) in bounds {
+        let Some(value) = properties.get_mut(key) else {
+            continue;
+        };
+        let Some(number) = value.as_f64() else {
+            tracing::warn!(key, "not clamping non numeric property");
+            continue;
+        };
+        if number >= *min && number <= *max {
+            continue;
+        }
+        let bound = number.clamp(*min, *max);
+        *value = if value.is_f64() || bound.fract() != 0.0 {
+            Value::from(bound)
+        } else {
+            Value::from(bound as i64)
+        };
+        clamped.push(key.clone());
+    }
+    clamped.sort();
+    if !clamped.is_empty() {
+        properties.insert(
+            CLAMPED_PROPERTIES_PROPERTY.to_string(),
+            Value::from(clamped.clone()),
+        );
+    }
+    clamped
+}
+
+/// Remove the largest properties until their JSON serialization fits `budget` bytes, listing
+/// the removed keys in `$dropped_properties`. Reserved `$` properties are never removed, the
+/// budget may not be met if they exceed it on their own. Returns the removed keys.
+pub fn drop_largest_properties(
+    properties: &mut HashMap<String, Value>,
+    budget: usize,
+) -> Vec<String> {
+    let mut sizes: Vec<(usize, String)> = properties
+        .iter()
+        .filter(|(key, _)| !key.starts_with('$'))
+        .map(|(key, value)| (serialized_len(value) + serialized_len(key), key.clone()))
+        .collect();
+    // Largest last, ties dropped in key order for stable results
+    sizes.sort_by(|(a_len, a_key), (b_len, b_key)| a_len.cmp(b_len).then(b_key.cmp(a_key)));
+
+    let mut dropped = Vec::new();
+    while serialized_len(&*properties) > budget {
+        let Some((_, key)) = sizes.pop() else {
+            break;
+        };
+        properties.remove(&key);
+        dropped.push(key);
+        properties.insert(
+            DROPPED_PROPERTIES_PROPERTY.to_string(),
+            Value::from(dropped.clone()),
+        );
+    }
+    dropped
+}
+
+fn serialized_len<T: serde::Serialize>(value: &T) -> usize {
+    serde_json::to_vec(value).map_or(0, |bytes| bytes.len())
+}
+
+const TRUTHY_STRINGS: [&str; 5] = ["true", "yes", "y", "on", "1"];
+const FALSY_STRINGS: [&str; 5] = ["false", "no", "n", "off", "0"];
+
+/// Convert the string values of the `keys` properties to booleans when they are a common
+/// truthy or falsy token, such as `yes` or `0`. Other values are left untouched.
+pub fn normalize_booleans(properties: &mut HashMap<String, Value>, keys: &HashSet<String>) {
+    for key in keys {
+        let Some(Value::String(value)) = properties.get(key) else {
+            continue;
+        };
+        let Some(normalized) = parse_boolean_token(value) else {
+            tracing::warn!(key, value, "not normalizing ambiguous boolean property");
+            continue;
+        };
+        properties.insert(key.clone(), Value::Bool(normalized));
+    }
+}
+
+fn parse_boolean_token(value: &str) -> Option<bool> {
+    let token = value.trim().to_lowercase();
+    if TRUTHY_STRINGS.contains(&token.as_str()) {
+        Some(true)
+    } else if FALSY_STRINGS.contains(&token.as_str()) {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+// Digits of an E.164 number, calling code included
+const MIN_PHONE_DIGITS: usize = 7;
+const MAX_PHONE_DIGITS: usize = 15;
+
+/// Rewrite the string values of the `keys` properties recognized as phone numbers into E.164,
+/// `+` followed by the calling code and the subscriber number. Spaces, dashes, dots and
+/// parentheses are dropped. Numbers starting with `+` or `00` are international, the others
+/// are given the `default_calling_code` in place of their trunk `0`. Other values are left
+/// untouched.
+pub fn normalize_phone_numbers(
+    properties: &mut HashMap<String, Value>,
+    keys: &HashSet<String>,
+    default_calling_code: Option<u16>,
+) {
+    for key in keys {
+        let Some(Value::String(value)) = properties.get_mut(key) else {
+            continue;
+        };
+        match to_e164(value, default_calling_code) {
+            Some(normalized) => *value = normalized,
+            None => tracing::warn!(key, value, "not normalizing unrecognized phone number"),
+        }
+    }
+}
+
+fn to_e164(value: &str, default_calling_code: Option<u16>) -> Option<String> {
+    let value = value.trim();
+    let (international, number) = match value.strip_prefix('+') {
+        Some(number) => (true, number),
+        None => match value.strip_prefix("00") {
+            Some(number) => (true, number),
+            None => (false, value),
+        },
+    };
+    if !number
+        .chars()
+        .all(|c| c.is_ascii_digit() || matches!(c, ' ' | '-' | '.' | '(' | ')'))
+    {
+        return None;
+    }
+    let digits: String = number.chars().filter(char::is_ascii_digit).collect();
+
+    let digits = if international {
+        // Calling codes never start with 0
+        if digits.starts_with('0') {
+            return None;
+        }
+        digits
+    } else {
+        let national = digits.strip_prefix('0').unwrap_or(&digits);
+        format!("{}{}", default_calling_code?, national)
+    };
+    (MIN_PHONE_DIGITS..=MAX_PHONE_DIGITS)
+        .contains(&digits.len())
+        .then(|| format!("+{}", digits))
+}
+
+/// Parse the `$geoip_disable` flag of an event, with the boolean tokens of `normalize_booleans`
+/// and 0 or 1. Capture does no GeoIP enrichment itself: a set flag is kept as `true` for
+/// ingestion to skip the event, unset and unparseable ones are removed. Returns whether GeoIP
+/// enrichment is disabled.
+pub fn normalize_geoip_disable(properties: &mut HashMap<String, Value>) -> bool {
+    let disabled = match properties.get(GEOIP_DISABLE_PROPERTY) {
+        None => return false,
+        Some(Value::Bool(value)) => Some(*value),
+        Some(Value::String(value)) => parse_boolean_token(value),
+        Some(Value::Number(value)) => match value.as_u64() {
+            Some(0) => Some(false),
+            Some(1) => Some(true),
+            _ => None,
+        },
+        Some(_) => None,
+    };
+    match disabled {
+        Some(true) => {
+            properties.insert(String::from(GEOIP_DISABLE_PROPERTY), Value::Bool(true));
+            true
+        }
+        Some(false) => {
+            properties.remove(GEOIP_DISABLE_PROPERTY);
+            false
+        }
+        None => {
+            let value = properties.remove(GEOIP_DISABLE_PROPERTY);
+            tracing::warn!(?value, "removed invalid $geoip_disable flag");
+            false
+        }
+    }
+}
+
+/// Remove the properties not listed in `allowlist`.
+pub fn prune_properties(properties: &mut HashMap<String, Value>, allowlist: &HashSet<String>) {
+    properties.retain(|key, _| allowlist.contains(key));
+}
+
+/// Remove the `$elements` and `$elements_chain` properties, setting `$elements_stripped` if
+/// any of them was present. Returns whether properties were removed.
+pub fn strip_elements(properties: &mut HashMap<String, Value>) -> bool {
+    let mut stripped = false;
+    for key in ELEMENTS_PROPERTIES {
+        stripped |= properties.remove(key).is_some();
+    }
+    if stripped {
+        properties.insert(String::from(ELEMENTS_STRIPPED_PROPERTY), Value::Bool(true));
+    }
+    stripped
+}
+
+/// Set the properties of `defaults` that an event is missing, values sent by the client being
+/// kept. Returns the number of properties set.
+pub fn apply_property_defaults(
+    properties: &mut HashMap<String, Value>,
+    defaults: &HashMap<String, Value>,
+) -> usize {
+    let mut applied = 0;
+    for (key, value) in defaults {
+        if !properties.contains_key(key) {
+            properties.insert(key.clone(), value.clone());
+            applied += 1;
+        }
+    }
+    applied
+}
+
+/// Rename property keys, in the order of `renames`. A renamed value replaces any existing value
+/// of its new key, except for reserved `$` keys: those are only replaced if they are renamed
+/// themselves first.
+pub fn rename_properties(properties: &mut HashMap<String, Value>, renames: &[(String, String)]) {
+    for (from, to) in renames {
+        if from == to || !properties.contains_key(from) {
+            continue;
+        }
+        if properties.contains_key(to) {
+            if to.starts_with('$') {
+                tracing::warn!(from, to, "not renaming property over a reserved property");
+                continue;
+            }
+            tracing::warn!(from, to, "renamed property replaces an existing one");
+        }
+        if let Some(value) = properties.remove(from) {
+            properties.insert(to.clone(), value);
+        }
+    }
+}
+
+/// Prefix the keys of non-reserved properties with `namespace`, such as `crm.`, keeping the
+/// keys that already start with it, that win on collisions. Applying it again is a no-op.
+pub fn namespace_properties(properties: &mut HashMap<String, Value>, namespace: &str) {
+    let keys: Vec<String> = properties
+        .keys()
+        .filter(|key| !key.starts_with('$') && !key.starts_with(namespace))
+        .cloned()
+        .collect();
+    for key in keys {
+        if let Some(value) = properties.remove(&key) {
+            properties
+                .entry(format!("{namespace}{key}"))
+                .or_insert(value);
+        }
+    }
+}
+
+/// Some clients escape unicode twice, `é` reaching us as the literal `\u00e9`. Decode these
+/// `\uXXXX` sequences, including surrogate pairs, in the string values of the `keys`
+/// properties. Other backslashes and invalid sequences are left untouched.
+pub fn unescape_unicode(properties: &mut HashMap<String, Value>, keys: &HashSet<String>) {
+    for key in keys {
+        if let Some(Value::String(value)) = properties.get_mut(key) {
+            if let Some(unescaped) = unescape_unicode_sequences(value) {
+                *value = unescaped;
+            }
+        }
+    }
+}
+
+/// Returns None if `value` holds no valid `\uXXXX` sequence.
+fn unescape_unicode_sequences(value: &str) -> Option<String> {
+    let code_unit = |rest: &str| {
+        let hex = rest.strip_prefix("\\u")?.get(..4)?;
+        if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        u16::from_str_radix(hex, 16).ok()
+    };
+
+    let mut output = String::with_capacity(value.len());
+    let mut unescaped = false;
+    let mut rest = value;
+    while let Some(start) = rest.find("\\u") {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(high) = code_unit(rest) else {
+            output.push('\\');
+            rest = &rest[1..];
+            continue;
+        };
+        // Characters outside of the BMP are escaped as a surrogate pair, \uD83D\uDE00
+        let low = code_unit(&rest[6..]);
+        let (decoded, len) = match char::decode_utf16([high, low.unwrap_or(0)]).next() {
+            Some(Ok(c)) if c.len_utf16() == 2 => (Some(c), 12),
+            Some(Ok(c)) => (Some(c), 6),
+            _ => (None, 6),
+        };
+        match decoded {
+            Some(c) => {
+                output.push(c);
+                unescaped = true;
+            }
+            // Lone surrogate, keep it escaped
+            None => output.push_str(&rest[..len]),
+        }
+        rest = &rest[len..];
+    }
+    output.push_str(rest);
+    unescaped.then_some(output)
+}
+
+/// Lowercase the `$lib` property, and flag it with `$lib_unknown` if it is not in `known`,
+/// expected lowercase. Events without a string `$lib` are left untouched.
+pub fn normalize_lib(properties: &mut HashMap<String, Value>, known: &HashSet<String>) {
+    let Some(Value::String(lib)) = properties.get_mut("$lib") else {
+        return;
+    };
+    *lib = lib.to_lowercase();
+    if !known.contains(lib.as_str()) {
+        properties.insert(String::from(LIB_UNKNOWN_PROPERTY), Value::Bool(true));
+    }
+}
+
+/// Remove the `strip` query parameters from the `$current_url` property, then truncate it to
+/// `max_chars` characters. Values that are not absolute URLs are left untouched.
+pub fn normalize_current_url(
+    properties: &mut HashMap<String, Value>,
+    strip: &HashSet<String>,
+    max_chars: Option<usize>,
+) {
+    let Some(Value::String(url)) = properties.get_mut("$current_url") else {
+        return;
+    };
+    if !is_absolute_url(url) {
+        tracing::warn!(url, "not normalizing invalid $current_url");
+        return;
+    }
+
+    if !strip.is_empty() {
+        let (rest, fragment) = match url.split_once('#') {
+            Some((rest, fragment)) => (rest, Some(fragment)),
+            None => (url.as_str(), None),
+        };
+        if let Some((base, query)) = rest.split_once('?') {
+            let kept: Vec<&str> = query
+                .split('&')
+                .filter(|param| {
+                    let name = param.split_once('=').map_or(*param, |(name, _)| name);
+                    !param.is_empty() && !strip.contains(name)
+                })
+                .collect();
+            let mut normalized = base.to_string();
+            if !kept.is_empty() {
+                normalized.push('?');
+                normalized.push_str(&kept.join("&"));
+            }
+            if let Some(fragment) = fragment {
+                normalized.push('#');
+                normalized.push_str(fragment);
+            }
+            *url = normalized;
+        }
+    }
+
+    if let Some(max_chars) = max_chars {
+        if let Some((index, _)) = url.char_indices().nth(max_chars) {
+            url.truncate(index);
+        }
+    }
+}
+
+// Campaign parameters of `$current_url` copied to `$`-prefixed properties by `extract_utm_params`
+const UTM_PARAMS: [&str; 5] = [
+    "utm_source",
+    "utm_medium",
+    "utm_campaign",
+    "utm_term",
+    "utm_content",
+];
+
+/// Copy the UTM parameters of the `$current_url` query string to `$utm_source` and the like,
+/// values set by the client being kept. Empty parameters are skipped, and the first of repeated
+/// ones wins. Returns the number of properties set.
+pub fn extract_utm_params(properties: &mut HashMap<String, Value>) -> usize {
+    let Some(Value::String(url)) = properties.get("$current_url") else {
+        return 0;
+    };
+    let query = url
+        .split('#')
+        .next()
+        .and_then(|rest| rest.split_once('?'))
+        .map_or("", |(_, query)| query);
+    let Ok(params) = serde_urlencoded::from_str::<Vec<(String, String)>>(query) else {
+        tracing::debug!(url, "not extracting utm params of an invalid query string");
+        return 0;
+    };
+
+    let mut extracted = 0;
+    for (name, value) in params {
+        if value.is_empty() || !UTM_PARAMS.contains(&name.as_str()) {
+            continue;
+        }
+        if let Entry::Vacant(entry) = properties.entry(format!("${}", name)) {
+            entry.insert(Value::String(value));
+            extracted += 1;
+        }
+    }
+    extracted
+}
+
+// Keys removed by `merge_case_colliding_keys`
+pub const MERGED_CASE_KEYS_PROPERTY: &str = "$merged_case_keys";
+
+/// Merge the property keys that only differ by case, such as `URL` and `url`, which collide in
+/// case-insensitive stores. The order properties were sent in is lost once they are parsed, so
+/// the key sorting last wins for the outcome to be deterministic: `url` over `URL`. Reserved `$`
+/// keys are left alone. Returns the removed keys, also listed in `$merged_case_keys`.
+pub fn merge_case_colliding_keys(properties: &mut HashMap<String, Value>) -> Vec<String> {
+    let mut groups: HashMap<String, Vec<&String>> = HashMap::new();
+    for key in properties.keys().filter(|key| !key.starts_with('$')) {
+        groups.entry(key.to_lowercase()).or_default().push(key);
+    }
+    let mut merged: Vec<String> = groups
+        .into_values()
+        .filter(|keys| keys.len() > 1)
+        .flat_map(|mut keys| {
+            keys.sort();
+            keys.pop();
+            keys.into_iter().cloned()
+        })
+        .collect();
+    if merged.is_empty() {
+        return merged;
+    }
+
+    merged.sort();
+    for key in &merged {
+        properties.remove(key);
+    }
+    properties.insert(
+        MERGED_CASE_KEYS_PROPERTY.to_string(),
+        Value::from(merged.clone()),
+    );
+    merged
+}
+
+// Scheme and host are required, as in the URLs browsers report
+fn is_absolute_url(url: &str) -> bool {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return false;
+    };
+    let valid_scheme = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+    let host = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    valid_scheme && !host.is_empty() && !url.contains(char::is_whitespace)
+}
+
+/// Structured `major.minor.patch` client library version. Parsing is lenient: a leading `v`
+/// is accepted, missing components default to 0 and anything after the patch is ignored.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LibVersion {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+}
+
+impl LibVersion {
+    /// Returns None if the version does not start with a number.
+    pub fn parse(raw: &str) -> Option<Self> {
+        let mut components = raw
+            .strip_prefix('v')
+            .unwrap_or(raw)
+            .splitn(3, '.')
+            .map(|c| {
+                let digits = c.find(|c: char| !c.is_ascii_digit()).unwrap_or(c.len());
+                c[..digits].parse::<u64>().ok()
+            });
+        let major = components.next()??;
+        let mut next = || components.next().flatten().unwrap_or(0);
+        Some(Self {
+            major,
+            minor: next(),
+            patch: next(),
+        })
+    }
+}
+
+impl fmt::Display for LibVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Reject events sent by a `$lib` older than its minimum version. Events from other libraries
+/// pass, as do events without a parseable `$lib_version` unless `strict`.
+pub fn check_lib_version(
+    properties: &HashMap<String, Value>,
+    minimums: &HashMap<String, LibVersion>,
+    strict: bool,
+) -> Result<(), CaptureError> {
+    let Some(Value::String(lib)) = properties.get("$lib") else {
+        return Ok(());
+    };
+    let Some(minimum) = minimums.get(&lib.to_lowercase()) else {
+        return Ok(());
+    };
+    let raw = match properties.get("$lib_version") {
+        Some(Value::String(raw)) => raw.clone(),
+        Some(Value::Number(raw)) => raw.to_string(),
+        _ => String::new(),
+    };
+    let unsupported = match LibVersion::parse(&raw) {
+        Some(version) => version < *minimum,
+        None => strict,
+    };
+    if unsupported {
+        return Err(CaptureError::UnsupportedSdkVersion {
+            lib: lib.clone(),
+            version: raw,
+        });
+    }
+    Ok(())
+}
+
+/// Copy `$lib_version` to `$lib_version__raw` when parsing it into a `LibVersion` loses
+/// information, so the exact string sent by the client stays available.
+pub fn preserve_raw_lib_version(properties: &mut HashMap<String, Value>) {
+    let Some(Value::String(raw)) = properties.get("$lib_version") else {
+        return;
+    };
+    let lossless = LibVersion::parse(raw).is_some_and(|version| version.to_string() == *raw);
+    if !lossless {
+        let raw = Value::String(raw.clone());
+        properties.insert(String::from(LIB_VERSION_RAW_PROPERTY), raw);
+    }
+}
+
+/// Mask the IPv4 and IPv6 addresses found in string properties, at any depth, listing the
+/// affected keys in `$redacted_ip_properties`. Returns the affected keys, sorted.
+pub fn redact_ip_addresses(properties: &mut HashMap<String, Value>) -> Vec<String> {
+    let mut redacted: Vec<String> = properties
+        .iter_mut()
+        .filter_map(|(key, value)| mask_ip_addresses(value).then(|| key.clone()))
+        .collect();
+    redacted.sort();
+    if !redacted.is_empty() {
+        properties.insert(
+            REDACTED_IP_PROPERTIES_PROPERTY.to_string(),
+            Value::from(redacted.clone()),
+        );
+    }
+    redacted
+}
+
+fn mask_ip_addresses(value: &mut Value) -> bool {
+    match value {
+        Value::String(s) => match mask_ip_sequences(s) {
+            Some(masked) => {
+                *s = masked;
+                true
+            }
+            None => false,
+        },
+        // Not short-circuiting, all the nested values must be masked
+        Value::Array(values) => values
+            .iter_mut()
+            .fold(false, |masked, v| mask_ip_addresses(v) | masked),
+        Value::Object(map) => map
+            .values_mut()
+            .fold(false, |masked, v| mask_ip_addresses(v) | masked),
+        _ => false,
+    }
+}
+
+/// Addresses are looked for in the runs of hex digits, dots and colons of the string, that
+/// are not part of a word. Punctuation around an address, as in `host:10.0.0.1.`, is kept.
+fn mask_ip_sequences(value: &str) -> Option<String> {
+    let is_candidate = |c: char| c.is_ascii_hexdigit() || c == '.' || c == ':';
+    let mut output = String::with_capacity(value.len());
+    let mut masked = false;
+    let mut rest = value;
+    let mut previous: Option<char> = None;
+
+    while let Some(start) = rest.find(is_candidate) {
+        let run_len = rest[start..]
+            .find(|c: char| !is_candidate(c))
+            .unwrap_or(rest.len() - start);
+        let run = &rest[start..start + run_len];
+        let before = rest[..start].chars().last().or(previous);
+        let after = rest[start + run_len..].chars().next();
+        output.push_str(&rest[..start]);
+
+        let punctuation = ['.', ':'];
+        let leading = run.len() - run.trim_start_matches(punctuation).len();
+        let trailing = run.len() - run.trim_end_matches(punctuation).len();
+        let address = [
+            (0, run.len()),
+            (0, run.len() - trailing),
+            (leading, run.len()),
+            (leading, run.len() - trailing),
+        ]
+        .into_iter()
+        .filter(|(from, to)| from < to)
+        .find(|(from, to)| {
+            let core = &run[*from..*to];
+            let bounded = (*from > 0 || !before.is_some_and(|c| c.is_alphanumeric()))
+                && (*to < run.len() || !after.is_some_and(|c| c.is_alphanumeric()));
+            bounded
+                && core.chars().any(|c| c.is_ascii_digit())
+                && core.parse::<std::net::IpAddr>().is_ok()
+        });
+        match address {
+            Some((from, to)) => {
+                output.push_str(&run[..from]);
+                output.push_str(IP_MASK);
+                output.push_str(&run[to..]);
+                masked = true;
+            }
+            None => output.push_str(run),
+        }
+        previous = run.chars().last();
+        rest = &rest[start + run_len..];
+    }
+    output.push_str(rest);
+
+    masked.then_some(output)
+}
+
+const NON_FINITE_LITERALS: [&str; 4] = ["-Infinity", "+Infinity", "Infinity", "NaN"];
+
+/// Replace the NaN and Infinity literals found outside of strings in a JSON-like payload with
+/// null. Returns None if the payload holds none.
+pub fn replace_non_finite(payload: &str) -> Option<String> {
+    let mut output = String::with_capacity(payload.len());
+    let mut replaced = false;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut rest = payload;
+
+    while let Some(c) = rest.chars().next() {
+        if in_string {
+            match (escaped, c) {
+                (true, _) => escaped = false,
+                (false, '\\') => escaped = true,
+                (false, '"') => in_string = false,
+                _ => {}
+            }
+        } else if c == '"' {
+            in_string = true;
+        } else if let Some(literal) = NON_FINITE_LITERALS.iter().find(|l| rest.starts_with(*l)) {
+            output.push_str("null");
+            rest = &rest[literal.len()..];
+            replaced = true;
+            continue;
+        }
+        output.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+
+    replaced.then_some(output)
+}
+
+// Escape of U+FFFD, the replacement character
+const REPLACEMENT_ESCAPE: &str = "\\ufffd";
+
+/// Replace the lone surrogate escapes found in the strings of a JSON payload with the escape of
+/// the replacement character: serde_json rejects them, and so do UTF-8 strict stores. Escaped
+/// high and low surrogate pairs, such as emojis, are kept. Returns None if the payload holds
+/// none.
+pub fn replace_lone_surrogates(payload: &str) -> Option<String> {
+    let bytes = payload.as_bytes();
+    let mut output = String::new();
+    let mut copied = 0;
+    let mut in_string = false;
+    let mut index = 0;
+
+    while index < bytes.len() {
+        match (in_string, bytes[index]) {
+            (false, b'"') => in_string = true,
+            (true, b'"') => in_string = false,
+            (true, b'\\') => {
+                let lone = match hex_escape_at(bytes, index) {
+                    Some(0xD800..=0xDBFF) => match hex_escape_at(bytes, index + 6) {
+                        Some(0xDC00..=0xDFFF) => {
+                            index += 12;
+                            continue;
+                        }
+                        _ => true,
+                    },
+                    Some(0xDC00..=0xDFFF) => true,
+                    _ => false,
+                };
+                if lone {
+                    output.push_str(&payload[copied..index]);
+                    output.push_str(REPLACEMENT_ESCAPE);
+                    index += 6;
+                    copied = index;
+                } else {
+                    // Skip the escaped character, that may be a quote
+                    index += 2;
+                }
+                continue;
+            }
+            _ => {}
+        }
+        index += 1;
+    }
+
+    if copied == 0 {
+        return None;
+    }
+    output.push_str(&payload[copied..]);
+    Some(output)
+}
+
+// Code unit of the `\uXXXX` escape starting at `index`
+fn hex_escape_at(bytes: &[u8], index: usize) -> Option<u16> {
+    let escape = bytes.get(index..index + 6)?;
+    if !escape.starts_with(b"\\u") {
+        return None;
+    }
+    let digits = std::str::from_utf8(&escape[2..]).ok()?;
+    u16::from_str_radix(digits, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use serde_json::json;
+
+    use crate::api::CaptureError;
+    use crate::config::MinimumLibVersions;
+    use crate::normalization::{
+        cap_arrays, check_lib_version, clamp_numbers, depth, drop_largest_properties,
+        extract_utm_params, merge_case_colliding_keys, namespace_properties, normalize_booleans,
+        normalize_current_url, normalize_geoip_disable, normalize_lib, normalize_phone_numbers,
+        preserve_raw_lib_version, redact_ip_addresses, rename_properties, replace_lone_surrogates,
+        replace_non_finite, strip_empty_properties, truncate_strings, unescape_unicode, LibVersion,
+        CLAMPED_PROPERTIES_PROPERTY, DROPPED_PROPERTIES_PROPERTY,
+        EMPTY_PROPERTIES_REMOVED_PROPERTY, GEOIP_DISABLE_PROPERTY, LIB_UNKNOWN_PROPERTY,
+        LIB_VERSION_RAW_PROPERTY, MERGED_CASE_KEYS_PROPERTY, REDACTED_IP_PROPERTIES_PROPERTY,
+    };
+
+    #[test]
+    fn truncates_nested_strings() {
+        let mut value = json!({"short": "abc", "long": "abcdef", "nested": ["ééééé", 12]});
+
+        assert_eq!(truncate_strings(&mut value, 4), 2);
+        assert_eq!(
+            value,
+            json!({"short": "abc", "long": "abcd", "nested": ["éééé", 12]})
+        );
+    }
+
+    #[test]
+    fn computes_depth() {
+        assert_eq!(depth(&json!("scalar")), 0);
+        assert_eq!(depth(&json!([])), 1);
+        assert_eq!(depth(&json!({"a": [1, {"b": 2}]})), 3);
+    }
+
+    #[test]
+    fn array_under_cap() {
+        let mut properties: HashMap<String, serde_json::Value> =
+            serde_json::from_value(json!({"items": [1, 2, 3], "name": "cart"})).unwrap();
+        let expected = properties.clone();
+
+        cap_arrays(&mut properties, 3);
+        assert_eq!(properties, expected);
+    }
+
+    #[test]
+    fn array_over_cap() {
+        let mut properties: HashMap<String, serde_json::Value> = serde_json::from_value(
+            json!({"items": [1, 2, 3, 4, 5], "tags": ["a"], "nested": {"list": [1, 2, 3, 4]}}),
+        )
+        .unwrap();
+
+        cap_arrays(&mut properties, 2);
+        assert_eq!(properties["items"], json!([1, 2]));
+        assert_eq!(properties["tags"], json!(["a"]));
+        assert_eq!(properties["nested"], json!({"list": [1, 2, 3, 4]}));
+        assert_eq!(properties["$truncated_arrays"], json!({"items": 5}));
+    }
+
+    #[test]
+    fn replaces_non_finite_literals() {
+        assert_eq!(
+            replace_non_finite(r#"{"a": NaN, "b": [Infinity, -Infinity], "c": "NaN \" Infinity"}"#)
+                .as_deref(),
+            Some(r#"{"a": null, "b": [null, null], "c": "NaN \" Infinity"}"#)
+        );
+        assert_eq!(replace_non_finite(r#"{"a": 1.5, "b": "NaN"}"#), None);
+    }
+
+    #[test]
+    fn merges_case_colliding_keys() {
+        let mut properties: HashMap<String, serde_json::Value> = serde_json::from_value(json!({
+            "URL": "https://a.com",
+            "url": "https://b.com",
+            "Url": "https://c.com",
+            "plan": "free",
+            "$browser": "Firefox",
+            "$Browser": "Chrome"
+        }))
+        .unwrap();
+
+        let merged = merge_case_colliding_keys(&mut properties);
+        assert_eq!(merged, vec!["URL", "Url"]);
+        assert_eq!(properties["url"], json!("https://b.com"));
+        assert_eq!(properties[MERGED_CASE_KEYS_PROPERTY], json!(["URL", "Url"]));
+        assert_eq!(properties["plan"], json!("free"));
+        // Reserved keys are exempt
+        assert_eq!(properties["$browser"], json!("Firefox"));
+        assert_eq!(properties["$Browser"], json!("Chrome"));
+
+        let mut properties: HashMap<String, serde_json::Value> =
+            serde_json::from_value(json!({"url": "https://a.com", "path": "/"})).unwrap();
+        assert!(merge_case_colliding_keys(&mut properties).is_empty());
+        assert_eq!(properties.len(), 2);
+    }
+
+    #[test]
+    fn replaces_lone_surrogates() {
+        assert_eq!(
+            replace_lone_surrogates(
+                r#"{"a": "x\ud800y", "b": ["\udc00", "\ud83d\ude00"], "\\ud800": 1}"#
+            )
+            .as_deref(),
+            Some(r#"{"a": "x\ufffdy", "b": ["\ufffd", "\ud83d\ude00"], "\\ud800": 1}"#)
+        );
+        assert_eq!(
+            replace_lone_surrogates(r#"{"a": "\ud83d\ude00 😀 \u00e9"}"#),
+            None
+        );
+    }
+
+    fn renames(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(from, to)| (from.to_string(), to.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn renames_properties() {
+        let mut properties: HashMap<String, serde_json::Value> =
+            serde_json::from_value(json!({"user_email": "a@b.c", "plan": "free"})).unwrap();
+
+        rename_properties(&mut properties, &renames(&[("user_email", "email")]));
+        assert_eq!(
+            properties,
+            serde_json::from_value::<HashMap<String, serde_json::Value>>(
+                json!({"email": "a@b.c", "plan": "free"})
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn rename_collision_last_write_wins() {
+        let mut properties: HashMap<String, serde_json::Value> =
+            serde_json::from_value(json!({"mail": "old", "user_email": "new", "email": "x"}))
+                .unwrap();
+
+        rename_properties(
+            &mut properties,
+            &renames(&[("mail", "email"), ("user_email", "email")]),
+        );
+        assert_eq!(properties.len(), 1);
+        assert_eq!(properties["email"], json!("new"));
+    }
+
+    #[test]
+    fn rename_reserved_keys() {
+        let mut properties: HashMap<String, serde_json::Value> =
+            serde_json::from_value(json!({"url": "custom", "$current_url": "https://a.b/"}))
+                .unwrap();
+
+        // Reserved keys are not overwritten
+        rename_properties(&mut properties, &renames(&[("url", "$current_url")]));
+        assert_eq!(properties["url"], json!("custom"));
+        assert_eq!(properties["$current_url"], json!("https://a.b/"));
+
+        // Unless explicitly moved out of the way first
+        rename_properties(
+            &mut properties,
+            &renames(&[("$current_url", "original_url"), ("url", "$current_url")]),
+        );
+        assert_eq!(properties["$current_url"], json!("custom"));
+        assert_eq!(properties["original_url"], json!("https://a.b/"));
+    }
+
+    fn lib_properties(lib: &str) -> HashMap<String, serde_json::Value> {
+        HashMap::from([(String::from("$lib"), json!(lib))])
+    }
+
+    #[test]
+    fn namespaces_properties() {
+        let mut properties: HashMap<String, serde_json::Value> = serde_json::from_value(json!({
+            "plan": "pro",
+            "crm.account": "acme",
+            "$current_url": "https://example.com",
+            "$set": {"email": "a@example.com"},
+        }))
+        .unwrap();
+        let expected: HashMap<String, serde_json::Value> = serde_json::from_value(json!({
+            "crm.plan": "pro",
+            "crm.account": "acme",
+            "$current_url": "https://example.com",
+            "$set": {"email": "a@example.com"},
+        }))
+        .unwrap();
+
+        namespace_properties(&mut properties, "crm.");
+        assert_eq!(properties, expected);
+
+        namespace_properties(&mut properties, "crm.");
+        assert_eq!(properties, expected);
+    }
+
+    #[test]
+    fn unescapes_double_escaped_unicode() {
+        let keys = HashSet::from([String::from("name"), String::from("emoji")]);
+        let mut properties: HashMap<String, serde_json::Value> = serde_json::from_value(json!({
+            "name": r"Ren\u00e9e \u00C9lise",
+            "emoji": r"hi \ud83d\ude00",
+            "other": r"Ren\u00e9e",
+        }))
+        .unwrap();
+
+        unescape_unicode(&mut properties, &keys);
+        assert_eq!(properties["name"], json!("Renée Élise"));
+        assert_eq!(properties["emoji"], json!("hi 😀"));
+        assert_eq!(properties["other"], json!(r"Ren\u00e9e"));
+    }
+
+    #[test]
+    fn keeps_normal_strings() {
+        let keys = HashSet::from([String::from("name")]);
+        for value in [
+            "Renée",
+            r"C:\users\name",
+            r"\u00g1 and \u12",
+            r"lone \ud83d surrogate",
+        ] {
+            let mut properties = HashMap::from([(String::from("name"), json!(value))]);
+            unescape_unicode(&mut properties, &keys);
+            assert_eq!(properties["name"], json!(value), "{value}");
+        }
+    }
+
+    #[test]
+    fn normalizes_lib_names() {
+        let known = HashSet::from([String::from("web"), String::from("posthog-python")]);
+
+        let mut properties = lib_properties("web");
+        normalize_lib(&mut properties, &known);
+        assert_eq!(properties, lib_properties("web"));
+
+        let mut properties = lib_properties("PostHog-Python");
+        normalize_lib(&mut properties, &known);
+        assert_eq!(properties, lib_properties("posthog-python"));
+
+        let mut properties = lib_properties("My-SDK");
+        normalize_lib(&mut properties, &known);
+        assert_eq!(properties["$lib"], json!("my-sdk"));
+        assert_eq!(properties[LIB_UNKNOWN_PROPERTY], json!(true));
+
+        let mut properties = HashMap::from([(String::from("$lib"), json!(12))]);
+        normalize_lib(&mut properties, &known);
+        assert!(!properties.contains_key(LIB_UNKNOWN_PROPERTY));
+    }
+
+    fn version_properties(version: &str) -> HashMap<String, serde_json::Value> {
+        HashMap::from([(String::from("$lib_version"), json!(version))])
+    }
+
+    #[test]
+    fn parses_lib_versions() {
+        let version = |major, minor, patch| LibVersion {
+            major,
+            minor,
+            patch,
+        };
+        assert_eq!(LibVersion::parse("1.2.3"), Some(version(1, 2, 3)));
+        assert_eq!(LibVersion::parse("v2.10"), Some(version(2, 10, 0)));
+        assert_eq!(LibVersion::parse("3.0.1-beta.2"), Some(version(3, 0, 1)));
+        assert_eq!(LibVersion::parse("latest"), None);
+    }
+
+    #[test]
+    fn keeps_clean_lib_versions_as_is() {
+        let mut properties = version_properties("1.2.3");
+        preserve_raw_lib_version(&mut properties);
+        assert_eq!(properties, version_properties("1.2.3"));
+    }
+
+    #[test]
+    fn preserves_lossy_lib_versions() {
+        for raw in ["v1.2.3", "1.2", "3.0.1-beta.2", "nightly"] {
+            let mut properties = version_properties(raw);
+            preserve_raw_lib_version(&mut properties);
+            assert_eq!(properties[LIB_VERSION_RAW_PROPERTY], json!(raw));
+        }
+    }
+
+    fn sdk_properties(lib: &str, version: &str) -> HashMap<String, serde_json::Value> {
+        HashMap::from([
+            (String::from("$lib"), json!(lib)),
+            (String::from("$lib_version"), json!(version)),
+        ])
+    }
+
+    #[test]
+    fn accepts_supported_lib_versions() {
+        let MinimumLibVersions(minimums) = "posthog-js:1.50.0, posthog-python:3".parse().unwrap();
+        for (lib, version) in [
+            ("web", "1.0.0"),
+            ("posthog-js", "1.50.0"),
+            ("posthog-js", "v1.78.5"),
+            ("Posthog-Python", "3.0.1"),
+        ] {
+            assert!(check_lib_version(&sdk_properties(lib, version), &minimums, true).is_ok());
+        }
+        assert!(check_lib_version(&HashMap::new(), &minimums, true).is_ok());
+    }
+
+    #[test]
+    fn rejects_old_lib_versions() {
+        let MinimumLibVersions(minimums) = "posthog-js:1.50.0".parse().unwrap();
+        assert!(matches!(
+            check_lib_version(&sdk_properties("posthog-js", "1.49.9"), &minimums, false),
+            Err(CaptureError::UnsupportedSdkVersion { lib, version })
+                if lib == "posthog-js" && version == "1.49.9"
+        ));
+    }
+
+    #[test]
+    fn unparseable_lib_versions_pass_unless_strict() {
+        let MinimumLibVersions(minimums) = "posthog-js:1.50.0".parse().unwrap();
+        let properties = sdk_properties("posthog-js", "nightly");
+        assert!(check_lib_version(&properties, &minimums, false).is_ok());
+        assert!(matches!(
+            check_lib_version(&properties, &minimums, true),
+            Err(CaptureError::UnsupportedSdkVersion { .. })
+        ));
+
+        assert!("posthog-js:latest".parse::<MinimumLibVersions>().is_err());
+        assert!(":1.0.0".parse::<MinimumLibVersions>().is_err());
+    }
+
+    #[test]
+    fn strips_empty_properties() {
+        let mut properties: HashMap<String, serde_json::Value> = serde_json::from_value(json!({
+            "empty_string": "",
+            "null": null,
+            "empty_array": [],
+            "empty_object": {},
+            "$set": {},
+            "$referrer": "",
+            "name": "value",
+            "zero": 0,
+            "false": false,
+            "blank": " ",
+            "nested": [null],
+        }))
+        .unwrap();
+
+        assert_eq!(strip_empty_properties(&mut properties), 4);
+        let expected: HashMap<String, serde_json::Value> = serde_json::from_value(json!({
+            "$set": {},
+            "$referrer": "",
+            "name": "value",
+            "zero": 0,
+            "false": false,
+            "blank": " ",
+            "nested": [null],
+            EMPTY_PROPERTIES_REMOVED_PROPERTY: 4,
+        }))
+        .unwrap();
+        assert_eq!(properties, expected);
+
+        let mut properties = HashMap::from([(String::from("name"), json!("value"))]);
+        assert_eq!(strip_empty_properties(&mut properties), 0);
+        assert!(!properties.contains_key(EMPTY_PROPERTIES_REMOVED_PROPERTY));
+    }
+
+    #[test]
+    fn clamps_numbers_into_bounds() {
+        let bounds = HashMap::from([
+            (String::from("revenue"), (0.0, 1000.0)),
+            (String::from("ratio"), (0.0, 1.0)),
+            (String::from("label"), (0.0, 1.0)),
+        ]);
+
+        let mut properties: HashMap<String, serde_json::Value> =
+            serde_json::from_value(json!({"revenue": 12, "ratio": 0.5})).unwrap();
+        assert!(clamp_numbers(&mut properties, &bounds).is_empty());
+        assert!(!properties.contains_key(CLAMPED_PROPERTIES_PROPERTY));
+
+        let mut properties: HashMap<String, serde_json::Value> =
+            serde_json::from_value(json!({"revenue": 99999, "ratio": -0.5, "other": 5})).unwrap();
+        assert_eq!(
+            clamp_numbers(&mut properties, &bounds),
+            vec!["ratio", "revenue"]
+        );
+        assert_eq!(properties["revenue"], json!(1000));
+        assert_eq!(properties["ratio"], json!(0.0));
+        assert_eq!(properties["other"], json!(5));
+        assert_eq!(
+            properties[CLAMPED_PROPERTIES_PROPERTY],
+            json!(["ratio", "revenue"])
+        );
+
+        let mut properties: HashMap<String, serde_json::Value> =
+            serde_json::from_value(json!({"label": "huge", "revenue": "99999"})).unwrap();
+        assert!(clamp_numbers(&mut properties, &bounds).is_empty());
+        assert_eq!(properties["label"], json!("huge"));
+        assert_eq!(properties["revenue"], json!("99999"));
+    }
+
+    #[test]
+    fn drops_largest_properties_over_budget() {
+        let properties = || -> HashMap<String, serde_json::Value> {
+            serde_json::from_value(json!({
+                "$current_url": "https://example.com/".repeat(5),
+                "small": "a",
+                "medium": "b".repeat(50),
+                "large": "c".repeat(100),
+                "larger": "d".repeat(150),
+            }))
+            .unwrap()
+        };
+        let len = |properties: &HashMap<String, serde_json::Value>| {
+            serde_json::to_vec(properties).unwrap().len()
+        };
+
+        let mut one_drop = properties();
+        let budget = len(&one_drop) - 100;
+        assert_eq!(
+            drop_largest_properties(&mut one_drop, budget),
+            vec!["larger"]
+        );
+        assert!(len(&one_drop) <= budget);
+        assert_eq!(one_drop[DROPPED_PROPERTIES_PROPERTY], json!(["larger"]));
+
+        let mut several_drops = properties();
+        assert_eq!(
+            drop_largest_properties(&mut several_drops, 200),
+            vec!["larger", "large", "medium"]
+        );
+        assert!(len(&several_drops) <= 200);
+        assert_eq!(several_drops["small"], json!("a"));
+
+        // Reserved properties are kept, even over budget
+        let mut reserved_only = properties();
+        drop_largest_properties(&mut reserved_only, 10);
+        assert!(reserved_only.contains_key("$current_url"));
+        assert_eq!(
+            reserved_only[DROPPED_PROPERTIES_PROPERTY],
+            json!(["larger", "large", "medium", "small"])
+        );
+    }
+
+    #[test]
+    fn normalizes_boolean_strings() {
+        let keys = HashSet::from([
+            String::from("is_premium"),
+            String::from("opted_in"),
+            String::from("churned"),
+            String::from("beta"),
+        ]);
+        let mut properties: HashMap<String, serde_json::Value> = serde_json::from_value(json!({
+            "is_premium": "yes",
+            "opted_in": "1",
+            "churned": " FALSE ",
+            "beta": "maybe",
+            "other": "yes"
+        }))
+        .unwrap();
+
+        normalize_booleans(&mut properties, &keys);
+        assert_eq!(properties["is_premium"], json!(true));
+        assert_eq!(properties["opted_in"], json!(true));
+        assert_eq!(properties["churned"], json!(false));
+        assert_eq!(properties["beta"], json!("maybe"));
+        assert_eq!(properties["other"], json!("yes"));
+    }
+
+    #[test]
+    fn geoip_disable_flag_set() {
+        for flag in [json!(true), json!("true"), json!(" Yes"), json!(1)] {
+            let mut properties = HashMap::from([(String::from(GEOIP_DISABLE_PROPERTY), flag)]);
+            assert!(normalize_geoip_disable(&mut properties));
+            assert_eq!(properties[GEOIP_DISABLE_PROPERTY], json!(true));
+        }
+    }
+
+    #[test]
+    fn geoip_disable_flag_unset_or_invalid() {
+        for flag in [
+            json!(false),
+            json!("0"),
+            json!("off"),
+            json!("sometimes"),
+            json!(7),
+        ] {
+            let mut properties = HashMap::from([
+                (String::from(GEOIP_DISABLE_PROPERTY), flag.clone()),
+                (String::from("plan"), json!("pro")),
+            ]);
+            assert!(!normalize_geoip_disable(&mut properties), "{flag}");
+            assert_eq!(
+                properties,
+                HashMap::from([(String::from("plan"), json!("pro"))])
+            );
+        }
+
+        let mut properties = HashMap::new();
+        assert!(!normalize_geoip_disable(&mut properties));
+        assert!(properties.is_empty());
+    }
+
+    #[test]
+    fn normalizes_phone_numbers() {
+        let keys = HashSet::from([String::from("phone"), String::from("mobile")]);
+        let normalize = |value: &str| {
+            let mut properties = HashMap::from([(String::from("phone"), json!(value))]);
+            normalize_phone_numbers(&mut properties, &keys, Some(44));
+            properties.remove("phone").unwrap()
+        };
+
+        assert_eq!(normalize("020 7946 0958"), json!("+442079460958"));
+        assert_eq!(normalize("001 415.555.0123"), json!("+14155550123"));
+        assert_eq!(normalize("+33 1 23 45 67 89"), json!("+33123456789"));
+        // Already normalized
+        assert_eq!(normalize("+14155550123"), json!("+14155550123"));
+        // Not phone numbers
+        assert_eq!(normalize("call me maybe"), json!("call me maybe"));
+        assert_eq!(normalize("+1 555"), json!("+1 555"));
+        assert_eq!(normalize("+0 415 555 0123"), json!("+0 415 555 0123"));
+
+        // National numbers need a default calling code
+        let mut properties = HashMap::from([
+            (String::from("mobile"), json!("07700 900123")),
+            (String::from("other"), json!("07700 900123")),
+        ]);
+        normalize_phone_numbers(&mut properties, &keys, None);
+        assert_eq!(properties["mobile"], json!("07700 900123"));
+        normalize_phone_numbers(&mut properties, &keys, Some(44));
+        assert_eq!(properties["mobile"], json!("+447700900123"));
+        assert_eq!(properties["other"], json!("07700 900123"));
+    }
+
+    #[test]
+    fn redacts_ip_addresses() {
+        let mut properties = HashMap::from([
+            (
+                String::from("message"),
+                json!("connection from 192.168.1.20, then host:10.0.0.1."),
+            ),
+            (
+                String::from("nested"),
+                json!({"peers": ["2001:db8::1", "none"]}),
+            ),
+            (String::from("version"), json!("release 1.2.3 at 12:30")),
+            (String::from("path"), json!("std::vec and deadbeef::cafe")),
+            (String::from("count"), json!(3)),
+        ]);
+
+        let redacted = redact_ip_addresses(&mut properties);
+        assert_eq!(redacted, vec!["message", "nested"]);
+        assert_eq!(
+            properties["message"],
+            json!("connection from [redacted], then host:[redacted].")
+        );
+        assert_eq!(
+            properties["nested"],
+            json!({"peers": ["[redacted]", "none"]})
+        );
+        assert_eq!(properties["version"], json!("release 1.2.3 at 12:30"));
+        assert_eq!(properties["path"], json!("std::vec and deadbeef::cafe"));
+        assert_eq!(
+            properties[REDACTED_IP_PROPERTIES_PROPERTY],
+            json!(["message", "nested"])
+        );
+    }
+
+    #[test]
+    fn keeps_properties_without_ip_addresses() {
+        let mut properties = HashMap::from([
+            (String::from("$browser"), json!("Firefox 118.0")),
+            (String::from("id"), json!("a1b2c3d4.5.6.7x")),
+        ]);
+
+        assert!(redact_ip_addresses(&mut properties).is_empty());
+        assert_eq!(properties.len(), 2);
+        assert_eq!(properties["id"], json!("a1b2c3d4.5.6.7x"));
+    }
+
+    #[test]
+    fn strips_current_url_params() {
+        let strip = HashSet::from([String::from("utm_source"), String::from("gclid")]);
+        for (url, expected) in [
+            (
+                "https://example.com/page?utm_source=ads&id=3&gclid=abc#top",
+                "https://example.com/page?id=3#top",
+            ),
+            (
+                "https://example.com/?utm_source=ads&gclid",
+                "https://example.com/",
+            ),
+            ("https://example.com/page", "https://example.com/page"),
+        ] {
+            let mut properties = HashMap::from([(String::from("$current_url"), json!(url))]);
+            normalize_current_url(&mut properties, &strip, None);
+            assert_eq!(properties["$current_url"], json!(expected));
+        }
+    }
+
+    #[test]
+    fn extracts_utm_params() {
+        let mut properties = HashMap::from([
+            (
+                String::from("$current_url"),
+                json!("https://example.com/?utm_source=news+letter&utm_medium=email&utm_campaign=&utm_source=ads&id=3#utm_term=x"),
+            ),
+            (String::from("$utm_medium"), json!("sent_by_client")),
+        ]);
+
+        assert_eq!(extract_utm_params(&mut properties), 1);
+        assert_eq!(properties["$utm_source"], json!("news letter"));
+        assert_eq!(properties["$utm_medium"], json!("sent_by_client"));
+        assert!(!properties.contains_key("$utm_campaign"));
+        assert!(!properties.contains_key("$utm_term"));
+        assert!(!properties.contains_key("$id"));
+
+        let mut properties = HashMap::from([(
+            String::from("$current_url"),
+            json!("https://example.com/page?id=3"),
+        )]);
+        assert_eq!(extract_utm_params(&mut properties), 0);
+        assert_eq!(properties.len(), 1);
+    }
+
+    #[test]
+    fn truncates_current_url() {
+        let mut properties = HashMap::from([(
+            String::from("$current_url"),
+            json!("https://example.com/é?utm_source=ads&q=long"),
+        )]);
+
+        normalize_current_url(
+            &mut properties,
+            &HashSet::from([String::from("utm_source")]),
+            Some(22),
+        );
+        assert_eq!(properties["$current_url"], json!("https://example.com/é?"));
+    }
+
+    #[test]
+    fn keeps_invalid_current_url() {
+        let strip = HashSet::from([String::from("utm_source")]);
+        for url in [
+            "/page?utm_source=ads",
+            "not a url://x?utm_source=ads",
+            "https://?a=1",
+        ] {
+            let mut properties = HashMap::from([(String::from("$current_url"), json!(url))]);
+            normalize_current_url(&mut properties, &strip, Some(5));
+            assert_eq!(properties["$current_url"], json!(url));
+        }
+    }
+}
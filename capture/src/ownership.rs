@@ -0,0 +1,21 @@
+// Ownership checks of (token, distinct_id) pairs, for multi-tenant embedders
+//
+// Embedders sharing distinct_ids across projects can refuse events sent with the token of a
+// project as a distinct_id belonging to another one. The default validator permits every pair.
+use async_trait::async_trait;
+
+#[async_trait]
+pub trait OwnershipValidator {
+    async fn is_permitted(&self, token: &str, distinct_id: &str) -> bool;
+}
+
+/// Default validator, permitting every pair.
+#[derive(Clone, Default)]
+pub struct AnyOwner {}
+
+#[async_trait]
+impl OwnershipValidator for AnyOwner {
+    async fn is_permitted(&self, _token: &str, _distinct_id: &str) -> bool {
+        true
+    }
+}
@@ -0,0 +1,307 @@
+// Decoding of protobuf request bodies, sent by the gRPC ingest path
+//
+// The messages mirror RawEvent and RawRequest, properties using the google.protobuf.Struct
+// encoding so that clients can build them from the well-known types:
+//
+//   message Batch {
+//     repeated Event events = 1;
+//     optional string api_key = 2;
+//   }
+//   message Event {
+//     optional string token = 1;
+//     optional string distinct_id = 2;
+//     optional string uuid = 3;
+//     string event = 4;
+//     google.protobuf.Struct properties = 5;
+//     optional string timestamp = 6;
+//     optional int64 offset = 7;
+//     google.protobuf.Struct set = 8;
+//     google.protobuf.Struct set_once = 9;
+//   }
+//
+// A single event is sent as a batch of one.
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use prost::Message;
+use serde_json::{Number, Value};
+use uuid::Uuid;
+
+use crate::api::CaptureError;
+use crate::event::{EventOffset, EventQuery, RawEvent};
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Batch {
+    #[prost(message, repeated, tag = "1")]
+    pub events: Vec<Event>,
+    #[prost(string, optional, tag = "2")]
+    pub api_key: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Event {
+    #[prost(string, optional, tag = "1")]
+    pub token: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub distinct_id: Option<String>,
+    #[prost(string, optional, tag = "3")]
+    pub uuid: Option<String>,
+    #[prost(string, tag = "4")]
+    pub event: String,
+    #[prost(message, optional, tag = "5")]
+    pub properties: Option<Struct>,
+    #[prost(string, optional, tag = "6")]
+    pub timestamp: Option<String>,
+    #[prost(int64, optional, tag = "7")]
+    pub offset: Option<i64>,
+    #[prost(message, optional, tag = "8")]
+    pub set: Option<Struct>,
+    #[prost(message, optional, tag = "9")]
+    pub set_once: Option<Struct>,
+}
+
+/// Wire compatible with google.protobuf.Struct.
+#[derive(Clone, PartialEq, Message)]
+pub struct Struct {
+    #[prost(map = "string, message", tag = "1")]
+    pub fields: HashMap<String, ProtoValue>,
+}
+
+/// Wire compatible with google.protobuf.Value.
+#[derive(Clone, PartialEq, Message)]
+pub struct ProtoValue {
+    #[prost(oneof = "Kind", tags = "1, 2, 3, 4, 5, 6")]
+    pub kind: Option<Kind>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum Kind {
+    #[prost(enumeration = "NullValue", tag = "1")]
+    NullValue(i32),
+    #[prost(double, tag = "2")]
+    NumberValue(f64),
+    #[prost(string, tag = "3")]
+    StringValue(String),
+    #[prost(bool, tag = "4")]
+    BoolValue(bool),
+    #[prost(message, tag = "5")]
+    StructValue(Struct),
+    #[prost(message, tag = "6")]
+    ListValue(ListValue),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum NullValue {
+    NullValue = 0,
+}
+
+/// Wire compatible with google.protobuf.ListValue.
+#[derive(Clone, PartialEq, Message)]
+pub struct ListValue {
+    #[prost(message, repeated, tag = "1")]
+    pub values: Vec<ProtoValue>,
+}
+
+impl From<ProtoValue> for Value {
+    fn from(value: ProtoValue) -> Self {
+        match value.kind {
+            None | Some(Kind::NullValue(_)) => Value::Null,
+            // Struct numbers are all doubles, integers are read back as they would be from JSON
+            Some(Kind::NumberValue(number)) if number.fract() == 0.0 && number.abs() < 1e15 => {
+                Value::from(number as i64)
+            }
+            Some(Kind::NumberValue(number)) => {
+                Number::from_f64(number).map_or(Value::Null, Value::Number)
+            }
+            Some(Kind::StringValue(string)) => Value::String(string),
+            Some(Kind::BoolValue(boolean)) => Value::Bool(boolean),
+            Some(Kind::StructValue(object)) => {
+                Value::Object(object.into_map().into_iter().collect())
+            }
+            Some(Kind::ListValue(list)) => {
+                Value::Array(list.values.into_iter().map(Value::from).collect())
+            }
+        }
+    }
+}
+
+impl Struct {
+    fn into_map(self) -> HashMap<String, Value> {
+        self.fields
+            .into_iter()
+            .map(|(key, value)| (key, Value::from(value)))
+            .collect()
+    }
+}
+
+impl TryFrom<Event> for RawEvent {
+    type Error = CaptureError;
+
+    fn try_from(event: Event) -> Result<Self, Self::Error> {
+        let uuid = event
+            .uuid
+            .map(|uuid| Uuid::parse_str(&uuid))
+            .transpose()
+            .map_err(|_| CaptureError::RequestDecodingError(String::from("invalid event uuid")))?;
+        Ok(RawEvent {
+            token: event.token,
+            distinct_id: event.distinct_id,
+            uuid,
+            event: event.event,
+            properties: event.properties.map(Struct::into_map).unwrap_or_default(),
+            timestamp: event.timestamp,
+            offset: event.offset.map(EventOffset::Millis),
+            set: event.set.map(Struct::into_map),
+            set_once: event.set_once.map(Struct::into_map),
+            original_index: 0,
+        })
+    }
+}
+
+impl RawEvent {
+    /// Decode a protobuf `Batch`. Events without a token of their own get the batch `api_key`,
+    /// then the one of the query, as for JSON bodies.
+    pub fn from_protobuf(query: &EventQuery, bytes: Bytes) -> Result<Vec<RawEvent>, CaptureError> {
+        let batch = Batch::decode(bytes).map_err(|e| {
+            tracing::error!("failed to decode protobuf body: {}", e);
+            CaptureError::RequestDecodingError(String::from("invalid protobuf body"))
+        })?;
+
+        let fallback_token = batch.api_key.or_else(|| query.api_key.clone());
+        batch
+            .events
+            .into_iter()
+            .map(|event| {
+                let mut event = RawEvent::try_from(event)?;
+                if event.extract_token().is_none() {
+                    event.token = fallback_token.clone();
+                }
+                Ok(event)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+    use serde_json::{json, Value};
+
+    use crate::api::CaptureError;
+    use crate::event::{EventOffset, EventQuery, RawEvent};
+    use crate::protobuf::{Batch, Event, Kind, ListValue, ProtoValue, Struct};
+
+    fn to_proto(value: &Value) -> ProtoValue {
+        let kind = match value {
+            Value::Null => Kind::NullValue(0),
+            Value::Bool(boolean) => Kind::BoolValue(*boolean),
+            Value::Number(number) => Kind::NumberValue(number.as_f64().unwrap()),
+            Value::String(string) => Kind::StringValue(string.clone()),
+            Value::Array(values) => Kind::ListValue(ListValue {
+                values: values.iter().map(to_proto).collect(),
+            }),
+            Value::Object(_) => Kind::StructValue(to_struct(value)),
+        };
+        ProtoValue { kind: Some(kind) }
+    }
+
+    fn to_struct(value: &Value) -> Struct {
+        Struct {
+            fields: value
+                .as_object()
+                .unwrap()
+                .iter()
+                .map(|(key, value)| (key.clone(), to_proto(value)))
+                .collect(),
+        }
+    }
+
+    fn event(name: &str, properties: Value) -> Event {
+        Event {
+            distinct_id: Some(String::from("user1")),
+            event: String::from(name),
+            properties: Some(to_struct(&properties)),
+            ..Default::default()
+        }
+    }
+
+    fn decode(batch: Batch) -> Result<Vec<RawEvent>, CaptureError> {
+        RawEvent::from_protobuf(&EventQuery::default(), batch.encode_to_vec().into())
+    }
+
+    #[test]
+    fn single_event() {
+        let properties = json!({
+            "$current_url": "https://example.com/",
+            "count": 3,
+            "ratio": 0.5,
+            "tags": ["a", null, true],
+            "nested": {"key": "value"}
+        });
+        let batch = Batch {
+            events: vec![Event {
+                token: Some(String::from("token")),
+                uuid: Some(String::from("018c3a1a-7ec4-7ea2-9b3b-5a4cf6f9c0b1")),
+                offset: Some(150),
+                set: Some(to_struct(&json!({"plan": "free"}))),
+                ..event("pageview", properties.clone())
+            }],
+            api_key: None,
+        };
+
+        let events = decode(batch).unwrap();
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event.event, "pageview");
+        assert_eq!(event.token.as_deref(), Some("token"));
+        assert_eq!(event.extract_distinct_id().as_deref(), Some("user1"));
+        assert_eq!(
+            event.uuid.unwrap().to_string(),
+            "018c3a1a-7ec4-7ea2-9b3b-5a4cf6f9c0b1"
+        );
+        assert_eq!(event.offset, Some(EventOffset::Millis(150)));
+        assert_eq!(json!(event.properties), properties);
+        assert_eq!(json!(event.set), json!({"plan": "free"}));
+        assert_eq!(event.set_once, None);
+    }
+
+    #[test]
+    fn batch_with_shared_token() {
+        let batch = Batch {
+            events: vec![
+                event("first", json!({})),
+                Event {
+                    token: Some(String::from("own_token")),
+                    ..event("second", json!({"index": 2}))
+                },
+            ],
+            api_key: Some(String::from("shared_token")),
+        };
+
+        let events = decode(batch).unwrap();
+        let names: Vec<&str> = events.iter().map(|e| e.event.as_str()).collect();
+        assert_eq!(names, vec!["first", "second"]);
+        assert_eq!(events[0].token.as_deref(), Some("shared_token"));
+        assert_eq!(events[1].token.as_deref(), Some("own_token"));
+        assert_eq!(events[1].properties["index"], json!(2));
+    }
+
+    #[test]
+    fn invalid_messages() {
+        let res = RawEvent::from_protobuf(&EventQuery::default(), "not protobuf".into());
+        assert!(matches!(res, Err(CaptureError::RequestDecodingError(_))));
+
+        let batch = Batch {
+            events: vec![Event {
+                uuid: Some(String::from("not-a-uuid")),
+                ..event("e", json!({}))
+            }],
+            api_key: None,
+        };
+        assert!(matches!(
+            decode(batch),
+            Err(CaptureError::RequestDecodingError(_))
+        ));
+    }
+}
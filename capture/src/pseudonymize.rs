@@ -0,0 +1,134 @@
+// Pseudonymization of distinct_ids, to share sample datasets without exposing user ids
+//
+// Pseudonyms are salted FNV-1a hashes: stable for a given salt, so the events of a user keep
+// pointing to the same person, but not cryptographic. They are meant for sample datasets, and
+// the salt must be kept secret for ids not to be guessed.
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::event::RawEvent;
+use crate::utils::fnv1a;
+
+// Placeholder ids sent by misconfigured clients, which don't identify anyone
+const RESERVED_DISTINCT_IDS: [&str; 14] = [
+    "anonymous",
+    "guest",
+    "distinctid",
+    "distinct_id",
+    "id",
+    "not_authenticated",
+    "email",
+    "undefined",
+    "true",
+    "false",
+    "null",
+    "0",
+    "[object object]",
+    "nan",
+];
+
+// Properties holding distinct_ids, resolved by `RawEvent::extract_distinct_id` or linked to
+// the person by `$identify` and `$create_alias`
+const DISTINCT_ID_PROPERTIES: [&str; 4] = ["distinct_id", "$user_id", "$anon_distinct_id", "alias"];
+
+/// Stable salted pseudonym of a distinct_id. Empty and reserved ids are returned as is.
+pub fn pseudonymize_distinct_id(id: &str, salt: &[u8]) -> String {
+    let lowercase = id.trim().to_lowercase();
+    if lowercase.is_empty() || RESERVED_DISTINCT_IDS.contains(&lowercase.as_str()) {
+        return id.to_string();
+    }
+    let salted: Vec<u8> = [salt, b":", id.as_bytes()].concat();
+    format!("{:016x}", fnv1a(&salted))
+}
+
+/// Replace the distinct_ids of a batch of events with their pseudonyms, in the top-level field
+/// and in the properties holding ids. Returns the mapping from ids to pseudonyms.
+pub fn pseudonymize_distinct_ids(events: &mut [RawEvent], salt: &[u8]) -> HashMap<String, String> {
+    let mut pseudonyms: HashMap<String, String> = HashMap::new();
+    let mut pseudonymize = |id: &str| {
+        pseudonyms
+            .entry(id.to_string())
+            .or_insert_with(|| pseudonymize_distinct_id(id, salt))
+            .clone()
+    };
+
+    for event in events.iter_mut() {
+        if let Some(distinct_id) = &event.distinct_id {
+            event.distinct_id = Some(pseudonymize(distinct_id));
+        }
+        for key in DISTINCT_ID_PROPERTIES {
+            let id = match event.properties.get(key) {
+                Some(Value::String(id)) => id.clone(),
+                Some(Value::Number(id)) => id.to_string(),
+                _ => continue,
+            };
+            event
+                .properties
+                .insert(key.to_string(), Value::String(pseudonymize(&id)));
+        }
+    }
+
+    pseudonyms
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use crate::event::RawEvent;
+    use crate::pseudonymize::{pseudonymize_distinct_id, pseudonymize_distinct_ids};
+
+    #[test]
+    fn pseudonyms_are_deterministic() {
+        let pseudonym = pseudonymize_distinct_id("user1", b"salt");
+        assert_eq!(pseudonym.len(), 16);
+        assert_ne!(pseudonym, "user1");
+        assert_eq!(pseudonym, pseudonymize_distinct_id("user1", b"salt"));
+        assert_ne!(pseudonym, pseudonymize_distinct_id("user2", b"salt"));
+        assert_ne!(pseudonym, pseudonymize_distinct_id("user1", b"pepper"));
+
+        for reserved in ["", "anonymous", "Undefined", "null"] {
+            assert_eq!(pseudonymize_distinct_id(reserved, b"salt"), reserved);
+        }
+    }
+
+    #[test]
+    fn pseudonyms_are_consistent_across_events() {
+        let mut events = vec![
+            RawEvent {
+                distinct_id: Some(String::from("user1")),
+                event: String::from("pageview"),
+                ..Default::default()
+            },
+            RawEvent {
+                event: String::from("$identify"),
+                properties: HashMap::from([
+                    (String::from("distinct_id"), json!("user1")),
+                    (String::from("$anon_distinct_id"), json!("device1")),
+                    (String::from("plan"), json!("pro")),
+                ]),
+                ..Default::default()
+            },
+            RawEvent {
+                distinct_id: Some(String::from("device1")),
+                event: String::from("pageview"),
+                ..Default::default()
+            },
+        ];
+
+        let mapping = pseudonymize_distinct_ids(&mut events, b"salt");
+        assert_eq!(mapping.len(), 2);
+        let user = &mapping["user1"];
+        let device = &mapping["device1"];
+        assert_ne!(user, device);
+
+        assert_eq!(events[0].distinct_id.as_ref(), Some(user));
+        assert_eq!(events[1].properties["distinct_id"], json!(user));
+        assert_eq!(events[1].properties["$anon_distinct_id"], json!(device));
+        assert_eq!(events[1].properties["plan"], json!("pro"));
+        assert_eq!(events[2].distinct_id.as_ref(), Some(device));
+    }
+}
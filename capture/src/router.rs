@@ -8,8 +8,16 @@ use axum::{
 };
 use tower_http::cors::{AllowHeaders, AllowOrigin, CorsLayer};
 use tower_http::trace::TraceLayer;
+use uuid::Uuid;
 
+use crate::concurrency_limits::ConcurrencyLimiter;
+use crate::config::ProcessingConfig;
+use crate::decode_metrics::DecodeFailureMetrics;
+use crate::dedup::RequestDedupCache;
 use crate::health::HealthRegistry;
+use crate::ownership::OwnershipValidator;
+use crate::token::TokenValidator;
+use crate::user_agent::UserAgentParser;
 use crate::{billing_limits::BillingLimiter, capture, redis::Client, sink, time::TimeSource};
 
 use crate::prometheus::{setup_metrics_recorder, track_metrics};
@@ -20,29 +28,67 @@ pub struct State {
     pub timesource: Arc<dyn TimeSource + Send + Sync>,
     pub redis: Arc<dyn Client + Send + Sync>,
     pub billing: BillingLimiter,
+    pub processing: Arc<ProcessingConfig>,
+    pub concurrency: Option<ConcurrencyLimiter>,
+    pub request_dedup: Option<RequestDedupCache>,
+    pub token_validator: Arc<dyn TokenValidator + Send + Sync>,
+    pub user_agent_parser: Arc<dyn UserAgentParser + Send + Sync>,
+    pub ownership_validator: Arc<dyn OwnershipValidator + Send + Sync>,
+    pub uuid_seen: Arc<dyn Fn(&Uuid) -> bool + Send + Sync>,
+    pub decode_failures: Arc<dyn DecodeFailureMetrics + Send + Sync>,
 }
 
 async fn index() -> &'static str {
     "capture"
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn router<
     TZ: TimeSource + Send + Sync + 'static,
     S: sink::EventSink + Send + Sync + 'static,
     R: Client + Send + Sync + 'static,
+    V: TokenValidator + Send + Sync + 'static,
+    P: UserAgentParser + Send + Sync + 'static,
+    O: OwnershipValidator + Send + Sync + 'static,
+    U: Fn(&Uuid) -> bool + Send + Sync + 'static,
+    D: DecodeFailureMetrics + Send + Sync + 'static,
 >(
     timesource: TZ,
     liveness: HealthRegistry,
     sink: S,
     redis: Arc<R>,
     billing: BillingLimiter,
+    processing: ProcessingConfig,
+    token_validator: V,
+    user_agent_parser: P,
+    ownership_validator: O,
+    uuid_seen: U,
+    decode_failures: D,
     metrics: bool,
 ) -> Router {
+    let concurrency = processing.max_concurrent_requests_per_token.map(|limit| {
+        ConcurrencyLimiter::new(
+            limit,
+            std::time::Duration::from_secs(processing.concurrency_retry_after_secs),
+            std::time::Duration::from_secs(processing.concurrency_idle_eviction_secs),
+        )
+    });
+    let request_dedup = processing
+        .request_dedup_ttl_secs
+        .map(|ttl| RequestDedupCache::new(std::time::Duration::from_secs(ttl)));
     let state = State {
         sink: Arc::new(sink),
         timesource: Arc::new(timesource),
         redis,
         billing,
+        processing: Arc::new(processing),
+        concurrency,
+        request_dedup,
+        token_validator: Arc::new(token_validator),
+        user_agent_parser: Arc::new(user_agent_parser),
+        ownership_validator: Arc::new(ownership_validator),
+        uuid_seen: Arc::new(uuid_seen),
+        decode_failures: Arc::new(decode_failures),
     };
 
     // Very permissive CORS policy, as old SDK versions
@@ -70,6 +116,19 @@ pub fn router<
                 .get(capture::event)
                 .options(capture::options),
         )
+        // Legacy endpoint, still used by old SDKs
+        .route(
+            "/e",
+            post(capture::event)
+                .get(capture::event)
+                .options(capture::options),
+        )
+        .route(
+            "/e/",
+            post(capture::event)
+                .get(capture::event)
+                .options(capture::options),
+        )
         .layer(TraceLayer::new_for_http())
         .layer(cors)
         .layer(axum::middleware::from_fn(track_metrics))
@@ -0,0 +1,224 @@
+// Adapter for events sent in the Rudderstack format, for customers dual-writing to us
+
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use serde::Deserialize;
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::api::CaptureError;
+use crate::event::RawEvent;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RudderstackEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    event: Option<String>,
+    name: Option<String>,
+    user_id: Option<Value>,
+    anonymous_id: Option<String>,
+    message_id: Option<String>,
+    timestamp: Option<String>,
+    original_timestamp: Option<String>,
+    #[serde(default)]
+    properties: HashMap<String, Value>,
+    #[serde(default)]
+    traits: HashMap<String, Value>,
+    #[serde(default)]
+    context: HashMap<String, Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RudderstackRequest {
+    Batch { batch: Vec<RudderstackEvent> },
+    One(Box<RudderstackEvent>),
+}
+
+impl RawEvent {
+    /// Parse a Rudderstack payload, holding one event or a batch of them. `track`, `identify`
+    /// and `page` events are supported, their `context` fields are carried into `$` prefixed
+    /// properties.
+    pub fn from_rudderstack(bytes: Bytes) -> Result<Vec<RawEvent>, CaptureError> {
+        let events = match serde_json::from_slice::<RudderstackRequest>(&bytes)? {
+            RudderstackRequest::Batch { batch } => batch,
+            RudderstackRequest::One(event) => vec![*event],
+        };
+        events.into_iter().map(RawEvent::try_from).collect()
+    }
+}
+
+impl TryFrom<RudderstackEvent> for RawEvent {
+    type Error = CaptureError;
+
+    fn try_from(rudderstack: RudderstackEvent) -> Result<Self, Self::Error> {
+        let RudderstackEvent {
+            kind,
+            event,
+            name,
+            user_id,
+            anonymous_id,
+            message_id,
+            timestamp,
+            original_timestamp,
+            mut properties,
+            mut traits,
+            context,
+        } = rudderstack;
+
+        let mut set = None;
+        let event = match kind.as_str() {
+            "track" => event.unwrap_or_default(),
+            "page" => {
+                if let Some(name) = name {
+                    properties
+                        .entry(String::from("title"))
+                        .or_insert(name.into());
+                }
+                let url = properties.get("url").cloned();
+                if let Some(url) = url {
+                    properties
+                        .entry(String::from("$current_url"))
+                        .or_insert(url);
+                }
+                String::from("$pageview")
+            }
+            "identify" => {
+                if let Some(Value::Object(context_traits)) = context.get("traits") {
+                    for (key, value) in context_traits {
+                        traits.entry(key.clone()).or_insert(value.clone());
+                    }
+                }
+                set = Some(traits);
+                String::from("$identify")
+            }
+            _ => {
+                return Err(CaptureError::RequestDecodingError(format!(
+                    "unsupported rudderstack event type: {}",
+                    kind
+                )))
+            }
+        };
+
+        for (key, value) in context {
+            properties.entry(format!("${}", key)).or_insert(value);
+        }
+
+        let user_id = match user_id {
+            Some(Value::String(id)) if !id.is_empty() => Some(id),
+            Some(Value::Number(id)) => Some(id.to_string()),
+            _ => None,
+        };
+        if let (Some(_), Some(anonymous_id)) = (&user_id, &anonymous_id) {
+            properties
+                .entry(String::from("$anon_distinct_id"))
+                .or_insert(anonymous_id.clone().into());
+        }
+
+        Ok(RawEvent {
+            token: None,
+            distinct_id: user_id.or(anonymous_id),
+            uuid: message_id.and_then(|id| Uuid::parse_str(&id).ok()),
+            event,
+            properties,
+            timestamp: timestamp.or(original_timestamp),
+            offset: None,
+            set,
+            set_once: None,
+            original_index: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::api::CaptureError;
+    use crate::event::RawEvent;
+
+    fn parse(payload: serde_json::Value) -> Result<Vec<RawEvent>, CaptureError> {
+        RawEvent::from_rudderstack(payload.to_string().into())
+    }
+
+    #[test]
+    fn track_event() {
+        let events = parse(json!({
+            "type": "track",
+            "event": "Order Completed",
+            "userId": "user1",
+            "anonymousId": "anon1",
+            "messageId": "018b6e8b-e1a4-7a2c-9c5e-8f1e6e0c3a10",
+            "originalTimestamp": "2023-10-26T12:00:00Z",
+            "properties": {"revenue": 12.5},
+            "context": {"library": {"name": "rudder-js"}, "ip": "10.0.0.1"}
+        }))
+        .unwrap();
+
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event.event, "Order Completed");
+        assert_eq!(event.distinct_id.as_deref(), Some("user1"));
+        assert_eq!(
+            event.uuid.unwrap().to_string(),
+            "018b6e8b-e1a4-7a2c-9c5e-8f1e6e0c3a10"
+        );
+        assert_eq!(event.timestamp.as_deref(), Some("2023-10-26T12:00:00Z"));
+        assert_eq!(event.properties["revenue"], json!(12.5));
+        assert_eq!(event.properties["$anon_distinct_id"], json!("anon1"));
+        assert_eq!(event.properties["$library"], json!({"name": "rudder-js"}));
+        assert_eq!(event.properties["$ip"], json!("10.0.0.1"));
+    }
+
+    #[test]
+    fn identify_event() {
+        let events = parse(json!({
+            "batch": [{
+                "type": "identify",
+                "userId": 42,
+                "traits": {"email": "a@b.c"},
+                "context": {"traits": {"email": "old@b.c", "plan": "free"}, "locale": "en-US"}
+            }]
+        }))
+        .unwrap();
+
+        let event = &events[0];
+        assert_eq!(event.event, "$identify");
+        assert_eq!(event.distinct_id.as_deref(), Some("42"));
+        let set = event.set.as_ref().unwrap();
+        assert_eq!(set["email"], json!("a@b.c"));
+        assert_eq!(set["plan"], json!("free"));
+        assert_eq!(event.properties["$locale"], json!("en-US"));
+    }
+
+    #[test]
+    fn page_event() {
+        let events = parse(json!({
+            "type": "page",
+            "name": "Pricing",
+            "anonymousId": "anon1",
+            "properties": {"url": "https://example.com/pricing"},
+            "context": {"userAgent": "Mozilla/5.0"}
+        }))
+        .unwrap();
+
+        let event = &events[0];
+        assert_eq!(event.event, "$pageview");
+        assert_eq!(event.distinct_id.as_deref(), Some("anon1"));
+        assert_eq!(
+            event.properties["$current_url"],
+            json!("https://example.com/pricing")
+        );
+        assert_eq!(event.properties["title"], json!("Pricing"));
+        assert_eq!(event.properties["$userAgent"], json!("Mozilla/5.0"));
+        assert!(!event.properties.contains_key("$anon_distinct_id"));
+    }
+
+    #[test]
+    fn unsupported_type() {
+        let res = parse(json!({"type": "alias", "userId": "user1"}));
+        assert!(matches!(res, Err(CaptureError::RequestDecodingError(_))));
+    }
+}
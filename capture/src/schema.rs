@@ -0,0 +1,297 @@
+// Lightweight validation of event properties against per-event schemas.
+//
+// Enterprise customers want to enforce that some events always carry a set of properties of
+// the right type. This is deliberately much simpler than JSON Schema: a schema lists the
+// required property keys, and the primitive type expected for some keys. Events without a
+// registered schema are not validated.
+use std::collections::{HashMap, HashSet};
+
+use serde_json::Value;
+
+use crate::api::CaptureError;
+use crate::event::RawEvent;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PropertyType {
+    String,
+    Number,
+    Boolean,
+    Array,
+    Object,
+}
+
+impl PropertyType {
+    fn matches(&self, value: &Value) -> bool {
+        matches!(
+            (self, value),
+            (Self::String, Value::String(_))
+                | (Self::Number, Value::Number(_))
+                | (Self::Boolean, Value::Bool(_))
+                | (Self::Array, Value::Array(_))
+                | (Self::Object, Value::Object(_))
+        )
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct EventSchema {
+    /// Keys that must be present in the properties
+    pub required: HashSet<String>,
+    /// Expected type of properties, checked when they are present
+    pub types: HashMap<String, PropertyType>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SchemaRegistry {
+    schemas: HashMap<String, EventSchema>,
+}
+
+impl SchemaRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, event: &str, schema: EventSchema) {
+        self.schemas.insert(event.to_string(), schema);
+    }
+
+    pub fn validate(&self, event: &RawEvent) -> Result<(), CaptureError> {
+        let Some(schema) = self.schemas.get(&event.event) else {
+            return Ok(());
+        };
+        let violation = |details: String| CaptureError::SchemaViolation {
+            event: event.event.clone(),
+            details,
+        };
+
+        let mut missing: Vec<&String> = schema
+            .required
+            .iter()
+            .filter(|key| !event.properties.contains_key(*key))
+            .collect();
+        if !missing.is_empty() {
+            missing.sort();
+            return Err(violation(format!(
+                "missing required properties {:?}",
+                missing
+            )));
+        }
+
+        for (key, expected) in &schema.types {
+            match event.properties.get(key) {
+                Some(value) if !expected.matches(value) => {
+                    return Err(violation(format!(
+                        "property {} is not a {:?}",
+                        key, expected
+                    )));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Enforces the property allowlist of a locked-down project: keys missing from it are removed, or
+/// rejected when strict. Reserved `$` properties are always kept. Returns the removed keys.
+pub fn enforce_property_allowlist(
+    properties: &mut HashMap<String, Value>,
+    allowed: &HashSet<String>,
+    strict: bool,
+) -> Result<Vec<String>, CaptureError> {
+    let mut unknown: Vec<String> = properties
+        .keys()
+        .filter(|key| !key.starts_with('$') && !allowed.contains(*key))
+        .cloned()
+        .collect();
+    unknown.sort();
+    if strict {
+        if let Some(key) = unknown.into_iter().next() {
+            return Err(CaptureError::UnknownProperty(key));
+        }
+        return Ok(Vec::new());
+    }
+    for key in &unknown {
+        properties.remove(key);
+    }
+    Ok(unknown)
+}
+
+/// Caps the number of distinct non-reserved property keys across a batch, protecting schema
+/// inference from clients serializing unique keys. Beyond `max`, the rarest keys are removed
+/// from every event, or the batch is rejected when strict. Returns the removed keys.
+pub fn limit_distinct_properties(
+    events: &mut [RawEvent],
+    max: usize,
+    strict: bool,
+) -> Result<Vec<String>, CaptureError> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for key in events.iter().flat_map(|event| event.properties.keys()) {
+        if !key.starts_with('$') {
+            *counts.entry(key).or_default() += 1;
+        }
+    }
+    if counts.len() <= max {
+        return Ok(Vec::new());
+    }
+    if strict {
+        return Err(CaptureError::TooManyDistinctProperties);
+    }
+
+    // Most frequent first, ties broken by key for the result not to depend on hashing
+    let mut keys: Vec<(&str, usize)> = counts.into_iter().collect();
+    keys.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+    let mut removed: Vec<String> = keys[max..].iter().map(|(key, _)| key.to_string()).collect();
+    removed.sort();
+    for event in events.iter_mut() {
+        for key in &removed {
+            event.properties.remove(key);
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use serde_json::json;
+
+    use crate::api::CaptureError;
+    use crate::event::RawEvent;
+    use crate::schema::{
+        enforce_property_allowlist, limit_distinct_properties, EventSchema, PropertyType,
+        SchemaRegistry,
+    };
+
+    fn registry() -> SchemaRegistry {
+        let mut registry = SchemaRegistry::new();
+        registry.register(
+            "purchase",
+            EventSchema {
+                required: HashSet::from([String::from("amount")]),
+                types: HashMap::from([
+                    (String::from("amount"), PropertyType::Number),
+                    (String::from("coupon"), PropertyType::String),
+                ]),
+            },
+        );
+        registry
+    }
+
+    fn event(name: &str, properties: serde_json::Value) -> RawEvent {
+        RawEvent {
+            event: name.to_string(),
+            properties: serde_json::from_value(properties).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn conforming_event() {
+        let registry = registry();
+        assert!(registry
+            .validate(&event("purchase", json!({"amount": 12.5})))
+            .is_ok());
+        assert!(registry
+            .validate(&event("purchase", json!({"amount": 3, "coupon": "SALE"})))
+            .is_ok());
+        assert!(registry.validate(&event("pageview", json!({}))).is_ok());
+    }
+
+    #[test]
+    fn missing_required_key() {
+        let res = registry().validate(&event("purchase", json!({"coupon": "SALE"})));
+        match res {
+            Err(CaptureError::SchemaViolation { event, details }) => {
+                assert_eq!(event, "purchase");
+                assert!(details.contains("amount"), "{}", details);
+            }
+            _ => panic!("expected a schema violation"),
+        }
+    }
+
+    #[test]
+    fn type_mismatch() {
+        let res = registry().validate(&event("purchase", json!({"amount": "12"})));
+        match res {
+            Err(CaptureError::SchemaViolation { details, .. }) => {
+                assert_eq!(details, "property amount is not a Number");
+            }
+            _ => panic!("expected a schema violation"),
+        }
+    }
+
+    #[test]
+    fn strips_unknown_properties() {
+        let allowed = HashSet::from([String::from("plan")]);
+        let mut properties = event(
+            "pageview",
+            json!({"plan": "pro", "color": "red", "$browser": "Firefox"}),
+        )
+        .properties;
+
+        let removed = enforce_property_allowlist(&mut properties, &allowed, false).unwrap();
+        assert_eq!(removed, vec!["color"]);
+        assert_eq!(properties.len(), 2);
+        assert!(properties.contains_key("plan"));
+        assert!(properties.contains_key("$browser"));
+    }
+
+    #[test]
+    fn rejects_unknown_properties() {
+        let allowed = HashSet::from([String::from("plan")]);
+        let mut properties = event("pageview", json!({"plan": "pro", "color": "red"})).properties;
+        assert!(matches!(
+            enforce_property_allowlist(&mut properties, &allowed, true),
+            Err(CaptureError::UnknownProperty(key)) if key == "color"
+        ));
+
+        let mut properties = event("pageview", json!({"plan": "pro", "$os": "Linux"})).properties;
+        assert!(enforce_property_allowlist(&mut properties, &allowed, true).is_ok());
+        assert_eq!(properties.len(), 2);
+    }
+
+    #[test]
+    fn batch_under_distinct_property_limit() {
+        let mut events = vec![
+            event("pageview", json!({"plan": "pro", "$browser": "Firefox"})),
+            event("pageview", json!({"plan": "free", "color": "red"})),
+        ];
+        let removed = limit_distinct_properties(&mut events, 2, true).unwrap();
+        assert!(removed.is_empty());
+        assert_eq!(events[0].properties.len(), 2);
+        assert_eq!(events[1].properties.len(), 2);
+    }
+
+    #[test]
+    fn batch_over_distinct_property_limit() {
+        let mut events = vec![
+            event(
+                "pageview",
+                json!({"plan": "pro", "key_1": 1, "$browser": "Firefox"}),
+            ),
+            event(
+                "pageview",
+                json!({"plan": "free", "color": "red", "key_2": 2}),
+            ),
+            event("pageview", json!({"color": "blue"})),
+        ];
+        assert!(matches!(
+            limit_distinct_properties(&mut events, 2, true),
+            Err(CaptureError::TooManyDistinctProperties)
+        ));
+
+        let removed = limit_distinct_properties(&mut events, 2, false).unwrap();
+        assert_eq!(removed, vec!["key_1", "key_2"]);
+        assert_eq!(
+            events[0].properties,
+            event("pageview", json!({"plan": "pro", "$browser": "Firefox"})).properties
+        );
+        assert_eq!(
+            events[1].properties,
+            event("pageview", json!({"plan": "free", "color": "red"})).properties
+        );
+    }
+}
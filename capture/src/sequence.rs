@@ -0,0 +1,123 @@
+/// Sinks needing strict per-user ordering can't rely on timestamps, which are set by clients.
+/// The SequenceAllocator stamps events with a sequence number, increasing per partition key
+/// within this capture process.
+///
+/// To bound memory use, the least recently used keys are evicted once `max_keys` are tracked.
+/// Sequences of new and evicted keys restart above every sequence evicted so far: they are not
+/// contiguous, but keep increasing for every key.
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::event::ProcessedEvent;
+
+struct Sequences {
+    // key -> (last sequence, last use)
+    entries: HashMap<String, (u64, u64)>,
+    // Bumped on every allocation, to find the least recently used key
+    clock: u64,
+    // Highest sequence of the evicted keys
+    floor: u64,
+}
+
+pub struct SequenceAllocator {
+    max_keys: usize,
+    sequences: Mutex<Sequences>,
+}
+
+impl SequenceAllocator {
+    pub fn new(max_keys: usize) -> Self {
+        SequenceAllocator {
+            max_keys: max_keys.max(1),
+            sequences: Mutex::new(Sequences {
+                entries: HashMap::new(),
+                clock: 0,
+                floor: 0,
+            }),
+        }
+    }
+
+    /// Returns the next sequence number for `key`, starting at 1.
+    pub fn next(&self, key: &str) -> u64 {
+        let mut sequences = self.sequences.lock().expect("poisoned sequence lock");
+        sequences.clock += 1;
+        let clock = sequences.clock;
+
+        if let Some((seq, last_use)) = sequences.entries.get_mut(key) {
+            *seq += 1;
+            *last_use = clock;
+            return *seq;
+        }
+
+        if sequences.entries.len() >= self.max_keys {
+            let oldest = sequences
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, last_use))| *last_use)
+                .map(|(key, _)| key.clone());
+            if let Some((evicted, _)) = oldest.and_then(|key| sequences.entries.remove(&key)) {
+                sequences.floor = sequences.floor.max(evicted);
+            }
+        }
+
+        let seq = sequences.floor + 1;
+        sequences.entries.insert(key.to_string(), (seq, clock));
+        seq
+    }
+
+    /// Stamps the event with the next sequence number of its partition key.
+    pub fn stamp(&self, event: &mut ProcessedEvent) {
+        event.seq = self.next(&event.key());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::event::ProcessedEvent;
+    use crate::sequence::SequenceAllocator;
+
+    #[test]
+    fn monotonic_per_key() {
+        let allocator = SequenceAllocator::new(10);
+        let sequences: Vec<u64> = (0..5).map(|_| allocator.next("token:user1")).collect();
+        assert_eq!(sequences, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn independent_keys() {
+        let allocator = SequenceAllocator::new(10);
+        assert_eq!(allocator.next("token:user1"), 1);
+        assert_eq!(allocator.next("token:user1"), 2);
+        assert_eq!(allocator.next("token:user2"), 1);
+        assert_eq!(allocator.next("token:user1"), 3);
+        assert_eq!(allocator.next("token:user2"), 2);
+    }
+
+    #[test]
+    fn monotonic_after_eviction() {
+        let allocator = SequenceAllocator::new(2);
+        for _ in 0..3 {
+            allocator.next("a");
+        }
+        allocator.next("b");
+        // Evicts a, the least recently used key
+        assert_eq!(allocator.next("c"), 4);
+        // Evicts b, a restarts above its previous sequence
+        assert_eq!(allocator.next("a"), 4);
+        assert_eq!(allocator.next("a"), 5);
+    }
+
+    #[test]
+    fn stamps_events() {
+        let allocator = SequenceAllocator::new(10);
+        let mut event = ProcessedEvent {
+            token: String::from("token"),
+            distinct_id: String::from("user1"),
+            ..Default::default()
+        };
+
+        allocator.stamp(&mut event);
+        assert_eq!(event.seq, 1);
+        allocator.stamp(&mut event);
+        assert_eq!(event.seq, 2);
+    }
+}
@@ -5,10 +5,15 @@ use std::sync::Arc;
 use time::Duration;
 
 use crate::billing_limits::BillingLimiter;
+use crate::capture::no_uuid_seen;
 use crate::config::Config;
+use crate::decode_metrics::NoopDecodeFailureMetrics;
 use crate::health::{ComponentStatus, HealthRegistry};
+use crate::ownership::AnyOwner;
 use crate::partition_limits::PartitionLimiter;
 use crate::redis::RedisClient;
+use crate::token::AlwaysValid;
+use crate::user_agent::BasicUserAgentParser;
 use crate::{router, sink};
 
 pub async fn serve<F>(config: Config, listener: TcpListener, shutdown: F)
@@ -37,6 +42,12 @@ where
             sink::PrintSink {},
             redis_client,
             billing,
+            config.processing,
+            AlwaysValid {},
+            BasicUserAgentParser {},
+            AnyOwner {},
+            no_uuid_seen,
+            NoopDecodeFailureMetrics {},
             config.export_prometheus,
         )
     } else {
@@ -49,8 +60,15 @@ where
             config.burst_limit,
             config.overflow_forced_keys,
         );
-        let sink = sink::KafkaSink::new(config.kafka, sink_liveness, partition)
-            .expect("failed to start Kafka sink");
+        let sink = sink::KafkaSink::new(
+            config.kafka,
+            sink_liveness,
+            partition,
+            config.partition_key_overrides,
+            config.partition_strategy,
+            config.event_partition_strategies,
+        )
+        .expect("failed to start Kafka sink");
 
         router::router(
             crate::time::SystemTime {},
@@ -58,6 +76,12 @@ where
             sink,
             redis_client,
             billing,
+            config.processing,
+            AlwaysValid {},
+            BasicUserAgentParser {},
+            AnyOwner {},
+            no_uuid_seen,
+            NoopDecodeFailureMetrics {},
             config.export_prometheus,
         )
     };
@@ -1,6 +1,10 @@
+use std::collections::HashMap;
+use std::io::Write;
 use std::time::Duration;
 
 use async_trait::async_trait;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use metrics::{absolute_counter, counter, gauge, histogram};
 use rdkafka::config::ClientConfig;
 use rdkafka::error::{KafkaError, RDKafkaErrorCode};
@@ -12,7 +16,9 @@ use tracing::log::{debug, error, info};
 use tracing::{info_span, instrument, Instrument};
 
 use crate::api::CaptureError;
-use crate::config::KafkaConfig;
+use crate::config::{
+    EventPartitionStrategies, KafkaConfig, PartitionKeyOverrides, PartitionStrategy,
+};
 use crate::event::ProcessedEvent;
 use crate::health::HealthHandle;
 use crate::partition_limits::PartitionLimiter;
@@ -24,6 +30,61 @@ pub trait EventSink {
     async fn send_batch(&self, events: Vec<ProcessedEvent>) -> Result<(), CaptureError>;
 }
 
+/// Serialize events as newline-delimited JSON, for sinks doing bulk writes. Fields are written
+/// in declaration order, so the output is stable for a given batch.
+pub fn serialize_batch(events: &[ProcessedEvent]) -> Result<String, CaptureError> {
+    let mut batch = String::new();
+    for event in events {
+        let line = serde_json::to_string(event).map_err(|e| {
+            error!("failed to serialize event: {}", e);
+            CaptureError::NonRetryableSinkError
+        })?;
+        batch.push_str(&line);
+        batch.push('\n');
+    }
+    Ok(batch)
+}
+
+/// Same as `serialize_batch`, gzip compressed.
+pub fn serialize_batch_gzip(events: &[ProcessedEvent]) -> Result<Vec<u8>, CaptureError> {
+    let batch = serialize_batch(events)?;
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(batch.as_bytes())
+        .and_then(|_| encoder.finish())
+        .map_err(|e| {
+            error!("failed to compress batch: {}", e);
+            CaptureError::NonRetryableSinkError
+        })
+}
+
+/// Forward events to the sink in chunks of `chunk_size`, in order, stopping at the first chunk
+/// refused with SinkBackpressure. Returns the number of events forwarded, the caller retrying
+/// the remaining ones later. The interrupted chunk may have been partially written, so retries
+/// can duplicate some of its events.
+pub async fn process_chunked(
+    events: Vec<ProcessedEvent>,
+    chunk_size: usize,
+    sink: &(dyn EventSink + Send + Sync),
+) -> Result<usize, CaptureError> {
+    let chunk_size = chunk_size.max(1);
+    let mut processed = 0;
+    let mut events = events.into_iter().peekable();
+    while events.peek().is_some() {
+        let chunk: Vec<ProcessedEvent> = events.by_ref().take(chunk_size).collect();
+        let len = chunk.len();
+        match sink.send_batch(chunk).await {
+            Ok(()) => processed += len,
+            Err(CaptureError::SinkBackpressure) => {
+                debug!("sink backpressure after {} events", processed);
+                break;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(processed)
+}
+
 pub struct PrintSink {}
 
 #[async_trait]
@@ -114,6 +175,9 @@ pub struct KafkaSink {
     producer: FutureProducer<KafkaContext>,
     topic: String,
     partition: PartitionLimiter,
+    key_overrides: HashMap<String, String>,
+    partition_strategy: PartitionStrategy,
+    event_partition_strategies: EventPartitionStrategies,
 }
 
 impl KafkaSink {
@@ -121,6 +185,9 @@ impl KafkaSink {
         config: KafkaConfig,
         liveness: HealthHandle,
         partition: PartitionLimiter,
+        key_overrides: PartitionKeyOverrides,
+        partition_strategy: PartitionStrategy,
+        event_partition_strategies: EventPartitionStrategies,
     ) -> anyhow::Result<KafkaSink> {
         info!("connecting to Kafka brokers at {}...", config.kafka_hosts);
 
@@ -159,6 +226,9 @@ impl KafkaSink {
         Ok(KafkaSink {
             producer,
             partition,
+            key_overrides: key_overrides.0,
+            partition_strategy,
+            event_partition_strategies,
             topic: config.kafka_topic,
         })
     }
@@ -172,21 +242,18 @@ impl KafkaSink {
         producer: FutureProducer<KafkaContext>,
         topic: String,
         event: ProcessedEvent,
-        limited: bool,
+        partition_key: Option<String>,
     ) -> Result<DeliveryFuture, CaptureError> {
         let payload = serde_json::to_string(&event).map_err(|e| {
             error!("failed to serialize event: {}", e);
             CaptureError::NonRetryableSinkError
         })?;
 
-        let key = event.key();
-        let partition_key = if limited { None } else { Some(key.as_str()) };
-
         match producer.send_result(FutureRecord {
             topic: topic.as_str(),
             payload: Some(&payload),
             partition: None,
-            key: partition_key,
+            key: partition_key.as_deref(),
             timestamp: None,
             headers: None,
         }) {
@@ -196,6 +263,10 @@ impl KafkaSink {
                     report_dropped_events("kafka_message_size", 1);
                     Err(CaptureError::EventTooBig)
                 }
+                Some(RDKafkaErrorCode::QueueFull) => {
+                    report_dropped_events("kafka_queue_full", 1);
+                    Err(CaptureError::SinkBackpressure)
+                }
                 _ => {
                     // TODO(maybe someday): Don't drop them but write them somewhere and try again
                     report_dropped_events("kafka_write_error", 1);
@@ -206,6 +277,21 @@ impl KafkaSink {
         }
     }
 
+    /// Returns the key to partition the event on, or None to let the producer pick a random
+    /// partition if the key is over its rate limit.
+    fn partition_key(&self, event: &ProcessedEvent) -> Option<String> {
+        let key = event.key_with(
+            &self.key_overrides,
+            &self.event_partition_strategies,
+            self.partition_strategy,
+        );
+        if self.partition.is_limited(&key) {
+            None
+        } else {
+            Some(key)
+        }
+    }
+
     async fn process_ack(delivery: DeliveryFuture) -> Result<(), CaptureError> {
         match delivery.await {
             Err(_) => {
@@ -237,9 +323,14 @@ impl KafkaSink {
 impl EventSink for KafkaSink {
     #[instrument(skip_all)]
     async fn send(&self, event: ProcessedEvent) -> Result<(), CaptureError> {
-        let limited = self.partition.is_limited(&event.key());
-        let ack =
-            Self::kafka_send(self.producer.clone(), self.topic.clone(), event, limited).await?;
+        let partition_key = self.partition_key(&event);
+        let ack = Self::kafka_send(
+            self.producer.clone(),
+            self.topic.clone(),
+            event,
+            partition_key,
+        )
+        .await?;
         histogram!("capture_event_batch_size", 1.0);
         Self::process_ack(ack)
             .instrument(info_span!("ack_wait_one"))
@@ -253,10 +344,10 @@ impl EventSink for KafkaSink {
         for event in events {
             let producer = self.producer.clone();
             let topic = self.topic.clone();
-            let limited = self.partition.is_limited(&event.key());
+            let partition_key = self.partition_key(&event);
 
             // We await kafka_send to get events in the producer queue sequentially
-            let ack = Self::kafka_send(producer, topic, event, limited).await?;
+            let ack = Self::kafka_send(producer, topic, event, partition_key).await?;
 
             // Then stash the returned DeliveryFuture, waiting concurrently for the write ACKs from brokers.
             set.spawn(Self::process_ack(ack));
@@ -295,12 +386,18 @@ mod tests {
     use crate::event::ProcessedEvent;
     use crate::health::HealthRegistry;
     use crate::partition_limits::PartitionLimiter;
-    use crate::sink::{EventSink, KafkaSink};
+    use crate::sink::{
+        process_chunked, serialize_batch, serialize_batch_gzip, EventSink, KafkaSink,
+    };
     use crate::utils::uuid_v7;
+    use async_trait::async_trait;
+    use flate2::read::GzDecoder;
     use rdkafka::mocking::MockCluster;
     use rdkafka::producer::DefaultProducerContext;
     use rdkafka::types::{RDKafkaApiKey, RDKafkaRespErr};
+    use std::io::Read;
     use std::num::NonZeroU32;
+    use std::sync::Mutex;
     use time::Duration;
 
     async fn start_on_mocked_sink() -> (MockCluster<'static, DefaultProducerContext>, KafkaSink) {
@@ -323,7 +420,15 @@ mod tests {
             kafka_topic: "events_plugin_ingestion".to_string(),
             kafka_tls: false,
         };
-        let sink = KafkaSink::new(config, handle, limiter).expect("failed to create sink");
+        let sink = KafkaSink::new(
+            config,
+            handle,
+            limiter,
+            config::PartitionKeyOverrides::default(),
+            config::PartitionStrategy::default(),
+            config::EventPartitionStrategies::default(),
+        )
+        .expect("failed to create sink");
         (cluster, sink)
     }
 
@@ -341,6 +446,18 @@ mod tests {
             now: "".to_string(),
             sent_at: None,
             token: "token1".to_string(),
+            process_person_profile: true,
+            event: "event".to_string(),
+            session_id: None,
+            timezone: None,
+            seq: 0,
+            trace_id: None,
+            is_test: false,
+            ingest_region: None,
+            processing_duration: None,
+            ingest_ts_nanos: 0,
+            original_index: 0,
+            expires_at: None,
         };
 
         // Wait for producer to be healthy, to keep kafka_message_timeout_ms short and tests faster
@@ -405,4 +522,101 @@ mod tests {
             Ok(()) => panic!("should have errored"),
         };
     }
+
+    #[test]
+    fn serialize_batch_round_trip() {
+        let events: Vec<ProcessedEvent> = (0..3)
+            .map(|i| ProcessedEvent {
+                uuid: uuid_v7(),
+                distinct_id: format!("user{}", i),
+                data: format!("{{\"event\":\"e{}\"}}", i),
+                token: String::from("token"),
+                ..Default::default()
+            })
+            .collect();
+
+        let batch = serialize_batch(&events).expect("failed to serialize batch");
+        assert!(batch.ends_with('\n'));
+        let parsed: Vec<serde_json::Value> = batch
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let expected: Vec<serde_json::Value> = events
+            .iter()
+            .map(|event| serde_json::to_value(event).unwrap())
+            .collect();
+        assert_eq!(parsed, expected);
+        assert_eq!(batch, serialize_batch(&events).unwrap());
+
+        let compressed = serialize_batch_gzip(&events).expect("failed to compress batch");
+        let mut decompressed = String::new();
+        GzDecoder::new(compressed.as_slice())
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, batch);
+
+        assert_eq!(serialize_batch(&[]).unwrap(), "");
+    }
+
+    /// Records the batches it receives, refusing them once `capacity` batches were accepted.
+    struct BoundedSink {
+        capacity: usize,
+        batches: Mutex<Vec<Vec<String>>>,
+    }
+
+    impl BoundedSink {
+        fn new(capacity: usize) -> Self {
+            Self {
+                capacity,
+                batches: Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl EventSink for BoundedSink {
+        async fn send(&self, event: ProcessedEvent) -> Result<(), CaptureError> {
+            self.send_batch(vec![event]).await
+        }
+
+        async fn send_batch(&self, events: Vec<ProcessedEvent>) -> Result<(), CaptureError> {
+            let mut batches = self.batches.lock().unwrap();
+            if batches.len() >= self.capacity {
+                return Err(CaptureError::SinkBackpressure);
+            }
+            batches.push(events.into_iter().map(|e| e.distinct_id).collect());
+            Ok(())
+        }
+    }
+
+    fn numbered_events(count: usize) -> Vec<ProcessedEvent> {
+        (0..count)
+            .map(|i| ProcessedEvent {
+                distinct_id: i.to_string(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn chunked_processing_forwards_all_chunks() {
+        let sink = BoundedSink::new(10);
+        let processed = process_chunked(numbered_events(5), 2, &sink).await.unwrap();
+        assert_eq!(processed, 5);
+        assert_eq!(
+            *sink.batches.lock().unwrap(),
+            vec![vec!["0", "1"], vec!["2", "3"], vec!["4"]]
+        );
+    }
+
+    #[tokio::test]
+    async fn chunked_processing_stops_on_backpressure() {
+        let sink = BoundedSink::new(2);
+        let processed = process_chunked(numbered_events(7), 3, &sink).await.unwrap();
+        assert_eq!(processed, 6);
+        assert_eq!(
+            *sink.batches.lock().unwrap(),
+            vec![vec!["0", "1", "2"], vec!["3", "4", "5"]]
+        );
+    }
 }
@@ -0,0 +1,143 @@
+// Adapter for Snowplow self-describing events, for customers migrating from Snowplow
+
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::api::CaptureError;
+use crate::event::RawEvent;
+
+// Property keeping the full Iglu schema URI of the event
+const IGLU_SCHEMA_PROPERTY: &str = "$iglu_schema";
+
+#[derive(Deserialize)]
+struct SelfDescribingEvent {
+    schema: String,
+    #[serde(default)]
+    data: HashMap<String, Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SnowplowData {
+    Batch(Vec<SelfDescribingEvent>),
+    One(SelfDescribingEvent),
+}
+
+/// Envelope of the events, its own schema being the one of the event wrapper
+#[derive(Deserialize)]
+struct SnowplowRequest {
+    data: SnowplowData,
+}
+
+impl RawEvent {
+    /// Parse a Snowplow payload, holding one self-describing event or a batch of them in
+    /// `data`. Events are named after their Iglu schema, such as `checkout` for
+    /// `iglu:com.acme/checkout/jsonschema/1-0-0`, and their `data` becomes the properties.
+    pub fn from_snowplow(bytes: Bytes) -> Result<Vec<RawEvent>, CaptureError> {
+        let events = match serde_json::from_slice::<SnowplowRequest>(&bytes)?.data {
+            SnowplowData::Batch(events) => events,
+            SnowplowData::One(event) => vec![event],
+        };
+        events.into_iter().map(RawEvent::try_from).collect()
+    }
+}
+
+impl TryFrom<SelfDescribingEvent> for RawEvent {
+    type Error = CaptureError;
+
+    fn try_from(snowplow: SelfDescribingEvent) -> Result<Self, Self::Error> {
+        let SelfDescribingEvent {
+            schema,
+            data: mut properties,
+        } = snowplow;
+
+        // iglu:vendor/name/format/version
+        let event = match schema
+            .strip_prefix("iglu:")
+            .map(|path| path.split('/').collect::<Vec<_>>())
+            .as_deref()
+        {
+            Some([vendor, name, _, _]) if !vendor.is_empty() && !name.is_empty() => {
+                name.to_string()
+            }
+            _ => {
+                return Err(CaptureError::RequestDecodingError(format!(
+                    "invalid iglu schema uri: {}",
+                    schema
+                )))
+            }
+        };
+        properties.insert(String::from(IGLU_SCHEMA_PROPERTY), Value::String(schema));
+
+        Ok(RawEvent {
+            event,
+            properties,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::api::CaptureError;
+    use crate::event::RawEvent;
+
+    const CHECKOUT_SCHEMA: &str = "iglu:com.acme/checkout/jsonschema/1-0-0";
+
+    fn parse(payload: serde_json::Value) -> Result<Vec<RawEvent>, CaptureError> {
+        RawEvent::from_snowplow(payload.to_string().into())
+    }
+
+    #[test]
+    fn single_event() {
+        let events = parse(json!({
+            "schema": "iglu:com.snowplowanalytics.snowplow/unstruct_event/jsonschema/1-0-0",
+            "data": {
+                "schema": CHECKOUT_SCHEMA,
+                "data": {"distinct_id": "user1", "total": 42.5}
+            }
+        }))
+        .unwrap();
+
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event.event, "checkout");
+        assert_eq!(event.extract_distinct_id().as_deref(), Some("user1"));
+        assert_eq!(event.properties["total"], json!(42.5));
+        assert_eq!(event.properties["$iglu_schema"], json!(CHECKOUT_SCHEMA));
+    }
+
+    #[test]
+    fn batched_events() {
+        let events = parse(json!({
+            "schema": "iglu:com.snowplowanalytics.snowplow/payload_data/jsonschema/1-0-4",
+            "data": [
+                {"schema": CHECKOUT_SCHEMA, "data": {"total": 1}},
+                {"schema": "iglu:com.acme/refund/jsonschema/2-0-1", "data": {"total": -1}},
+            ]
+        }))
+        .unwrap();
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].event, "checkout");
+        assert_eq!(events[1].event, "refund");
+        assert_eq!(events[1].properties["total"], json!(-1));
+        assert_eq!(
+            events[1].properties["$iglu_schema"],
+            json!("iglu:com.acme/refund/jsonschema/2-0-1")
+        );
+    }
+
+    #[test]
+    fn invalid_schema() {
+        for schema in ["com.acme/checkout/jsonschema/1-0-0", "iglu:checkout"] {
+            let res = parse(json!({"data": {"schema": schema, "data": {}}}));
+            assert!(matches!(res, Err(CaptureError::RequestDecodingError(_))));
+        }
+    }
+}
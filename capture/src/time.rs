@@ -1,6 +1,50 @@
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use time::format_description::well_known::Rfc3339;
+use time::format_description::OwnedFormatItem;
+use time::{Duration, OffsetDateTime, PrimitiveDateTime};
+
+// Epoch values above this are in milliseconds, it is year 5138 in seconds
+const MAX_EPOCH_SECONDS: f64 = 1e11;
+
+// Longer durations would overflow time::Duration
+const MAX_DURATION_SECONDS: f64 = 1e15;
+
+// Top-level areas of the IANA time zone database
+const IANA_AREAS: [&str; 11] = [
+    "Africa",
+    "America",
+    "Antarctica",
+    "Arctic",
+    "Asia",
+    "Atlantic",
+    "Australia",
+    "Europe",
+    "Indian",
+    "Pacific",
+    "Etc",
+];
+
+// Latest ingest timestamp handed out by SystemTime, for them to never go backwards
+static LAST_INGEST_NANOS: AtomicI64 = AtomicI64::new(0);
+
 pub trait TimeSource {
     // Return an ISO timestamp
     fn current_time(&self) -> String;
+
+    // Return the nanoseconds since the epoch, for ordering events. Defaults to the parsed
+    // current_time, 0 if it is not RFC3339
+    fn current_time_nanos(&self) -> i128 {
+        OffsetDateTime::parse(&self.current_time(), &Rfc3339)
+            .map_or(0, |time| time.unix_timestamp_nanos())
+    }
+}
+
+// For contexts holding a clock to be printed
+impl std::fmt::Debug for dyn TimeSource + Send + Sync {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TimeSource")
+    }
 }
 
 #[derive(Clone)]
@@ -13,4 +57,206 @@ impl TimeSource for SystemTime {
         time.format(&time::format_description::well_known::Rfc3339)
             .expect("failed to format timestamp")
     }
+
+    // Monotonic within the process, even if the system clock is set back. i64 nanoseconds
+    // last until 2262.
+    fn current_time_nanos(&self) -> i128 {
+        let nanos = OffsetDateTime::now_utc().unix_timestamp_nanos();
+        let nanos = i64::try_from(nanos).unwrap_or(i64::MAX);
+        let last = LAST_INGEST_NANOS.fetch_max(nanos, Ordering::Relaxed);
+        i128::from(last.max(nanos))
+    }
+}
+
+/// Parse an event timestamp sent by a client, trying in order: RFC3339, epoch seconds or
+/// milliseconds (told apart by their magnitude), then each of the `formats`. Formats without
+/// an offset are assumed to be in UTC. Returns None if the value matches none of them.
+pub fn parse_event_timestamp(value: &str, formats: &[OwnedFormatItem]) -> Option<OffsetDateTime> {
+    let value = value.trim();
+    if let Ok(timestamp) = OffsetDateTime::parse(value, &Rfc3339) {
+        return Some(timestamp);
+    }
+    // Integer epochs are converted exactly, going through f64 loses sub-second precision
+    if let Ok(epoch) = value.parse::<i64>() {
+        let nanos = if (epoch.unsigned_abs() as f64) < MAX_EPOCH_SECONDS {
+            i128::from(epoch) * 1_000_000_000
+        } else {
+            i128::from(epoch) * 1_000_000
+        };
+        return OffsetDateTime::from_unix_timestamp_nanos(nanos).ok();
+    }
+    if let Ok(epoch) = value.parse::<f64>() {
+        if !epoch.is_finite() {
+            return None;
+        }
+        let seconds = if epoch.abs() < MAX_EPOCH_SECONDS {
+            epoch
+        } else {
+            epoch / 1000.0
+        };
+        return OffsetDateTime::from_unix_timestamp_nanos((seconds * 1e9) as i128).ok();
+    }
+    formats.iter().find_map(|format| {
+        OffsetDateTime::parse(value, format)
+            .or_else(|_| PrimitiveDateTime::parse(value, format).map(|t| t.assume_utc()))
+            .ok()
+    })
+}
+
+/// Parse an ISO-8601 duration made of weeks, days, hours, minutes and seconds, such as `PT5S`
+/// or `P1DT2H30.5M`. Years and months, that have no fixed length, are not supported.
+pub fn parse_iso_duration(value: &str) -> Option<Duration> {
+    let rest = value.trim().strip_prefix('P')?;
+    let (date, time) = match rest.split_once('T') {
+        Some((_, "")) => return None,
+        Some((date, time)) => (date, time),
+        None if rest.is_empty() => return None,
+        None => (rest, ""),
+    };
+
+    let mut seconds = 0.0;
+    for (mut part, units) in [
+        (date, &[('W', 604800.0), ('D', 86400.0)][..]),
+        (time, &[('H', 3600.0), ('M', 60.0), ('S', 1.0)][..]),
+    ] {
+        // Units must be in order, and appear once at most
+        let mut units = units.iter();
+        while !part.is_empty() {
+            let end = part.find(|c: char| c.is_ascii_alphabetic())?;
+            let (number, unit) = part.split_at(end);
+            let unit = unit.chars().next()?;
+            let (_, unit_seconds) = units.find(|(name, _)| *name == unit)?;
+            if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit() || c == '.') {
+                return None;
+            }
+            seconds += number.parse::<f64>().ok()? * unit_seconds;
+            part = &part[end + 1..];
+        }
+    }
+    if seconds > MAX_DURATION_SECONDS {
+        return None;
+    }
+    Some(Duration::seconds_f64(seconds))
+}
+
+/// Whether `name` is shaped like an IANA time zone name: `UTC`, `GMT` or an `Area/Location`
+/// path under one of the database areas, such as `America/Argentina/Buenos_Aires`. The `time`
+/// crate ships no zone database, this does not check the location exists.
+pub fn is_iana_zone_name(name: &str) -> bool {
+    if name == "UTC" || name == "GMT" {
+        return true;
+    }
+    let mut components = name.split('/');
+    let area = components.next().unwrap_or_default();
+    let mut locations = components.peekable();
+    IANA_AREAS.contains(&area)
+        && locations.peek().is_some()
+        && locations.all(|location| {
+            location.starts_with(|c: char| c.is_ascii_uppercase())
+                && location
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '+'))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use time::macros::datetime;
+
+    use crate::config::TimestampFormats;
+    use crate::time::{is_iana_zone_name, parse_event_timestamp, parse_iso_duration};
+    use time::Duration;
+
+    #[test]
+    fn parses_rfc3339() {
+        assert_eq!(
+            parse_event_timestamp("2023-10-26T12:00:00.250+02:00", &[]),
+            Some(datetime!(2023-10-26 10:00:00.250 UTC))
+        );
+    }
+
+    #[test]
+    fn parses_epochs() {
+        let expected = Some(datetime!(2023-10-26 12:00:00 UTC));
+        assert_eq!(parse_event_timestamp("1698321600", &[]), expected);
+        assert_eq!(parse_event_timestamp("1698321600000", &[]), expected);
+        assert_eq!(
+            parse_event_timestamp("1698321600.5", &[]),
+            Some(datetime!(2023-10-26 12:00:00.5 UTC))
+        );
+    }
+
+    #[test]
+    fn parses_configured_formats() {
+        let TimestampFormats(formats) = TimestampFormats::from_str(
+            "[day]/[month]/[year] [hour]:[minute]:[second] [offset_hour sign:mandatory]; \
+             [year]-[month]-[day] [hour]:[minute]:[second]",
+        )
+        .unwrap();
+
+        assert_eq!(
+            parse_event_timestamp("26/10/2023 14:00:00 +02", &formats),
+            Some(datetime!(2023-10-26 12:00:00 UTC))
+        );
+        assert_eq!(
+            parse_event_timestamp("2023-10-26 12:00:00", &formats),
+            Some(datetime!(2023-10-26 12:00:00 UTC))
+        );
+        assert_eq!(parse_event_timestamp("2023-10-26 12:00:00", &[]), None);
+    }
+
+    #[test]
+    fn rejects_unparseable() {
+        assert_eq!(parse_event_timestamp("yesterday", &[]), None);
+        assert_eq!(parse_event_timestamp("NaN", &[]), None);
+        assert!(TimestampFormats::from_str("[notacomponent]").is_err());
+    }
+
+    #[test]
+    fn parses_iso_durations() {
+        assert_eq!(parse_iso_duration("PT5S"), Some(Duration::seconds(5)));
+        assert_eq!(
+            parse_iso_duration("PT0.25S"),
+            Some(Duration::milliseconds(250))
+        );
+        assert_eq!(
+            parse_iso_duration("P1DT2H30M"),
+            Some(Duration::days(1) + Duration::hours(2) + Duration::minutes(30))
+        );
+        assert_eq!(parse_iso_duration("P2W"), Some(Duration::weeks(2)));
+
+        for invalid in [
+            "", "5", "P", "PT", "P1DT", "PT5", "PTS", "P1M", "P1Y", "PT-5S", "PT1S1M", "PT1M1M",
+            "P1e20W",
+        ] {
+            assert_eq!(parse_iso_duration(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn checks_iana_zone_names() {
+        for valid in [
+            "UTC",
+            "Europe/Paris",
+            "America/Argentina/Buenos_Aires",
+            "America/Port-au-Prince",
+            "Etc/GMT+5",
+        ] {
+            assert!(is_iana_zone_name(valid), "{valid}");
+        }
+        for invalid in [
+            "",
+            "Paris",
+            "Europe",
+            "Europe/",
+            "europe/paris",
+            "Mars/Olympus_Mons",
+            "Europe/Paris ",
+            "+02:00",
+        ] {
+            assert!(!is_iana_zone_name(invalid), "{invalid}");
+        }
+    }
 }
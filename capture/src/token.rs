@@ -1,6 +1,10 @@
 use std::error::Error;
 use std::fmt::Display;
 
+use async_trait::async_trait;
+
+use crate::utils::fnv1a;
+
 /// Validate that a token is the correct shape
 
 #[derive(Debug, PartialEq)]
@@ -60,9 +64,47 @@ pub fn validate_token(token: &str) -> Result<(), InvalidTokenReason> {
     Ok(())
 }
 
+/// Extract the token from an `Authorization: Bearer <token>` header value, as sent by
+/// server-side SDKs. The scheme is matched case-insensitively, other schemes return None.
+pub fn extract_token_from_auth(header: &str) -> Option<String> {
+    let (scheme, token) = header.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+
+    match token.trim() {
+        "" => None,
+        token => Some(token.to_string()),
+    }
+}
+
+/// Checks that a token belongs to an active project, for instance against a token to team
+/// cache maintained by the embedder.
+#[async_trait]
+pub trait TokenValidator {
+    async fn is_valid(&self, token: &str) -> bool;
+}
+
+/// Default validator, accepting every token.
+#[derive(Clone, Default)]
+pub struct AlwaysValid {}
+
+#[async_trait]
+impl TokenValidator for AlwaysValid {
+    async fn is_valid(&self, _token: &str) -> bool {
+        true
+    }
+}
+
+/// Short stable id of a token, logged instead of the token itself to correlate the logs of a
+/// project without leaking its token.
+pub fn token_log_id(token: &str) -> String {
+    format!("{:08x}", fnv1a(token.as_bytes()) >> 32)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::token::{validate_token, InvalidTokenReason};
+    use crate::token::{extract_token_from_auth, token_log_id, validate_token, InvalidTokenReason};
 
     #[test]
     fn blocks_empty_tokens() {
@@ -96,4 +138,37 @@ mod tests {
         assert!(valid.is_err());
         assert_eq!(valid.unwrap_err(), InvalidTokenReason::PersonalApiKey);
     }
+
+    #[test]
+    fn extracts_bearer_token() {
+        assert_eq!(
+            extract_token_from_auth("Bearer phc_token"),
+            Some(String::from("phc_token"))
+        );
+        assert_eq!(
+            extract_token_from_auth("bearer  phc_token "),
+            Some(String::from("phc_token"))
+        );
+    }
+
+    #[test]
+    fn ignores_other_auth_schemes() {
+        assert_eq!(extract_token_from_auth("Basic dXNlcjpwYXNz"), None);
+        assert_eq!(extract_token_from_auth("phc_token"), None);
+        assert_eq!(extract_token_from_auth("Bearer "), None);
+        assert_eq!(extract_token_from_auth(""), None);
+    }
+
+    #[test]
+    fn token_log_ids_are_stable() {
+        let id = token_log_id("phc_VXRzc3poSG9GZm1JenRianJ6TTJFZGh4OWY2QXzx9f3");
+        assert_eq!(id.len(), 8);
+        assert_eq!(
+            id,
+            token_log_id("phc_VXRzc3poSG9GZm1JenRianJ6TTJFZGh4OWY2QXzx9f3")
+        );
+        assert_ne!(id, token_log_id("phc_other"));
+        // Values must not depend on the process, pin one
+        assert_eq!(token_log_id(""), "cbf29ce4");
+    }
 }
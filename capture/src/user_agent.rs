@@ -0,0 +1,195 @@
+// Enrichment of events with the browser, OS and device type read from the request User-Agent
+//
+// Server-side and older SDKs do not send the device properties posthog-js sets. The default
+// parser only looks for the tokens of the most common browsers and platforms, embedders
+// needing exhaustive parsing can plug their own.
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+const BROWSER_PROPERTY: &str = "$browser";
+const OS_PROPERTY: &str = "$os";
+const DEVICE_TYPE_PROPERTY: &str = "$device_type";
+
+// Lowercase tokens of the user agents of crawlers and HTTP libraries
+const BOT_TOKENS: [&str; 8] = [
+    "bot",
+    "crawler",
+    "spider",
+    "curl/",
+    "wget/",
+    "python-requests",
+    "headlesschrome",
+    "lighthouse",
+];
+
+/// Fields read from a User-Agent, named as the values posthog-js sets.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UserAgentInfo {
+    pub browser: Option<String>,
+    pub os: Option<String>,
+    pub device_type: Option<String>,
+}
+
+impl UserAgentInfo {
+    /// Set `$browser`, `$os` and `$device_type` from the parsed fields, keeping the values the
+    /// client already set.
+    pub fn enrich(&self, properties: &mut HashMap<String, Value>) {
+        for (key, value) in [
+            (BROWSER_PROPERTY, &self.browser),
+            (OS_PROPERTY, &self.os),
+            (DEVICE_TYPE_PROPERTY, &self.device_type),
+        ] {
+            if let Some(value) = value {
+                properties
+                    .entry(key.to_string())
+                    .or_insert_with(|| Value::String(value.clone()));
+            }
+        }
+    }
+}
+
+pub trait UserAgentParser {
+    fn parse(&self, user_agent: &str) -> UserAgentInfo;
+}
+
+/// Default parser, matching the tokens of the most common browsers and platforms.
+#[derive(Clone, Default)]
+pub struct BasicUserAgentParser {}
+
+impl UserAgentParser for BasicUserAgentParser {
+    fn parse(&self, user_agent: &str) -> UserAgentInfo {
+        let lowercase = user_agent.to_lowercase();
+        if BOT_TOKENS.iter().any(|token| lowercase.contains(token)) {
+            return UserAgentInfo {
+                device_type: Some(String::from("Bot")),
+                ..Default::default()
+            };
+        }
+        UserAgentInfo {
+            browser: browser(user_agent).map(String::from),
+            os: os(user_agent).map(String::from),
+            device_type: device_type(user_agent).map(String::from),
+        }
+    }
+}
+
+fn browser(user_agent: &str) -> Option<&'static str> {
+    // Most browsers also announce the engines of others, the most specific tokens go first
+    let browsers = [
+        ("Edg/", "Microsoft Edge"),
+        ("EdgA/", "Microsoft Edge"),
+        ("OPR/", "Opera"),
+        ("SamsungBrowser/", "Samsung Internet"),
+        ("CriOS/", "Chrome iOS"),
+        ("FxiOS/", "Firefox iOS"),
+        ("Firefox/", "Firefox"),
+        ("Chrome/", "Chrome"),
+        ("MSIE ", "Internet Explorer"),
+        ("Trident/", "Internet Explorer"),
+    ];
+    if let Some((_, name)) = browsers
+        .iter()
+        .find(|(token, _)| user_agent.contains(token))
+    {
+        return Some(name);
+    }
+    match (
+        user_agent.contains("Safari/"),
+        user_agent.contains("Mobile"),
+    ) {
+        (true, true) => Some("Mobile Safari"),
+        (true, false) => Some("Safari"),
+        _ => None,
+    }
+}
+
+fn os(user_agent: &str) -> Option<&'static str> {
+    let systems = [
+        ("iPhone", "iOS"),
+        ("iPad", "iOS"),
+        ("Android", "Android"),
+        ("Windows", "Windows"),
+        ("CrOS", "Chrome OS"),
+        ("Mac OS X", "Mac OS X"),
+        ("Linux", "Linux"),
+    ];
+    systems
+        .iter()
+        .find(|(token, _)| user_agent.contains(token))
+        .map(|(_, name)| *name)
+}
+
+fn device_type(user_agent: &str) -> Option<&'static str> {
+    if user_agent.contains("iPad") || user_agent.contains("Tablet") {
+        Some("Tablet")
+    } else if user_agent.contains("Mobi") || user_agent.contains("iPhone") {
+        Some("Mobile")
+    } else if user_agent.contains("Android") {
+        // Android tablets leave Mobile out of their user agent
+        Some("Tablet")
+    } else if user_agent.starts_with("Mozilla/") {
+        Some("Desktop")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use crate::user_agent::{BasicUserAgentParser, UserAgentInfo, UserAgentParser};
+
+    const DESKTOP_CHROME: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) \
+        AppleWebKit/537.36 (KHTML, like Gecko) Chrome/118.0.0.0 Safari/537.36";
+    const MOBILE_SAFARI: &str = "Mozilla/5.0 (iPhone; CPU iPhone OS 17_0 like Mac OS X) \
+        AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.0 Mobile/15E148 Safari/604.1";
+    const GOOGLEBOT: &str =
+        "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)";
+
+    #[test]
+    fn parses_desktop_user_agent() {
+        assert_eq!(
+            BasicUserAgentParser::default().parse(DESKTOP_CHROME),
+            UserAgentInfo {
+                browser: Some(String::from("Chrome")),
+                os: Some(String::from("Mac OS X")),
+                device_type: Some(String::from("Desktop")),
+            }
+        );
+        assert_eq!(
+            BasicUserAgentParser::default().parse(MOBILE_SAFARI),
+            UserAgentInfo {
+                browser: Some(String::from("Mobile Safari")),
+                os: Some(String::from("iOS")),
+                device_type: Some(String::from("Mobile")),
+            }
+        );
+    }
+
+    #[test]
+    fn parses_bot_user_agent() {
+        assert_eq!(
+            BasicUserAgentParser::default().parse(GOOGLEBOT),
+            UserAgentInfo {
+                device_type: Some(String::from("Bot")),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn enrich_keeps_client_values() {
+        let mut properties = HashMap::from([(String::from("$browser"), json!("Arc"))]);
+
+        BasicUserAgentParser::default()
+            .parse(DESKTOP_CHROME)
+            .enrich(&mut properties);
+        assert_eq!(properties["$browser"], json!("Arc"));
+        assert_eq!(properties["$os"], json!("Mac OS X"));
+        assert_eq!(properties["$device_type"], json!("Desktop"));
+    }
+}
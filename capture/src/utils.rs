@@ -1,6 +1,9 @@
 use rand::RngCore;
+use serde_json::Value;
 use uuid::Uuid;
 
+use crate::config::UuidPolicy;
+
 pub fn random_bytes<const N: usize>() -> [u8; N] {
     let mut ret = [0u8; N];
     rand::thread_rng().fill_bytes(&mut ret);
@@ -36,3 +39,48 @@ pub fn uuid_v7() -> Uuid {
 
     encode_unix_timestamp_millis(now_millis, &bytes)
 }
+
+/// Unix time in milliseconds embedded in a v7 uuid, `None` for other versions.
+pub fn uuid_v7_timestamp_millis(uuid: &Uuid) -> Option<u64> {
+    if uuid.get_version_num() != 7 {
+        return None;
+    }
+    let bytes = uuid.as_bytes();
+    Some(
+        bytes[..6]
+            .iter()
+            .fold(0, |millis, byte| millis << 8 | u64::from(*byte)),
+    )
+}
+
+pub fn uuid_v4() -> Uuid {
+    uuid::Builder::from_random_bytes(random_bytes()).into_uuid()
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// 64-bit FNV-1a hash, which unlike `DefaultHasher` is stable across processes and releases.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// Generate a uuid of the version required by the policy.
+pub fn new_uuid(policy: UuidPolicy) -> Uuid {
+    match policy {
+        UuidPolicy::V7 => uuid_v7(),
+        UuidPolicy::V4 => uuid_v4(),
+    }
+}
+
+/// Read a boolean sent either as a JSON boolean or as a `"true"`/`"false"` string.
+pub fn coerce_bool(value: &Value) -> Option<bool> {
+    match value {
+        Value::Bool(value) => Some(*value),
+        Value::String(value) if value.eq_ignore_ascii_case("true") => Some(true),
+        Value::String(value) if value.eq_ignore_ascii_case("false") => Some(false),
+        _ => None,
+    }
+}
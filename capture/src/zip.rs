@@ -0,0 +1,297 @@
+// Decoding of zip archives of JSON files, posted by batch-upload tools
+//
+// Entries are listed from the central directory, at the end of the archive, and may be stored
+// or deflated. Zip64 and encrypted archives are not supported.
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use flate2::Crc;
+
+use crate::api::CaptureError;
+use crate::config::ProcessingConfig;
+use crate::decompression::{decompressed_size_limit, decompression_budget, inflate_raw};
+use crate::event::{EventQuery, RawEvent};
+
+pub static ZIP_MAGIC_NUMBERS: [u8; 4] = [b'P', b'K', 3, 4];
+
+const CENTRAL_DIRECTORY_SIGNATURE: [u8; 4] = [b'P', b'K', 1, 2];
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: [u8; 4] = [b'P', b'K', 5, 6];
+const END_OF_CENTRAL_DIRECTORY_SIZE: usize = 22;
+const CENTRAL_DIRECTORY_HEADER_SIZE: usize = 46;
+const LOCAL_HEADER_SIZE: usize = 30;
+// The end of central directory record is followed by a comment of 64 KiB at most
+const MAX_COMMENT_SIZE: usize = u16::MAX as usize;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+const FLAG_ENCRYPTED: u16 = 1;
+
+pub struct ZipEvents {
+    pub events: Vec<RawEvent>,
+    /// Entries that could not be decoded, the events of the others are kept
+    pub errors: Vec<ZipEntryError>,
+}
+
+#[derive(Debug)]
+pub struct ZipEntryError {
+    pub name: String,
+    pub error: CaptureError,
+}
+
+struct Entry<'a> {
+    name: String,
+    flags: u16,
+    method: u16,
+    crc: u32,
+    uncompressed_size: u64,
+    data: &'a [u8],
+}
+
+/// Extract the events of all the JSON files of a zip archive, each holding a batch or a single
+/// event. Directories are skipped. The decompressed size of all the files combined is bounded
+/// by `max_decompressed_bytes` and they share the decompression time budget, the archive is
+/// rejected as a whole when going over either, or holding more than `max_zip_entries` entries.
+pub fn parse_zip(
+    query: &EventQuery,
+    body: Bytes,
+    config: &ProcessingConfig,
+) -> Result<ZipEvents, CaptureError> {
+    if body.len() > config.max_compressed_bytes {
+        return Err(CaptureError::RequestTooLarge);
+    }
+    let entries = list_entries(&body, config.max_zip_entries)?;
+
+    let start = Instant::now();
+    let budget = decompression_budget(config);
+    let mut remaining_bytes = decompressed_size_limit(
+        config,
+        config.max_total_decompressed_bytes.unwrap_or(u64::MAX),
+    );
+    let mut events = Vec::new();
+    let mut errors = Vec::new();
+    for entry in entries {
+        if entry.name.ends_with('/') {
+            continue;
+        }
+        if entry.uncompressed_size > remaining_bytes {
+            tracing::error!(
+                entry = entry.name,
+                "zip entries exceed the decompressed size limit"
+            );
+            return Err(CaptureError::DecompressedTooLarge);
+        }
+        let remaining_budget = budget.saturating_sub(start.elapsed());
+        let decoded = decode_entry(&entry, remaining_budget, remaining_bytes).and_then(|data| {
+            remaining_bytes -= data.len() as u64;
+            RawEvent::from_bytes_with(query, data.into(), config)
+        });
+        match decoded {
+            Ok(entry_events) => events.extend(entry_events),
+            Err(
+                error @ (CaptureError::DecompressedTooLarge | CaptureError::DecompressionTimeout),
+            ) => return Err(error),
+            Err(error) => {
+                tracing::warn!(entry = entry.name, "failed to decode zip entry: {}", error);
+                errors.push(ZipEntryError {
+                    name: entry.name,
+                    error,
+                });
+            }
+        }
+    }
+    Ok(ZipEvents { events, errors })
+}
+
+fn invalid() -> CaptureError {
+    CaptureError::RequestDecodingError(String::from("invalid zip archive"))
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Option<u16> {
+    let field = bytes.get(offset..offset + 2)?;
+    Some(u16::from_le_bytes([field[0], field[1]]))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    let field = bytes.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([field[0], field[1], field[2], field[3]]))
+}
+
+fn list_entries(body: &[u8], max_entries: usize) -> Result<Vec<Entry<'_>>, CaptureError> {
+    // Last end of central directory record, in case the comment holds the signature
+    let search_start = body
+        .len()
+        .saturating_sub(END_OF_CENTRAL_DIRECTORY_SIZE + MAX_COMMENT_SIZE);
+    let end = body
+        .get(search_start..)
+        .and_then(|tail| {
+            tail.windows(4)
+                .rposition(|window| window == END_OF_CENTRAL_DIRECTORY_SIGNATURE)
+        })
+        .map(|position| search_start + position)
+        .ok_or_else(invalid)?;
+
+    let count = u16_at(body, end + 10).ok_or_else(invalid)?;
+    let directory_offset = u32_at(body, end + 16).ok_or_else(invalid)?;
+    if count == u16::MAX || directory_offset == u32::MAX {
+        return Err(CaptureError::RequestDecodingError(String::from(
+            "zip64 archives are not supported",
+        )));
+    }
+    if usize::from(count) > max_entries {
+        tracing::error!(count, max_entries, "zip archive has too many entries");
+        return Err(CaptureError::RequestDecodingError(format!(
+            "zip archive has more than {} entries",
+            max_entries
+        )));
+    }
+
+    let mut offset = directory_offset as usize;
+    let mut entries = Vec::with_capacity(usize::from(count));
+    for _ in 0..count {
+        let header = body
+            .get(offset..offset + CENTRAL_DIRECTORY_HEADER_SIZE)
+            .filter(|header| header.starts_with(&CENTRAL_DIRECTORY_SIGNATURE))
+            .ok_or_else(invalid)?;
+        let field16 = |at| u16_at(header, at).unwrap_or_default();
+        let field32 = |at| u32_at(header, at).unwrap_or_default();
+        let (name_len, extra_len, comment_len) = (field16(28), field16(30), field16(32));
+        let (compressed_size, uncompressed_size) = (field32(20), field32(24));
+        let local_offset = field32(42);
+        if [compressed_size, uncompressed_size, local_offset].contains(&u32::MAX) {
+            return Err(CaptureError::RequestDecodingError(String::from(
+                "zip64 archives are not supported",
+            )));
+        }
+
+        let name_start = offset + CENTRAL_DIRECTORY_HEADER_SIZE;
+        let name = body
+            .get(name_start..name_start + usize::from(name_len))
+            .ok_or_else(invalid)?;
+        let data = local_data(body, local_offset as usize, compressed_size as usize)?;
+        entries.push(Entry {
+            name: String::from_utf8_lossy(name).into_owned(),
+            flags: field16(8),
+            method: field16(10),
+            crc: field32(16),
+            uncompressed_size: u64::from(uncompressed_size),
+            data,
+        });
+        offset =
+            name_start + usize::from(name_len) + usize::from(extra_len) + usize::from(comment_len);
+    }
+    Ok(entries)
+}
+
+// Data of an entry, following its local header that has its own name and extra field lengths
+fn local_data(body: &[u8], offset: usize, size: usize) -> Result<&[u8], CaptureError> {
+    let header = body
+        .get(offset..offset + LOCAL_HEADER_SIZE)
+        .filter(|header| header.starts_with(&ZIP_MAGIC_NUMBERS))
+        .ok_or_else(invalid)?;
+    let name_len = u16_at(header, 26).ok_or_else(invalid)?;
+    let extra_len = u16_at(header, 28).ok_or_else(invalid)?;
+    let start = offset + LOCAL_HEADER_SIZE + usize::from(name_len) + usize::from(extra_len);
+    body.get(start..start + size).ok_or_else(invalid)
+}
+
+fn decode_entry(entry: &Entry, budget: Duration, max_bytes: u64) -> Result<Vec<u8>, CaptureError> {
+    if entry.flags & FLAG_ENCRYPTED != 0 {
+        return Err(CaptureError::RequestDecodingError(String::from(
+            "encrypted zip entries are not supported",
+        )));
+    }
+    let data = match entry.method {
+        METHOD_STORED => entry.data.to_vec(),
+        METHOD_DEFLATED => inflate_raw(entry.data, budget, max_bytes)?,
+        method => {
+            return Err(CaptureError::RequestDecodingError(format!(
+                "unsupported zip compression method {}",
+                method
+            )))
+        }
+    };
+
+    let mut crc = Crc::new();
+    crc.update(&data);
+    if data.len() as u64 != entry.uncompressed_size || crc.sum() != entry.crc {
+        return Err(CaptureError::RequestDecodingError(String::from(
+            "zip entry checksum mismatch",
+        )));
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::Engine;
+
+    use crate::api::CaptureError;
+    use crate::config::ProcessingConfig;
+    use crate::event::EventQuery;
+    use crate::zip::{parse_zip, ZipEvents};
+
+    // events/monday.json holding two pageviews, and events/tuesday.json holding one signup
+    const TWO_EVENT_FILES: &str = "UEsDBBQAAAAIAKU6Tl1CthbdQwAAAHsAAAASAAAAZXZlbnRzL21vbmRheS5qc29ui65WSi1LzStRslIqSExPLctMLVfSUUrJLC7JzEsuic9MAUqUFqcWGQJFEwsy47NTK4EiJfnZqXlKtTpEajbCpjkWAFBLAwQUAAAACAClOk5dGSGUKjgAAAA6AAAAEwAAAGV2ZW50cy90dWVzZGF5Lmpzb26rVkotS80rUbJSKs5MzystUNJRSsksLsnMSy6Jz0wBCpcWpxYZA0UTCzLjs1MrgSIl+dmpeUq1AFBLAQIUAxQAAAAIAKU6Tl1CthbdQwAAAHsAAAASAAAAAAAAAAAAAACAAQAAAABldmVudHMvbW9uZGF5Lmpzb25QSwECFAMUAAAACAClOk5dGSGUKjgAAAA6AAAAEwAAAAAAAAAAAAAAgAFzAAAAZXZlbnRzL3R1ZXNkYXkuanNvblBLBQYAAAAAAgACAIEAAADcAAAAAAA=";
+    // Offset of the deflated data of events/tuesday.json
+    const TUESDAY_DATA_OFFSET: usize = 164;
+
+    fn archive() -> Vec<u8> {
+        base64::engine::general_purpose::STANDARD
+            .decode(TWO_EVENT_FILES)
+            .unwrap()
+    }
+
+    fn parse(body: Vec<u8>, config: &ProcessingConfig) -> Result<ZipEvents, CaptureError> {
+        parse_zip(&EventQuery::default(), body.into(), config)
+    }
+
+    #[test]
+    fn two_event_files() {
+        let parsed = parse(archive(), &ProcessingConfig::default()).unwrap();
+
+        assert!(parsed.errors.is_empty());
+        let names: Vec<&str> = parsed.events.iter().map(|e| e.event.as_str()).collect();
+        assert_eq!(names, vec!["pageview", "pageview", "signup"]);
+        assert_eq!(
+            parsed.events[2].extract_distinct_id().as_deref(),
+            Some("user3")
+        );
+    }
+
+    #[test]
+    fn corrupt_entry() {
+        let mut body = archive();
+        body[TUESDAY_DATA_OFFSET + 10] ^= 0xff;
+
+        let parsed = parse(body, &ProcessingConfig::default()).unwrap();
+        assert_eq!(parsed.events.len(), 2);
+        assert_eq!(parsed.errors.len(), 1);
+        assert_eq!(parsed.errors[0].name, "events/tuesday.json");
+        assert!(matches!(
+            parsed.errors[0].error,
+            CaptureError::RequestDecodingError(_)
+        ));
+    }
+
+    #[test]
+    fn archive_limits() {
+        let config = ProcessingConfig {
+            max_zip_entries: 1,
+            ..Default::default()
+        };
+        assert!(matches!(
+            parse(archive(), &config),
+            Err(CaptureError::RequestDecodingError(_))
+        ));
+
+        // Both files are decompressed within the limit, but not together
+        let config = ProcessingConfig {
+            max_decompressed_bytes: Some(150),
+            ..Default::default()
+        };
+        assert!(matches!(
+            parse(archive(), &config),
+            Err(CaptureError::DecompressedTooLarge)
+        ));
+    }
+}
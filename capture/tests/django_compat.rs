@@ -6,12 +6,18 @@ use base64::engine::general_purpose;
 use base64::Engine;
 use capture::api::{CaptureError, CaptureResponse, CaptureResponseCode};
 use capture::billing_limits::BillingLimiter;
+use capture::capture::no_uuid_seen;
+use capture::config::ProcessingConfig;
+use capture::decode_metrics::NoopDecodeFailureMetrics;
 use capture::event::ProcessedEvent;
 use capture::health::HealthRegistry;
+use capture::ownership::AnyOwner;
 use capture::redis::MockRedisClient;
 use capture::router::router;
 use capture::sink::EventSink;
 use capture::time::TimeSource;
+use capture::token::AlwaysValid;
+use capture::user_agent::BasicUserAgentParser;
 use serde::Deserialize;
 use serde_json::{json, Value};
 use std::fs::File;
@@ -113,6 +119,12 @@ async fn it_matches_django_capture_behaviour() -> anyhow::Result<()> {
             sink.clone(),
             redis,
             billing,
+            ProcessingConfig::default(),
+            AlwaysValid {},
+            BasicUserAgentParser {},
+            AnyOwner {},
+            no_uuid_seen,
+            NoopDecodeFailureMetrics {},
             false,
         );
 
@@ -185,6 +197,16 @@ async fn it_matches_django_capture_behaviour() -> anyhow::Result<()> {
             if let Some(object) = expected.as_object_mut() {
                 // site_url is unused in the pipeline now, let's drop it
                 object.remove("site_url");
+                // Django does not set an ingest timestamp
+                object.insert(
+                    String::from("ingest_ts_nanos"),
+                    json!(message.ingest_ts_nanos),
+                );
+                // Nor the position of events within their request
+                object.insert(
+                    String::from("original_index"),
+                    json!(message.original_index),
+                );
             }
 
             let match_config = assert_json_diff::Config::new(assert_json_diff::CompareMode::Strict);
@@ -202,3 +224,82 @@ async fn it_matches_django_capture_behaviour() -> anyhow::Result<()> {
     assert_eq!(0, mismatches, "some events didn't match");
     Ok(())
 }
+
+fn test_client(sink: MemorySink, config: ProcessingConfig) -> TestClient {
+    let redis = Arc::new(MockRedisClient::new());
+    let billing = BillingLimiter::new(Duration::weeks(1), redis.clone())
+        .expect("failed to create billing limiter");
+    let app = router(
+        FixedTime {
+            time: String::from("2023-09-15T09:15:02.328551+00:00"),
+        },
+        HealthRegistry::new("dummy"),
+        sink,
+        redis,
+        billing,
+        config,
+        AlwaysValid {},
+        BasicUserAgentParser {},
+        AnyOwner {},
+        no_uuid_seen,
+        NoopDecodeFailureMetrics {},
+        false,
+    );
+    TestClient::new(app)
+}
+
+async fn post_event(client: &TestClient, body: Value, request_id: &str) -> StatusCode {
+    let res = client
+        .post("/i/v0/e/")
+        .header("Content-type", "application/json")
+        .header("X-Request-Id", request_id)
+        .header("X-Forwarded-For", "127.0.0.1")
+        .body(body.to_string())
+        .send()
+        .await;
+    res.status()
+}
+
+#[tokio::test]
+async fn repeated_request_ids_are_scoped_by_token() {
+    let sink = MemorySink::default();
+    let config = ProcessingConfig {
+        request_dedup_ttl_secs: Some(60),
+        ..Default::default()
+    };
+    let client = test_client(sink.clone(), config);
+
+    for token in ["token_a", "token_b", "token_a"] {
+        let body = json!({"token": token, "event": "pageview", "distinct_id": "user1"});
+        assert_eq!(post_event(&client, body, "batch-42").await, StatusCode::OK);
+    }
+
+    // The retry of token_a is skipped, the request of token_b is not
+    let tokens: Vec<String> = sink.events().into_iter().map(|event| event.token).collect();
+    assert_eq!(tokens, vec!["token_a", "token_b"]);
+}
+
+#[tokio::test]
+async fn tokenless_events_are_rejected_one_by_one() {
+    let sink = MemorySink::default();
+    let client = test_client(sink.clone(), ProcessingConfig::default());
+
+    let batch = json!([
+        {"token": "token_a", "event": "pageview", "distinct_id": "user1"},
+        {"event": "pageview", "distinct_id": "user2"},
+    ]);
+    assert_eq!(post_event(&client, batch, "batch-1").await, StatusCode::OK);
+    let distinct_ids: Vec<String> = sink
+        .events()
+        .into_iter()
+        .map(|event| event.distinct_id)
+        .collect();
+    assert_eq!(distinct_ids, vec!["user1"]);
+
+    // Nothing is left to ingest without any token
+    let tokenless = json!([{"event": "pageview", "distinct_id": "user2"}]);
+    assert_eq!(
+        post_event(&client, tokenless, "batch-2").await,
+        StatusCode::UNAUTHORIZED
+    );
+}